@@ -15,6 +15,10 @@ cargo fmt --all -- --check
 
 cargo test
 cargo test --release
+cargo test --no-default-features
+cargo test --features ptxed_verify
+cargo test -p hwtracer_core
+cargo test -p hwtracer_core --no-default-features
 
 which cargo-deny | cargo install cargo-deny
 cargo-deny check license
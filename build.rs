@@ -1,17 +1,30 @@
 use cc;
+#[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::__cpuid_count;
 use rerun_except::rerun_except;
 use std::env;
 use std::fs;
+use std::io::{self, Read};
 use std::os::unix::fs as unix_fs;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 const FEATURE_CHECKS_PATH: &str = "feature_checks";
 
+// Linux reports the number of LBR entries in this file, if the CPU has an LBR.
+const LBR_CAPS_PATH: &str = "/sys/bus/event_source/devices/cpu/caps/branches";
+
 const C_DEPS_DIR: &str = "c_deps";
 const C_DEPS_MAKEFILE: &str = "c_deps.mk";
 
+// The number of seconds that fetching or building libipt may take before the build is aborted,
+// unless overridden by `BUILD_TIMEOUT_ENV` (where 0 means no limit).
+const DEFAULT_BUILD_TIMEOUT: u64 = 1800;
+const BUILD_TIMEOUT_ENV: &str = "HWTRACER_BUILD_TIMEOUT";
+
 /// Simple feature check, returning `true` if we have the feature.
 ///
 /// The checks themselves are in files under `FEATURE_CHECKS_PATH`.
@@ -44,25 +57,86 @@ fn make_c_deps_dir() -> PathBuf {
     c_deps_dir
 }
 
-fn build_libipt(c_deps_dir: &Path) {
-    eprintln!("Building libipt...");
+/// Read all of `r` in a new thread, so that a child process writing to it can't block on a full
+/// pipe.
+fn drain<R: Read + Send + 'static>(mut r: R) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf).ok();
+        buf
+    })
+}
 
-    let prev_dir = env::current_dir().unwrap();
-    env::set_current_dir(&c_deps_dir).unwrap();
-    let res = Command::new("make")
-        .arg("-f")
-        .arg(C_DEPS_MAKEFILE)
-        .arg("libipt")
-        .output()
-        .unwrap_or_else(|_| panic!("Fatal error when building libipt"));
-    if !res.status.success() {
-        eprintln!("libipt build failed\n>>> stdout");
-        eprintln!("stdout: {}", String::from_utf8_lossy(&res.stdout));
+/// Run `make` with the arguments `args` in the current directory, to `what` (e.g. "build")
+/// libipt. The build is aborted if `make` fails, or if it runs for longer than the timeout (see
+/// `BUILD_TIMEOUT_ENV`), e.g. because a download has stalled.
+fn run_make(args: &[&str], what: &str) {
+    let timeout = match env::var(BUILD_TIMEOUT_ENV) {
+        Ok(val) => val.parse::<u64>().unwrap_or_else(|_| {
+            panic!(
+                "{} must be a number of seconds, not {:?}",
+                BUILD_TIMEOUT_ENV, val
+            )
+        }),
+        Err(_) => DEFAULT_BUILD_TIMEOUT,
+    };
+    let mut cmd = Command::new("make");
+    cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+    // Put `make` in a process group of its own, so that the processes it starts (e.g. a stalled
+    // download) can be killed along with it.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd
+        .spawn()
+        .unwrap_or_else(|_| panic!("Fatal error when trying to {} libipt", what));
+    let stdout = drain(child.stdout.take().unwrap());
+    let stderr = drain(child.stderr.take().unwrap());
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if timeout != 0 && Instant::now() >= deadline {
+            // The ID of the process group is that of `make`.
+            unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+            child.wait().ok();
+            // Processes which left the group may still hold the pipes open, so its output can't
+            // be waited for.
+            panic!(
+                "Failed to {} libipt within {} seconds (set {} to change this limit)",
+                what, timeout, BUILD_TIMEOUT_ENV
+            );
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        eprintln!("libipt {} failed\n>>> stdout", what);
+        eprintln!(
+            "stdout: {}",
+            String::from_utf8_lossy(&stdout.join().unwrap())
+        );
         eprintln!("\n>>> stderr");
-        eprintln!("stderr: {}", String::from_utf8_lossy(&res.stderr));
+        eprintln!(
+            "stderr: {}",
+            String::from_utf8_lossy(&stderr.join().unwrap())
+        );
         panic!();
     }
+}
+
+fn build_libipt(c_deps_dir: &Path) {
+    eprintln!("Building libipt...");
 
+    let prev_dir = env::current_dir().unwrap();
+    env::set_current_dir(&c_deps_dir).unwrap();
+    run_make(&["-f", C_DEPS_MAKEFILE, "libipt"], "build");
     env::set_current_dir(&prev_dir).unwrap();
 }
 
@@ -73,28 +147,49 @@ fn fetch_libipt(c_deps_dir: &Path) {
 
     let prev_dir = env::current_dir().unwrap();
     env::set_current_dir(c_deps_dir).unwrap();
-    let res = Command::new("make")
-        .arg("processor-trace") // target just fetches the code.
-        .output()
-        .unwrap_or_else(|_| panic!("Fatal error when fetching libipt"));
-    if !res.status.success() {
-        eprintln!("libipt fetch failed\n>>> stdout");
-        eprintln!("stdout: {}", String::from_utf8_lossy(&res.stdout));
-        eprintln!("\n>>> stderr");
-        eprintln!("stderr: {}", String::from_utf8_lossy(&res.stderr));
-        panic!();
-    }
-
+    run_make(&["processor-trace"], "fetch"); // target just fetches the code.
     env::set_current_dir(&prev_dir).unwrap();
 }
 
 // Checks if the CPU supports Intel Processor Trace.
+#[cfg(target_arch = "x86_64")]
 fn cpu_supports_pt() -> bool {
     let res = unsafe { __cpuid_count(0x7, 0x0) };
     (res.ebx & (1 << 25)) != 0
 }
 
+#[cfg(not(target_arch = "x86_64"))]
+fn cpu_supports_pt() -> bool {
+    false
+}
+
+// Checks if the system supports sampling the Last Branch Record.
+fn system_supports_lbr() -> bool {
+    fs::read_to_string(LBR_CAPS_PATH)
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .map_or(false, |depth| depth > 0)
+}
+
 fn main() {
+    // Additional circumstances under which to re-run this build.rs.
+    println!("cargo:rerun-if-env-changed=IPT_PATH");
+    println!("cargo:rerun-if-env-changed={}", BUILD_TIMEOUT_ENV);
+    rerun_except(&[
+        "README.md",
+        "deny.toml",
+        "LICENSE-*",
+        "COPYRIGHT",
+        "bors.toml",
+        ".buildbot.sh",
+    ])
+    .unwrap();
+
+    // Without the `c_backends` feature there's no C code to build, and no backends to detect.
+    if env::var_os("CARGO_FEATURE_C_BACKENDS").is_none() {
+        return;
+    }
+
     let mut c_build = cc::Build::new();
 
     let c_deps_dir = make_c_deps_dir();
@@ -147,18 +242,24 @@ fn main() {
         }
         println!("cargo:rustc-link-lib=static=ipt");
     }
+
+    // Check if we should build the CoreSight backend.
+    if cfg!(all(target_os = "linux", target_arch = "aarch64"))
+        && feature_check("check_coresight.c", "check_coresight")
+    {
+        c_build.file("src/backends/coresight/collect.c");
+        println!("cargo:rustc-cfg=coresight");
+    }
+    // Check if we should build the LBR backend.
+    if cfg!(all(target_os = "linux", target_arch = "x86_64"))
+        && feature_check("check_lbr.c", "check_lbr")
+    {
+        c_build.file("src/backends/lbr/collect.c");
+        println!("cargo:rustc-cfg=lbr");
+        if system_supports_lbr() {
+            println!("cargo:rustc-cfg=lbr_test");
+        }
+    }
     c_build.include("src/util");
     c_build.compile("hwtracer_c");
-
-    // Additional circumstances under which to re-run this build.rs.
-    println!("cargo:rerun-if-env-changed=IPT_PATH");
-    rerun_except(&[
-        "README.md",
-        "deny.toml",
-        "LICENSE-*",
-        "COPYRIGHT",
-        "bors.toml",
-        ".buildbot.sh",
-    ])
-    .unwrap();
 }
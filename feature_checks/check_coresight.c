@@ -0,0 +1,8 @@
+#include <linux/perf_event.h>
+
+int
+check(void)
+{
+    // The perf configuration struct version that first supported AUX tracing.
+    return PERF_ATTR_SIZE_VER5;
+}
@@ -0,0 +1,10 @@
+#include <linux/perf_event.h>
+
+int
+check(void)
+{
+    // Branch stack sampling, as used to read the LBR.
+    struct perf_branch_entry entry;
+    (void) entry;
+    return PERF_SAMPLE_BRANCH_STACK | PERF_SAMPLE_BRANCH_USER | PERF_SAMPLE_BRANCH_ANY;
+}
@@ -0,0 +1,742 @@
+//! The data types of hwtracer, such as `Block`, and the parts of its traits, such as `RawTrace`,
+//! which don't depend on `std`.
+//!
+//! These are re-exported by hwtracer, which should normally be used instead. This crate is for
+//! code which only needs the data types, in environments without `std` (e.g. analysing traces on
+//! embedded devices). With the `alloc` feature (enabled by default), a few methods which allocate
+//! (e.g. `Block::to_hex_string()`) are also available.
+
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+use alloc::string::{String, ToString};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Display, Formatter};
+use core::time::Duration;
+
+/// Information about a basic block.
+///
+/// Blocks display as the addresses of their first and last instructions, e.g.
+/// `[0x1000..0x1010]`.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct Block {
+    /// Virtual address of the first instruction in this block.
+    first_instr: u64,
+    /// Virtual address of the last instruction in this block.
+    last_instr: u64,
+}
+
+impl Block {
+    /// Creates a new basic block from the addresses of its first and last instructions.
+    pub fn new(first_instr: u64, last_instr: u64) -> Self {
+        Self {
+            first_instr,
+            last_instr,
+        }
+    }
+
+    /// Creates a new basic block from the address of its first instruction and its length in
+    /// bytes, as returned by `len()`: i.e. the last instruction is at `first_instr + len`.
+    pub fn from_len(first_instr: u64, len: u64) -> Self {
+        Self::new(first_instr, first_instr + len)
+    }
+
+    /// Returns the virtual address of the first instruction in this block.
+    pub fn first_instr(&self) -> u64 {
+        self.first_instr
+    }
+
+    /// Returns the virtual address of the last instruction in this block.
+    pub fn last_instr(&self) -> u64 {
+        self.last_instr
+    }
+
+    /// Returns the distance in bytes between the first and last instructions of this block.
+    ///
+    /// Note that this doesn't include the length of the last instruction itself.
+    #[allow(clippy::len_without_is_empty)] // A block always contains at least one instruction.
+    pub fn len(&self) -> u64 {
+        self.last_instr - self.first_instr
+    }
+
+    /// Returns `true` if `addr` lies between the first and last instructions (inclusive) of this
+    /// block.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.first_instr && addr <= self.last_instr
+    }
+
+    /// Returns the block formatted as by `Display`, e.g. `[0x1000..0x1010]`.
+    #[cfg(feature = "alloc")]
+    pub fn to_hex_string(&self) -> String {
+        self.to_string()
+    }
+
+    /// Returns the path of the module (e.g. the executable or a shared object) containing the
+    /// first instruction of this block in `maps`, and the offset of the instruction in the
+    /// module's file, or `None` if it isn't in a named mapping.
+    ///
+    /// Unlike addresses, which change from run to run with ASLR, these identify the same code in
+    /// different runs of a program, so they can be used to compare traces of those runs.
+    #[cfg(feature = "alloc")]
+    pub fn module_offset(&self, maps: &Maps) -> Option<(String, u64)> {
+        maps.find(self.first_instr)
+            .map(|m| (m.path().to_string(), m.file_offset(self.first_instr)))
+    }
+}
+
+impl Display for Block {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "[0x{:x}..0x{:x}]", self.first_instr, self.last_instr)
+    }
+}
+
+// Addresses are much easier to read in hex.
+impl Debug for Block {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Block")
+            .field("first_instr", &format_args!("0x{:x}", self.first_instr))
+            .field("last_instr", &format_args!("0x{:x}", self.last_instr))
+            .finish()
+    }
+}
+
+/// Statistics about the quality of a trace. See `Trace::stats()`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TraceStats {
+    /// The number of blocks successfully decoded.
+    pub decoded_blocks: u64,
+    /// The number of instructions executed in the blocks which were decoded, or 0 if the backend
+    /// can't count them.
+    pub retired_instrs: u64,
+    /// The number of errors encountered while decoding, excluding overflows.
+    pub decode_errors: u64,
+    /// The number of times the hardware trace buffer overflowed.
+    pub overflow_events: u64,
+    /// The size of the raw trace data (in bytes), or 0 if the backend doesn't retain raw data.
+    pub bytes: usize,
+}
+
+/// How long it took to collect and decode a trace. See `Trace::timing()`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TraceTiming {
+    /// The wall-clock time for which the tracer was running, or `None` if unknown.
+    pub collection: Option<Duration>,
+    /// The time spent decoding the trace the first time its blocks were iterated over, or `None`
+    /// if unknown or if the trace hasn't been fully decoded yet. This excludes the time spent by
+    /// the consumer of the iterator.
+    pub decoding: Option<Duration>,
+}
+
+/// The kind of control flow transfer which ends a block. See `Trace::iter_classified_blocks()`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum EdgeKind {
+    /// A near call.
+    Call,
+    /// A near return.
+    Return,
+    /// A near unconditional jump, either direct or indirect.
+    Jump,
+    /// A near conditional jump.
+    ConditionalJump,
+    /// A far call (e.g. a system call).
+    FarCall,
+    /// A far return (e.g. a return from an interrupt).
+    FarReturn,
+    /// A far jump.
+    FarJump,
+    /// The kind of transfer is unknown.
+    Unknown,
+}
+
+/// How the hardware compressed the IP in the packet which gave the target of an indirect branch,
+/// by sending only the bits which differ from the previous IP. See
+/// `ClassifiedBlock::ip_compression()`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IpCompression {
+    /// Only the low 16 bits were sent.
+    Update16,
+    /// Only the low 32 bits were sent.
+    Update32,
+    /// The low 48 bits were sent, and sign-extended.
+    SignExtend48,
+    /// Only the low 48 bits were sent.
+    Update48,
+    /// The full 64-bit IP was sent.
+    Full,
+}
+
+/// How the target of a return was found. See `ClassifiedBlock::return_kind()`.
+///
+/// With return compression, when a return goes back to the instruction after its call, Intel PT
+/// records only that the return was taken, and the decoder finds its target from the calls it
+/// has seen. A compressed return is therefore only as trustworthy as the decoder's call stack.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ReturnKind {
+    /// The target was inferred from the decoder's call stack.
+    Compressed,
+    /// The target was given by a packet in the trace.
+    Observed,
+}
+
+/// An IP carried by an Intel PT packet. See `PtPacket`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PacketIp {
+    /// How the IP was compressed.
+    pub ipc: IpCompression,
+    /// The bits of the IP which were sent (as given by `ipc`). The remaining bits are those of
+    /// the previous IP in the packet stream, which aren't filled in here.
+    pub ip: u64,
+}
+
+impl PacketIp {
+    /// Returns the full IP, by filling in the bits which weren't sent from `last_ip`, the full IP
+    /// of the previous IP-carrying packet in the packet stream.
+    pub fn full_ip(&self, last_ip: u64) -> u64 {
+        match self.ipc {
+            IpCompression::Update16 => (last_ip & !0xffff) | (self.ip & 0xffff),
+            IpCompression::Update32 => (last_ip & !0xffff_ffff) | (self.ip & 0xffff_ffff),
+            IpCompression::Update48 => (last_ip & !0xffff_ffff_ffff) | (self.ip & 0xffff_ffff_ffff),
+            IpCompression::SignExtend48 => (((self.ip << 16) as i64) >> 16) as u64,
+            IpCompression::Full => self.ip,
+        }
+    }
+}
+
+/// An Intel PT packet. See `Trace::iter_packets()`.
+///
+/// Only the most common kinds of packet are modelled: the rest are reported as `Other`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PtPacket {
+    /// A Packet Stream Boundary, at which decoding can start.
+    Psb,
+    /// The end of the packets following a PSB packet.
+    PsbEnd,
+    /// Padding.
+    Pad,
+    /// The hardware's internal buffers overflowed, so packets were lost.
+    Ovf,
+    /// The outcomes of `count` conditional branches (or compressed returns), as bits which are
+    /// set for taken branches. The oldest branch is in bit `count - 1`.
+    Tnt { count: u8, bits: u64 },
+    /// The target of an indirect branch, or `None` if the IP was suppressed.
+    Tip(Option<PacketIp>),
+    /// Tracing was enabled, at the given IP.
+    TipPge(Option<PacketIp>),
+    /// Tracing was disabled, at the given IP.
+    TipPgd(Option<PacketIp>),
+    /// The source IP of an asynchronous event (e.g. an interrupt).
+    Fup(Option<PacketIp>),
+    /// The execution mode: `csl` is set in 64-bit mode, and `csd` for 32-bit default operands.
+    ModeExec { csl: bool, csd: bool },
+    /// The transactional memory state.
+    ModeTsx { intx: bool, abort: bool },
+    /// A time stamp counter value.
+    Tsc(u64),
+    /// The number of cycles since the last CYC packet.
+    Cyc(u64),
+    /// The low bits of the crystal clock counter.
+    Mtc(u8),
+    /// The core:bus clock ratio.
+    Cbr(u8),
+    /// The operand of a PTWRITE instruction. If `ip` is set, then the next FUP packet gives the
+    /// address of the instruction.
+    Ptw { payload: u64, ip: bool },
+    /// Any other kind of packet.
+    Other,
+}
+
+/// The vendor of a CPU. See `PtCpu`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PtCpuVendor {
+    Intel,
+    /// The vendor couldn't be determined.
+    Unknown,
+}
+
+/// The kind of CPU which collected an Intel PT trace. See `Trace::pt_cpu()`.
+///
+/// Microarchitectures differ in their errata, which the decoder must work around, so a trace is
+/// decoded for the CPU which collected it rather than the one decoding it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PtCpu {
+    pub vendor: PtCpuVendor,
+    pub family: u16,
+    pub model: u8,
+    pub stepping: u8,
+}
+
+/// A basic block paired with the kind of control flow transfer which ended it.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ClassifiedBlock {
+    block: Block,
+    edge_kind: EdgeKind,
+    ip_compression: Option<IpCompression>,
+    return_kind: Option<ReturnKind>,
+}
+
+impl ClassifiedBlock {
+    /// Creates a new classified block from a block and the kind of transfer which ended it.
+    pub fn new(block: Block, edge_kind: EdgeKind) -> Self {
+        Self {
+            block,
+            edge_kind,
+            ip_compression: None,
+            return_kind: None,
+        }
+    }
+
+    /// Returns the block, recording that its start address came from a packet whose IP was
+    /// compressed as `ipc`.
+    pub fn with_ip_compression(self, ipc: IpCompression) -> Self {
+        Self {
+            ip_compression: Some(ipc),
+            ..self
+        }
+    }
+
+    /// Returns the block, recording that the return which ended it was of kind `rk`.
+    pub fn with_return_kind(self, rk: ReturnKind) -> Self {
+        Self {
+            return_kind: Some(rk),
+            ..self
+        }
+    }
+
+    /// Returns the block.
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+
+    /// Returns the kind of control flow transfer made by the block's last instruction.
+    pub fn edge_kind(&self) -> EdgeKind {
+        self.edge_kind
+    }
+
+    /// If the block's start address was the target of an indirect branch (or of a far transfer),
+    /// as given by a trace packet, returns how the hardware compressed that address. Returns
+    /// `None` for blocks reached otherwise, and for backends which can't tell.
+    pub fn ip_compression(&self) -> Option<IpCompression> {
+        self.ip_compression
+    }
+
+    /// If the block ended with a near return, returns how the return's target was found. Returns
+    /// `None` for blocks ending otherwise, and for backends which can't tell.
+    pub fn return_kind(&self) -> Option<ReturnKind> {
+        self.return_kind
+    }
+}
+
+/// A basic block paired with the time at which it was executed.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TimedBlock {
+    block: Block,
+    tsc: Option<u64>,
+}
+
+impl TimedBlock {
+    /// Creates a new timed block from a block and an optional time stamp counter value.
+    pub fn new(block: Block, tsc: Option<u64>) -> Self {
+        Self { block, tsc }
+    }
+
+    /// Returns the block.
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+
+    /// Returns the approximate value of the CPU's time stamp counter when the block was executed,
+    /// or `None` if the trace contains no timing information at this point.
+    pub fn tsc(&self) -> Option<u64> {
+        self.tsc
+    }
+}
+
+/// A named memory mapping of a process, such as part of a shared object. See `Maps`.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Mapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    path: String,
+}
+
+#[cfg(feature = "alloc")]
+impl Mapping {
+    /// Creates a mapping of the addresses from `start` up to (but excluding) `end` to the file
+    /// `path`, starting at the offset `offset` in the file. Pseudo-files such as the VDSO have
+    /// names like `[vdso]`.
+    pub fn new(start: u64, end: u64, offset: u64, path: String) -> Self {
+        Self {
+            start,
+            end,
+            offset,
+            path,
+        }
+    }
+
+    /// Returns the first address of the mapping.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the address after the last address of the mapping.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the offset in the file of the start of the mapping.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the path of the mapped file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns `true` if `addr` is in the mapping.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Returns the offset in the file of the byte mapped at `addr`, which must be in the mapping.
+    pub fn file_offset(&self, addr: u64) -> u64 {
+        debug_assert!(self.contains(addr));
+        self.offset + (addr - self.start)
+    }
+}
+
+/// The named memory mappings of a process, e.g. as listed in `/proc/<pid>/maps`. See
+/// `Block::module_offset()`.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Maps {
+    // Sorted by start address.
+    mappings: Vec<Mapping>,
+}
+
+#[cfg(feature = "alloc")]
+impl Maps {
+    /// Creates a set of mappings from `mappings`, which mustn't overlap.
+    pub fn new(mut mappings: Vec<Mapping>) -> Self {
+        mappings.sort_by_key(|m| m.start);
+        Self { mappings }
+    }
+
+    /// Returns the mappings, in order of address.
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// Returns the mapping containing `addr`, or `None` if there isn't one.
+    pub fn find(&self, addr: u64) -> Option<&Mapping> {
+        let i = match self.mappings.binary_search_by_key(&addr, |m| m.start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some(&self.mappings[i]).filter(|m| m.contains(addr))
+    }
+}
+
+/// The parts of a trace which don't depend on `std`: its raw data and how it was collected.
+///
+/// hwtracer's `Trace` trait extends this with the decoding of the trace, which needs `std`.
+pub trait RawTrace {
+    /// Get the capacity of the trace in bytes.
+    ///
+    /// This is the amount of memory allocated to store the trace, which may exceed the size of
+    /// the trace data itself. For backends which keep the raw trace, the size of the data is
+    /// `raw_data().unwrap().len()`.
+    fn capacity(&self) -> usize;
+
+    /// Returns the most bytes of trace data which were waiting at once in the buffer that the
+    /// hardware writes into during collection (e.g. the AUX buffer of the PerfPT backend), or
+    /// `None` if this isn't known. Compared with `hw_buffer_size()`, this shows how close the
+    /// trace came to overflowing, to help size the buffer for a workload.
+    ///
+    /// Backends which don't record this, and traces which were deserialized, return `None`.
+    fn bytes_used(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the size in bytes of the buffer that the hardware wrote the trace into during
+    /// collection, or `None` if this isn't known. See `bytes_used()`.
+    fn hw_buffer_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the raw trace data as collected from the hardware, before any decoding.
+    ///
+    /// The format is backend-specific. Returns `None` for backends that don't retain raw data.
+    fn raw_data(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Returns the offset in `raw_data()` at which decoding starts, or `None` if there's nowhere
+    /// to start or the backend doesn't retain raw data. Data before the offset can't be decoded,
+    /// so the first block is the first one recorded after it.
+    fn sync_offset(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the kind of CPU which collected the trace, for backends which collect Intel PT
+    /// traces, or `None` otherwise. Such traces are decoded for this CPU, so they can be decoded
+    /// on a machine with a different microarchitecture.
+    fn pt_cpu(&self) -> Option<PtCpu> {
+        None
+    }
+
+    /// Returns `true` if the hardware trace buffer overflowed during collection.
+    ///
+    /// An overflowed trace contains only the blocks executed up until the overflow. Its block
+    /// iterator yields an error (hwtracer's `HWTracerError::HWBufferOverflow`) instead of
+    /// silently ending.
+    fn overflowed(&self) -> bool;
+
+    /// Returns `true` if collection stopped before the tracer did, because the trace reached a
+    /// size limit set in the backend's configuration (e.g. `PerfPTConfig::max_bytes`).
+    ///
+    /// An auto-stopped trace contains only the blocks executed before collection stopped. Unlike
+    /// an overflowed trace, its block iterator ends without error.
+    fn auto_stopped(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if the tracing hardware was periodically turned off during collection (see
+    /// `PerfPTConfig::duty_cycle()`), so that the trace holds only samples of the execution.
+    ///
+    /// The blocks of a duty-cycled trace are those of disjoint segments of execution, one after
+    /// another. The block iterator reports each gap between segments as an error (hwtracer's
+    /// `HWTracerError::TracingDisabled`), so e.g. `iter_edges()` yields no edge across it.
+    fn duty_cycled(&self) -> bool {
+        false
+    }
+}
+
+/// Collects traces of a single thread, without depending on `std`.
+///
+/// hwtracer's `ThreadTracer` trait extends this, collecting a `Box<dyn Trace>` and reporting
+/// errors as `HWTracerError`s.
+pub trait RawThreadTracer {
+    /// The trace collected by the tracer.
+    type Trace;
+    /// The error reported if the tracer can't be started or stopped.
+    type Error;
+
+    /// Start recording a trace.
+    ///
+    /// Tracing continues until `stop_tracing()` is called.
+    fn start_tracing(&mut self) -> Result<(), Self::Error>;
+
+    /// Turns off the tracer.
+    ///
+    /// `start_tracing()` must have been called prior. The returned trace isn't decoded yet.
+    fn stop_tracing(&mut self) -> Result<Self::Trace, Self::Error>;
+
+    /// Returns `true` if the tracer has been started and not yet stopped.
+    ///
+    /// The default implementation knows nothing of the tracer's state and returns `false`, so
+    /// backends should override it.
+    fn is_tracing(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{
+        Block, ClassifiedBlock, EdgeKind, IpCompression, PacketIp, RawThreadTracer, RawTrace,
+        ReturnKind,
+    };
+    #[cfg(feature = "alloc")]
+    use super::{Mapping, Maps};
+    use std::collections::HashMap;
+    use std::format;
+    use std::string::ToString;
+
+    #[test]
+    fn test_block_len() {
+        assert_eq!(Block::new(0x1000, 0x1010).len(), 0x10);
+        assert_eq!(Block::new(0x1000, 0x1000).len(), 0);
+    }
+
+    #[test]
+    fn test_block_from_len() {
+        assert_eq!(Block::from_len(0x1000, 0x10), Block::new(0x1000, 0x1010));
+        assert_eq!(Block::from_len(0x1000, 0), Block::new(0x1000, 0x1000));
+        assert_eq!(Block::from_len(0x1000, 0x10).len(), 0x10);
+    }
+
+    #[test]
+    fn test_block_fmt() {
+        let block = Block::new(0x1000, 0x1010);
+        assert_eq!(block.to_string(), "[0x1000..0x1010]");
+        #[cfg(feature = "alloc")]
+        assert_eq!(block.to_hex_string(), "[0x1000..0x1010]");
+        assert_eq!(
+            format!("{:?}", block),
+            "Block { first_instr: 0x1000, last_instr: 0x1010 }"
+        );
+    }
+
+    #[test]
+    fn test_classified_block_ip_compression() {
+        let blk = Block::new(0x1000, 0x1010);
+        let cb = ClassifiedBlock::new(blk, EdgeKind::Call);
+        assert_eq!(cb.ip_compression(), None);
+        let cb = cb.with_ip_compression(IpCompression::Update16);
+        assert_eq!(cb.ip_compression(), Some(IpCompression::Update16));
+        assert_eq!(cb.block(), &blk);
+        assert_eq!(cb.edge_kind(), EdgeKind::Call);
+    }
+
+    #[test]
+    fn test_classified_block_return_kind() {
+        let blk = Block::new(0x1000, 0x1010);
+        let cb = ClassifiedBlock::new(blk, EdgeKind::Return);
+        assert_eq!(cb.return_kind(), None);
+        let cb = cb
+            .with_ip_compression(IpCompression::Full)
+            .with_return_kind(ReturnKind::Compressed);
+        assert_eq!(cb.return_kind(), Some(ReturnKind::Compressed));
+        assert_eq!(cb.ip_compression(), Some(IpCompression::Full));
+    }
+
+    #[test]
+    fn test_block_contains() {
+        let blk = Block::new(0x1000, 0x1010);
+        assert!(blk.contains(0x1000));
+        assert!(blk.contains(0x1008));
+        assert!(blk.contains(0x1010));
+        assert!(!blk.contains(0xfff));
+        assert!(!blk.contains(0x1011));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_block_module_offset() {
+        let maps = Maps::new(std::vec![
+            Mapping::new(0x5000, 0x6000, 0x1000, "/lib/libb.so".to_string()),
+            Mapping::new(0x1000, 0x3000, 0, "/bin/a".to_string()),
+        ]);
+        assert_eq!(maps.mappings()[0].path(), "/bin/a");
+        let offset = |addr| Block::new(addr, addr + 0x10).module_offset(&maps);
+        assert_eq!(offset(0x1000), Some(("/bin/a".to_string(), 0)));
+        assert_eq!(offset(0x2fff), Some(("/bin/a".to_string(), 0x1fff)));
+        assert_eq!(offset(0x5010), Some(("/lib/libb.so".to_string(), 0x1010)));
+        assert_eq!(offset(0xfff), None);
+        assert_eq!(offset(0x3000), None);
+        assert_eq!(offset(0x6000), None);
+        assert_eq!(
+            Block::new(0x1000, 0x1010).module_offset(&Maps::default()),
+            None
+        );
+    }
+
+    // Check that blocks can be used as keys for counting purposes.
+    #[test]
+    fn test_block_hash() {
+        let mut counts = HashMap::new();
+        for blk in &[Block::new(1, 2), Block::new(3, 4), Block::new(1, 2)] {
+            *counts.entry(*blk).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&Block::new(1, 2)], 2);
+        assert_eq!(counts[&Block::new(3, 4)], 1);
+    }
+
+    #[test]
+    fn test_packet_ip_full_ip() {
+        let last = 0x7fff_1234_5678;
+        let pip = |ipc, ip| PacketIp { ipc, ip };
+        assert_eq!(
+            pip(IpCompression::Update16, 0xabcd).full_ip(last),
+            0x7fff_1234_abcd
+        );
+        assert_eq!(
+            pip(IpCompression::Update32, 0xabcd_ef01).full_ip(last),
+            0x7fff_abcd_ef01
+        );
+        assert_eq!(
+            pip(IpCompression::Update48, 0x1111_2222_3333).full_ip(u64::max_value()),
+            0xffff_1111_2222_3333
+        );
+        assert_eq!(
+            pip(IpCompression::SignExtend48, 0x8000_0000_1000).full_ip(last),
+            0xffff_8000_0000_1000
+        );
+        assert_eq!(
+            pip(IpCompression::SignExtend48, 0x4000_0000_1000).full_ip(last),
+            0x4000_0000_1000
+        );
+        assert_eq!(pip(IpCompression::Full, 0x1000).full_ip(last), 0x1000);
+    }
+
+    // A tracer which "collects" a fixed buffer, as a tracer without `std` might.
+    struct BufTracer {
+        tracing: bool,
+    }
+
+    struct BufTrace(&'static [u8]);
+
+    impl RawTrace for BufTrace {
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+
+        fn raw_data(&self) -> Option<&[u8]> {
+            Some(self.0)
+        }
+
+        fn overflowed(&self) -> bool {
+            false
+        }
+    }
+
+    impl RawThreadTracer for BufTracer {
+        type Trace = BufTrace;
+        type Error = ();
+
+        fn start_tracing(&mut self) -> Result<(), ()> {
+            if self.tracing {
+                return Err(());
+            }
+            self.tracing = true;
+            Ok(())
+        }
+
+        fn stop_tracing(&mut self) -> Result<BufTrace, ()> {
+            if !self.tracing {
+                return Err(());
+            }
+            self.tracing = false;
+            Ok(BufTrace(&[1, 2, 3]))
+        }
+
+        fn is_tracing(&self) -> bool {
+            self.tracing
+        }
+    }
+
+    #[test]
+    fn test_raw_tracer() {
+        let mut tracer = BufTracer { tracing: false };
+        assert_eq!(tracer.stop_tracing().err(), Some(()));
+        tracer.start_tracing().unwrap();
+        assert!(tracer.is_tracing());
+        assert_eq!(tracer.start_tracing(), Err(()));
+        let trace = tracer.stop_tracing().unwrap();
+        assert!(!tracer.is_tracing());
+        assert_eq!(trace.capacity(), 3);
+        assert_eq!(trace.raw_data(), Some(&[1, 2, 3][..]));
+        assert_eq!(trace.sync_offset(), None);
+        assert!(!trace.overflowed() && !trace.auto_stopped() && !trace.duty_cycled());
+    }
+}
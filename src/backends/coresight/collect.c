@@ -0,0 +1,364 @@
+// Copyright (c) 2020 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/*
+ * A minimal collector for Arm CoreSight ETM traces, using the Linux perf AUX
+ * interface to the `cs_etm` PMU.
+ *
+ * Unlike the perf_pt collector, there is no thread copying data out of the
+ * AUX buffer while tracing is running. The trace is copied out only when
+ * tracing stops, so a trace can be no larger than the AUX buffer.
+ */
+
+#define _GNU_SOURCE
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+#include <string.h>
+#include <errno.h>
+#include <syscall.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdatomic.h>
+#include <sys/mman.h>
+#include <sys/ioctl.h>
+#include <linux/perf_event.h>
+
+#define SYSFS_CS_ETM_TYPE   "/sys/bus/event_source/devices/cs_etm/type"
+#define MAX_CS_ETM_TYPE_STR 8
+
+/*
+ * Must stay in sync with the Rust-side.
+ */
+enum cs_cerror_kind {
+    cs_cerror_unused,
+    cs_cerror_unknown,
+    cs_cerror_errno,
+};
+
+struct cs_cerror {
+    enum cs_cerror_kind kind; // What sort of error is this?
+    int code;                 // The error code itself.
+};
+
+/*
+ * Passed from Rust to C to configure tracing.
+ * Must stay in sync with the Rust-side.
+ */
+struct cs_config {
+    size_t      data_bufsize;          // Data buf size (in pages).
+    size_t      aux_bufsize;           // AUX buf size (in pages).
+};
+
+/*
+ * Storage for a trace. The buffer is malloc(3)d by C and free(3)d by Rust.
+ *
+ * Shared with Rust code. Must stay in sync.
+ */
+struct cs_trace {
+    void *buf;
+    uint64_t len;
+    bool overflowed;
+};
+
+/*
+ * Stores all information about the tracer.
+ * Exposed to Rust only as an opaque pointer.
+ */
+struct cs_tracer_ctx {
+    int                 perf_fd;            // FD used to talk to the perf API.
+    void                *aux_buf;           // Ptr to the start of the the AUX buffer.
+    size_t              aux_bufsize;        // The size of the AUX buffer's mmap(2).
+    void                *base_buf;          // Ptr to the start of the base buffer.
+    size_t              base_bufsize;       // The size the base buffer's mmap(2).
+};
+
+// See the corresponding struct in the perf_pt collector.
+struct perf_record_aux_sample {
+    struct perf_event_header header;
+    __u64    aux_offset;
+    __u64    aux_size;
+    __u64    flags;
+};
+
+// Private prototypes.
+static void cs_set_err(struct cs_cerror *, int, int);
+static int open_perf(struct cs_cerror *);
+static bool aux_truncated(struct perf_event_mmap_page *);
+
+// Exposed Prototypes.
+struct cs_tracer_ctx *cs_init_tracer(struct cs_config *, struct cs_cerror *);
+bool cs_start_tracer(struct cs_tracer_ctx *, struct cs_cerror *);
+bool cs_stop_tracer(struct cs_tracer_ctx *, struct cs_trace *, struct cs_cerror *);
+bool cs_free_tracer(struct cs_tracer_ctx *, struct cs_cerror *);
+
+/*
+ * Sets the error information (if not already set).
+ */
+static void
+cs_set_err(struct cs_cerror *err, int kind, int code) {
+    if (err->kind == cs_cerror_unused) {
+        err->kind = kind;
+        err->code = code;
+    }
+}
+
+/*
+ * Opens the perf file descriptor for the calling thread and returns it.
+ *
+ * Returns a file descriptor, or -1 on error.
+ */
+static int
+open_perf(struct cs_cerror *err) {
+    struct perf_event_attr attr;
+    memset(&attr, 0, sizeof(attr));
+    attr.size = sizeof(attr);
+
+    // Get the perf "type" for CoreSight ETM.
+    FILE *type_file = fopen(SYSFS_CS_ETM_TYPE, "r");
+    if (type_file == NULL) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        return -1;
+    }
+    char type_str[MAX_CS_ETM_TYPE_STR];
+    if (fgets(type_str, sizeof(type_str), type_file) == NULL) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        fclose(type_file);
+        return -1;
+    }
+    if (fclose(type_file) == -1) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        return -1;
+    }
+    attr.type = atoi(type_str);
+
+    // Exclude the kernel and hyper-visor and start disabled.
+    //
+    // XXX We leave the choice of ETM sink (attr.config2) to the kernel.
+    attr.exclude_kernel = 1;
+    attr.exclude_hv = 1;
+    attr.disabled = 1;
+
+    pid_t target_tid = syscall(__NR_gettid);
+    int fd = syscall(SYS_perf_event_open, &attr, target_tid, -1, -1, 0);
+    if (fd == -1) {
+        cs_set_err(err, cs_cerror_errno, errno);
+    }
+    return fd;
+}
+
+/*
+ * Scan the records in the data buffer (whose header is `hdr`) and decide if
+ * the kernel truncated any of the data written into the AUX buffer.
+ */
+static bool
+aux_truncated(struct perf_event_mmap_page *hdr)
+{
+    void *data = (void *) hdr + hdr->data_offset;
+    __u64 head = atomic_load_explicit((_Atomic __u64 *) &hdr->data_head,
+                                      memory_order_acquire);
+    __u64 size = hdr->data_size;
+    __u64 tail = hdr->data_tail;
+
+    // PERF_RECORD_AUX records are small and naturally aligned, so a record
+    // never straddles the end of the ring buffer.
+    while (tail < head) {
+        struct perf_event_header *rec_hdr = data + (tail % size);
+        if (rec_hdr->size == 0) {
+            break;
+        }
+        if (rec_hdr->type == PERF_RECORD_AUX) {
+            struct perf_record_aux_sample *rec = (void *) rec_hdr;
+            if (rec->flags & PERF_AUX_FLAG_TRUNCATED) {
+                return true;
+            }
+        } else if (rec_hdr->type == PERF_RECORD_LOST) {
+            // We lost records, so we can't be sure the AUX data is complete.
+            return true;
+        }
+        tail += rec_hdr->size;
+    }
+    return false;
+}
+
+/*
+ * --------------------------------------
+ * Functions exposed to the outside world
+ * --------------------------------------
+ */
+
+/*
+ * Initialise a tracer context.
+ */
+struct cs_tracer_ctx *
+cs_init_tracer(struct cs_config *tr_conf, struct cs_cerror *err)
+{
+    struct cs_tracer_ctx *tr_ctx = malloc(sizeof(*tr_ctx));
+    if (tr_ctx == NULL) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        return NULL;
+    }
+    memset(tr_ctx, 0, sizeof(*tr_ctx));
+    tr_ctx->perf_fd = -1;
+
+    tr_ctx->perf_fd = open_perf(err);
+    if (tr_ctx->perf_fd == -1) {
+        goto fail;
+    }
+
+    // The buffer layout is as described in the perf_pt collector.
+    int page_size = getpagesize();
+    tr_ctx->base_bufsize = (1 + tr_conf->data_bufsize) * page_size;
+    tr_ctx->base_buf = mmap(NULL, tr_ctx->base_bufsize, PROT_READ | PROT_WRITE,
+                            MAP_SHARED, tr_ctx->perf_fd, 0);
+    if (tr_ctx->base_buf == MAP_FAILED) {
+        tr_ctx->base_buf = NULL;
+        cs_set_err(err, cs_cerror_errno, errno);
+        goto fail;
+    }
+
+    struct perf_event_mmap_page *base_header = tr_ctx->base_buf;
+    base_header->aux_offset = base_header->data_offset + base_header->data_size;
+    base_header->aux_size = tr_ctx->aux_bufsize = tr_conf->aux_bufsize * page_size;
+
+    tr_ctx->aux_buf = mmap(NULL, base_header->aux_size, PROT_READ | PROT_WRITE,
+                           MAP_SHARED, tr_ctx->perf_fd, base_header->aux_offset);
+    if (tr_ctx->aux_buf == MAP_FAILED) {
+        tr_ctx->aux_buf = NULL;
+        cs_set_err(err, cs_cerror_errno, errno);
+        goto fail;
+    }
+
+    return tr_ctx;
+
+fail:
+    cs_free_tracer(tr_ctx, err);
+    return NULL;
+}
+
+/*
+ * Turn on the tracing hardware.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+cs_start_tracer(struct cs_tracer_ctx *tr_ctx, struct cs_cerror *err)
+{
+    if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_ENABLE, 0) < 0) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Turn off the tracing hardware and copy the trace out of the AUX buffer into
+ * a newly allocated buffer in `trace`.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+cs_stop_tracer(struct cs_tracer_ctx *tr_ctx, struct cs_trace *trace,
+               struct cs_cerror *err)
+{
+    if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_DISABLE, 0) < 0) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        return false;
+    }
+
+    struct perf_event_mmap_page *hdr = tr_ctx->base_buf;
+    __u64 head = atomic_load_explicit((_Atomic __u64 *) &hdr->aux_head,
+                                      memory_order_acquire);
+    __u64 tail = hdr->aux_tail;
+    __u64 size = hdr->aux_size;
+    __u64 len = head - tail;
+    if (len > size) {
+        // Can't happen in non-overwrite mode, but be defensive.
+        len = size;
+        tail = head - size;
+    }
+
+    // Allocate at least one byte, as malloc(0) may return NULL.
+    void *buf = malloc(len > 0 ? len : 1);
+    if (buf == NULL) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        return false;
+    }
+
+    // Copy the data out, removing wrap in the process.
+    __u64 start = tail % size;
+    if (start + len <= size) {
+        memcpy(buf, tr_ctx->aux_buf + start, len);
+    } else {
+        __u64 first = size - start;
+        memcpy(buf, tr_ctx->aux_buf + start, first);
+        memcpy(buf + first, tr_ctx->aux_buf, len - first);
+    }
+    atomic_store_explicit((_Atomic __u64 *) &hdr->aux_tail, head, memory_order_release);
+
+    trace->buf = buf;
+    trace->len = len;
+    trace->overflowed = aux_truncated(hdr);
+    return true;
+}
+
+/*
+ * Clean up and free a tracer context and its contents.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+cs_free_tracer(struct cs_tracer_ctx *tr_ctx, struct cs_cerror *err) {
+    bool ret = true;
+
+    if ((tr_ctx->aux_buf) &&
+        (munmap(tr_ctx->aux_buf, tr_ctx->aux_bufsize) == -1)) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        ret = false;
+    }
+    if ((tr_ctx->base_buf) &&
+        (munmap(tr_ctx->base_buf, tr_ctx->base_bufsize) == -1)) {
+        cs_set_err(err, cs_cerror_errno, errno);
+        ret = false;
+    }
+    if (tr_ctx->perf_fd >= 0) {
+        close(tr_ctx->perf_fd);
+    }
+    free(tr_ctx);
+    return ret;
+}
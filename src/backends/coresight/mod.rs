@@ -0,0 +1,315 @@
+use super::{BackendKind, CoreSightConfig};
+use crate::errors::HWTracerError;
+use crate::{
+    serialize, Block, RawThreadTracer, RawTrace, ThreadTracer, Trace, Tracer, TracerState,
+};
+use libc::{c_int, c_void, free, size_t};
+use std::cmp;
+#[cfg(test)]
+use std::fs::File;
+use std::io::{Read, Write};
+use std::iter;
+use std::ptr;
+use std::slice;
+
+#[repr(C)]
+#[allow(dead_code)] // Only C constructs these.
+#[derive(PartialEq)]
+enum CoreSightCErrorKind {
+    Unused,
+    Unknown,
+    Errno,
+}
+
+/// Represents an error occurring in the C code in this backend.
+#[repr(C)]
+struct CoreSightCError {
+    typ: CoreSightCErrorKind,
+    code: c_int,
+}
+
+impl CoreSightCError {
+    fn new() -> Self {
+        Self {
+            typ: CoreSightCErrorKind::Unused,
+            code: 0,
+        }
+    }
+}
+
+impl From<CoreSightCError> for HWTracerError {
+    fn from(err: CoreSightCError) -> HWTracerError {
+        // If this assert crashes out, then we forgot a cs_set_err() somewhere in C code.
+        debug_assert!(err.typ != CoreSightCErrorKind::Unused);
+        match err.typ {
+            CoreSightCErrorKind::Unused => HWTracerError::Unknown,
+            CoreSightCErrorKind::Unknown => HWTracerError::Unknown,
+            CoreSightCErrorKind::Errno => HWTracerError::CFailed(err.code),
+        }
+    }
+}
+
+extern "C" {
+    fn cs_init_tracer(conf: *const CoreSightConfig, err: *mut CoreSightCError) -> *mut c_void;
+    fn cs_start_tracer(tr_ctx: *mut c_void, err: *mut CoreSightCError) -> bool;
+    fn cs_stop_tracer(
+        tr_ctx: *mut c_void,
+        trace: *mut CoreSightTrace,
+        err: *mut CoreSightCError,
+    ) -> bool;
+    fn cs_free_tracer(tr_ctx: *mut c_void, err: *mut CoreSightCError) -> bool;
+}
+
+/// A raw CoreSight ETM trace, obtained via Linux perf.
+///
+/// Decoding requires OpenCSD, which hwtracer doesn't yet support, so for now only the raw trace
+/// data is available.
+#[repr(C)]
+#[derive(Debug)]
+pub struct CoreSightTrace {
+    // The trace buffer, malloc(3)d by the C code.
+    buf: *mut u8,
+    // The length of the trace (in bytes).
+    len: u64,
+    // Set by the C code if the AUX buffer overflowed during collection.
+    overflowed: bool,
+}
+
+/// The trace buffer is only ever accessed through `&self`, so it is safe to move between threads.
+unsafe impl Send for CoreSightTrace {}
+
+impl CoreSightTrace {
+    // Returns the raw ETM trace data.
+    fn bytes(&self) -> &[u8] {
+        if self.buf.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.buf as *const u8, self.len as usize) }
+    }
+
+    /// Read the backend-specific part of a serialized trace. See `Trace::serialize`.
+    pub(crate) fn deserialize(r: &mut dyn Read) -> Result<Self, HWTracerError> {
+        let overflowed = serialize::read_u8(r)? != 0;
+        let len = serialize::read_u64(r)?;
+        // malloc(3) may return NULL for a zero-sized allocation, so allocate at least one byte.
+        let buf = unsafe { libc::malloc(cmp::max(len as size_t, 1)) as *mut u8 };
+        if buf.is_null() {
+            return Err(HWTracerError::Unknown);
+        }
+        let trace = Self {
+            buf,
+            len,
+            overflowed,
+        };
+        let sl = unsafe { slice::from_raw_parts_mut(trace.buf, len as usize) };
+        r.read_exact(sl)?;
+        Ok(trace)
+    }
+}
+
+impl RawTrace for CoreSightTrace {
+    fn raw_data(&self) -> Option<&[u8]> {
+        Some(self.bytes())
+    }
+
+    fn capacity(&self) -> usize {
+        self.len as usize
+    }
+
+    fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+impl Trace for CoreSightTrace {
+    #[cfg(test)]
+    fn to_file(&self, file: &mut File) {
+        file.write_all(self.bytes()).unwrap();
+    }
+
+    /// After the common header, a serialized CoreSight trace consists of:
+    ///
+    ///  - 1 byte: non-zero if the trace overflowed.
+    ///  - 8 bytes: the length of the raw ETM trace data.
+    ///  - The raw ETM trace data itself.
+    fn serialize(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        serialize::write_header(w, BackendKind::CoreSight)?;
+        w.write_all(&[self.overflowed as u8])?;
+        w.write_all(&self.len.to_le_bytes())?;
+        w.write_all(self.bytes())?;
+        Ok(())
+    }
+
+    /// Decoding CoreSight traces is not yet supported, so this yields a single error.
+    fn iter_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(iter::once(Err(HWTracerError::UnsupportedOperation(
+            "decoding CoreSight traces is not yet supported".into(),
+        ))))
+    }
+}
+
+impl Drop for CoreSightTrace {
+    fn drop(&mut self) {
+        if !self.buf.is_null() {
+            unsafe { free(self.buf as *mut c_void) };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CoreSightTracer {
+    config: CoreSightConfig,
+}
+
+impl CoreSightTracer {
+    pub(super) fn new(config: CoreSightConfig) -> Result<Self, HWTracerError> {
+        // Check for inavlid configuration.
+        fn power_of_2(v: size_t) -> bool {
+            v != 0 && (v & (v - 1)) == 0
+        }
+        if !power_of_2(config.data_bufsize) {
+            return Err(HWTracerError::BadConfig(String::from(
+                "data_bufsize must be a positive power of 2",
+            )));
+        }
+        if !power_of_2(config.aux_bufsize) {
+            return Err(HWTracerError::BadConfig(String::from(
+                "aux_bufsize must be a positive power of 2",
+            )));
+        }
+        Ok(Self { config })
+    }
+}
+
+impl Tracer for CoreSightTracer {
+    fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
+        Box::new(CoreSightThreadTracer::new(self.config.clone()))
+    }
+
+    fn name(&self) -> &'static str {
+        "CoreSight"
+    }
+}
+
+/// A tracer that uses the Linux Perf interface to Arm CoreSight ETM.
+pub struct CoreSightThreadTracer {
+    // The configuration for this tracer.
+    config: CoreSightConfig,
+    // Opaque C pointer representing the tracer context.
+    tracer_ctx: *mut c_void,
+    // The state of the tracer.
+    state: TracerState,
+}
+
+impl CoreSightThreadTracer {
+    fn new(config: CoreSightConfig) -> Self {
+        Self {
+            config,
+            tracer_ctx: ptr::null_mut(),
+            state: TracerState::Stopped,
+        }
+    }
+}
+
+impl RawThreadTracer for CoreSightThreadTracer {
+    type Trace = Box<dyn Trace>;
+    type Error = HWTracerError;
+
+    fn start_tracing(&mut self) -> Result<(), HWTracerError> {
+        if self.state == TracerState::Started {
+            return Err(TracerState::Started.as_error());
+        }
+
+        let mut cerr = CoreSightCError::new();
+        self.tracer_ctx =
+            unsafe { cs_init_tracer(&self.config as *const CoreSightConfig, &mut cerr) };
+        if self.tracer_ctx.is_null() {
+            return Err(cerr.into());
+        }
+
+        let mut cerr = CoreSightCError::new();
+        if !unsafe { cs_start_tracer(self.tracer_ctx, &mut cerr) } {
+            unsafe { cs_free_tracer(self.tracer_ctx, &mut CoreSightCError::new()) };
+            self.tracer_ctx = ptr::null_mut();
+            return Err(cerr.into());
+        }
+        self.state = TracerState::Started;
+        Ok(())
+    }
+
+    fn stop_tracing(&mut self) -> Result<Box<dyn Trace>, HWTracerError> {
+        if self.state == TracerState::Stopped {
+            return Err(TracerState::Stopped.as_error());
+        }
+        let mut trace = CoreSightTrace {
+            buf: ptr::null_mut(),
+            len: 0,
+            overflowed: false,
+        };
+        let mut cerr = CoreSightCError::new();
+        let rc = unsafe { cs_stop_tracer(self.tracer_ctx, &mut trace, &mut cerr) };
+        self.state = TracerState::Stopped;
+
+        let mut free_cerr = CoreSightCError::new();
+        let free_rc = unsafe { cs_free_tracer(self.tracer_ctx, &mut free_cerr) };
+        self.tracer_ctx = ptr::null_mut();
+        if !rc {
+            return Err(cerr.into());
+        }
+        if !free_rc {
+            return Err(free_cerr.into());
+        }
+        Ok(Box::new(trace))
+    }
+
+    fn is_tracing(&self) -> bool {
+        self.state == TracerState::Started
+    }
+}
+
+impl ThreadTracer for CoreSightThreadTracer {}
+
+impl Drop for CoreSightThreadTracer {
+    fn drop(&mut self) {
+        if self.state == TracerState::Started {
+            let _ = self.stop_tracing();
+        }
+    }
+}
+
+#[cfg(all(coresight, test))]
+mod tests {
+    use super::CoreSightThreadTracer;
+    use crate::backends::CoreSightConfig;
+    use crate::{test_helpers, HWTracerError};
+
+    #[test]
+    fn test_basic_usage() {
+        test_helpers::test_basic_usage(CoreSightThreadTracer::new(CoreSightConfig::default()));
+    }
+
+    #[test]
+    fn test_already_started() {
+        test_helpers::test_already_started(CoreSightThreadTracer::new(CoreSightConfig::default()));
+    }
+
+    #[test]
+    fn test_not_started() {
+        test_helpers::test_not_started(CoreSightThreadTracer::new(CoreSightConfig::default()));
+    }
+
+    // Check that we collect some raw data, even though we can't decode it.
+    #[test]
+    fn test_raw_data() {
+        let mut tracer = CoreSightThreadTracer::new(CoreSightConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert!(!trace.raw_data().unwrap().is_empty());
+        let mut itr = trace.iter_blocks();
+        match itr.next() {
+            Some(Err(HWTracerError::UnsupportedOperation(_))) => (),
+            _ => panic!(),
+        }
+    }
+}
@@ -1,41 +1,163 @@
+use crate::backends::BackendKind;
 use crate::errors::HWTracerError;
-use crate::{Block, ThreadTracer, Trace, Tracer, TracerState};
+use crate::stream::BlockSource;
+use crate::{
+    serialize, Block, BlockStream, RawThreadTracer, RawTrace, ThreadTracer, Trace, Tracer,
+    TracerState,
+};
+use libc::pid_t;
 #[cfg(test)]
 use std::fs::File;
-use std::iter::Iterator;
+use std::io::{Read, Write};
+use std::iter::{self, Iterator};
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
 
-/// An empty dummy trace.
+/// A dummy trace, holding the blocks that its tracer was made with (if any).
 #[derive(Debug)]
-struct DummyTrace {}
+pub(crate) struct DummyTrace {
+    blocks: Arc<Vec<Block>>,
+}
+
+impl DummyTrace {
+    /// Read the backend-specific part of a serialized trace. See `Trace::serialize`.
+    pub(crate) fn deserialize(r: &mut dyn Read) -> Result<Self, HWTracerError> {
+        // Dummy traces without blocks used to be serialized as just the header.
+        let mut nblocks = [0; 8];
+        if r.read(&mut nblocks[..1])? == 0 {
+            return Ok(DummyTrace {
+                blocks: Arc::new(Vec::new()),
+            });
+        }
+        r.read_exact(&mut nblocks[1..])?;
+        let mut blocks = Vec::new();
+        for _ in 0..u64::from_le_bytes(nblocks) {
+            let first_instr = serialize::read_u64(r)?;
+            let last_instr = serialize::read_u64(r)?;
+            blocks.push(Block::new(first_instr, last_instr));
+        }
+        Ok(DummyTrace {
+            blocks: Arc::new(blocks),
+        })
+    }
+}
+
+impl RawTrace for DummyTrace {
+    fn capacity(&self) -> usize {
+        0
+    }
+
+    fn overflowed(&self) -> bool {
+        false
+    }
+}
 
 impl Trace for DummyTrace {
     #[cfg(test)]
     fn to_file(&self, _: &mut File) {}
 
+    /// After the common header, a serialized dummy trace consists of:
+    ///
+    ///  - 8 bytes: the number of blocks.
+    ///  - For each block, 8 bytes each for the addresses of its first and last instructions.
+    ///
+    /// A trace consisting of only the header is also accepted, and has no blocks.
+    fn serialize(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        serialize::write_header(w, BackendKind::Dummy)?;
+        w.write_all(&(self.blocks.len() as u64).to_le_bytes())?;
+        for b in self.blocks.iter() {
+            w.write_all(&b.first_instr().to_le_bytes())?;
+            w.write_all(&b.last_instr().to_le_bytes())?;
+        }
+        Ok(())
+    }
+
     fn iter_blocks<'t: 'i, 'i>(
         &'t self,
     ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
-        Box::new(DummyBlockIterator {})
+        Box::new(self.blocks.iter().copied().map(Ok))
     }
 
-    #[cfg(test)]
-    fn capacity(&self) -> usize {
-        0
+    /// A dummy trace made from blocks can't know which instructions the blocks contain, so
+    /// yields a single `HWTracerError::UnsupportedOperation` error.
+    fn iter_instrs<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<u64, HWTracerError>> + 'i> {
+        if self.blocks.is_empty() {
+            return Box::new(iter::empty());
+        }
+        Box::new(iter::once(Err(HWTracerError::UnsupportedOperation(
+            "dummy traces have no instructions".into(),
+        ))))
     }
 }
 
+/// A tracer which doesn't use any hardware.
+///
+/// By default its traces are empty, but a tracer made with `with_blocks()` gives traces
+/// consisting of predetermined blocks. This is useful for testing code which consumes traces on
+/// machines without tracing hardware.
 #[derive(Debug)]
-pub struct DummyTracer {}
+pub struct DummyTracer {
+    // The blocks of every trace.
+    blocks: Arc<Vec<Block>>,
+}
 
 impl DummyTracer {
     pub(super) fn new() -> Self {
-        DummyTracer {}
+        Self::with_blocks(Vec::new())
+    }
+
+    /// Create a dummy tracer whose traces consist of `blocks`, regardless of what code was
+    /// executed whilst tracing.
+    ///
+    /// ```
+    /// use hwtracer::{backends::dummy::DummyTracer, Block, Tracer};
+    ///
+    /// let blocks = vec![Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2008)];
+    /// let tracer = DummyTracer::with_blocks(blocks.clone());
+    /// let mut thr_tracer = tracer.thread_tracer();
+    /// thr_tracer.start_tracing().unwrap();
+    /// let trace = thr_tracer.stop_tracing().unwrap();
+    /// let got = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+    /// assert_eq!(got, blocks);
+    /// ```
+    pub fn with_blocks(blocks: Vec<Block>) -> Self {
+        DummyTracer {
+            blocks: Arc::new(blocks),
+        }
     }
 }
 
 impl Tracer for DummyTracer {
     fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
-        Box::new(DummyThreadTracer::new())
+        Box::new(DummyThreadTracer::with_blocks(Arc::clone(&self.blocks)))
+    }
+
+    fn name(&self) -> &'static str {
+        "Dummy"
+    }
+
+    fn thread_tracer_for(&self, _tid: pid_t) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        Ok(Box::new(DummyThreadTracer::with_blocks(Arc::clone(
+            &self.blocks,
+        ))))
+    }
+
+    fn thread_tracer_on_exec(&self, _pid: pid_t) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        Ok(Box::new(DummyThreadTracer::with_blocks(Arc::clone(
+            &self.blocks,
+        ))))
+    }
+
+    fn cgroup_cpu_tracer(
+        &self,
+        _cgroup_fd: RawFd,
+        _cpu: usize,
+    ) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        Ok(Box::new(DummyThreadTracer::with_blocks(Arc::clone(
+            &self.blocks,
+        ))))
     }
 }
 
@@ -43,18 +165,30 @@ impl Tracer for DummyTracer {
 pub struct DummyThreadTracer {
     // Keeps track of the state of the tracer.
     state: TracerState,
+    // The blocks of every trace.
+    blocks: Arc<Vec<Block>>,
 }
 
 impl DummyThreadTracer {
     /// Create a dummy tracer.
+    #[cfg(test)]
     fn new() -> Self {
+        Self::with_blocks(Arc::new(Vec::new()))
+    }
+
+    // Create a dummy tracer whose traces consist of `blocks`.
+    fn with_blocks(blocks: Arc<Vec<Block>>) -> Self {
         Self {
             state: TracerState::Stopped,
+            blocks,
         }
     }
 }
 
-impl ThreadTracer for DummyThreadTracer {
+impl RawThreadTracer for DummyThreadTracer {
+    type Trace = Box<dyn Trace>;
+    type Error = HWTracerError;
+
     fn start_tracing(&mut self) -> Result<(), HWTracerError> {
         if self.state != TracerState::Stopped {
             return Err(TracerState::Started.as_error());
@@ -68,27 +202,67 @@ impl ThreadTracer for DummyThreadTracer {
             return Err(TracerState::Stopped.as_error());
         }
         self.state = TracerState::Stopped;
-        Ok(Box::new(DummyTrace {}))
+        Ok(Box::new(DummyTrace {
+            blocks: Arc::clone(&self.blocks),
+        }))
+    }
+
+    fn is_tracing(&self) -> bool {
+        self.state == TracerState::Started
     }
 }
 
-// Iterate over the blocks of a DummyTrace.
-//
-// Note: there will never be any blocks, but we have to implement the interface.
-struct DummyBlockIterator {}
+impl ThreadTracer for DummyThreadTracer {
+    /// The stream yields all of the tracer's blocks straight away.
+    fn start_streaming(&mut self) -> Result<BlockStream<'_>, HWTracerError> {
+        self.start_tracing()?;
+        Ok(BlockStream::new(Box::new(DummyBlockSource {
+            blocks: Arc::clone(&self.blocks),
+            idx: 0,
+            tracer: self,
+        })))
+    }
+}
 
-impl Iterator for DummyBlockIterator {
+// The source of a dummy `BlockStream`.
+struct DummyBlockSource<'a> {
+    tracer: &'a mut DummyThreadTracer,
+    blocks: Arc<Vec<Block>>,
+    // The index of the next block to yield.
+    idx: usize,
+}
+
+impl<'a> Iterator for DummyBlockSource<'a> {
     type Item = Result<Block, HWTracerError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        None
+        let block = self.blocks.get(self.idx)?;
+        self.idx += 1;
+        Some(Ok(*block))
+    }
+}
+
+impl<'a> BlockSource for DummyBlockSource<'a> {
+    fn stop_tracing(&mut self) -> Result<(), HWTracerError> {
+        self.tracer.stop_tracing().map(|_| ())
+    }
+}
+
+impl<'a> Drop for DummyBlockSource<'a> {
+    fn drop(&mut self) {
+        if self.tracer.state == TracerState::Started {
+            let _ = self.tracer.stop_tracing();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::DummyThreadTracer;
-    use crate::{test_helpers, ThreadTracer};
+    use super::{DummyThreadTracer, DummyTracer};
+    use crate::{
+        deserialize_trace, test_helpers, Block, HWTracerError, RawThreadTracer, ThreadTracer,
+        Tracer, TracerState,
+    };
 
     #[test]
     fn test_basic_usage() {
@@ -100,6 +274,11 @@ mod tests {
         test_helpers::test_repeated_tracing(DummyThreadTracer::new());
     }
 
+    #[test]
+    fn test_concurrent_tracing() {
+        test_helpers::test_concurrent_tracing(DummyThreadTracer::new);
+    }
+
     #[test]
     fn test_already_started() {
         test_helpers::test_already_started(DummyThreadTracer::new());
@@ -120,4 +299,147 @@ mod tests {
         let expects = Vec::new();
         test_helpers::test_expected_blocks(trace, expects.iter());
     }
+
+    fn test_blocks() -> Vec<Block> {
+        vec![Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2008)]
+    }
+
+    #[test]
+    fn test_with_blocks() {
+        let tracer = DummyTracer::with_blocks(test_blocks());
+        for _ in 0..2 {
+            let mut thr_tracer = tracer.thread_tracer();
+            thr_tracer.start_tracing().unwrap();
+            let trace = thr_tracer.stop_tracing().unwrap();
+            test_helpers::test_expected_blocks(trace, test_blocks().iter());
+        }
+    }
+
+    #[test]
+    fn test_with_blocks_serialize() {
+        let tracer = DummyTracer::with_blocks(test_blocks());
+        let mut thr_tracer = tracer.thread_tracer();
+        thr_tracer.start_tracing().unwrap();
+        let trace = thr_tracer.stop_tracing().unwrap();
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        test_helpers::test_expected_blocks(trace2, test_blocks().iter());
+
+        // Truncated block data is rejected.
+        buf.pop();
+        assert!(deserialize_trace(&mut buf.as_slice()).is_err());
+    }
+
+    // Dummy traces serialized before they could have blocks consist of just the header.
+    #[test]
+    fn test_deserialize_header_only() {
+        let trace = deserialize_trace(&mut &b"HWTRACER\x01\x00\x00"[..]).unwrap();
+        assert_eq!(trace.iter_blocks().count(), 0);
+    }
+
+    #[test]
+    fn test_with_blocks_instrs() {
+        let tracer = DummyTracer::with_blocks(test_blocks());
+        let mut thr_tracer = tracer.thread_tracer();
+        thr_tracer.start_tracing().unwrap();
+        let trace = thr_tracer.stop_tracing().unwrap();
+        let instrs = trace.iter_instrs().collect::<Vec<_>>();
+        match instrs.as_slice() {
+            [Err(HWTracerError::UnsupportedOperation(_))] => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_with_blocks_streaming() {
+        let tracer = DummyTracer::with_blocks(test_blocks());
+        let mut thr_tracer = tracer.thread_tracer();
+        let mut stream = thr_tracer.start_streaming().unwrap();
+        let got = stream.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, test_blocks());
+        stream.stop_tracing().unwrap();
+        assert!(stream.next().is_none());
+    }
+
+    // The dummy backend keeps no trace data to take snapshots of.
+    #[test]
+    fn test_snapshot_unsupported() {
+        let mut tracer = DummyThreadTracer::new();
+        tracer.start_tracing().unwrap();
+        match tracer.snapshot() {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+        tracer.stop_tracing().unwrap();
+    }
+
+    #[test]
+    fn test_instr_iterator() {
+        let mut tracer = DummyThreadTracer::new();
+        tracer.start_tracing().unwrap();
+        let trace = tracer.stop_tracing().unwrap();
+
+        // We expect exactly 0 instructions.
+        assert_eq!(trace.iter_instrs().count(), 0);
+    }
+
+    #[test]
+    fn test_thread_tracer_for() {
+        let tracer = DummyTracer::new();
+        let mut thr_tracer = tracer.thread_tracer_for(1).unwrap();
+        thr_tracer.start_tracing().unwrap();
+        assert_eq!(thr_tracer.stop_tracing().unwrap().iter_blocks().count(), 0);
+    }
+
+    #[test]
+    fn test_backend_info() {
+        let tracer = DummyTracer::new();
+        assert_eq!(tracer.name(), "Dummy");
+        let info = tracer.backend_info();
+        assert_eq!(info.name, "Dummy");
+        assert!(info.decoder_version.is_none());
+    }
+
+    #[test]
+    fn test_raw_data() {
+        let mut tracer = DummyThreadTracer::new();
+        tracer.start_tracing().unwrap();
+        let trace = tracer.stop_tracing().unwrap();
+        assert!(trace.raw_data().is_none());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut tracer = DummyThreadTracer::new();
+        match tracer.reset() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+        tracer.start_tracing().unwrap();
+        tracer.reset().unwrap();
+        tracer.stop_tracing().unwrap();
+    }
+
+    #[test]
+    fn test_streaming() {
+        let mut tracer = DummyThreadTracer::new();
+        let mut stream = tracer.start_streaming().unwrap();
+        assert!(stream.next().is_none());
+        stream.stop_tracing().unwrap();
+        match stream.stop_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+        assert!(stream.next().is_none());
+    }
+
+    // Check that dropping a stream stops the tracer.
+    #[test]
+    fn test_streaming_drop() {
+        let mut tracer = DummyThreadTracer::new();
+        tracer.start_streaming().unwrap();
+        tracer.start_tracing().unwrap();
+        tracer.stop_tracing().unwrap();
+    }
 }
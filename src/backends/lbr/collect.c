@@ -0,0 +1,358 @@
+// Copyright (c) 2020 King's College London
+// created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/*
+ * A collector for Intel Last Branch Record (LBR) samples, using Linux perf
+ * branch stack sampling.
+ *
+ * The LBR holds only the most recent taken branches, so this is much cheaper
+ * than Intel PT, but the trace is a series of samples rather than a complete
+ * record of control flow. Every `sample_period` branches, perf records the
+ * contents of the LBR into the data buffer. As with the CoreSight collector,
+ * the samples are read out only when tracing stops.
+ */
+
+#define _GNU_SOURCE
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+#include <string.h>
+#include <errno.h>
+#include <syscall.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdatomic.h>
+#include <sys/mman.h>
+#include <sys/ioctl.h>
+#include <linux/perf_event.h>
+
+/*
+ * Must stay in sync with the Rust-side.
+ */
+enum lbr_cerror_kind {
+    lbr_cerror_unused,
+    lbr_cerror_unknown,
+    lbr_cerror_errno,
+    lbr_cerror_no_hw_support, // `code` is the errno explaining why.
+};
+
+struct lbr_cerror {
+    enum lbr_cerror_kind kind; // What sort of error is this?
+    int code;                  // The error code itself.
+};
+
+/*
+ * Passed from Rust to C to configure tracing.
+ * Must stay in sync with the Rust-side.
+ */
+struct lbr_config {
+    size_t      data_bufsize;          // Data buf size (in pages).
+    uint64_t    sample_period;         // Branches between samples.
+};
+
+/*
+ * A block reconstructed from two consecutive LBR entries.
+ *
+ * Shared with Rust code. Must stay in sync.
+ */
+struct lbr_block {
+    uint64_t first_instr;
+    uint64_t last_instr;
+};
+
+/*
+ * Storage for a trace. The buffer is malloc(3)d by C and free(3)d by Rust.
+ *
+ * Shared with Rust code. Must stay in sync.
+ */
+struct lbr_trace {
+    struct lbr_block *blocks;
+    uint64_t nblocks;
+    bool overflowed;
+};
+
+/*
+ * Stores all information about the tracer.
+ * Exposed to Rust only as an opaque pointer.
+ */
+struct lbr_tracer_ctx {
+    int                 perf_fd;            // FD used to talk to the perf API.
+    void                *base_buf;          // Ptr to the start of the base buffer.
+    size_t              base_bufsize;       // The size the base buffer's mmap(2).
+};
+
+// Private prototypes.
+static void lbr_set_err(struct lbr_cerror *, int, int);
+static int open_perf(struct lbr_config *, struct lbr_cerror *);
+static bool read_samples(struct perf_event_mmap_page *, struct lbr_trace *,
+                         struct lbr_cerror *);
+
+// Exposed Prototypes.
+struct lbr_tracer_ctx *lbr_init_tracer(struct lbr_config *, struct lbr_cerror *);
+bool lbr_start_tracer(struct lbr_tracer_ctx *, struct lbr_cerror *);
+bool lbr_stop_tracer(struct lbr_tracer_ctx *, struct lbr_trace *, struct lbr_cerror *);
+bool lbr_free_tracer(struct lbr_tracer_ctx *, struct lbr_cerror *);
+
+/*
+ * Sets the error information (if not already set).
+ */
+static void
+lbr_set_err(struct lbr_cerror *err, int kind, int code) {
+    if (err->kind == lbr_cerror_unused) {
+        err->kind = kind;
+        err->code = code;
+    }
+}
+
+/*
+ * Opens the perf file descriptor for the calling thread and returns it.
+ *
+ * Returns a file descriptor, or -1 on error.
+ */
+static int
+open_perf(struct lbr_config *tr_conf, struct lbr_cerror *err) {
+    struct perf_event_attr attr;
+    memset(&attr, 0, sizeof(attr));
+    attr.size = sizeof(attr);
+
+    // Sample the LBR every `sample_period` branches.
+    attr.type = PERF_TYPE_HARDWARE;
+    attr.config = PERF_COUNT_HW_BRANCH_INSTRUCTIONS;
+    attr.sample_period = tr_conf->sample_period;
+    attr.sample_type = PERF_SAMPLE_BRANCH_STACK;
+    attr.branch_sample_type = PERF_SAMPLE_BRANCH_USER | PERF_SAMPLE_BRANCH_ANY;
+
+    // Exclude the kernel and hyper-visor and start disabled.
+    attr.exclude_kernel = 1;
+    attr.exclude_hv = 1;
+    attr.disabled = 1;
+
+    pid_t target_tid = syscall(__NR_gettid);
+    int fd = syscall(SYS_perf_event_open, &attr, target_tid, -1, -1, 0);
+    if (fd == -1) {
+        // perf reports a lack of LBR support as one of these.
+        int kind = ((errno == ENOENT) || (errno == EOPNOTSUPP)) ?
+            lbr_cerror_no_hw_support : lbr_cerror_errno;
+        lbr_set_err(err, kind, errno);
+    }
+    return fd;
+}
+
+/*
+ * Read the samples out of the data buffer (whose header is `hdr`), turning
+ * them into blocks in `trace`.
+ *
+ * The entries of each sample are ordered most recent first. The code between
+ * the target of one taken branch and the source of the next is a block.
+ *
+ * Returns true on success or false otherwise.
+ */
+static bool
+read_samples(struct perf_event_mmap_page *hdr, struct lbr_trace *trace,
+             struct lbr_cerror *err)
+{
+    void *data = (void *) hdr + hdr->data_offset;
+    __u64 head = atomic_load_explicit((_Atomic __u64 *) &hdr->data_head,
+                                      memory_order_acquire);
+    __u64 tail = hdr->data_tail;
+    __u64 size = hdr->data_size;
+    __u64 len = head - tail;
+
+    // Samples may straddle the end of the ring buffer, so copy them out,
+    // removing wrap in the process.
+    void *samples = malloc(len > 0 ? len : 1);
+    if (samples == NULL) {
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        return false;
+    }
+    __u64 start = tail % size;
+    if (start + len <= size) {
+        memcpy(samples, data + start, len);
+    } else {
+        __u64 first = size - start;
+        memcpy(samples, data + start, first);
+        memcpy(samples + first, data, len - first);
+    }
+    atomic_store_explicit((_Atomic __u64 *) &hdr->data_tail, head, memory_order_release);
+
+    // Each entry in a sample gives rise to at most one block, so the number
+    // of entries bounds the number of blocks.
+    size_t max_blocks = len / sizeof(struct perf_branch_entry);
+    trace->blocks = malloc((max_blocks > 0 ? max_blocks : 1) * sizeof(struct lbr_block));
+    if (trace->blocks == NULL) {
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        free(samples);
+        return false;
+    }
+    trace->nblocks = 0;
+
+    // The kernel silently drops samples which don't fit in the data buffer,
+    // so if there isn't room for another sample, we assume some were lost.
+    __u64 max_sample_size = 0;
+    void *next = samples;
+    while (next < samples + len) {
+        struct perf_event_header *rec_hdr = next;
+        if (rec_hdr->size == 0) {
+            break;
+        }
+        if (rec_hdr->type == PERF_RECORD_SAMPLE) {
+            if (rec_hdr->size > max_sample_size) {
+                max_sample_size = rec_hdr->size;
+            }
+            __u64 nr = *(__u64 *) (next + sizeof(*rec_hdr));
+            struct perf_branch_entry *entries = next + sizeof(*rec_hdr) + sizeof(nr);
+            for (__u64 i = nr; i > 1; i--) {
+                uint64_t first = entries[i - 1].to;
+                uint64_t last = entries[i - 2].from;
+                // Discard anything that doesn't look like straight-line code,
+                // e.g. if the LBR missed a branch.
+                if (first <= last) {
+                    trace->blocks[trace->nblocks].first_instr = first;
+                    trace->blocks[trace->nblocks].last_instr = last;
+                    trace->nblocks++;
+                }
+            }
+        } else if (rec_hdr->type == PERF_RECORD_LOST) {
+            trace->overflowed = true;
+        }
+        next += rec_hdr->size;
+    }
+    if (size - len < max_sample_size) {
+        trace->overflowed = true;
+    }
+
+    free(samples);
+    return true;
+}
+
+/*
+ * --------------------------------------
+ * Functions exposed to the outside world
+ * --------------------------------------
+ */
+
+/*
+ * Initialise a tracer context.
+ */
+struct lbr_tracer_ctx *
+lbr_init_tracer(struct lbr_config *tr_conf, struct lbr_cerror *err)
+{
+    struct lbr_tracer_ctx *tr_ctx = malloc(sizeof(*tr_ctx));
+    if (tr_ctx == NULL) {
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        return NULL;
+    }
+    memset(tr_ctx, 0, sizeof(*tr_ctx));
+    tr_ctx->perf_fd = -1;
+
+    tr_ctx->perf_fd = open_perf(tr_conf, err);
+    if (tr_ctx->perf_fd == -1) {
+        goto fail;
+    }
+
+    // The data buffer is preceded by one management page (the header), hence
+    // `1 + data_bufsize'. There's no AUX buffer.
+    int page_size = getpagesize();
+    tr_ctx->base_bufsize = (1 + tr_conf->data_bufsize) * page_size;
+    tr_ctx->base_buf = mmap(NULL, tr_ctx->base_bufsize, PROT_READ | PROT_WRITE,
+                            MAP_SHARED, tr_ctx->perf_fd, 0);
+    if (tr_ctx->base_buf == MAP_FAILED) {
+        tr_ctx->base_buf = NULL;
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        goto fail;
+    }
+
+    return tr_ctx;
+
+fail:
+    lbr_free_tracer(tr_ctx, err);
+    return NULL;
+}
+
+/*
+ * Start sampling.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+lbr_start_tracer(struct lbr_tracer_ctx *tr_ctx, struct lbr_cerror *err)
+{
+    if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_ENABLE, 0) < 0) {
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        return false;
+    }
+    return true;
+}
+
+/*
+ * Stop sampling and turn the samples into a newly allocated buffer of blocks
+ * in `trace`.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+lbr_stop_tracer(struct lbr_tracer_ctx *tr_ctx, struct lbr_trace *trace,
+                struct lbr_cerror *err)
+{
+    if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_DISABLE, 0) < 0) {
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        return false;
+    }
+    return read_samples(tr_ctx->base_buf, trace, err);
+}
+
+/*
+ * Clean up and free a tracer context and its contents.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+lbr_free_tracer(struct lbr_tracer_ctx *tr_ctx, struct lbr_cerror *err) {
+    bool ret = true;
+
+    if ((tr_ctx->base_buf) &&
+        (munmap(tr_ctx->base_buf, tr_ctx->base_bufsize) == -1)) {
+        lbr_set_err(err, lbr_cerror_errno, errno);
+        ret = false;
+    }
+    if (tr_ctx->perf_fd >= 0) {
+        close(tr_ctx->perf_fd);
+    }
+    free(tr_ctx);
+    return ret;
+}
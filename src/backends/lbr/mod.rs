@@ -0,0 +1,427 @@
+use super::{BackendKind, LBRConfig};
+use crate::errors::HWTracerError;
+use crate::{
+    serialize, Block, RawThreadTracer, RawTrace, ThreadTracer, Trace, Tracer, TracerState,
+};
+use libc::{c_int, c_void, free, size_t};
+use std::cmp;
+#[cfg(test)]
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ptr;
+use std::slice;
+
+// The most blocks a serialized trace may claim to hold. This is far more than a trace collected
+// by this backend can hold, and stops a corrupt trace making us allocate unlimited memory.
+const MAX_SERIALIZED_BLOCKS: u64 = 1 << 28;
+
+#[repr(C)]
+#[allow(dead_code)] // Only C constructs these.
+#[derive(PartialEq)]
+enum LBRCErrorKind {
+    Unused,
+    Unknown,
+    Errno,
+    NoHWSupport,
+}
+
+/// Represents an error occurring in the C code in this backend.
+#[repr(C)]
+struct LBRCError {
+    typ: LBRCErrorKind,
+    code: c_int,
+}
+
+impl LBRCError {
+    fn new() -> Self {
+        Self {
+            typ: LBRCErrorKind::Unused,
+            code: 0,
+        }
+    }
+}
+
+impl From<LBRCError> for HWTracerError {
+    fn from(err: LBRCError) -> HWTracerError {
+        // If this assert crashes out, then we forgot a lbr_set_err() somewhere in C code.
+        debug_assert!(err.typ != LBRCErrorKind::Unused);
+        match err.typ {
+            LBRCErrorKind::Unused => HWTracerError::Unknown,
+            LBRCErrorKind::Unknown => HWTracerError::Unknown,
+            LBRCErrorKind::Errno => HWTracerError::CFailed(err.code),
+            LBRCErrorKind::NoHWSupport => HWTracerError::Unsupported {
+                kind: BackendKind::LBR,
+                reason: format!(
+                    "LBR sampling not supported: {}",
+                    HWTracerError::CFailed(err.code)
+                ),
+            },
+        }
+    }
+}
+
+extern "C" {
+    fn lbr_init_tracer(conf: *const LBRConfig, err: *mut LBRCError) -> *mut c_void;
+    fn lbr_start_tracer(tr_ctx: *mut c_void, err: *mut LBRCError) -> bool;
+    fn lbr_stop_tracer(tr_ctx: *mut c_void, trace: *mut LBRTrace, err: *mut LBRCError) -> bool;
+    fn lbr_free_tracer(tr_ctx: *mut c_void, err: *mut LBRCError) -> bool;
+}
+
+// A block reconstructed by the C code. Must stay in sync with the C code.
+#[repr(C)]
+#[derive(Debug)]
+struct LBRBlock {
+    first_instr: u64,
+    last_instr: u64,
+}
+
+/// A trace made of Last Branch Record samples, obtained via Linux perf.
+///
+/// Each sample holds the most recent taken branches at the time it was taken, from which blocks
+/// are reconstructed. The blocks of each sample are contiguous, but those of consecutive samples
+/// are generally not: code executed between samples is missing from the trace.
+#[repr(C)]
+#[derive(Debug)]
+pub struct LBRTrace {
+    // The blocks, malloc(3)d by the C code.
+    blocks: *mut LBRBlock,
+    // The number of blocks.
+    nblocks: u64,
+    // Set by the C code if samples were lost during collection.
+    overflowed: bool,
+}
+
+/// The blocks are only ever accessed through `&self`, so it is safe to move between threads.
+unsafe impl Send for LBRTrace {}
+
+impl LBRTrace {
+    fn blocks(&self) -> &[LBRBlock] {
+        if self.blocks.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.blocks, self.nblocks as usize) }
+    }
+
+    /// Read the backend-specific part of a serialized trace. See `Trace::serialize`.
+    pub(crate) fn deserialize(r: &mut dyn Read) -> Result<Self, HWTracerError> {
+        let overflowed = serialize::read_u8(r)? != 0;
+        let nblocks = serialize::read_u64(r)?;
+        if nblocks > MAX_SERIALIZED_BLOCKS {
+            return Err(serialize::bad_format("too many blocks in LBR trace"));
+        }
+        // malloc(3) may return NULL for a zero-sized allocation, so allocate at least one block.
+        let size = cmp::max(nblocks as size_t, 1)
+            .checked_mul(std::mem::size_of::<LBRBlock>())
+            .ok_or_else(|| serialize::bad_format("too many blocks in LBR trace"))?;
+        let blocks = unsafe { libc::malloc(size) as *mut LBRBlock };
+        if blocks.is_null() {
+            return Err(HWTracerError::Unknown);
+        }
+        let mut trace = Self {
+            blocks,
+            nblocks: 0,
+            overflowed,
+        };
+        for i in 0..nblocks {
+            let first_instr = serialize::read_u64(r)?;
+            let last_instr = serialize::read_u64(r)?;
+            unsafe {
+                trace.blocks.add(i as usize).write(LBRBlock {
+                    first_instr,
+                    last_instr,
+                })
+            };
+            trace.nblocks += 1;
+        }
+        Ok(trace)
+    }
+}
+
+impl RawTrace for LBRTrace {
+    fn capacity(&self) -> usize {
+        self.nblocks as usize * std::mem::size_of::<LBRBlock>()
+    }
+
+    fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+impl Trace for LBRTrace {
+    #[cfg(test)]
+    fn to_file(&self, file: &mut File) {
+        for b in self.blocks() {
+            writeln!(file, "0x{:x} 0x{:x}", b.first_instr, b.last_instr).unwrap();
+        }
+    }
+
+    /// After the common header, a serialized LBR trace consists of:
+    ///
+    ///  - 1 byte: non-zero if the trace overflowed.
+    ///  - 8 bytes: the number of blocks.
+    ///  - For each block, 8 bytes each for the addresses of its first and last instructions.
+    fn serialize(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        serialize::write_header(w, BackendKind::LBR)?;
+        w.write_all(&[self.overflowed as u8])?;
+        w.write_all(&self.nblocks.to_le_bytes())?;
+        for b in self.blocks() {
+            w.write_all(&b.first_instr.to_le_bytes())?;
+            w.write_all(&b.last_instr.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn iter_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        let blocks = self
+            .blocks()
+            .iter()
+            .map(|b| Ok(Block::new(b.first_instr, b.last_instr)));
+        if self.overflowed {
+            Box::new(blocks.chain(std::iter::once(Err(HWTracerError::HWBufferOverflow))))
+        } else {
+            Box::new(blocks)
+        }
+    }
+}
+
+impl Drop for LBRTrace {
+    fn drop(&mut self) {
+        if !self.blocks.is_null() {
+            unsafe { free(self.blocks as *mut c_void) };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LBRTracer {
+    config: LBRConfig,
+}
+
+impl LBRTracer {
+    pub(super) fn new(config: LBRConfig) -> Result<Self, HWTracerError> {
+        // Check for inavlid configuration.
+        if config.data_bufsize == 0 || (config.data_bufsize & (config.data_bufsize - 1)) != 0 {
+            return Err(HWTracerError::BadConfig(String::from(
+                "data_bufsize must be a positive power of 2",
+            )));
+        }
+        if config.sample_period == 0 {
+            return Err(HWTracerError::BadConfig(String::from(
+                "sample_period must be positive",
+            )));
+        }
+        Ok(Self { config })
+    }
+}
+
+impl Tracer for LBRTracer {
+    fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
+        Box::new(LBRThreadTracer::new(self.config.clone()))
+    }
+
+    fn name(&self) -> &'static str {
+        "LBR"
+    }
+}
+
+/// A tracer that uses Linux perf to sample the Last Branch Record.
+pub struct LBRThreadTracer {
+    // The configuration for this tracer.
+    config: LBRConfig,
+    // Opaque C pointer representing the tracer context.
+    tracer_ctx: *mut c_void,
+    // The state of the tracer.
+    state: TracerState,
+}
+
+impl LBRThreadTracer {
+    fn new(config: LBRConfig) -> Self {
+        Self {
+            config,
+            tracer_ctx: ptr::null_mut(),
+            state: TracerState::Stopped,
+        }
+    }
+}
+
+impl RawThreadTracer for LBRThreadTracer {
+    type Trace = Box<dyn Trace>;
+    type Error = HWTracerError;
+
+    fn start_tracing(&mut self) -> Result<(), HWTracerError> {
+        if self.state == TracerState::Started {
+            return Err(TracerState::Started.as_error());
+        }
+
+        let mut cerr = LBRCError::new();
+        self.tracer_ctx = unsafe { lbr_init_tracer(&self.config as *const LBRConfig, &mut cerr) };
+        if self.tracer_ctx.is_null() {
+            return Err(cerr.into());
+        }
+
+        let mut cerr = LBRCError::new();
+        if !unsafe { lbr_start_tracer(self.tracer_ctx, &mut cerr) } {
+            unsafe { lbr_free_tracer(self.tracer_ctx, &mut LBRCError::new()) };
+            self.tracer_ctx = ptr::null_mut();
+            return Err(cerr.into());
+        }
+        self.state = TracerState::Started;
+        Ok(())
+    }
+
+    fn stop_tracing(&mut self) -> Result<Box<dyn Trace>, HWTracerError> {
+        if self.state == TracerState::Stopped {
+            return Err(TracerState::Stopped.as_error());
+        }
+        let mut trace = LBRTrace {
+            blocks: ptr::null_mut(),
+            nblocks: 0,
+            overflowed: false,
+        };
+        let mut cerr = LBRCError::new();
+        let rc = unsafe { lbr_stop_tracer(self.tracer_ctx, &mut trace, &mut cerr) };
+        self.state = TracerState::Stopped;
+
+        let mut free_cerr = LBRCError::new();
+        let free_rc = unsafe { lbr_free_tracer(self.tracer_ctx, &mut free_cerr) };
+        self.tracer_ctx = ptr::null_mut();
+        if !rc {
+            return Err(cerr.into());
+        }
+        if !free_rc {
+            return Err(free_cerr.into());
+        }
+        Ok(Box::new(trace))
+    }
+
+    fn is_tracing(&self) -> bool {
+        self.state == TracerState::Started
+    }
+}
+
+impl ThreadTracer for LBRThreadTracer {}
+
+impl Drop for LBRThreadTracer {
+    fn drop(&mut self) {
+        if self.state == TracerState::Started {
+            let _ = self.stop_tracing();
+        }
+    }
+}
+
+#[cfg(all(lbr_test, test))]
+mod tests {
+    use super::{LBRCError, LBRCErrorKind, LBRThreadTracer};
+    use crate::backends::{BackendConfig, LBRConfig, TracerBuilder};
+    use crate::serialize::write_header;
+    use crate::{backends::BackendKind, deserialize_trace, test_helpers, HWTracerError};
+
+    #[test]
+    fn test_basic_usage() {
+        test_helpers::test_basic_usage(LBRThreadTracer::new(LBRConfig::default()));
+    }
+
+    #[test]
+    fn test_repeated_tracing() {
+        test_helpers::test_repeated_tracing(LBRThreadTracer::new(LBRConfig::default()));
+    }
+
+    #[test]
+    fn test_concurrent_tracing() {
+        test_helpers::test_concurrent_tracing(|| LBRThreadTracer::new(LBRConfig::default()));
+    }
+
+    #[test]
+    fn test_already_started() {
+        test_helpers::test_already_started(LBRThreadTracer::new(LBRConfig::default()));
+    }
+
+    #[test]
+    fn test_not_started() {
+        test_helpers::test_not_started(LBRThreadTracer::new(LBRConfig::default()));
+    }
+
+    // Check that sampling a long-running loop gives well-formed blocks.
+    #[test]
+    fn test_blocks() {
+        let config = LBRConfig {
+            sample_period: 1000,
+            ..Default::default()
+        };
+        let mut tracer = LBRThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100000));
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!blocks.is_empty());
+        for b in blocks {
+            assert!(b.first_instr() <= b.last_instr());
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let config = LBRConfig {
+            sample_period: 1000,
+            ..Default::default()
+        };
+        let mut tracer = LBRThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100000));
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert!(trace
+            .iter_blocks()
+            .map(|b| b.unwrap())
+            .eq(trace2.iter_blocks().map(|b| b.unwrap())));
+    }
+
+    // Check that a serialized trace claiming an absurd number of blocks is rejected, rather than
+    // overflowing the allocation.
+    #[test]
+    fn test_deserialize_too_many_blocks() {
+        for &nblocks in &[u64::MAX, super::MAX_SERIALIZED_BLOCKS + 1] {
+            let mut buf = Vec::new();
+            write_header(&mut buf, BackendKind::LBR).unwrap();
+            buf.push(0);
+            buf.extend_from_slice(&nblocks.to_le_bytes());
+            match deserialize_trace(&mut buf.as_slice()) {
+                Err(HWTracerError::Custom(e)) => {
+                    assert_eq!(
+                        e.downcast_ref::<std::io::Error>().unwrap().kind(),
+                        std::io::ErrorKind::InvalidData
+                    );
+                }
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_zero_sample_period() {
+        let mut bldr = TracerBuilder::new().lbr();
+        match bldr.config() {
+            BackendConfig::LBR(ref mut lbr_conf) => lbr_conf.sample_period = 0,
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => assert_eq!(s, "sample_period must be positive"),
+            _ => panic!(),
+        }
+    }
+
+    // Check that a lack of LBR support found by the C code is reported as an unusable backend.
+    #[test]
+    fn test_cerror_no_hw_support() {
+        let mut cerr = LBRCError::new();
+        cerr.typ = LBRCErrorKind::NoHWSupport;
+        cerr.code = libc::ENOENT;
+        match HWTracerError::from(cerr) {
+            HWTracerError::Unsupported {
+                kind: BackendKind::LBR,
+                ..
+            } => (),
+            _ => panic!(),
+        }
+    }
+}
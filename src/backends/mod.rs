@@ -2,51 +2,151 @@ use crate::backends::dummy::DummyTracer;
 use crate::errors::HWTracerError;
 use crate::Tracer;
 
+#[cfg(coresight)]
+use crate::backends::coresight::CoreSightTracer;
+#[cfg(coresight)]
+pub mod coresight;
+#[cfg(lbr)]
+use crate::backends::lbr::LBRTracer;
+#[cfg(lbr)]
+pub mod lbr;
 #[cfg(perf_pt)]
 pub mod perf_pt;
 #[cfg(perf_pt)]
 use crate::backends::perf_pt::PerfPTTracer;
-#[cfg(perf_pt)]
+#[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::__cpuid_count;
 use libc::size_t;
 pub mod dummy;
+use std::cmp;
+use std::fmt;
+#[cfg(lbr)]
+use std::fs;
+use std::ops::Range;
+#[cfg(any(perf_pt, coresight))]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BackendKind {
     Dummy,
     PerfPT,
+    CoreSight,
+    LBR,
 }
 
 const PERF_PT_DFLT_DATA_BUFSIZE: size_t = 64;
 const PERF_PT_DFLT_AUX_BUFSIZE: size_t = 1024;
 const PERF_PT_DFLT_INITIAL_TRACE_BUFSIZE: size_t = 1024 * 1024; // 1MiB
+const CORESIGHT_DFLT_DATA_BUFSIZE: size_t = 64;
+const CORESIGHT_DFLT_AUX_BUFSIZE: size_t = 1024;
+const LBR_DFLT_DATA_BUFSIZE: size_t = 64;
+const LBR_DFLT_SAMPLE_PERIOD: u64 = 10000;
+
+// Linux exposes a perf PMU by this name if the kernel supports Intel PT.
+#[cfg(perf_pt)]
+const PERF_PT_PMU_PATH: &str = "/sys/bus/event_source/devices/intel_pt";
+
+// Linux exposes a perf PMU by this name if the system has CoreSight ETM support.
+#[cfg(coresight)]
+const CORESIGHT_PMU_PATH: &str = "/sys/bus/event_source/devices/cs_etm";
+
+// Linux reports the number of LBR entries in this file, if the CPU has an LBR.
+#[cfg(lbr)]
+const LBR_CAPS_PATH: &str = "/sys/bus/event_source/devices/cpu/caps/branches";
 
 impl BackendKind {
+    // All backends, most preferred first.
+    const ALL: [BackendKind; 4] = [
+        BackendKind::PerfPT,
+        BackendKind::CoreSight,
+        BackendKind::LBR,
+        BackendKind::Dummy,
+    ];
+
     // Finds a suitable `BackendKind` for the current hardware/OS.
+    //
+    // LBR traces are only samples of the executed code, so the LBR backend is never the default.
     fn default_platform_backend() -> BackendKind {
-        let tr_kinds = vec![BackendKind::PerfPT, BackendKind::Dummy];
-        for kind in tr_kinds {
-            if Self::match_platform(&kind).is_ok() {
-                return kind;
-            }
+        match Self::all_available()
+            .into_iter()
+            .find(|k| *k != BackendKind::LBR)
+        {
+            Some(kind) => kind,
+            // The Dummy backend should always be usable.
+            None => unreachable!(),
         }
-        // The Dummy backend should always be usable.
-        unreachable!();
     }
 
-    /// Returns `Ok` if the this backend is appropriate for the current platform.
-    fn match_platform(&self) -> Result<(), HWTracerError> {
+    /// Returns the backends which are appropriate for the current platform, most preferred first.
+    /// The Dummy backend is always included (last).
+    pub fn all_available() -> Vec<BackendKind> {
+        Self::ALL
+            .iter()
+            .filter(|k| k.is_available())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns `Ok` if the this backend is appropriate for the current platform. Otherwise returns
+    /// `HWTracerError::Unsupported`, whether the backend wasn't compiled in or the hardware or
+    /// kernel doesn't support it, so that consumers can fall back to another backend (e.g. the
+    /// Dummy backend).
+    ///
+    /// This is checked at runtime, so a binary built with a backend compiled in can still be run
+    /// on machines which don't support it.
+    pub fn match_platform(&self) -> Result<(), HWTracerError> {
         match self {
             BackendKind::Dummy => Ok(()),
             BackendKind::PerfPT => {
                 #[cfg(not(perf_pt))]
-                return Err(HWTracerError::BackendUnavailable(BackendKind::PerfPT));
+                return Err(HWTracerError::not_compiled_in(BackendKind::PerfPT));
                 #[cfg(perf_pt)]
                 {
-                    if !Self::pt_supported() {
-                        return Err(HWTracerError::NoHWSupport(
-                            "Intel PT not supported by CPU".into(),
-                        ));
+                    if !pt_supported() {
+                        return Err(HWTracerError::Unsupported {
+                            kind: BackendKind::PerfPT,
+                            reason: "Intel PT not supported by the CPU".into(),
+                        });
+                    }
+                    if !Path::new(PERF_PT_PMU_PATH).exists() {
+                        return Err(HWTracerError::Unsupported {
+                            kind: BackendKind::PerfPT,
+                            reason: "Intel PT not supported by the kernel".into(),
+                        });
+                    }
+                    Ok(())
+                }
+            }
+            BackendKind::CoreSight => {
+                #[cfg(not(coresight))]
+                return Err(HWTracerError::not_compiled_in(BackendKind::CoreSight));
+                #[cfg(coresight)]
+                {
+                    if !Path::new(CORESIGHT_PMU_PATH).exists() {
+                        return Err(HWTracerError::Unsupported {
+                            kind: BackendKind::CoreSight,
+                            reason: "CoreSight ETM not supported by the system".into(),
+                        });
+                    }
+                    Ok(())
+                }
+            }
+            BackendKind::LBR => {
+                #[cfg(not(lbr))]
+                return Err(HWTracerError::not_compiled_in(BackendKind::LBR));
+                #[cfg(lbr)]
+                {
+                    let depth = fs::read_to_string(LBR_CAPS_PATH)
+                        .ok()
+                        .and_then(|s| s.trim().parse::<u32>().ok());
+                    if depth.unwrap_or(0) == 0 {
+                        return Err(HWTracerError::Unsupported {
+                            kind: BackendKind::LBR,
+                            reason: "LBR not supported by the system".into(),
+                        });
                     }
                     Ok(())
                 }
@@ -54,14 +154,47 @@ impl BackendKind {
         }
     }
 
-    /// Checks if the CPU supports Intel Processor Trace.
-    #[cfg(perf_pt)]
-    fn pt_supported() -> bool {
-        let res = unsafe { __cpuid_count(0x7, 0x0) };
-        (res.ebx & (1 << 25)) != 0
+    /// Returns `true` if this backend is appropriate for the current platform. See
+    /// `match_platform()`.
+    pub fn is_available(&self) -> bool {
+        self.match_platform().is_ok()
+    }
+}
+
+/// Parses the name of a backend, e.g. `"perf_pt"`, as found in a configuration file. Names are
+/// case-insensitive, and underscores are ignored, so the names returned by `Tracer::name()` (e.g.
+/// `"PerfPT"`) are also accepted. Unrecognised names give `HWTracerError::UnknownBackend`.
+impl FromStr for BackendKind {
+    type Err = HWTracerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.replace('_', "").to_lowercase();
+        match name.as_str() {
+            "dummy" => Ok(BackendKind::Dummy),
+            "perfpt" => Ok(BackendKind::PerfPT),
+            "coresight" => Ok(BackendKind::CoreSight),
+            "lbr" => Ok(BackendKind::LBR),
+            _ => Err(HWTracerError::UnknownBackend(s.to_owned())),
+        }
     }
 }
 
+/// Checks at runtime if the CPU supports Intel Processor Trace.
+///
+/// This says nothing about whether the PerfPT backend was compiled in, or whether the kernel
+/// supports Intel PT. Use `BackendKind::PerfPT.match_platform()` to check those too.
+#[cfg(target_arch = "x86_64")]
+pub fn pt_supported() -> bool {
+    let res = unsafe { __cpuid_count(0x7, 0x0) };
+    (res.ebx & (1 << 25)) != 0
+}
+
+/// Checks at runtime if the CPU supports Intel Processor Trace.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn pt_supported() -> bool {
+    false
+}
+
 /// Generic configuration interface for all backends.
 /// If a field is `None` at `build()` time then the backend will select a default value. Ant
 /// attributes which don't apply to a given backend are also checked.
@@ -69,20 +202,189 @@ impl BackendKind {
 pub enum BackendConfig {
     Dummy,
     PerfPT(PerfPTConfig),
+    CoreSight(CoreSightConfig),
+    LBR(LBRConfig),
+}
+
+/// Where a backend stores trace data as it is collected.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TraceSink {
+    /// Store the trace in a heap buffer, which grows as required.
+    Memory,
+    /// Write the trace to the file at the specified path, which is replaced by a new file each
+    /// time tracing starts. The resulting trace memory-maps the file, so the trace is read from
+    /// disk as it is decoded. Earlier traces keep their own (now unlinked) files, so they remain
+    /// decodable. The file is not deleted when the trace is dropped. Since each thread tracer
+    /// writes to the same path, the path only holds the trace most recently started.
+    File(PathBuf),
+}
+
+/// Which timing packets the PerfPT backend emits. See `PerfPTConfig::timing_mode()`.
+///
+/// Each mode also emits the packets of the modes before it. Finer timing makes traces larger, and
+/// so more prone to overflow.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum TimingMode {
+    /// No timing packets.
+    None,
+    /// TSC packets, which give the time stamp counter only occasionally (e.g. at each PSB).
+    Tsc,
+    /// MTC packets, which regularly update the time from the CPU's crystal clock.
+    Mtc,
+    /// CYC packets, which count the core cycles between packets, for cycle-accurate timing.
+    Cyc,
 }
 
 /// Configures the PerfPT backend.
 ///
-// Must stay in sync with the C code.
+/// Each thread tracer opens a perf event for its thread alone, rather than one per CPU, so the
+/// event (and its AUX buffer) follows the thread as it migrates between CPUs. The trace of a
+/// thread is therefore a single stream, in execution order, however the thread is scheduled.
 #[derive(Clone, Debug)]
-#[repr(C)]
 pub struct PerfPTConfig {
-    /// Data buffer size, in pages. Must be a power of 2.
+    /// Data buffer size, in pages. Must be a non-zero power of 2. This is independent of
+    /// `aux_bufsize`, and excludes the header page which perf maps in front of the data buffer.
+    /// The data buffer holds perf's records announcing new trace data in the AUX buffer, rather
+    /// than the trace itself: if it fills up, the records are lost, and collection fails with
+    /// `HWTracerError::HWBufferOverflow`.
     pub data_bufsize: size_t,
-    /// AUX buffer size, in pages. Must be a power of 2.
+    /// AUX buffer size, in pages. Must be a non-zero power of 2, since the kernel refuses to map
+    /// an Intel PT AUX buffer of any other size. A larger AUX buffer makes it less likely that
+    /// long-running traced code will overflow the buffer.
     pub aux_bufsize: size_t,
     /// The initial trace storage buffer size (in bytes) of new traces.
     pub initial_trace_bufsize: size_t,
+    /// Emit timing packets, so that `Trace::iter_timed_blocks()` can report when blocks ran. This
+    /// makes traces larger, and so more prone to overflow. This is the same as setting `timing` to
+    /// `TimingMode::Tsc`, and if `timing` is finer, it takes precedence.
+    pub tsc: bool,
+    /// Which timing packets to emit. See `timing_mode()`.
+    pub timing: TimingMode,
+    /// Don't trace kernel code. Tracing the kernel requires more privileges than tracing
+    /// user-space code, and kernel blocks can't be decoded using the code of the current process.
+    pub exclude_kernel: bool,
+    /// Don't trace user-space code.
+    pub exclude_user: bool,
+    /// Where to store the trace. With `TraceSink::File`, `initial_trace_bufsize` is ignored and
+    /// streaming is not supported.
+    pub sink: TraceSink,
+    /// If set, tracing stops automatically once (roughly) this many bytes of trace data have been
+    /// collected, bounding the time and memory spent on long-running traced code. See
+    /// `Trace::auto_stopped()`. Must be positive.
+    pub max_bytes: Option<u64>,
+    /// If set, the encoding of how often PSB packets are emitted: roughly every `2^(n + 11)`
+    /// bytes of trace data. Decoding can only resume after an error at a PSB packet, so more
+    /// frequent PSBs make decoding more robust, at the cost of larger traces. Only the encodings
+    /// which the CPU reports as supported are accepted. If `None`, the CPU's default is used.
+    pub psb_period: Option<u8>,
+    /// Trace in snapshot mode: the AUX buffer keeps only the most recent trace data, overwriting
+    /// the oldest, so tracing never overflows. `ThreadTracer::snapshot()` copies out the current
+    /// contents of the buffer, and the trace returned when tracing stops holds its final
+    /// contents. The size of the buffer (and so of each trace) is set by `aux_bufsize`. Snapshot
+    /// mode can't be used with a file sink, `max_bytes` or streaming.
+    pub snapshot: bool,
+    /// When decoding the trace fails part-way through (e.g. because packets were lost to an
+    /// overflow), skip to the next PSB packet and carry on, rather than ending the block
+    /// iterator. Each skipped region is reported as an `HWTracerError::DecodeGap` in place of the
+    /// blocks it held. See also `psb_period`.
+    pub resync_on_error: bool,
+    /// Keep the blocks of a trace in memory once its block iterator has been consumed in full,
+    /// so that later calls to `iter_blocks()` replay them rather than decoding the trace again.
+    /// This makes repeated iteration cheaper, at the cost of memory proportional to the number
+    /// of blocks. Traces which fail to decode aren't cached. See `Trace::is_decoded()`.
+    pub cache_blocks: bool,
+    /// A callback made as the AUX buffer fills up. See `on_aux_watermark()`.
+    pub aux_watermark: Option<Arc<AuxWatermark>>,
+    /// The address ranges to which tracing is restricted, or empty to trace all code. See
+    /// `filter_range()`.
+    pub filter_ranges: Vec<Range<u64>>,
+    /// Record the operand of each PTWRITE instruction executed by the traced code, along with
+    /// the address of the instruction. See `Trace::iter_ptwrites()`. Requires CPU support.
+    pub ptwrite: bool,
+    /// Let the hardware compress returns to the instruction after their call, recording only
+    /// that the return was taken (see `ReturnKind`). Disabling this makes every return record its
+    /// target, so that returns are exact even where the decoder's call stack is wrong (e.g. after
+    /// a `longjmp(3)`), at the cost of larger traces.
+    pub ret_compression: bool,
+    /// The periods (in microseconds) for which the tracing hardware is alternately on and off,
+    /// or `None` to trace continuously. See `duty_cycle()`.
+    pub duty_cycle_us: Option<(u64, u64)>,
+}
+
+impl PerfPTConfig {
+    /// Call `callback` from the collection thread whenever the unread trace data in the AUX
+    /// buffer reaches (roughly) `frac` of its size, so that a consumer can react before the buffer
+    /// overflows, e.g. by taking a streamed trace, or by pausing the traced thread. `frac` must be
+    /// in the range (0, 1].
+    ///
+    /// No locks are held when `callback` is called, but trace data isn't copied out of the AUX
+    /// buffer while it runs, so it should return promptly. Can't be used in snapshot mode, since
+    /// the AUX buffer never fills up.
+    pub fn on_aux_watermark<F>(&mut self, frac: f64, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.aux_watermark = Some(Arc::new(AuxWatermark {
+            frac,
+            callback: Box::new(callback),
+        }));
+    }
+
+    /// Restrict tracing to the code at the addresses `start..end` of the current process, so
+    /// that the hardware records nothing while the traced thread runs other code. This greatly
+    /// reduces the size of traces, and the time taken to decode them.
+    ///
+    /// Each call adds a range. How many ranges can be used at once depends on the CPU. The
+    /// kernel identifies user-space code by file, so each range must lie within an executable
+    /// segment of an object loaded from a file (i.e. not the VDSO). Can't be used when tracing
+    /// other programs.
+    pub fn filter_range(&mut self, start: u64, end: u64) {
+        self.filter_ranges.push(start..end);
+    }
+
+    /// Sample the control flow of the traced code, rather than recording all of it: the tracing
+    /// hardware is turned off after `on_us` microseconds, back on after a further `off_us`, and so
+    /// on, which greatly reduces the overhead (and size) of long-running traces. The resulting
+    /// traces consist of disjoint segments of execution (see `Trace::duty_cycled()`). Both
+    /// periods must be positive, and are only honoured roughly, since the hardware is turned on
+    /// and off by the collection thread. Can't be used when tracing starts on `execve(2)`.
+    pub fn duty_cycle(&mut self, on_us: u64, off_us: u64) {
+        self.duty_cycle_us = Some((on_us, off_us));
+    }
+
+    /// Emit exactly the timing packets of `mode`, overriding `tsc`. Finer modes give more precise
+    /// block times (see `Trace::iter_timed_blocks()`), at the cost of larger traces. MTC and CYC
+    /// packets require CPU support.
+    pub fn timing_mode(&mut self, mode: TimingMode) {
+        self.tsc = false;
+        self.timing = mode;
+    }
+
+    // The timing packets to emit, taking both `tsc` and `timing` into account.
+    #[allow(dead_code)] // Only used by the PerfPT backend, if compiled in.
+    pub(crate) fn effective_timing(&self) -> TimingMode {
+        match self.tsc {
+            true => cmp::max(self.timing, TimingMode::Tsc),
+            false => self.timing,
+        }
+    }
+}
+
+/// A callback made when the AUX buffer reaches a given fill level. See
+/// `PerfPTConfig::on_aux_watermark()`.
+pub struct AuxWatermark {
+    /// The fill level, as a fraction of the AUX buffer size.
+    pub(crate) frac: f64,
+    #[allow(dead_code)] // Only used by the PerfPT backend, if compiled in.
+    pub(crate) callback: Box<dyn Fn() + Send + Sync>,
+}
+
+impl fmt::Debug for AuxWatermark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuxWatermark")
+            .field("frac", &self.frac)
+            .finish()
+    }
 }
 
 impl Default for PerfPTConfig {
@@ -91,15 +393,87 @@ impl Default for PerfPTConfig {
             data_bufsize: PERF_PT_DFLT_DATA_BUFSIZE,
             aux_bufsize: PERF_PT_DFLT_AUX_BUFSIZE,
             initial_trace_bufsize: PERF_PT_DFLT_INITIAL_TRACE_BUFSIZE,
+            tsc: false,
+            timing: TimingMode::None,
+            exclude_kernel: true,
+            exclude_user: false,
+            sink: TraceSink::Memory,
+            max_bytes: None,
+            psb_period: None,
+            snapshot: false,
+            resync_on_error: false,
+            cache_blocks: false,
+            aux_watermark: None,
+            filter_ranges: Vec::new(),
+            ptwrite: false,
+            ret_compression: true,
+            duty_cycle_us: None,
+        }
+    }
+}
+
+/// Configures the CoreSight backend.
+///
+// Must stay in sync with the C code.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct CoreSightConfig {
+    /// Data buffer size, in pages. Must be a non-zero power of 2.
+    pub data_bufsize: size_t,
+    /// AUX buffer size, in pages. Must be a non-zero power of 2. The trace is copied out of this
+    /// buffer when tracing stops, so it bounds how much trace a single run can collect.
+    pub aux_bufsize: size_t,
+}
+
+impl Default for CoreSightConfig {
+    fn default() -> Self {
+        Self {
+            data_bufsize: CORESIGHT_DFLT_DATA_BUFSIZE,
+            aux_bufsize: CORESIGHT_DFLT_AUX_BUFSIZE,
+        }
+    }
+}
+
+/// Configures the LBR backend.
+///
+// Must stay in sync with the C code.
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct LBRConfig {
+    /// Data buffer size, in pages. Must be a non-zero power of 2. The samples are read out of
+    /// this buffer when tracing stops, so it bounds how many samples a single run can collect.
+    pub data_bufsize: size_t,
+    /// The number of branches executed between samples of the LBR. Must be non-zero. Smaller
+    /// periods capture more of the executed code, but fill the data buffer faster.
+    pub sample_period: u64,
+}
+
+impl Default for LBRConfig {
+    fn default() -> Self {
+        Self {
+            data_bufsize: LBR_DFLT_DATA_BUFSIZE,
+            sample_period: LBR_DFLT_SAMPLE_PERIOD,
         }
     }
 }
 
 impl BackendConfig {
+    // Returns the default configuration for the backend `kind`.
+    fn default_for(kind: BackendKind) -> Self {
+        match kind {
+            BackendKind::Dummy => BackendConfig::Dummy,
+            BackendKind::PerfPT => BackendConfig::PerfPT(PerfPTConfig::default()),
+            BackendKind::CoreSight => BackendConfig::CoreSight(CoreSightConfig::default()),
+            BackendKind::LBR => BackendConfig::LBR(LBRConfig::default()),
+        }
+    }
+
     fn backend_kind(&self) -> BackendKind {
         match self {
             BackendConfig::Dummy => BackendKind::Dummy,
             BackendConfig::PerfPT { .. } => BackendKind::PerfPT,
+            BackendConfig::CoreSight { .. } => BackendKind::CoreSight,
+            BackendConfig::LBR { .. } => BackendKind::LBR,
         }
     }
 }
@@ -124,6 +498,17 @@ impl BackendConfig {
 /// }
 /// ```
 ///
+/// # Make a tracer using the first available backend from a list of acceptable backends.
+/// ```
+/// use hwtracer::backends::{BackendKind, TracerBuilder};
+///
+/// let kind = BackendKind::all_available()
+///     .into_iter()
+///     .find(|k| *k == BackendKind::PerfPT || *k == BackendKind::Dummy)
+///     .unwrap();
+/// TracerBuilder::new().backend(kind).build().unwrap();
+/// ```
+///
 /// # Make a tracer with an appropriate default backend and using custom backend options if the PerfPT backend was chosen.
 /// ```
 /// use hwtracer::backends::{TracerBuilder, BackendConfig};
@@ -140,11 +525,16 @@ pub struct TracerBuilder {
 impl TracerBuilder {
     /// Create a new TracerBuilder using an appropriate default backend and configuration.
     pub fn new() -> Self {
-        let config = match BackendKind::default_platform_backend() {
-            BackendKind::Dummy => BackendConfig::Dummy,
-            BackendKind::PerfPT => BackendConfig::PerfPT(PerfPTConfig::default()),
-        };
-        Self { config }
+        Self {
+            config: BackendConfig::default_for(BackendKind::default_platform_backend()),
+        }
+    }
+
+    /// Choose to use the backend `kind` wth default options. This is useful when the backend is
+    /// chosen at runtime, e.g. from `BackendKind::all_available()`.
+    pub fn backend(mut self, kind: BackendKind) -> Self {
+        self.config = BackendConfig::default_for(kind);
+        self
     }
 
     /// Choose to use the PerfPT backend wth default options.
@@ -153,6 +543,18 @@ impl TracerBuilder {
         self
     }
 
+    /// Choose to use the CoreSight backend wth default options.
+    pub fn coresight(mut self) -> Self {
+        self.config = BackendConfig::CoreSight(CoreSightConfig::default());
+        self
+    }
+
+    /// Choose to use the LBR backend wth default options.
+    pub fn lbr(mut self) -> Self {
+        self.config = BackendConfig::LBR(LBRConfig::default());
+        self
+    }
+
     /// Choose to use the Dummy backend.
     pub fn dummy(mut self) -> Self {
         self.config = BackendConfig::Dummy;
@@ -178,6 +580,20 @@ impl TracerBuilder {
                 #[cfg(not(perf_pt))]
                 unreachable!();
             }
+            BackendConfig::CoreSight(_cs_conf) => {
+                // _cs_conf will be unused if coresight wasn't built in.
+                #[cfg(coresight)]
+                return Ok(Box::new(CoreSightTracer::new(_cs_conf)?));
+                #[cfg(not(coresight))]
+                unreachable!();
+            }
+            BackendConfig::LBR(_lbr_conf) => {
+                // _lbr_conf will be unused if lbr wasn't built in.
+                #[cfg(lbr)]
+                return Ok(Box::new(LBRTracer::new(_lbr_conf)?));
+                #[cfg(not(lbr))]
+                unreachable!();
+            }
             BackendConfig::Dummy => Ok(Box::new(DummyTracer::new())),
         }
     }
@@ -185,7 +601,24 @@ impl TracerBuilder {
 
 #[cfg(test)]
 mod tests {
-    use super::{BackendConfig, TracerBuilder};
+    use super::{
+        pt_supported, BackendConfig, BackendKind, PerfPTConfig, TimingMode, TracerBuilder,
+    };
+    use crate::errors::HWTracerError;
+
+    // Check that `tsc` and `timing` combine, and that `timing_mode()` overrides `tsc`.
+    #[test]
+    fn test_effective_timing() {
+        let mut config = PerfPTConfig::default();
+        assert_eq!(config.effective_timing(), TimingMode::None);
+        config.tsc = true;
+        assert_eq!(config.effective_timing(), TimingMode::Tsc);
+        config.timing = TimingMode::Cyc;
+        assert_eq!(config.effective_timing(), TimingMode::Cyc);
+        config.tsc = true;
+        config.timing_mode(TimingMode::None);
+        assert_eq!(config.effective_timing(), TimingMode::None);
+    }
 
     // Check that building a default Tracer works.
     #[test]
@@ -209,10 +642,142 @@ mod tests {
     fn test_backend_unavailable() {
         match TracerBuilder::new().perf_pt().build() {
             Ok(_) => panic!("backend should be unavailable"),
-            Err(e) => assert_eq!(e.to_string(), "Backend unavailble: PerfPT"),
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "Backend unavailble: PerfPT (not compiled in to hwtracer)"
+            ),
+        }
+    }
+
+    #[cfg(not(coresight))]
+    #[test]
+    fn test_coresight_unavailable() {
+        match TracerBuilder::new().coresight().build() {
+            Ok(_) => panic!("backend should be unavailable"),
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "Backend unavailble: CoreSight (not compiled in to hwtracer)"
+            ),
+        }
+    }
+
+    #[cfg(not(lbr))]
+    #[test]
+    fn test_lbr_unavailable() {
+        match TracerBuilder::new().lbr().build() {
+            Ok(_) => panic!("backend should be unavailable"),
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "Backend unavailble: LBR (not compiled in to hwtracer)"
+            ),
+        }
+    }
+
+    // Whatever the reason a backend can't be used, consumers can detect it in the same way.
+    #[test]
+    fn test_unavailable_is_unsupported() {
+        for kind in &BackendKind::ALL {
+            if let Err(e) = kind.match_platform() {
+                match e {
+                    HWTracerError::Unsupported { kind: k, .. } => assert_eq!(k, *kind),
+                    _ => panic!(),
+                }
+                let res = match kind {
+                    BackendKind::PerfPT => TracerBuilder::new().perf_pt().build(),
+                    BackendKind::CoreSight => TracerBuilder::new().coresight().build(),
+                    BackendKind::LBR => TracerBuilder::new().lbr().build(),
+                    BackendKind::Dummy => unreachable!(),
+                };
+                match res {
+                    Err(HWTracerError::Unsupported { kind: k, .. }) => assert_eq!(k, *kind),
+                    _ => panic!(),
+                }
+            }
+        }
+    }
+
+    // The LBR backend is never chosen by default, since its traces are incomplete.
+    #[test]
+    fn test_lbr_not_default() {
+        assert_ne!(BackendKind::default_platform_backend(), BackendKind::LBR);
+    }
+
+    #[test]
+    fn test_backend_kind_from_str() {
+        assert_eq!(
+            "perf_pt".parse::<BackendKind>().unwrap(),
+            BackendKind::PerfPT
+        );
+        assert_eq!(
+            "PerfPT".parse::<BackendKind>().unwrap(),
+            BackendKind::PerfPT
+        );
+        assert_eq!("dummy".parse::<BackendKind>().unwrap(), BackendKind::Dummy);
+        assert_eq!(
+            "CoreSight".parse::<BackendKind>().unwrap(),
+            BackendKind::CoreSight
+        );
+        assert_eq!("LBR".parse::<BackendKind>().unwrap(), BackendKind::LBR);
+        match "perf".parse::<BackendKind>() {
+            Err(HWTracerError::UnknownBackend(s)) => assert_eq!(s, "perf"),
+            _ => panic!(),
+        }
+        let tracer = TracerBuilder::new()
+            .backend("dummy".parse().unwrap())
+            .build();
+        assert_eq!(tracer.unwrap().name(), "Dummy");
+    }
+
+    // The Dummy backend is usable everywhere.
+    #[test]
+    fn test_dummy_available() {
+        assert!(BackendKind::Dummy.is_available());
+    }
+
+    // The PerfPT backend can't be available if the CPU doesn't support Intel PT.
+    #[test]
+    fn test_perf_pt_available() {
+        if !pt_supported() {
+            assert!(!BackendKind::PerfPT.is_available());
         }
     }
 
+    // Check that every available backend can be built by kind.
+    #[test]
+    fn test_builder_all_available() {
+        let kinds = BackendKind::all_available();
+        assert_eq!(kinds.last(), Some(&BackendKind::Dummy));
+        for kind in kinds {
+            assert!(TracerBuilder::new().backend(kind).build().is_ok());
+        }
+    }
+
+    // Check that tracing a closure hands back the closure's result.
+    #[test]
+    fn test_trace_closure() {
+        let tracer = TracerBuilder::new().build().unwrap();
+        let (trace, res) = tracer.trace(|| 6 * 7).unwrap();
+        assert_eq!(res, 42);
+        assert!(!trace.overflowed());
+    }
+
+    // Check that a panic in a traced closure is caught, and that the trace is still returned.
+    #[test]
+    fn test_trace_catch_unwind() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let (trace, res) = tracer.trace_catch_unwind(|| 6 * 7).unwrap();
+        assert_eq!(res.unwrap(), 42);
+        assert!(!trace.overflowed());
+
+        let (_, res) = tracer
+            .trace_catch_unwind(|| -> u64 { panic!("in traced code") })
+            .unwrap();
+        assert_eq!(
+            *res.unwrap_err().downcast::<&str>().unwrap(),
+            "in traced code"
+        );
+    }
+
     // Ensure we can share `Tracer`s between threads.
     #[test]
     fn test_shared_tracers_betwen_threads() {
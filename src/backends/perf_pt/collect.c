@@ -64,6 +64,8 @@
 #include "perf_pt_private.h"
 
 #define SYSFS_PT_TYPE   "/sys/bus/event_source/devices/intel_pt/type"
+// This file exists only if the kernel was built with perf events.
+#define PERF_PARANOID_PATH "/proc/sys/kernel/perf_event_paranoid"
 #define MAX_PT_TYPE_STR 8
 
 #define MAX_OPEN_PERF_TRIES  20000
@@ -71,10 +73,74 @@
 
 #define AUX_BUF_WAKE_RATIO 0.5
 
+// The bit of `perf_event_attr.config` which asks for TSC packets.
+// See /sys/bus/event_source/devices/intel_pt/format/tsc
+#define PERF_PT_CONFIG_TSC (1 << 10)
+
+// The bits of `perf_event_attr.config` which ask for PTW packets, each
+// followed by a FUP packet giving the IP of the PTWRITE instruction.
+// See /sys/bus/event_source/devices/intel_pt/format/{ptw,fup_on_ptw}
+#define PERF_PT_CONFIG_PTW        (1 << 12)
+#define PERF_PT_CONFIG_FUP_ON_PTW (1 << 5)
+
+// The bit of `perf_event_attr.config` which asks for MTC packets, and the
+// first bit of the field which sets their period.
+// See /sys/bus/event_source/devices/intel_pt/format/{mtc,mtc_period}
+#define PERF_PT_CONFIG_MTC              (1 << 9)
+#define PERF_PT_CONFIG_MTC_PERIOD_SHIFT 14
+
+// The bit of `perf_event_attr.config` which asks for CYC packets.
+// See /sys/bus/event_source/devices/intel_pt/format/cyc
+#define PERF_PT_CONFIG_CYC (1 << 1)
+
+// The bit of `perf_event_attr.config` which turns off return compression.
+// See /sys/bus/event_source/devices/intel_pt/format/noretcomp
+#define PERF_PT_CONFIG_NORETCOMP (1 << 11)
+
+// The first bit of the field of `perf_event_attr.config` which sets the PSB
+// period. See /sys/bus/event_source/devices/intel_pt/format/psb_period
+#define PERF_PT_CONFIG_PSB_PERIOD_SHIFT 24
+
 #ifndef INFTIM
 #define INFTIM -1
 #endif
 
+/*
+ * The size limit of a trace, enforced as trace data is read out of the AUX
+ * buffer.
+ */
+struct trace_limit {
+    int         perf_fd;        // Used to turn off tracing at the limit.
+    __u64       max_bytes;      // The limit, or 0 for no limit.
+    __u64       total_bytes;    // Bytes collected so far, across all data
+                                // taken by perf_pt_take_trace().
+};
+
+/*
+ * A callback made when the unread data in the AUX buffer reaches a threshold.
+ */
+struct aux_watermark {
+    size_t      bytes;          // The threshold, or 0 for no callback.
+    void        (*cb)(void *);  // The callback, passed `data`.
+    void        *data;
+};
+
+/*
+ * Periodically turns the tracing hardware off and on again, so that only a
+ * sample of the traced code's control flow is recorded.
+ */
+struct duty_cycle {
+    int             perf_fd;    // Used to turn tracing on and off.
+    __u64           on_us;      // How long the hardware stays on, or 0 for
+                                // no duty cycling.
+    __u64           off_us;     // How long the hardware stays off.
+    bool            on;         // Is the hardware currently on?
+    bool            stopped;    // Has the hardware been turned off for good?
+                                // Guarded by the tracer's `trace_lock`.
+    struct timespec next;       // When to next turn the hardware on or off
+                                // (on the monotonic clock).
+};
+
 /*
  * Stores all information about the tracer.
  * Exposed to Rust only as an opaque pointer.
@@ -89,6 +155,15 @@ struct tracer_ctx {
     size_t              aux_bufsize;        // The size of the AUX buffer's mmap(2).
     void                *base_buf;          // Ptr to the start of the base buffer.
     size_t              base_bufsize;       // The size the base buffer's mmap(2).
+    struct perf_pt_trace
+                        *trace;             // The trace being collected.
+    pthread_mutex_t     trace_lock;         // Guards `trace` while tracing.
+    struct trace_limit  limit;              // Limits the size of `trace`.
+    bool                snapshot;           // Is the AUX buffer overwritten?
+    bool                enable_on_exec;     // Does the kernel enable tracing?
+    struct aux_watermark
+                        watermark;          // Called as the AUX buffer fills.
+    struct duty_cycle   duty_cycle;         // Turns tracing on and off.
 };
 
 /*
@@ -100,6 +175,37 @@ struct perf_pt_config {
     size_t      aux_bufsize;           // AUX buf size (in pages).
     size_t      initial_trace_bufsize; // Initial capacity (in bytes) of a
                                        // trace storage buffer.
+    bool        tsc;                   // Emit TSC timing packets?
+    bool        exclude_kernel;        // Don't trace kernel code?
+    bool        exclude_user;          // Don't trace user-space code?
+    __u64       max_bytes;             // Stop tracing after collecting this
+                                       // many bytes (0 for no limit).
+    int         psb_period;            // PSB period encoding (-1 for the
+                                       // CPU's default).
+    bool        snapshot;              // Overwrite old data in the AUX
+                                       // buffer, for taking snapshots?
+    bool        enable_on_exec;        // Start tracing when the target
+                                       // next calls execve(2)?
+    double      aux_watermark;         // Call `aux_watermark_cb` when this
+                                       // fraction of the AUX buffer is
+                                       // unread (0 for never).
+    void        (*aux_watermark_cb)(void *);
+    void        *aux_watermark_data;   // Passed to `aux_watermark_cb`.
+    int         cgroup_cpu;            // If not -1, the target is a cgroup
+                                       // file descriptor, whose tasks are
+                                       // traced while they run on this CPU.
+    const char  *addr_filter;          // Trace only the code matched by this
+                                       // perf address filter (NULL for all
+                                       // code).
+    bool        ptwrite;               // Emit PTW packets for PTWRITE
+                                       // instructions?
+    bool        mtc;                   // Emit MTC timing packets?
+    bool        cyc;                   // Emit CYC timing packets?
+    bool        noretcomp;             // Turn off return compression?
+    __u64       duty_cycle_on_us;      // Turn the hardware off after this
+                                       // long (0 for never).
+    __u64       duty_cycle_off_us;     // Turn the hardware back on after
+                                       // this long.
 };
 
 /*
@@ -111,15 +217,54 @@ struct perf_pt_trace_buf {
     void *p;
 };
 
+/*
+ * The time at which the kernel reported that the trace data up to `offset`
+ * had been written to the AUX buffer. Shared with Rust code. Must stay in
+ * sync.
+ */
+struct perf_pt_aux_time {
+    __u64 offset;
+    __u64 time;
+};
+
+/*
+ * A growable array of `struct perf_pt_aux_time`s. Allocated by C, and freed
+ * by Rust. Shared with Rust code. Must stay in sync.
+ */
+struct perf_pt_aux_times {
+    struct perf_pt_aux_time *p;
+    size_t len;
+    size_t capacity;
+};
+
 /*
  * Storage for a trace.
  *
+ * If `fd` is not -1, then trace data is appended to the file `fd` instead of
+ * `buf`, and `buf` and `capacity` are unused.
+ *
+ * `auto_stopped` is set if tracing was stopped because the size limit of the
+ * trace was reached.
+ *
+ * `aux_pos` is the position in the AUX data stream (as counted by the
+ * monotonic `aux_head`) up to which data has been copied into the trace.
+ *
+ * `aux_size` is the size of the AUX buffer, and `aux_peak` the most data that
+ * was waiting in it to be copied into the trace at once.
+ *
  * Shared with Rust code. Must stay in sync.
  */
 struct perf_pt_trace {
     struct perf_pt_trace_buf buf;
     __u64 len;
     __u64 capacity;
+    bool overflowed;
+    int fd;
+    bool auto_stopped;
+    __u64 aux_pos;
+    struct perf_pt_aux_times aux_times;
+    __u64 aux_size;
+    __u64 aux_peak;
 };
 
 /*
@@ -131,9 +276,15 @@ struct tracer_thread_args {
     sem_t               *tracer_init_sem;   // Tracer init sync.
     struct perf_pt_trace
                         *trace;             // Pointer to trace storage.
+    pthread_mutex_t     *trace_lock;        // Guards `trace`.
     void                *aux_buf;           // The AUX buffer itself;
     struct perf_event_mmap_page
                         *base_header;       // Pointer to the header in the base buffer.
+    struct trace_limit  *limit;             // Limits the size of `trace`.
+    bool                snapshot;           // Is the AUX buffer overwritten?
+    struct aux_watermark
+                        *watermark;         // Called as the AUX buffer fills.
+    struct duty_cycle   *duty_cycle;        // Turns tracing on and off.
     struct perf_pt_cerror
                         *err;               // Errors generated inside the thread.
 };
@@ -159,17 +310,33 @@ struct read_format {
 
 // Private prototypes.
 static bool handle_sample(void *, struct perf_event_mmap_page *, struct
-                          perf_pt_trace *, void *, struct perf_pt_cerror *);
+                          perf_pt_trace *, struct trace_limit *, bool, void *,
+                          struct perf_pt_cerror *);
 static bool read_aux(void *, struct perf_event_mmap_page *,
+                     struct perf_pt_trace *, struct trace_limit *,
+                     struct perf_pt_cerror *);
+static bool copy_aux(void *, struct perf_event_mmap_page *,
                      struct perf_pt_trace *, struct perf_pt_cerror *);
+static bool reserve_trace(struct perf_pt_trace *, __u64, struct perf_pt_cerror *);
+static bool record_aux_time(struct perf_pt_trace *, struct perf_record_aux_sample *,
+                            struct perf_pt_cerror *);
+static bool duty_cycle_step(struct duty_cycle *, struct perf_pt_trace *,
+                            pthread_mutex_t *, struct perf_pt_cerror *);
+static __u64 aux_unread(struct perf_event_mmap_page *);
+static size_t aux_watermark_bytes(struct perf_pt_config *);
+static bool write_all(int, void *, size_t, struct perf_pt_cerror *);
 static bool poll_loop(int, int, struct perf_event_mmap_page *, void *,
-                      struct perf_pt_trace *, struct perf_pt_cerror *);
+                      struct perf_pt_trace *, struct trace_limit *, bool,
+                      struct aux_watermark *, struct duty_cycle *,
+                      pthread_mutex_t *, struct perf_pt_cerror *);
 static void *tracer_thread(void *);
-static int open_perf(size_t, struct perf_pt_cerror *);
+static int open_perf(struct perf_pt_config *, pid_t, struct perf_pt_cerror *);
 
 // Exposed Prototypes.
-struct tracer_ctx *perf_pt_init_tracer(struct perf_pt_config *, struct perf_pt_cerror *);
+struct tracer_ctx *perf_pt_init_tracer(struct perf_pt_config *, pid_t, struct perf_pt_cerror *);
 bool perf_pt_start_tracer(struct tracer_ctx *, struct perf_pt_trace *, struct perf_pt_cerror *);
+bool perf_pt_take_trace(struct tracer_ctx *, struct perf_pt_trace *, struct perf_pt_cerror *);
+bool perf_pt_snapshot(struct tracer_ctx *, struct perf_pt_trace *, bool, struct perf_pt_cerror *);
 bool perf_pt_stop_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_cerror *);
 bool perf_pt_free_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_cerror *);
 
@@ -183,8 +350,8 @@ bool perf_pt_free_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_cerror *);
  */
 static bool
 handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
-              struct perf_pt_trace *trace, void *data_tmp,
-              struct perf_pt_cerror *err)
+              struct perf_pt_trace *trace, struct trace_limit *limit,
+              bool snapshot, void *data_tmp, struct perf_pt_cerror *err)
 {
     // We need to use atomics with orderings to protect against 2 cases.
     //
@@ -233,14 +400,26 @@ handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
         case PERF_RECORD_AUX:
                 // Data was written to the AUX buffer.
                 rec_aux_sample = next_sample;
+                // Once the trace has overflowed, there is a gap in the packet
+                // stream and any data arriving afterwards is useless to us.
+                // Likewise, nothing is wanted once the size limit is reached.
+                // In snapshot mode, the data is left in the AUX buffer until
+                // a snapshot is taken.
+                if (trace->overflowed || trace->auto_stopped || snapshot) {
+                    break;
+                }
+                if (read_aux(aux_buf, hdr, trace, limit, err) == false) {
+                    return false;
+                }
                 // Check that the data written into the AUX buffer was not
                 // truncated. If it was, then we didn't read out of the data buffer
-                // quickly/frequently enough.
+                // quickly/frequently enough. The data up to the truncation
+                // point is still valid, so we keep it and flag the trace for
+                // the consumer to inspect.
                 if (rec_aux_sample->flags & PERF_AUX_FLAG_TRUNCATED) {
-                    perf_pt_set_err(err, perf_pt_cerror_ipt, pte_overflow);
-                    return false;
+                    trace->overflowed = true;
                 }
-                if (read_aux(aux_buf, hdr, trace, err) == false) {
+                if (!record_aux_time(trace, rec_aux_sample, err)) {
                     return false;
                 }
                 break;
@@ -260,12 +439,69 @@ handle_sample(void *aux_buf, struct perf_event_mmap_page *hdr,
 }
 
 /*
- * Read data out of the AUX buffer.
+ * Write `len` bytes starting at `buf` to the file `fd`.
+ *
+ * Returns true on success and false otherwise.
+ */
+static bool
+write_all(int fd, void *buf, size_t len, struct perf_pt_cerror *err)
+{
+    while (len > 0) {
+        ssize_t written = write(fd, buf, len);
+        if (written == -1) {
+            if (errno == EINTR) {
+                continue;
+            }
+            perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+            return false;
+        }
+        buf += written;
+        len -= written;
+    }
+    return true;
+}
+
+/*
+ * Read data out of the AUX buffer, stopping tracing if the size limit of the
+ * trace is reached.
  *
  * Reads from `aux_buf` (whose meta-data is in `hdr`) into `trace`.
+ *
+ * Returns true on success and false otherwise.
  */
-bool
+static bool
 read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
+         struct perf_pt_trace *trace, struct trace_limit *limit,
+         struct perf_pt_cerror *err)
+{
+    __u64 old_len = trace->len;
+    if (!copy_aux(aux_buf, hdr, trace, err)) {
+        return false;
+    }
+    limit->total_bytes += trace->len - old_len;
+    if ((limit->max_bytes == 0) || (limit->total_bytes < limit->max_bytes)) {
+        return true;
+    }
+
+    // The limit has been reached, so turn off the tracing hardware. Disabling
+    // the event flushes any packets still buffered by the hardware into the
+    // AUX buffer, so copy those out too rather than cut the trace short.
+    if (ioctl(limit->perf_fd, PERF_EVENT_IOC_DISABLE, 0) < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        return false;
+    }
+    trace->auto_stopped = true;
+    return copy_aux(aux_buf, hdr, trace, err);
+}
+
+/*
+ * Copy the data in the AUX buffer `aux_buf` (whose meta-data is in `hdr`) to
+ * the end of `trace`.
+ *
+ * Returns true on success and false otherwise.
+ */
+static bool
+copy_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
          struct perf_pt_trace *trace, struct perf_pt_cerror *err)
 {
     // Use of atomics here for the same reasons as for handle_sample().
@@ -286,9 +522,127 @@ read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
         // Wrap-around.
         new_data_size = (size - tail) + head;
     }
+    if (new_data_size > trace->aux_peak) {
+        trace->aux_peak = new_data_size;
+    }
+
+    // If the trace is collected into a file, append the new AUX data to it.
+    if (trace->fd != -1) {
+        if (tail <= head) {
+            if (!write_all(trace->fd, aux_buf + tail, head - tail, err)) {
+                return false;
+            }
+        } else {
+            if (!write_all(trace->fd, aux_buf + tail, size - tail, err)) {
+                return false;
+            }
+            if (!write_all(trace->fd, aux_buf, head, err)) {
+                return false;
+            }
+        }
+        trace->len += new_data_size;
+        trace->aux_pos = head_monotonic;
+        atomic_store_explicit((_Atomic __u64 *) &hdr->aux_tail, head, memory_order_release);
+        return true;
+    }
 
     // Reallocate the trace storage buffer if more space is required.
-    __u64 required_capacity = trace->len + new_data_size;
+    if (!reserve_trace(trace, new_data_size, err)) {
+        return false;
+    }
+
+    // Finally append the new AUX data to the end of the trace storage buffer.
+    if (tail <= head) {
+        memcpy(trace->buf.p + trace->len, aux_buf + tail, head - tail);
+        trace->len += head - tail;
+    } else {
+        memcpy(trace->buf.p + trace->len, aux_buf + tail, size - tail);
+        trace->len += size - tail;
+        memcpy(trace->buf.p + trace->len, aux_buf, head);
+        trace->len += size + head;
+    }
+    trace->aux_pos = head_monotonic;
+    atomic_store_explicit((_Atomic __u64 *) &hdr->aux_tail, head, memory_order_release);
+    return true;
+}
+
+/*
+ * Record the time at which the AUX data reported by `rec` was written, if
+ * that data is part of `trace`.
+ *
+ * Returns true on success and false otherwise.
+ */
+static bool
+record_aux_time(struct perf_pt_trace *trace, struct perf_record_aux_sample *rec,
+                struct perf_pt_cerror *err)
+{
+    // The data reported by the record ends at this position in the AUX data
+    // stream. The trace may have been copied beyond it, and data before the
+    // start of the trace may have been taken by perf_pt_take_trace().
+    __u64 end = rec->aux_offset + rec->aux_size;
+    if ((end > trace->aux_pos) || (trace->aux_pos - end > trace->len)) {
+        return true;
+    }
+
+    struct perf_pt_aux_times *times = &trace->aux_times;
+    if (times->len == times->capacity) {
+        size_t new_capacity = (times->capacity == 0) ? 16 : times->capacity * 2;
+        void *new_p = realloc(times->p, new_capacity * sizeof(struct perf_pt_aux_time));
+        if (new_p == NULL) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+            return false;
+        }
+        times->p = new_p;
+        times->capacity = new_capacity;
+    }
+
+    // With only PERF_SAMPLE_TIME set in `sample_type`, the sample ID appended
+    // to the record (see `sample_id_all`) is just the time.
+    __u64 time = *(__u64 *) ((void *) rec + rec->header.size - sizeof(__u64));
+    times->p[times->len].offset = trace->len - (trace->aux_pos - end);
+    times->p[times->len].time = time;
+    times->len++;
+    return true;
+}
+
+/*
+ * Returns the number of bytes of data in the AUX buffer (whose meta-data is in
+ * `hdr`) which have not yet been copied out.
+ */
+static __u64
+aux_unread(struct perf_event_mmap_page *hdr)
+{
+    // Use of atomics here for the same reasons as for handle_sample().
+    __u64 head = atomic_load_explicit((_Atomic __u64 *) &hdr->aux_head,
+                                      memory_order_acquire);
+    __u64 size = hdr->aux_size;
+    __u64 tail = atomic_load_explicit((_Atomic __u64 *) &hdr->aux_tail,
+                                      memory_order_relaxed);
+    // As in copy_aux(), the head is monotonic, but the tail is wrapped.
+    head %= size;
+    return (tail <= head) ? head - tail : (size - tail) + head;
+}
+
+/*
+ * Returns the number of unread bytes in the AUX buffer at which the
+ * consumer's watermark callback should be made, or 0 if there is no callback.
+ */
+static size_t
+aux_watermark_bytes(struct perf_pt_config *tr_conf)
+{
+    return (size_t) ((double) tr_conf->aux_bufsize * getpagesize() * tr_conf->aux_watermark);
+}
+
+/*
+ * Make sure that the storage buffer of `trace` has room for `len` more bytes,
+ * reallocating it if not.
+ *
+ * Returns true on success and false otherwise.
+ */
+static bool
+reserve_trace(struct perf_pt_trace *trace, __u64 len, struct perf_pt_cerror *err)
+{
+    __u64 required_capacity = trace->len + len;
     if (required_capacity > trace->capacity) {
         // Over-allocate to 2x what we need, checking that the result fits in
         // the size_t argument of realloc(3).
@@ -306,29 +660,56 @@ read_aux(void *aux_buf, struct perf_event_mmap_page *hdr,
         trace->capacity = new_capacity;
         trace->buf.p = new_buf;
     }
+    return true;
+}
 
-    // Finally append the new AUX data to the end of the trace storage buffer.
-    if (tail <= head) {
-        memcpy(trace->buf.p + trace->len, aux_buf + tail, head - tail);
-        trace->len += head - tail;
-    } else {
-        memcpy(trace->buf.p + trace->len, aux_buf + tail, size - tail);
-        trace->len += size - tail;
-        memcpy(trace->buf.p + trace->len, aux_buf, head);
-        trace->len += size + head;
+/*
+ * Helpers for the times kept by `struct duty_cycle`.
+ */
+static void
+timespec_add_us(struct timespec *ts, __u64 us)
+{
+    ts->tv_sec += us / 1000000;
+    ts->tv_nsec += (us % 1000000) * 1000;
+    if (ts->tv_nsec >= 1000000000) {
+        ts->tv_sec++;
+        ts->tv_nsec -= 1000000000;
     }
-    atomic_store_explicit((_Atomic __u64 *) &hdr->aux_tail, head, memory_order_release);
-    return true;
+}
+
+static bool
+timespec_before(const struct timespec *a, const struct timespec *b)
+{
+    return (a->tv_sec < b->tv_sec) ||
+           ((a->tv_sec == b->tv_sec) && (a->tv_nsec < b->tv_nsec));
+}
+
+// `a - b`, where `b` is before `a`.
+static struct timespec
+timespec_sub(const struct timespec *a, const struct timespec *b)
+{
+    struct timespec d = {a->tv_sec - b->tv_sec, a->tv_nsec - b->tv_nsec};
+    if (d.tv_nsec < 0) {
+        d.tv_sec--;
+        d.tv_nsec += 1000000000;
+    }
+    return d;
 }
 
 /*
  * Take trace data out of the AUX buffer.
  *
+ * `trace_lock` is held whilst `trace` is updated, as the trace may be
+ * concurrently consumed by perf_pt_take_trace().
+ *
  * Returns true on success and false otherwise.
  */
 static bool
 poll_loop(int perf_fd, int stop_fd, struct perf_event_mmap_page *mmap_hdr,
-          void *aux, struct perf_pt_trace *trace, struct perf_pt_cerror *err)
+          void *aux, struct perf_pt_trace *trace, struct trace_limit *limit,
+          bool snapshot, struct aux_watermark *watermark,
+          struct duty_cycle *duty_cycle, pthread_mutex_t *trace_lock,
+          struct perf_pt_cerror *err)
 {
     int n_events = 0;
     bool ret = true;
@@ -345,8 +726,33 @@ poll_loop(int perf_fd, int stop_fd, struct perf_event_mmap_page *mmap_hdr,
         goto done;
     }
 
+    // The hardware is turned on just after the poll loop starts.
+    if (duty_cycle->on_us != 0) {
+        if (clock_gettime(CLOCK_MONOTONIC, &duty_cycle->next) == -1) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+            ret = false;
+            goto done;
+        }
+        duty_cycle->on = true;
+        timespec_add_us(&duty_cycle->next, duty_cycle->on_us);
+    }
+
     while (1) {
-        n_events = poll(pfds, 2, INFTIM);
+        if (duty_cycle->on_us == 0) {
+            n_events = poll(pfds, 2, INFTIM);
+        } else {
+            // Wake up when the hardware is next due to be turned on or off.
+            struct timespec now, timeout = {0, 0};
+            if (clock_gettime(CLOCK_MONOTONIC, &now) == -1) {
+                perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+                ret = false;
+                goto done;
+            }
+            if (timespec_before(&now, &duty_cycle->next)) {
+                timeout = timespec_sub(&duty_cycle->next, &now);
+            }
+            n_events = ppoll(pfds, 2, &timeout, NULL);
+        }
         if (n_events == -1) {
             perf_pt_set_err(err, perf_pt_cerror_errno, errno);
             ret = false;
@@ -366,9 +772,30 @@ poll_loop(int perf_fd, int stop_fd, struct perf_event_mmap_page *mmap_hdr,
                     ret = false;
                     break;
                 }
+
+                // Tell the consumer that the AUX buffer is filling up. No
+                // locks are held, so the callback may consume the trace.
+                if ((watermark->bytes != 0) &&
+                    (aux_unread(mmap_hdr) >= watermark->bytes)) {
+                    watermark->cb(watermark->data);
+                }
             }
 
-            if (!handle_sample(aux, mmap_hdr, trace, data_tmp, err)) {
+            int rc = pthread_mutex_lock(trace_lock);
+            if (rc != 0) {
+                perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+                ret = false;
+                break;
+            }
+            bool handled = handle_sample(aux, mmap_hdr, trace, limit, snapshot,
+                                         data_tmp, err);
+            rc = pthread_mutex_unlock(trace_lock);
+            if (!handled) {
+                ret = false;
+                break;
+            }
+            if (rc != 0) {
+                perf_pt_set_err(err, perf_pt_cerror_errno, rc);
                 ret = false;
                 break;
             }
@@ -382,6 +809,11 @@ poll_loop(int perf_fd, int stop_fd, struct perf_event_mmap_page *mmap_hdr,
         if (pfds[0].revents & POLLHUP) {
             break;
         }
+
+        if (!duty_cycle_step(duty_cycle, trace, trace_lock, err)) {
+            ret = false;
+            break;
+        }
     }
 
 done:
@@ -393,12 +825,68 @@ done:
 }
 
 /*
- * Opens the perf file descriptor and returns it.
+ * Turn the tracing hardware off (or back on) if it has been on (or off) for
+ * long enough, and work out when to do so next.
+ *
+ * Returns true on success and false otherwise.
+ */
+static bool
+duty_cycle_step(struct duty_cycle *duty_cycle, struct perf_pt_trace *trace,
+                pthread_mutex_t *trace_lock, struct perf_pt_cerror *err)
+{
+    if (duty_cycle->on_us == 0) {
+        return true;
+    }
+    struct timespec now;
+    if (clock_gettime(CLOCK_MONOTONIC, &now) == -1) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        return false;
+    }
+    if (timespec_before(&now, &duty_cycle->next)) {
+        return true;
+    }
+
+    // `trace_lock` stops the size limit being reached, or the parent stopping
+    // the tracer (either of which turns the hardware off for good), whilst
+    // the hardware is turned on.
+    int rc = pthread_mutex_lock(trace_lock);
+    if (rc != 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+        return false;
+    }
+    bool ret = true;
+    if (!trace->auto_stopped && !duty_cycle->stopped) {
+        unsigned long req = duty_cycle->on ? PERF_EVENT_IOC_DISABLE : PERF_EVENT_IOC_ENABLE;
+        if (ioctl(duty_cycle->perf_fd, req, 0) < 0) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+            ret = false;
+        }
+        duty_cycle->on = !duty_cycle->on;
+    }
+    rc = pthread_mutex_unlock(trace_lock);
+    if (rc != 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+        ret = false;
+    }
+
+    // The period is measured from now, rather than from when the toggle was
+    // due, so that a late wake-up doesn't cause a burst of toggles.
+    duty_cycle->next = now;
+    timespec_add_us(&duty_cycle->next,
+                    duty_cycle->on ? duty_cycle->on_us : duty_cycle->off_us);
+    return ret;
+}
+
+/*
+ * Opens the perf file descriptor for tracing the thread `target_tid` and
+ * returns it. If `target_tid` is 0, the calling thread is traced. If
+ * `tr_conf->cgroup_cpu` is set, then `target_tid` is instead the file
+ * descriptor of a cgroup.
  *
  * Returns a file descriptor, or -1 on error.
  */
 static int
-open_perf(size_t aux_bufsize, struct perf_pt_cerror *err) {
+open_perf(struct perf_pt_config *tr_conf, pid_t target_tid, struct perf_pt_cerror *err) {
     struct perf_event_attr attr;
     memset(&attr, 0, sizeof(attr));
     attr.size = sizeof(attr);
@@ -409,7 +897,14 @@ open_perf(size_t aux_bufsize, struct perf_pt_cerror *err) {
     // Get the perf "type" for Intel PT.
     FILE *pt_type_file = fopen(SYSFS_PT_TYPE, "r");
     if (pt_type_file == NULL) {
-        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        // If the file doesn't exist, the kernel doesn't know about Intel PT,
+        // possibly because it doesn't support perf at all.
+        if ((errno == ENOENT) && (access(PERF_PARANOID_PATH, F_OK) == -1)) {
+            perf_pt_set_err(err, perf_pt_cerror_no_perf_events, ENOSYS);
+        } else {
+            int kind = (errno == ENOENT) ? perf_pt_cerror_no_hw_support : perf_pt_cerror_errno;
+            perf_pt_set_err(err, kind, errno);
+        }
         ret = -1;
         goto clean;
     }
@@ -421,32 +916,87 @@ open_perf(size_t aux_bufsize, struct perf_pt_cerror *err) {
     }
     attr.type = atoi(pt_type_str);
 
-    // Exclude the kernel.
-    attr.exclude_kernel = 1;
+    // Trace only the requested privilege levels.
+    attr.exclude_kernel = tr_conf->exclude_kernel;
+    attr.exclude_user = tr_conf->exclude_user;
 
     // Exclude the hyper-visor.
     attr.exclude_hv = 1;
 
-    // Start disabled.
+    // Start disabled, optionally letting the kernel enable tracing when the
+    // target execs, so that not a single instruction of the new program is
+    // missed.
     attr.disabled = 1;
+    attr.enable_on_exec = tr_conf->enable_on_exec;
+
+    // Optionally emit timing information.
+    if (tr_conf->tsc) {
+        attr.config |= PERF_PT_CONFIG_TSC;
+    }
+    if (tr_conf->mtc) {
+        attr.config |= PERF_PT_CONFIG_MTC |
+            ((__u64) PERF_PT_MTC_PERIOD << PERF_PT_CONFIG_MTC_PERIOD_SHIFT);
+    }
+    if (tr_conf->cyc) {
+        attr.config |= PERF_PT_CONFIG_CYC;
+    }
+
+    // Optionally record the target of every return.
+    if (tr_conf->noretcomp) {
+        attr.config |= PERF_PT_CONFIG_NORETCOMP;
+    }
+
+    // Optionally trace the operands of PTWRITE instructions.
+    if (tr_conf->ptwrite) {
+        attr.config |= PERF_PT_CONFIG_PTW | PERF_PT_CONFIG_FUP_ON_PTW;
+    }
+
+    // Optionally change how often PSB packets are emitted.
+    if (tr_conf->psb_period != -1) {
+        attr.config |= (__u64) tr_conf->psb_period << PERF_PT_CONFIG_PSB_PERIOD_SHIFT;
+    }
 
     // No skid.
     attr.precise_ip = 3;
 
+    // Timestamp the PERF_RECORD_AUX records with CLOCK_MONOTONIC, so that
+    // consumers can correlate trace data with their own events.
+    attr.sample_type = PERF_SAMPLE_TIME;
+    attr.sample_id_all = 1;
+    attr.use_clockid = 1;
+    attr.clockid = CLOCK_MONOTONIC;
+
     // Notify for every sample.
     attr.watermark = 1;
     attr.wakeup_watermark = 1;
 
-    // Generate a PERF_RECORD_AUX sample when the AUX buffer is almost full.
-    attr.aux_watermark = (size_t) ((double) aux_bufsize * getpagesize()) * AUX_BUF_WAKE_RATIO;
+    // Generate a PERF_RECORD_AUX sample when the AUX buffer is almost full,
+    // or sooner if that's needed to notice the trace reaching its size limit.
+    attr.aux_watermark = (size_t) ((double) tr_conf->aux_bufsize * getpagesize()) * AUX_BUF_WAKE_RATIO;
+    if ((tr_conf->max_bytes != 0) && (tr_conf->max_bytes < attr.aux_watermark)) {
+        attr.aux_watermark = tr_conf->max_bytes;
+    }
+    // ... and sooner still if the consumer asked to be told about it.
+    size_t watermark = aux_watermark_bytes(tr_conf);
+    if ((watermark != 0) && (watermark < attr.aux_watermark)) {
+        attr.aux_watermark = watermark;
+    }
 
     // Acquire file descriptor through which to talk to Intel PT. This syscall
     // could return EBUSY, meaning another process or thread has locked the
     // Perf device.
     struct timespec wait_time = {0, OPEN_PERF_WAIT_NSECS};
-    pid_t target_tid = syscall(__NR_gettid);
+    int cpu = -1;
+    unsigned long flags = 0;
+    if (tr_conf->cgroup_cpu != -1) {
+        // The kernel only allows cgroups to be traced one CPU at a time.
+        cpu = tr_conf->cgroup_cpu;
+        flags = PERF_FLAG_PID_CGROUP;
+    } else if (target_tid == 0) {
+        target_tid = syscall(__NR_gettid);
+    }
     for (int tries = MAX_OPEN_PERF_TRIES; tries > 0; tries--) {
-        ret = syscall(SYS_perf_event_open, &attr, target_tid, -1, -1, 0);
+        ret = syscall(SYS_perf_event_open, &attr, target_tid, cpu, -1, flags);
         if ((ret == -1) && (errno == EBUSY)) {
             nanosleep(&wait_time, NULL); // Doesn't matter if this is interrupted.
         } else {
@@ -455,7 +1005,34 @@ open_perf(size_t aux_bufsize, struct perf_pt_cerror *err) {
     }
 
     if (ret == -1) {
-        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        int kind;
+        switch (errno) {
+            case EACCES:
+            case EPERM:
+                kind = perf_pt_cerror_perms;
+                break;
+            case ENOENT:
+            case ENODEV:
+            case EOPNOTSUPP:
+                kind = perf_pt_cerror_no_hw_support;
+                break;
+            case ENOSYS:
+                kind = perf_pt_cerror_no_perf_events;
+                break;
+            default:
+                kind = perf_pt_cerror_errno;
+        }
+        perf_pt_set_err(err, kind, errno);
+    }
+
+    // Optionally trace only the requested address ranges. The event starts
+    // disabled, so the filter applies from the first traced instruction.
+    if ((ret != -1) && (tr_conf->addr_filter != NULL)) {
+        if (ioctl(ret, PERF_EVENT_IOC_SET_FILTER, tr_conf->addr_filter) == -1) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+            close(ret);
+            ret = -1;
+        }
     }
 
 clean:
@@ -484,8 +1061,13 @@ tracer_thread(void *arg)
     int perf_fd = thr_args->perf_fd;
     int stop_fd_rd = thr_args->stop_fd_rd;
     struct perf_pt_trace *trace = thr_args->trace;
+    pthread_mutex_t *trace_lock = thr_args->trace_lock;
     void *aux_buf = thr_args->aux_buf;
     struct perf_event_mmap_page *base_header = thr_args->base_header;
+    struct trace_limit *limit = thr_args->limit;
+    bool snapshot = thr_args->snapshot;
+    struct aux_watermark *watermark = thr_args->watermark;
+    struct duty_cycle *duty_cycle = thr_args->duty_cycle;
     struct perf_pt_cerror *err = thr_args->err;
 
     // Resume the interpreter loop.
@@ -497,7 +1079,8 @@ tracer_thread(void *arg)
     sem_posted = true;
 
     // Start reading out of the AUX buffer.
-    if (!poll_loop(perf_fd, stop_fd_rd, base_header, aux_buf, trace, err)) {
+    if (!poll_loop(perf_fd, stop_fd_rd, base_header, aux_buf, trace, limit,
+                   snapshot, watermark, duty_cycle, trace_lock, err)) {
         ret = false;
         goto clean;
     }
@@ -517,10 +1100,12 @@ clean:
  */
 
 /*
- * Initialise a tracer context.
+ * Initialise a tracer context for tracing the thread `target_tid`, or the
+ * calling thread if `target_tid` is 0 (or the cgroup `target_tid`: see
+ * open_perf()).
  */
 struct tracer_ctx *
-perf_pt_init_tracer(struct perf_pt_config *tr_conf, struct perf_pt_cerror *err)
+perf_pt_init_tracer(struct perf_pt_config *tr_conf, pid_t target_tid, struct perf_pt_cerror *err)
 {
     struct tracer_ctx *tr_ctx = NULL;
     bool failing = false;
@@ -538,13 +1123,32 @@ perf_pt_init_tracer(struct perf_pt_config *tr_conf, struct perf_pt_cerror *err)
     tr_ctx->stop_fds[0] = tr_ctx->stop_fds[1] = -1;
     tr_ctx->perf_fd = -1;
 
+    // perf_pt_free_tracer() assumes that the lock is initialised, so we can't
+    // use the usual clean up path if this fails.
+    int rc = pthread_mutex_init(&tr_ctx->trace_lock, NULL);
+    if (rc != 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+        free(tr_ctx);
+        return NULL;
+    }
+
     // Obtain a file descriptor through which to speak to perf.
-    tr_ctx->perf_fd = open_perf(tr_conf->aux_bufsize, err);
+    tr_ctx->perf_fd = open_perf(tr_conf, target_tid, err);
     if (tr_ctx->perf_fd == -1) {
         perf_pt_set_err(err, perf_pt_cerror_errno, errno);
         failing = true;
         goto clean;
     }
+    tr_ctx->limit.perf_fd = tr_ctx->perf_fd;
+    tr_ctx->limit.max_bytes = tr_conf->max_bytes;
+    tr_ctx->snapshot = tr_conf->snapshot;
+    tr_ctx->enable_on_exec = tr_conf->enable_on_exec;
+    tr_ctx->watermark.bytes = aux_watermark_bytes(tr_conf);
+    tr_ctx->watermark.cb = tr_conf->aux_watermark_cb;
+    tr_ctx->watermark.data = tr_conf->aux_watermark_data;
+    tr_ctx->duty_cycle.perf_fd = tr_ctx->perf_fd;
+    tr_ctx->duty_cycle.on_us = tr_conf->duty_cycle_on_us;
+    tr_ctx->duty_cycle.off_us = tr_conf->duty_cycle_off_us;
 
     // Allocate mmap(2) buffers for speaking to perf.
     //
@@ -584,8 +1188,10 @@ perf_pt_init_tracer(struct perf_pt_config *tr_conf, struct perf_pt_cerror *err)
 
     // Allocate the AUX buffer.
     //
-    // Mapped R/W so as to have a saturating ring buffer.
-    tr_ctx->aux_buf = mmap(NULL, base_header->aux_size, PROT_READ | PROT_WRITE,
+    // Mapped R/W so as to have a saturating ring buffer, or read-only in
+    // snapshot mode, which makes the kernel overwrite the oldest data instead.
+    int aux_prot = tr_conf->snapshot ? PROT_READ : PROT_READ | PROT_WRITE;
+    tr_ctx->aux_buf = mmap(NULL, base_header->aux_size, aux_prot,
         MAP_SHARED, tr_ctx->perf_fd, base_header->aux_offset);
     if (tr_ctx->aux_buf == MAP_FAILED) {
         perf_pt_set_err(err, perf_pt_cerror_errno, errno);
@@ -639,15 +1245,28 @@ perf_pt_start_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_trace *trace, str
     // coming from inside the thread. We initialise it to "no errors".
     tr_ctx->tracer_thread_err.kind = perf_pt_cerror_unused;
     tr_ctx->tracer_thread_err.code = 0;
+    tr_ctx->duty_cycle.stopped = false;
+
+    // Data already in the AUX data stream doesn't belong to this trace.
+    struct perf_event_mmap_page *base_header = tr_ctx->base_buf;
+    trace->aux_pos = atomic_load_explicit((_Atomic __u64 *) &base_header->aux_head,
+                                          memory_order_acquire);
+    trace->aux_size = base_header->aux_size;
 
     // Build the arguments struct for the tracer thread.
+    tr_ctx->trace = trace;
     struct tracer_thread_args thr_args = {
         tr_ctx->perf_fd,
         tr_ctx->stop_fds[0],
         &tracer_init_sem,
         trace,
+        &tr_ctx->trace_lock,
         tr_ctx->aux_buf,
         tr_ctx->base_buf, // The header is the first region in the base buf.
+        &tr_ctx->limit,
+        tr_ctx->snapshot,
+        &tr_ctx->watermark,
+        &tr_ctx->duty_cycle,
         &tr_ctx->tracer_thread_err,
     };
 
@@ -671,8 +1290,9 @@ perf_pt_start_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_trace *trace, str
         }
     }
 
-    // Turn on tracing hardware.
-    if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_ENABLE, 0) < 0) {
+    // Turn on tracing hardware, unless the kernel will do it on exec.
+    if ((!tr_ctx->enable_on_exec) &&
+        (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_ENABLE, 0) < 0)) {
         perf_pt_set_err(err, perf_pt_cerror_errno, errno);
         ret = false;
         goto clean;
@@ -697,6 +1317,106 @@ clean:
     return ret;
 }
 
+/*
+ * Move the trace data collected so far into `out`, so that it can be consumed
+ * whilst tracing continues.
+ *
+ * The buffers of `out` and the trace being collected are exchanged, so the
+ * buffer previously held by `out` is reused to collect subsequent trace data.
+ * Once the trace has overflowed, it stays overflowed.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_take_trace(struct tracer_ctx *tr_ctx, struct perf_pt_trace *out, struct perf_pt_cerror *err)
+{
+    int rc = pthread_mutex_lock(&tr_ctx->trace_lock);
+    if (rc != 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+        return false;
+    }
+
+    // The tracer thread only reads the AUX buffer when perf tells it to, so
+    // there may be more trace data waiting. Pick it up now.
+    struct perf_pt_trace *trace = tr_ctx->trace;
+    bool ret = true;
+    if (!trace->overflowed && !trace->auto_stopped) {
+        ret = read_aux(tr_ctx->aux_buf, tr_ctx->base_buf, trace, &tr_ctx->limit, err);
+    }
+
+    if (ret) {
+        struct perf_pt_trace spare = *out;
+        *out = *trace;
+        trace->buf = spare.buf;
+        trace->capacity = spare.capacity;
+        trace->len = 0;
+        trace->aux_times = spare.aux_times;
+        trace->aux_times.len = 0;
+        trace->aux_peak = 0;
+    }
+
+    rc = pthread_mutex_unlock(&tr_ctx->trace_lock);
+    if (rc != 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+        ret = false;
+    }
+    return ret;
+}
+
+/*
+ * Copy the contents of the AUX buffer of a tracer in snapshot mode into
+ * `out`, replacing its previous contents.
+ *
+ * Tracing is paused whilst the AUX buffer is copied, and then resumed if
+ * `resume` is true.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_snapshot(struct tracer_ctx *tr_ctx, struct perf_pt_trace *out, bool resume,
+                 struct perf_pt_cerror *err)
+{
+    // Disabling the event stops the kernel writing into the AUX buffer, and
+    // brings its head up to date.
+    if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_DISABLE, 0) < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        return false;
+    }
+
+    struct perf_event_mmap_page *hdr = tr_ctx->base_buf;
+    __u64 head_monotonic =
+            atomic_load_explicit((_Atomic __u64 *) &hdr->aux_head,
+                                 memory_order_acquire);
+    __u64 size = hdr->aux_size;
+    __u64 head = head_monotonic % size;
+
+    // The AUX buffer starts zeroed, so if there's any data after the head,
+    // then the buffer has wrapped and the oldest data starts at the head.
+    bool wrapped = head_monotonic >= size;
+    for (__u64 i = head; !wrapped && i < size; i++) {
+        wrapped = ((char *) tr_ctx->aux_buf)[i] != 0;
+    }
+
+    out->len = 0;
+    bool ret = reserve_trace(out, wrapped ? size : head, err);
+    if (ret) {
+        if (wrapped) {
+            memcpy(out->buf.p, tr_ctx->aux_buf + head, size - head);
+            out->len = size - head;
+        }
+        memcpy(out->buf.p + out->len, tr_ctx->aux_buf, head);
+        out->len += head;
+        out->aux_size = size;
+        out->aux_peak = out->len;
+    }
+
+    if (resume && (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_ENABLE, 0) < 0)) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        ret = false;
+    }
+    return ret;
+}
+
 /*
  * Turn off the tracer.
  *
@@ -710,11 +1430,25 @@ perf_pt_stop_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_cerror *err)
 {
     int ret = true;
 
-    // Turn off tracer hardware.
+    // Turn off tracer hardware. `trace_lock` stops the tracer thread turning
+    // it back on afterwards if it is duty cycling.
+    int rc = pthread_mutex_lock(&tr_ctx->trace_lock);
+    if (rc != 0) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+        ret = false;
+    }
+    tr_ctx->duty_cycle.stopped = true;
     if (ioctl(tr_ctx->perf_fd, PERF_EVENT_IOC_DISABLE, 0) < 0) {
         perf_pt_set_err(err, perf_pt_cerror_errno, errno);
         ret = false;
     }
+    if (rc == 0) {
+        rc = pthread_mutex_unlock(&tr_ctx->trace_lock);
+        if (rc != 0) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, rc);
+            ret = false;
+        }
+    }
 
     // Signal poll loop to end.
     if (close(tr_ctx->stop_fds[1]) == -1) {
@@ -778,6 +1512,7 @@ perf_pt_free_tracer(struct tracer_ctx *tr_ctx, struct perf_pt_cerror *err) {
         close(tr_ctx->perf_fd);
         tr_ctx->perf_fd = -1;
     }
+    pthread_mutex_destroy(&tr_ctx->trace_lock);
     if (tr_ctx != NULL) {
         free(tr_ctx);
     }
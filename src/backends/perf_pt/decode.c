@@ -47,78 +47,292 @@
 #include <errno.h>
 #include <stdlib.h>
 #include <unistd.h>
+#include <fcntl.h>
+#include <string.h>
+#include <elf.h>
+#include <cpuid.h>
 #include <hwtracer_util.h>
 
 #include "perf_pt_private.h"
 
+/*
+ * A cache of code sections which can be shared by many decoders, so that the
+ * code needn't be reloaded for each trace.
+ */
+struct perf_pt_cache {
+    struct pt_image_section_cache *iscache;
+    int *isids;       // The IDs of the sections in `iscache`.
+    size_t nisids;    // The number of elements in `isids`.
+    size_t isids_cap; // The allocated capacity of `isids`.
+};
+
+// The address of the code which a decoder on this thread most recently failed
+// to find in its image, if `nomap_valid` is set. See `nomap_cb()`.
+static __thread uint64_t nomap_ip;
+static __thread bool nomap_valid;
+
+// Exactly one of `image` and `cache` is non-NULL.
 struct load_self_image_args {
     struct pt_image *image;
+    struct perf_pt_cache *cache;
     int vdso_fd;
     char *vdso_filename;
     struct perf_pt_cerror *err;
 };
 
+// Rust callbacks.
+void perf_pt_push_tip(void *, uint64_t, bool, enum perf_pt_ip_compression, uint64_t);
+
 // Private prototypes.
-static bool handle_events(struct pt_block_decoder *, int *, struct perf_pt_cerror *);
+static bool init_config(void *, uint64_t, const struct perf_pt_cpu *,
+                        const struct perf_pt_errata *, struct pt_config *,
+                        struct perf_pt_cerror *);
+static struct pt_image *alloc_image(struct perf_pt_cerror *);
+static int nomap_cb(uint8_t *, size_t, const struct pt_asid *, uint64_t, void *);
+static struct pt_image *init_self_image(int, char *, struct perf_pt_cerror *);
+static struct pt_image *init_file_image(struct perf_pt_image_file *, size_t,
+                                        struct perf_pt_cerror *);
+static struct pt_block_decoder *init_block_decoder(void *, uint64_t,
+                                                   const struct perf_pt_cpu *,
+                                                   const struct perf_pt_errata *,
+                                                   struct pt_image *, int *,
+                                                   struct perf_pt_cerror *);
+static bool handle_event(struct pt_event *, bool *, bool *, struct perf_pt_cerror *);
+static bool handle_events(struct pt_block_decoder *, int *, bool *, bool *,
+                          struct perf_pt_cerror *);
+static bool handle_insn_events(struct pt_insn_decoder *, int *, struct perf_pt_cerror *);
 static bool load_self_image(struct load_self_image_args *);
 static int load_self_image_cb(struct dl_phdr_info *, size_t, void *);
 static bool block_is_terminated(struct pt_block *);
+static enum perf_pt_edge_kind edge_kind(enum pt_insn_class);
+static enum perf_pt_ip_compression ip_compression(enum pt_ip_compression);
+static void convert_packet(struct pt_packet *, struct perf_pt_packet *);
+static bool cache_add_elf_segments(struct perf_pt_cache *, const char *, uint64_t,
+                                   bool, struct perf_pt_cerror *);
+static bool cache_add_section(struct perf_pt_cache *, const char *, uint64_t,
+                              uint64_t, uint64_t, struct perf_pt_cerror *);
 
 // Public prototypes.
-void *perf_pt_init_block_decoder(void *, uint64_t, int, char *, int *,
-                                 struct perf_pt_cerror *);
-bool perf_pt_next_block(struct pt_block_decoder *, int *, uint64_t *,
-                        uint64_t *, struct perf_pt_cerror *);
+void *perf_pt_init_block_decoder(void *, uint64_t, const struct perf_pt_cpu *, int, char *,
+                                 int *, struct perf_pt_cerror *);
+void *perf_pt_init_file_block_decoder(void *, uint64_t, const struct perf_pt_cpu *,
+                                      const struct perf_pt_errata *,
+                                      struct perf_pt_image_file *, size_t, int *,
+                                      struct perf_pt_cerror *);
+bool perf_pt_next_block(struct pt_block_decoder *, int *, uint64_t *, uint64_t *,
+                        uint64_t *, enum perf_pt_edge_kind *, bool *, bool *,
+                        struct perf_pt_cerror *);
+bool perf_pt_block_time(struct pt_block_decoder *, uint64_t *);
+bool perf_pt_has_timing(void *, uint64_t, bool *, struct perf_pt_cerror *);
+bool perf_pt_resync_block_decoder(struct pt_block_decoder *, int *, uint64_t *);
+bool perf_pt_block_offset(struct pt_block_decoder *, uint64_t *);
+bool perf_pt_tip_packets(void *, uint64_t, void *, struct perf_pt_cerror *);
+void *perf_pt_init_packet_decoder(void *, uint64_t, bool *, struct perf_pt_cerror *);
+bool perf_pt_next_packet(struct pt_packet_decoder *, struct perf_pt_packet *,
+                         struct perf_pt_cerror *);
+void perf_pt_free_packet_decoder(struct pt_packet_decoder *);
 void perf_pt_free_block_decoder(struct pt_block_decoder *);
+void *perf_pt_init_insn_decoder(void *, uint64_t, const struct perf_pt_cpu *, int, char *,
+                                int *, struct perf_pt_cerror *);
+bool perf_pt_next_insn(struct pt_insn_decoder *, int *, uint64_t *,
+                       struct perf_pt_cerror *);
+void perf_pt_free_insn_decoder(struct pt_insn_decoder *);
+struct perf_pt_cache *perf_pt_alloc_cache(struct perf_pt_cerror *);
+bool perf_pt_cache_add_self(struct perf_pt_cache *, int, char *, struct perf_pt_cerror *);
+bool perf_pt_cache_add_elf(struct perf_pt_cache *, char *, uint64_t, struct perf_pt_cerror *);
+bool perf_pt_cache_add_core(struct perf_pt_cache *, char *, struct perf_pt_cerror *);
+bool perf_pt_cache_add_file(struct perf_pt_cache *, char *, uint64_t, uint64_t,
+                            struct perf_pt_cerror *);
+void *perf_pt_init_cached_block_decoder(void *, uint64_t, const struct perf_pt_cpu *,
+                                        struct perf_pt_cache *, int *,
+                                        struct perf_pt_cerror *);
+void perf_pt_free_cache(struct perf_pt_cache *);
+void perf_pt_libipt_version(char *, size_t);
+void perf_pt_detect_cpu(struct perf_pt_cpu *);
+bool perf_pt_take_nomap_ip(int, uint64_t *);
 
 /*
- * Get ready to retrieve the basic blocks from a PT trace using the code of the
- * current process for control flow recovery.
+ * Populate the libipt decoder configuration `*config` for decoding the raw
+ * buffer `buf` of length `len`, which was collected by the CPU `cpu`. If `cpu`
+ * is NULL, then the trace is decoded for the current CPU.
  *
- * Accepts a raw buffer `buf` of length `len`.
+ * If `errata` is NULL, then the errata of the CPU are worked around. Otherwise
+ * exactly those in `*errata` are.
  *
- * `vdso_fd` is an open file descriptor for the filename `vdso_filename`. This
- * is where the VDSO code will be written. libipt will read this file lazily,
- * so it's up to the caller to make sure this file lives long enough for their
- * purposes.
+ * Returns true on success or false otherwise.
+ */
+static bool
+init_config(void *buf, uint64_t len, const struct perf_pt_cpu *cpu,
+            const struct perf_pt_errata *errata, struct pt_config *config,
+            struct perf_pt_cerror *err) {
+    memset(config, 0, sizeof(*config));
+    config->size = sizeof(*config);
+    config->begin = buf;
+    config->end = buf + len;
+
+    int rv;
+    if (cpu == NULL) {
+        rv = pt_cpu_read(&config->cpu);
+        if (rv != pte_ok) {
+            perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+            return false;
+        }
+    } else {
+        config->cpu.vendor =
+            cpu->vendor == perf_pt_cpu_intel ? pcv_intel : pcv_unknown;
+        config->cpu.family = cpu->family;
+        config->cpu.model = cpu->model;
+        config->cpu.stepping = cpu->stepping;
+    }
+
+    // Describe how MTC packets (if any) were emitted, so that the decoder can
+    // use them to keep track of time. The ratio of the TSC to the crystal
+    // clock isn't recorded in the trace, so that of the current CPU is used.
+    config->mtc_freq = PERF_PT_MTC_PERIOD;
+    unsigned int eax, ebx, ecx, edx;
+    if (__get_cpuid(0x15, &eax, &ebx, &ecx, &edx)) {
+        config->cpuid_0x15_eax = eax;
+        config->cpuid_0x15_ebx = ebx;
+    }
+
+    // Work around CPU bugs.
+    if (errata != NULL) {
+        config->errata.bdm70 = errata->bdm70;
+        config->errata.bdm64 = errata->bdm64;
+        config->errata.skd007 = errata->skd007;
+        config->errata.skd022 = errata->skd022;
+        config->errata.skd010 = errata->skd010;
+        config->errata.skl014 = errata->skl014;
+        config->errata.apl12 = errata->apl12;
+        config->errata.apl11 = errata->apl11;
+    } else if (config->cpu.vendor) {
+        rv = pt_cpu_errata(&config->errata, &config->cpu);
+        if (rv < 0) {
+            perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+            return false;
+        }
+    }
+
+    return true;
+}
+
+/*
+ * Allocate an empty libipt memory image, which records the address of any code
+ * that is read from it but isn't there (see `perf_pt_take_nomap_ip()`).
+ *
+ * Returns a pointer to the image or NULL on error.
+ */
+static struct pt_image *
+alloc_image(struct perf_pt_cerror *err) {
+    struct pt_image *image = pt_image_alloc(NULL);
+    if (image == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
+        return NULL;
+    }
+    int rv = pt_image_set_callback(image, nomap_cb, NULL);
+    if (rv < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+        pt_image_free(image);
+        return NULL;
+    }
+    return image;
+}
+
+/*
+ * Called by libipt when a decoder reads code at `ip` which isn't in any
+ * section of its image.
+ *
+ * Decoding happens on the thread which asked for the next block or
+ * instruction, and the error is reported on that thread before decoding
+ * continues, so a thread-local is enough to carry the address to the caller.
+ */
+static int
+nomap_cb(uint8_t *buffer, size_t size, const struct pt_asid *asid, uint64_t ip,
+         void *context) {
+    nomap_ip = ip;
+    nomap_valid = true;
+    return -pte_nomap;
+}
+
+/*
+ * Build a libipt memory image from the code of the current process, from
+ * which a decoder can recover control flow.
+ *
+ * See `perf_pt_init_block_decoder()` for the meaning of `vdso_fd` and
+ * `vdso_filename`.
+ *
+ * Returns a pointer to the image or NULL on error.
+ */
+static struct pt_image *
+init_self_image(int vdso_fd, char *vdso_filename, struct perf_pt_cerror *err) {
+    struct pt_image *image = alloc_image(err);
+    if (image == NULL) {
+        return NULL;
+    }
+
+    struct load_self_image_args load_args = {image, NULL, vdso_fd, vdso_filename, err};
+    if (!load_self_image(&load_args)) {
+        pt_image_free(image);
+        return NULL;
+    }
+
+    return image;
+}
+
+/*
+ * Build a libipt memory image from the `nfiles` files described by `files`.
+ *
+ * Returns a pointer to the image or NULL on error.
+ */
+static struct pt_image *
+init_file_image(struct perf_pt_image_file *files, size_t nfiles,
+                struct perf_pt_cerror *err) {
+    struct pt_image *image = alloc_image(err);
+    if (image == NULL) {
+        return NULL;
+    }
+
+    for (size_t i = 0; i < nfiles; i++) {
+        struct perf_pt_image_file *file = &files[i];
+        int rv = pt_image_add_file(image, file->filename, file->offset,
+                                   file->size, NULL, file->vaddr);
+        if (rv < 0) {
+            perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+            pt_image_free(image);
+            return NULL;
+        }
+    }
+
+    return image;
+}
+
+/*
+ * Make a block decoder for the raw buffer `buf` of length `len`, which
+ * recovers control flow from the code in `image`.
  *
  * `*decoder_status` will be updated to reflect the status of the decoder after
  * it has been synchronised.
  *
+ * On success, the decoder takes ownership of `image` (it is freed by
+ * `perf_pt_free_block_decoder()`). On failure, the caller must free `image`.
+ *
  * Returns a pointer to a configured libipt block decoder or NULL on error.
  */
-void *
-perf_pt_init_block_decoder(void *buf, uint64_t len, int vdso_fd, char *vdso_filename,
-                           int *decoder_status, struct perf_pt_cerror *err) {
+static struct pt_block_decoder *
+init_block_decoder(void *buf, uint64_t len, const struct perf_pt_cpu *cpu,
+                   const struct perf_pt_errata *errata, struct pt_image *image,
+                   int *decoder_status, struct perf_pt_cerror *err) {
     bool failing = false;
+    struct pt_block_decoder *decoder = NULL;
 
     // Make a block decoder configuration.
     struct pt_config config;
-    memset(&config, 0, sizeof(config));
-    config.size = sizeof(config);
-    config.begin = buf;
-    config.end = buf + len;
-    config.flags.variant.block.end_on_call = 1;
-    config.flags.variant.block.end_on_jump = 1;
-
-    // Decode for the current CPU.
-    struct pt_block_decoder *decoder = NULL;
-    int rv = pt_cpu_read(&config.cpu);
-    if (rv != pte_ok) {
-        perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+    if (!init_config(buf, len, cpu, errata, &config, err)) {
         failing = true;
         goto clean;
     }
-
-    // Work around CPU bugs.
-    if (config.cpu.vendor) {
-        rv = pt_cpu_errata(&config.errata, &config.cpu);
-        if (rv < 0) {
-            perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
-            failing = true;
-            goto clean;
-        }
-    }
+    config.flags.variant.block.end_on_call = 1;
+    config.flags.variant.block.end_on_jump = 1;
 
     // Instantiate a decoder.
     decoder = pt_blk_alloc_decoder(&config);
@@ -128,6 +342,13 @@ perf_pt_init_block_decoder(void *buf, uint64_t len, int vdso_fd, char *vdso_file
         goto clean;
     }
 
+    int rv = pt_blk_set_image(decoder, image);
+    if (rv < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+        failing = true;
+        goto clean;
+    }
+
     // Sync the decoder.
     *decoder_status = pt_blk_sync_forward(decoder);
     if (*decoder_status == -pte_eos) {
@@ -140,30 +361,136 @@ perf_pt_init_block_decoder(void *buf, uint64_t len, int vdso_fd, char *vdso_file
         goto clean;
     }
 
+clean:
+    if (failing) {
+        pt_blk_free_decoder(decoder);
+        return NULL;
+    }
+    return decoder;
+}
+
+/*
+ * Get ready to retrieve the basic blocks from a PT trace using the code of the
+ * current process for control flow recovery.
+ *
+ * Accepts a raw buffer `buf` of length `len`, collected by the CPU `cpu`. If
+ * `cpu` is NULL, then the trace is decoded for the current CPU.
+ *
+ * `vdso_fd` is an open file descriptor for the filename `vdso_filename`. This
+ * is where the VDSO code will be written. libipt will read this file lazily,
+ * so it's up to the caller to make sure this file lives long enough for their
+ * purposes.
+ *
+ * `*decoder_status` will be updated to reflect the status of the decoder after
+ * it has been synchronised.
+ *
+ * Returns a pointer to a configured libipt block decoder or NULL on error.
+ */
+void *
+perf_pt_init_block_decoder(void *buf, uint64_t len, const struct perf_pt_cpu *cpu,
+                           int vdso_fd, char *vdso_filename, int *decoder_status,
+                           struct perf_pt_cerror *err) {
     // Build and load a memory image from which to recover control flow.
-    struct pt_image *image = pt_image_alloc(NULL);
+    struct pt_image *image = init_self_image(vdso_fd, vdso_filename, err);
     if (image == NULL) {
-        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
+        return NULL;
+    }
+
+    struct pt_block_decoder *decoder =
+        init_block_decoder(buf, len, cpu, NULL, image, decoder_status, err);
+    if (decoder == NULL) {
+        pt_image_free(image);
+    }
+    return decoder;
+}
+
+/*
+ * Get ready to retrieve the basic blocks from a PT trace using the code in the
+ * `nfiles` files described by `files` for control flow recovery. This allows
+ * decoding traces of code other than that of the current process.
+ *
+ * If `errata` isn't NULL, then exactly the errata in `*errata` are worked
+ * around, rather than those of the CPU. The other arguments are as for
+ * `perf_pt_init_block_decoder()`.
+ *
+ * Returns a pointer to a configured libipt block decoder or NULL on error.
+ */
+void *
+perf_pt_init_file_block_decoder(void *buf, uint64_t len, const struct perf_pt_cpu *cpu,
+                                const struct perf_pt_errata *errata,
+                                struct perf_pt_image_file *files, size_t nfiles,
+                                int *decoder_status, struct perf_pt_cerror *err) {
+    struct pt_image *image = init_file_image(files, nfiles, err);
+    if (image == NULL) {
+        return NULL;
+    }
+
+    struct pt_block_decoder *decoder =
+        init_block_decoder(buf, len, cpu, errata, image, decoder_status, err);
+    if (decoder == NULL) {
+        pt_image_free(image);
+    }
+    return decoder;
+}
+
+/*
+ * Get ready to retrieve the individual instructions from a PT trace using the
+ * code of the current process for control flow recovery.
+ *
+ * The arguments are as for `perf_pt_init_block_decoder()`.
+ *
+ * Returns a pointer to a configured libipt instruction decoder or NULL on
+ * error.
+ */
+void *
+perf_pt_init_insn_decoder(void *buf, uint64_t len, const struct perf_pt_cpu *cpu,
+                          int vdso_fd, char *vdso_filename, int *decoder_status,
+                          struct perf_pt_cerror *err) {
+    bool failing = false;
+    struct pt_insn_decoder *decoder = NULL;
+
+    // Build and load a memory image from which to recover control flow. Once
+    // set, the image is freed along with the decoder.
+    struct pt_image *image = init_self_image(vdso_fd, vdso_filename, err);
+    if (image == NULL) {
+        return NULL;
+    }
+
+    struct pt_config config;
+    if (!init_config(buf, len, cpu, NULL, &config, err)) {
         failing = true;
         goto clean;
     }
 
-    struct load_self_image_args load_args = {image, vdso_fd, vdso_filename, err};
-    if (!load_self_image(&load_args)) {
+    decoder = pt_insn_alloc_decoder(&config);
+    if (decoder == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
         failing = true;
         goto clean;
     }
 
-    rv = pt_blk_set_image(decoder, image);
+    int rv = pt_insn_set_image(decoder, image);
     if (rv < 0) {
         perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
         failing = true;
         goto clean;
     }
 
+    *decoder_status = pt_insn_sync_forward(decoder);
+    if (*decoder_status == -pte_eos) {
+        // There were no instructions in the stream. The user will find out on
+        // next call to perf_pt_next_insn().
+        goto clean;
+    } else if (*decoder_status < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -*decoder_status);
+        failing = true;
+        goto clean;
+    }
+
 clean:
     if (failing) {
-        pt_blk_free_decoder(decoder);
+        pt_insn_free_decoder(decoder);
+        pt_image_free(image);
         return NULL;
     }
     return decoder;
@@ -171,23 +498,45 @@ clean:
 
 /*
  * Updates `*first_instr` and `*last_instr` with the address of the first and last
- * instructions of the next block in the instruction stream.
+ * instructions of the next block in the instruction stream, `*ninsn` with the
+ * number of instructions in the block, and `*edge` with the kind of control
+ * flow transfer made by the last instruction.
  *
  * If first instruction address is 0, this indicates that the end of
- * the instruction stream has been reached.
+ * the instruction stream has been reached, unless `*overflow` is set.
+ *
+ * `*overflow` is set if the hardware's internal buffers overflowed (an OVF
+ * packet) before the next block, in which case no block is returned. Control
+ * flow up to the point where tracing resumed is lost, including the rest of
+ * any block which the overflow interrupted. Decoding can continue with the
+ * next call.
+ *
+ * `*gap` is set if tracing was disabled and then re-enabled somewhere other
+ * than where it was disabled (e.g. when duty cycling), so that the execution
+ * either side of the gap isn't connected by control flow. If this happens
+ * before the next block, then no block is returned. Otherwise the block
+ * decoded so far is returned, ending where tracing was disabled, and the gap
+ * follows it. Decoding can continue with the next call.
  *
  * `*decoder_status` will be updated with the new decoder status after the operation.
  *
- * Returns true on success or false otherwise. Upon failure, `*first_instr` and
- * `*last_instr` are undefined.
+ * Returns true on success or false otherwise. Upon failure, `*first_instr`,
+ * `*last_instr`, `*ninsn` and `*edge` are undefined.
  */
 bool
 perf_pt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
-        uint64_t *first_instr, uint64_t *last_instr, struct perf_pt_cerror *err) {
+        uint64_t *first_instr, uint64_t *last_instr, uint64_t *ninsn,
+        enum perf_pt_edge_kind *edge, bool *overflow, bool *gap,
+        struct perf_pt_cerror *err) {
+    *overflow = false;
+    *gap = false;
     // If there are events pending, look at those first.
-    if (handle_events(decoder, decoder_status, err) != true) {
+    if (handle_events(decoder, decoder_status, overflow, gap, err) != true) {
         // handle_events will have already called perf_pt_set_err().
         return false;
+    } else if (*overflow || *gap) {
+        *first_instr = 0;
+        return true;
     } else if (*decoder_status & pts_eos) {
         // End of stream.
         *first_instr = 0;
@@ -205,10 +554,22 @@ perf_pt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
     block.iclass = ptic_other;
     bool first_block = true;
     *last_instr = 0;
+    *ninsn = 0;
     while (!block_is_terminated(&block)) {
-        if (handle_events(decoder, decoder_status, err) != true) {
+        if (handle_events(decoder, decoder_status, overflow, gap, err) != true) {
             // handle_events will have already called perf_pt_set_err().
             return false;
+        } else if (*overflow) {
+            // The partial block decoded so far is lost with the overflow.
+            *first_instr = 0;
+            return true;
+        } else if (*gap) {
+            // The partial block decoded so far ends where tracing was disabled.
+            if (first_block) {
+                *first_instr = 0;
+                return true;
+            }
+            break;
         } else if (*decoder_status & pts_eos) {
             // End of stream.
             *first_instr = 0;
@@ -244,6 +605,7 @@ perf_pt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
         if (block.ninsn == 0) {
             panic("Detected a block with 0 instructions");
         }
+        *ninsn += block.ninsn;
 
         if (first_block) {
             // The address of the block's first instruction that we report back
@@ -253,21 +615,322 @@ perf_pt_next_block(struct pt_block_decoder *decoder, int *decoder_status,
         }
 
     }
-    // The address of the block's last instruction.
-    *last_instr = block.end_ip;
+    // The address of the block's last instruction.
+    *last_instr = block.end_ip;
+    *edge = edge_kind(block.iclass);
+
+    return true;
+}
+
+/*
+ * Updates `*tsc` with the decoder's estimate of the time stamp counter at the
+ * current position in the trace. This is the time of the most recent timing
+ * packet seen by the decoder.
+ *
+ * Returns true on success, or false if the trace contains no timing
+ * information (so far).
+ */
+bool
+perf_pt_block_time(struct pt_block_decoder *decoder, uint64_t *tsc) {
+    uint32_t lost_mtc, lost_cyc;
+    return pt_blk_time(decoder, tsc, &lost_mtc, &lost_cyc) >= 0;
+}
+
+/*
+ * Determine whether the raw trace `buf` of length `len` contains any timing
+ * packets (TSC or CYC), storing the answer in `*has_timing`.
+ *
+ * Only the packets themselves are examined, so no code is needed. Any
+ * undecodable packets are skipped up to the next PSB packet.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_has_timing(void *buf, uint64_t len, bool *has_timing,
+                   struct perf_pt_cerror *err) {
+    *has_timing = false;
+
+    struct pt_config config;
+    if (!init_config(buf, len, NULL, NULL, &config, err)) {
+        return false;
+    }
+    struct pt_packet_decoder *decoder = pt_pkt_alloc_decoder(&config);
+    if (decoder == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
+        return false;
+    }
+
+    // Stops at the end of the packet stream, where there's no PSB to sync to.
+    int rv = pt_pkt_sync_forward(decoder);
+    while ((rv >= 0) && !*has_timing) {
+        struct pt_packet packet;
+        rv = pt_pkt_next(decoder, &packet, sizeof(packet));
+        if (rv == -pte_eos) {
+            break;
+        } else if (rv < 0) {
+            rv = pt_pkt_sync_forward(decoder);
+            continue;
+        }
+        *has_timing = (packet.type == ppt_tsc) || (packet.type == ppt_cyc);
+    }
+
+    pt_pkt_free_decoder(decoder);
+    return true;
+}
+
+/*
+ * Synchronise the decoder at the next PSB packet, skipping the rest of the
+ * packet stream up to that point. This allows decoding to continue after an
+ * error.
+ *
+ * `*decoder_status` will be updated to reflect the status of the decoder after
+ * it has been synchronised, and `*skipped` with the number of bytes of the
+ * packet stream which were skipped.
+ *
+ * Returns true if the decoder was synchronised, or false if there is nowhere
+ * left to synchronise (or if synchronisation failed).
+ */
+bool
+perf_pt_resync_block_decoder(struct pt_block_decoder *decoder, int *decoder_status,
+                             uint64_t *skipped) {
+    // If the decoder was never synchronised, then everything up to the next
+    // PSB packet is skipped.
+    uint64_t from = 0;
+    if (pt_blk_get_offset(decoder, &from) < 0) {
+        from = 0;
+    }
+
+    *decoder_status = pt_blk_sync_forward(decoder);
+    if (*decoder_status < 0) {
+        return false;
+    }
+
+    uint64_t to = from;
+    if (pt_blk_get_sync_offset(decoder, &to) < 0) {
+        to = from;
+    }
+    *skipped = (to > from) ? to - from : 0;
+    return true;
+}
+
+/*
+ * Updates `*offset` with the offset in the packet stream of the block decoder
+ * `decoder`: the packets before it have been consumed by the decoder.
+ *
+ * Returns true on success, or false if the decoder isn't synchronised.
+ */
+bool
+perf_pt_block_offset(struct pt_block_decoder *decoder, uint64_t *offset) {
+    return pt_blk_get_offset(decoder, offset) >= 0;
+}
+
+/*
+ * Returns the full IP of the IP-carrying packet `ip`, whose missing bits are
+ * those of `last_ip`, the full IP of the previous such packet.
+ */
+static uint64_t
+full_ip(const struct pt_packet_ip *ip, uint64_t last_ip)
+{
+    switch (ip->ipc) {
+        case pt_ipc_update_16:
+            return (last_ip & ~0xffffULL) | (ip->ip & 0xffffULL);
+        case pt_ipc_update_32:
+            return (last_ip & ~0xffffffffULL) | (ip->ip & 0xffffffffULL);
+        case pt_ipc_update_48:
+            return (last_ip & ~0xffffffffffffULL) | (ip->ip & 0xffffffffffffULL);
+        case pt_ipc_sext_48:
+            return (uint64_t) (((int64_t) (ip->ip << 16)) >> 16);
+        case pt_ipc_full:
+            return ip->ip;
+        default:
+            return last_ip;
+    }
+}
+
+/*
+ * Find the TIP and TIP.PGE packets in the raw trace `buf` of length `len`
+ * whose IPs aren't suppressed, passing each in turn to the Rust callback
+ * `perf_pt_push_tip()`, along with `tips`: the packet's offset, whether it is
+ * a TIP.PGE, its IP compression, and its full IP.
+ *
+ * As in perf_pt_has_timing(), undecodable packets are skipped up to the next
+ * PSB packet.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_tip_packets(void *buf, uint64_t len, void *tips,
+                    struct perf_pt_cerror *err) {
+    struct pt_config config;
+    if (!init_config(buf, len, NULL, NULL, &config, err)) {
+        return false;
+    }
+    struct pt_packet_decoder *decoder = pt_pkt_alloc_decoder(&config);
+    if (decoder == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
+        return false;
+    }
+
+    // The IP compression of a packet is relative to the last IP, which is
+    // reset at each PSB packet.
+    uint64_t last_ip = 0;
+    int rv = pt_pkt_sync_forward(decoder);
+    while (rv >= 0) {
+        uint64_t offset = 0;
+        if (pt_pkt_get_offset(decoder, &offset) < 0) {
+            break;
+        }
+        struct pt_packet packet;
+        rv = pt_pkt_next(decoder, &packet, sizeof(packet));
+        if (rv == -pte_eos) {
+            break;
+        } else if (rv < 0) {
+            last_ip = 0;
+            rv = pt_pkt_sync_forward(decoder);
+            continue;
+        }
+        switch (packet.type) {
+            case ppt_psb:
+                last_ip = 0;
+                break;
+            case ppt_tip:
+            case ppt_tip_pge:
+            case ppt_tip_pgd:
+            case ppt_fup:
+                if (ip_compression(packet.payload.ip.ipc) == perf_pt_ipc_suppressed) {
+                    break;
+                }
+                last_ip = full_ip(&packet.payload.ip, last_ip);
+                if ((packet.type == ppt_tip) || (packet.type == ppt_tip_pge)) {
+                    perf_pt_push_tip(tips, offset, packet.type == ppt_tip_pge,
+                                     ip_compression(packet.payload.ip.ipc), last_ip);
+                }
+                break;
+            default:
+                break;
+        }
+    }
+
+    pt_pkt_free_decoder(decoder);
+    return true;
+}
+
+/*
+ * Makes a packet decoder for the raw trace `buf` of length `len`, synchronised
+ * at its first PSB packet. If there is no PSB packet, `*eos` is set to true, and
+ * there are no packets to decode.
+ *
+ * Returns a pointer to the decoder, or NULL on error.
+ */
+void *
+perf_pt_init_packet_decoder(void *buf, uint64_t len, bool *eos,
+                            struct perf_pt_cerror *err) {
+    struct pt_config config;
+    if (!init_config(buf, len, NULL, NULL, &config, err)) {
+        return NULL;
+    }
+    struct pt_packet_decoder *decoder = pt_pkt_alloc_decoder(&config);
+    if (decoder == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
+        return NULL;
+    }
+
+    *eos = false;
+    int rv = pt_pkt_sync_forward(decoder);
+    if (rv == -pte_eos) {
+        *eos = true;
+    } else if (rv < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+        pt_pkt_free_decoder(decoder);
+        return NULL;
+    }
+    return decoder;
+}
+
+/*
+ * Decodes the next packet into `*packet`. At the end of the packet stream,
+ * `packet->kind` is `perf_pt_packet_eos`.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_next_packet(struct pt_packet_decoder *decoder, struct perf_pt_packet *packet,
+                    struct perf_pt_cerror *err) {
+    struct pt_packet pkt;
+    int rv = pt_pkt_next(decoder, &pkt, sizeof(pkt));
+    if (rv == -pte_eos) {
+        packet->kind = perf_pt_packet_eos;
+        return true;
+    } else if (rv < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+        return false;
+    }
+    convert_packet(&pkt, packet);
+    return true;
+}
+
+/*
+ * Frees the packet decoder `decoder`.
+ */
+void
+perf_pt_free_packet_decoder(struct pt_packet_decoder *decoder) {
+    if (decoder != NULL) {
+        pt_pkt_free_decoder(decoder);
+    }
+}
+
+/*
+ * Updates `*addr` with the address of the next instruction in the instruction
+ * stream.
+ *
+ * If the address is 0, this indicates that the end of the instruction stream
+ * has been reached.
+ *
+ * `*decoder_status` will be updated with the new decoder status after the operation.
+ *
+ * Returns true on success or false otherwise. Upon failure, `*addr` is
+ * undefined.
+ */
+bool
+perf_pt_next_insn(struct pt_insn_decoder *decoder, int *decoder_status,
+                  uint64_t *addr, struct perf_pt_cerror *err) {
+    // If there are events pending, look at those first.
+    if (handle_insn_events(decoder, decoder_status, err) != true) {
+        // handle_insn_events will have already called perf_pt_set_err().
+        return false;
+    } else if (*decoder_status & pts_eos) {
+        // End of stream.
+        *addr = 0;
+        return true;
+    }
+
+    struct pt_insn insn;
+    *decoder_status = pt_insn_next(decoder, &insn, sizeof(insn));
+    // As for blocks, +ve status codes are dealt with when we are next called.
+    if (*decoder_status == -pte_eos) {
+        // End of stream is flagged as an error in the case of pt_insn_next().
+        *addr = 0;
+        return true;
+    } else if (*decoder_status < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -*decoder_status);
+        return false;
+    }
 
+    *addr = insn.ip;
     return true;
 }
 
 /*
  * Given a decoder and pointer to the decoder status, handle any pending events in
- * the PT packet stream and update the decoder status.
+ * the PT packet stream and update the decoder status. `*overflow` is set if
+ * one of the events was an overflow, and `*gap` if tracing was re-enabled
+ * somewhere other than where it was disabled.
  *
- * Returns true on success, or false if an error occurred (e.g.) trace buffer
- * overflow.
+ * Returns true on success, or false if an error occurred.
  */
 static bool
-handle_events(struct pt_block_decoder *decoder, int *decoder_status, struct perf_pt_cerror *err) {
+handle_events(struct pt_block_decoder *decoder, int *decoder_status, bool *overflow,
+              bool *gap, struct perf_pt_cerror *err) {
     bool ret = true;
 
     while(*decoder_status & pts_event_pending) {
@@ -277,80 +940,138 @@ handle_events(struct pt_block_decoder *decoder, int *decoder_status, struct perf
             perf_pt_set_err(err, perf_pt_cerror_ipt, -*decoder_status);
             return false;
         }
+        if (!handle_event(&event, overflow, gap, err)) {
+            ret = false;
+        }
+    }
+    return ret;
+}
 
-        switch (event.type) {
-            // Tracing enabled/disabled packets (TIP.PGE/TIP.PGD).
-            // These tell us the chip has enabled or disabled tracing. We
-            // expect to see an enabled packet at the start of a trace as part
-            // of a PSB+ sequence, and a disabled packet at the end of our
-            // trace. Additional enable/disable packets may appear in the
-            // middle of the trace in the event of e.g. a context switch.
-            case ptev_enabled:
-            case ptev_disabled:
-            case ptev_async_disabled:
-                break;
-            // Trace overflow packet (OVF).
-            // This happens when the head of the ring buffer being used to
-            // store trace packets catches up with the tail. In such a
-            // scenario, packets were probably lost.
-            case ptev_overflow:
-                // We translate the overflow event to an overflow error for
-                // Rust to detect later.
-                perf_pt_set_err(err, perf_pt_cerror_ipt, pte_overflow);
-                ret = false;
-                break;
-            // Execution mode packet (MODE.Exec).
-            // We expect one of these at the start of our trace and every time
-            // the CPU changes between 16/32/64-bit execution modes.
-            case ptev_exec_mode:
-                break;
-            // Transaction mode packet (MODE.TSX).
-            // This is Intel TSX hardware transactional memory event notifying
-            // us of the start, commit or abort of a transaction. These can
-            // appear in the PSB+ sequence at the start of a trace.
-            case ptev_tsx:
-                break;
-            // Execution stop packet (EXSTOP).
-            // Indicates that the core has gone to sleep, e.g. if a deep
-            // C-state is entered. The core may wake up later.
-            case ptev_exstop:
-                break;
-            // MWAIT packet.
-            // Intel chips have hardware support for concurrency primitives in
-            // the form of `MONITOR`/`MWAIT`. This packet indicates that a
-            // `MWAIT` instruction woke up a hardware thread.
-            case ptev_mwait:
-                break;
-            // Power entry packet (PWRE).
-            // Indicates the entry of a C-state region.
-            case ptev_pwre:
-                break;
-            // Power exit packet (PWRX).
-            // Indicates the entry of a C-state region, thus returning the core
-            // back to C0.
-            case ptev_pwrx:
-                break;
-            // Core Bus Ratio (CBR) packet.
-            // We expect one of these at the start of the trace and every time
-            // the core clock speed changes.
-            case ptev_cbr:
-                break;
-            // Maintenance packet.
-            // This is a model-specific packet which we are explicitly told to
-            // ignore in the Intel manual.
-            case ptev_mnt:
-                break;
-            // We conservatively crash when receiving any other kind of packet.
-            // This includes packets which we don't expect to see because we
-            // didn't ask them to be emitted, e.g. TSC, STOP and CYC packets.
-            // We print what packet crashed us before dying to aid debugging.
-            default:
-                panic("Unhandled packet event type %d", event.type);
+/*
+ * As `handle_events()`, but for an instruction decoder. Overflows are errors.
+ */
+static bool
+handle_insn_events(struct pt_insn_decoder *decoder, int *decoder_status, struct perf_pt_cerror *err) {
+    bool ret = true;
+
+    while(*decoder_status & pts_event_pending) {
+        struct pt_event event;
+        *decoder_status = pt_insn_event(decoder, &event, sizeof(event));
+        if (*decoder_status < 0) {
+            perf_pt_set_err(err, perf_pt_cerror_ipt, -*decoder_status);
+            return false;
+        }
+        if (!handle_event(&event, NULL, NULL, err)) {
+            ret = false;
         }
     }
     return ret;
 }
 
+/*
+ * Handle a single event from the PT packet stream. If `overflow` isn't NULL,
+ * then `*overflow` is set for an overflow event, otherwise such an event is an
+ * error. If `gap` isn't NULL, then `*gap` is set when tracing is re-enabled
+ * somewhere other than where it was disabled.
+ *
+ * Returns true on success, or false if the event indicates an error.
+ */
+static bool
+handle_event(struct pt_event *event, bool *overflow, bool *gap, struct perf_pt_cerror *err) {
+    bool ret = true;
+
+    switch (event->type) {
+        // Tracing enabled/disabled packets (TIP.PGE/TIP.PGD).
+        // These tell us the chip has enabled or disabled tracing. We
+        // expect to see an enabled packet at the start of a trace as part
+        // of a PSB+ sequence, and a disabled packet at the end of our
+        // trace. Additional enable/disable packets may appear in the
+        // middle of the trace in the event of e.g. a system call. Usually
+        // tracing resumes where it was disabled, but otherwise (e.g. when
+        // duty cycling, or delivering a signal) control flow is lost in
+        // between.
+        case ptev_enabled:
+            if ((gap != NULL) && !event->status_update && !event->variant.enabled.resumed) {
+                *gap = true;
+            }
+            break;
+        case ptev_disabled:
+        case ptev_async_disabled:
+            break;
+        // Trace overflow packet (OVF).
+        // This happens when the head of the ring buffer being used to
+        // store trace packets catches up with the tail. In such a
+        // scenario, packets were probably lost. Decoding resumes where the
+        // packets do, so the block decoder reports the overflow and carries
+        // on.
+        case ptev_overflow:
+            if (overflow != NULL) {
+                *overflow = true;
+                break;
+            }
+            // We translate the overflow event to an overflow error for
+            // Rust to detect later.
+            perf_pt_set_err(err, perf_pt_cerror_ipt, pte_overflow);
+            ret = false;
+            break;
+        // Execution mode packet (MODE.Exec).
+        // We expect one of these at the start of our trace and every time
+        // the CPU changes between 16/32/64-bit execution modes.
+        case ptev_exec_mode:
+            break;
+        // Transaction mode packet (MODE.TSX).
+        // This is Intel TSX hardware transactional memory event notifying
+        // us of the start, commit or abort of a transaction. These can
+        // appear in the PSB+ sequence at the start of a trace.
+        case ptev_tsx:
+            break;
+        // Execution stop packet (EXSTOP).
+        // Indicates that the core has gone to sleep, e.g. if a deep
+        // C-state is entered. The core may wake up later.
+        case ptev_exstop:
+            break;
+        // MWAIT packet.
+        // Intel chips have hardware support for concurrency primitives in
+        // the form of `MONITOR`/`MWAIT`. This packet indicates that a
+        // `MWAIT` instruction woke up a hardware thread.
+        case ptev_mwait:
+            break;
+        // Power entry packet (PWRE).
+        // Indicates the entry of a C-state region.
+        case ptev_pwre:
+            break;
+        // Power exit packet (PWRX).
+        // Indicates the entry of a C-state region, thus returning the core
+        // back to C0.
+        case ptev_pwrx:
+            break;
+        // Core Bus Ratio (CBR) packet.
+        // We expect one of these at the start of the trace and every time
+        // the core clock speed changes.
+        case ptev_cbr:
+            break;
+        // Maintenance packet.
+        // This is a model-specific packet which we are explicitly told to
+        // ignore in the Intel manual.
+        case ptev_mnt:
+            break;
+        // PTWRITE packet (PTW).
+        // The operand of a `PTWRITE` instruction, which is only traced if
+        // the config asked for it. These don't affect control flow, and
+        // are reported from the packet stream instead.
+        case ptev_ptwrite:
+            break;
+        // We conservatively crash when receiving any other kind of packet.
+        // This includes events which we don't expect to see because we
+        // didn't ask for them, e.g. STOP and tick events. Timing packets
+        // (e.g. TSC) don't generate events, but update the decoder's time.
+        // We print what packet crashed us before dying to aid debugging.
+        default:
+            panic("Unhandled packet event type %d", event->type);
+    }
+    return ret;
+}
+
 /*
  * Decides if a block is terminated by a control flow dispatch.
  *
@@ -386,6 +1107,140 @@ block_is_terminated(struct pt_block *blk)
     return ret;
 }
 
+/*
+ * Classifies the control flow transfer made by an instruction of class
+ * `iclass`.
+ */
+static enum perf_pt_edge_kind
+edge_kind(enum pt_insn_class iclass)
+{
+    switch (iclass) {
+        case ptic_call:
+            return perf_pt_edge_call;
+        case ptic_return:
+            return perf_pt_edge_return;
+        case ptic_jump:
+            return perf_pt_edge_jump;
+        case ptic_cond_jump:
+            return perf_pt_edge_cond_jump;
+        case ptic_far_call:
+            return perf_pt_edge_far_call;
+        case ptic_far_return:
+            return perf_pt_edge_far_return;
+        case ptic_far_jump:
+            return perf_pt_edge_far_jump;
+        default:
+            return perf_pt_edge_unknown;
+    }
+}
+
+/*
+ * Converts libipt's IP compression `ipc` to ours.
+ */
+static enum perf_pt_ip_compression
+ip_compression(enum pt_ip_compression ipc)
+{
+    switch (ipc) {
+        case pt_ipc_update_16:
+            return perf_pt_ipc_update_16;
+        case pt_ipc_update_32:
+            return perf_pt_ipc_update_32;
+        case pt_ipc_sext_48:
+            return perf_pt_ipc_sext_48;
+        case pt_ipc_update_48:
+            return perf_pt_ipc_update_48;
+        case pt_ipc_full:
+            return perf_pt_ipc_full;
+        default:
+            // Reserved encodings carry no usable IP, just like suppressed IPs.
+            return perf_pt_ipc_suppressed;
+    }
+}
+
+/*
+ * Converts the libipt packet `pkt` to ours, `*packet`.
+ */
+static void
+convert_packet(struct pt_packet *pkt, struct perf_pt_packet *packet)
+{
+    packet->ipc = perf_pt_ipc_suppressed;
+    packet->payload = 0;
+    packet->count = 0;
+    switch (pkt->type) {
+        case ppt_psb:
+            packet->kind = perf_pt_packet_psb;
+            break;
+        case ppt_psbend:
+            packet->kind = perf_pt_packet_psbend;
+            break;
+        case ppt_pad:
+            packet->kind = perf_pt_packet_pad;
+            break;
+        case ppt_ovf:
+            packet->kind = perf_pt_packet_ovf;
+            break;
+        case ppt_tnt_8:
+        case ppt_tnt_64:
+            packet->kind = perf_pt_packet_tnt;
+            packet->payload = pkt->payload.tnt.payload;
+            packet->count = pkt->payload.tnt.bit_size;
+            break;
+        case ppt_tip:
+        case ppt_tip_pge:
+        case ppt_tip_pgd:
+        case ppt_fup:
+            if (pkt->type == ppt_tip) {
+                packet->kind = perf_pt_packet_tip;
+            } else if (pkt->type == ppt_tip_pge) {
+                packet->kind = perf_pt_packet_tip_pge;
+            } else if (pkt->type == ppt_tip_pgd) {
+                packet->kind = perf_pt_packet_tip_pgd;
+            } else {
+                packet->kind = perf_pt_packet_fup;
+            }
+            packet->ipc = ip_compression(pkt->payload.ip.ipc);
+            packet->payload = pkt->payload.ip.ip;
+            break;
+        case ppt_mode:
+            if (pkt->payload.mode.leaf == pt_mol_exec) {
+                packet->kind = perf_pt_packet_mode_exec;
+                packet->payload = pkt->payload.mode.bits.exec.csl |
+                    (pkt->payload.mode.bits.exec.csd << 1);
+            } else if (pkt->payload.mode.leaf == pt_mol_tsx) {
+                packet->kind = perf_pt_packet_mode_tsx;
+                packet->payload = pkt->payload.mode.bits.tsx.intx |
+                    (pkt->payload.mode.bits.tsx.abrt << 1);
+            } else {
+                packet->kind = perf_pt_packet_other;
+            }
+            break;
+        case ppt_tsc:
+            packet->kind = perf_pt_packet_tsc;
+            packet->payload = pkt->payload.tsc.tsc;
+            break;
+        case ppt_cyc:
+            packet->kind = perf_pt_packet_cyc;
+            packet->payload = pkt->payload.cyc.value;
+            break;
+        case ppt_mtc:
+            packet->kind = perf_pt_packet_mtc;
+            packet->payload = pkt->payload.mtc.ctc;
+            break;
+        case ppt_cbr:
+            packet->kind = perf_pt_packet_cbr;
+            packet->payload = pkt->payload.cbr.ratio;
+            break;
+        case ppt_ptw:
+            packet->kind = perf_pt_packet_ptw;
+            packet->payload = pkt->payload.ptw.payload;
+            packet->count = pkt->payload.ptw.ip;
+            break;
+        default:
+            packet->kind = perf_pt_packet_other;
+            break;
+    }
+}
+
 /*
  * Loads the libipt image `image` with the code of the current process.
  *
@@ -398,7 +1253,7 @@ load_self_image(struct load_self_image_args *args)
         return false;
     }
 
-    if (fsync(args->vdso_fd) == -1) {
+    if ((args->vdso_fd != -1) && (fsync(args->vdso_fd) == -1)) {
         perf_pt_set_err(args->err, perf_pt_cerror_errno, errno);
         return false;
     }
@@ -432,6 +1287,11 @@ load_self_image_cb(struct dl_phdr_info *info, size_t size, void *data)
         vdso = strcmp(filename, VDSO_NAME) == 0;
     }
 
+    // The caller may already have the VDSO code.
+    if (vdso && (args->vdso_fd == -1)) {
+        return 0;
+    }
+
     for (i = 0; i < info->dlpi_phnum; i++) {
         phdr = info->dlpi_phdr[i];
         // Ensure we only use loadable and executable sections.
@@ -463,12 +1323,18 @@ load_self_image_cb(struct dl_phdr_info *info, size_t size, void *data)
             offset = phdr.p_offset;
         }
 
-        // XXX This could be made faster using a libipt instruction cache.
-        int rv = pt_image_add_file(args->image, filename, offset,
-                                   phdr.p_filesz, NULL, vaddr);
-        if (rv < 0) {
-            perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
-            return 1;
+        if (args->cache != NULL) {
+            if (!cache_add_section(args->cache, filename, offset, phdr.p_filesz,
+                                   vaddr, err)) {
+                return 1;
+            }
+        } else {
+            int rv = pt_image_add_file(args->image, filename, offset,
+                                       phdr.p_filesz, NULL, vaddr);
+            if (rv < 0) {
+                perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+                return 1;
+            }
         }
     }
 
@@ -503,6 +1369,293 @@ dump_vdso(int fd, uint64_t vaddr, size_t len, struct perf_pt_cerror *err)
 void
 perf_pt_free_block_decoder(struct pt_block_decoder *decoder) {
     if (decoder != NULL) {
+        struct pt_image *image = pt_blk_get_image(decoder);
         pt_blk_free_decoder(decoder);
+        pt_image_free(image);
+    }
+}
+
+/*
+ * Free an instruction decoder and its image.
+ */
+void
+perf_pt_free_insn_decoder(struct pt_insn_decoder *decoder) {
+    if (decoder != NULL) {
+        struct pt_image *image = pt_insn_get_image(decoder);
+        pt_insn_free_decoder(decoder);
+        pt_image_free(image);
+    }
+}
+
+/*
+ * Allocate an empty decoder cache.
+ *
+ * Returns a pointer to the cache or NULL on error.
+ */
+struct perf_pt_cache *
+perf_pt_alloc_cache(struct perf_pt_cerror *err) {
+    struct perf_pt_cache *cache = malloc(sizeof(*cache));
+    if (cache == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        return NULL;
+    }
+    memset(cache, 0, sizeof(*cache));
+
+    cache->iscache = pt_iscache_alloc(NULL);
+    if (cache->iscache == NULL) {
+        perf_pt_set_err(err, perf_pt_cerror_unknown, 0);
+        free(cache);
+        return NULL;
+    }
+    return cache;
+}
+
+/*
+ * Add `size` bytes of code from `offset` in the file `filename`, loaded at
+ * `vaddr`, to the cache. Adding a section which is already in the cache does
+ * nothing.
+ *
+ * Returns true on success or false otherwise.
+ */
+static bool
+cache_add_section(struct perf_pt_cache *cache, const char *filename,
+                  uint64_t offset, uint64_t size, uint64_t vaddr,
+                  struct perf_pt_cerror *err) {
+    int isid = pt_iscache_add_file(cache->iscache, filename, offset, size, vaddr);
+    if (isid < 0) {
+        perf_pt_set_err(err, perf_pt_cerror_ipt, -isid);
+        return false;
+    }
+
+    // libipt gives identical sections the same ID.
+    for (size_t i = 0; i < cache->nisids; i++) {
+        if (cache->isids[i] == isid) {
+            return true;
+        }
+    }
+
+    if (cache->nisids == cache->isids_cap) {
+        size_t new_cap = (cache->isids_cap == 0) ? 16 : cache->isids_cap * 2;
+        int *new_isids = realloc(cache->isids, new_cap * sizeof(*new_isids));
+        if (new_isids == NULL) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+            return false;
+        }
+        cache->isids = new_isids;
+        cache->isids_cap = new_cap;
+    }
+    cache->isids[cache->nisids++] = isid;
+    return true;
+}
+
+/*
+ * Add the code of the current process to the cache. Code which is already
+ * cached is not added again. Sections are used in the order they were added,
+ * so code loaded at the same address as previously cached code takes
+ * precedence.
+ *
+ * See `perf_pt_init_block_decoder()` for the meaning of `vdso_fd` and
+ * `vdso_filename`. The VDSO file must live as long as the cache. If `vdso_fd`
+ * is -1, then the VDSO is not added.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_cache_add_self(struct perf_pt_cache *cache, int vdso_fd, char *vdso_filename,
+                       struct perf_pt_cerror *err) {
+    struct load_self_image_args load_args = {NULL, cache, vdso_fd, vdso_filename, err};
+    return load_self_image(&load_args);
+}
+
+/*
+ * Add the executable segments of the ELF file `filename` to the cache,
+ * assuming that it was loaded `base` bytes from its link-time addresses (i.e.
+ * `base` is 0 for non-position-independent executables).
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_cache_add_elf(struct perf_pt_cache *cache, char *filename, uint64_t base,
+                      struct perf_pt_cerror *err) {
+    return cache_add_elf_segments(cache, filename, base, false, err);
+}
+
+/*
+ * Add the executable segments of the core dump `filename` to the cache, at
+ * the addresses they were dumped from.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_cache_add_core(struct perf_pt_cache *cache, char *filename,
+                       struct perf_pt_cerror *err) {
+    return cache_add_elf_segments(cache, filename, 0, true, err);
+}
+
+/*
+ * Add the whole of the file `filename`, of size `size`, to the cache as code
+ * loaded at `vaddr`.
+ *
+ * Returns true on success or false otherwise.
+ */
+bool
+perf_pt_cache_add_file(struct perf_pt_cache *cache, char *filename, uint64_t size,
+                       uint64_t vaddr, struct perf_pt_cerror *err) {
+    return cache_add_section(cache, filename, 0, size, vaddr, err);
+}
+
+/*
+ * Add the loadable and executable segments of the ELF file `filename` to the
+ * cache, `base` bytes from their addresses in the file. If `core` is true, then
+ * the file must be a core dump.
+ *
+ * Returns true on success or false otherwise.
+ */
+static bool
+cache_add_elf_segments(struct perf_pt_cache *cache, const char *filename,
+                       uint64_t base, bool core, struct perf_pt_cerror *err) {
+    bool ret = false;
+
+    int fd = open(filename, O_RDONLY);
+    if (fd == -1) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        return false;
+    }
+
+    // A short read means the file is not a valid ELF file.
+    ElfW(Ehdr) ehdr;
+    ssize_t nread = pread(fd, &ehdr, sizeof(ehdr), 0);
+    if (nread != sizeof(ehdr)) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, (nread == -1) ? errno : ENOEXEC);
+        goto clean;
+    }
+    if ((memcmp(ehdr.e_ident, ELFMAG, SELFMAG) != 0) ||
+        (ehdr.e_ident[EI_CLASS] != ELFCLASS64) ||
+        (ehdr.e_phentsize != sizeof(ElfW(Phdr))) ||
+        (core && (ehdr.e_type != ET_CORE))) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, ENOEXEC);
+        goto clean;
+    }
+
+    for (ElfW(Half) i = 0; i < ehdr.e_phnum; i++) {
+        ElfW(Phdr) phdr;
+        nread = pread(fd, &phdr, sizeof(phdr), ehdr.e_phoff + i * sizeof(phdr));
+        if (nread != sizeof(phdr)) {
+            perf_pt_set_err(err, perf_pt_cerror_errno, (nread == -1) ? errno : ENOEXEC);
+            goto clean;
+        }
+        // Ensure we only use loadable and executable sections.
+        if ((phdr.p_type != PT_LOAD) || (!(phdr.p_flags & PF_X))) {
+            continue;
+        }
+        // Core dumps may omit the contents of segments (e.g. those backed by
+        // files), leaving nothing to add.
+        if (phdr.p_filesz == 0) {
+            continue;
+        }
+        if (!cache_add_section(cache, filename, phdr.p_offset, phdr.p_filesz,
+                               base + phdr.p_vaddr, err)) {
+            goto clean;
+        }
+    }
+    ret = true;
+
+clean:
+    if (close(fd) == -1) {
+        perf_pt_set_err(err, perf_pt_cerror_errno, errno);
+        ret = false;
+    }
+    return ret;
+}
+
+/*
+ * Get ready to retrieve the basic blocks from a PT trace using the code in
+ * `cache` for control flow recovery. The cache must outlive the decoder.
+ *
+ * The other arguments are as for `perf_pt_init_block_decoder()`.
+ *
+ * Returns a pointer to a configured libipt block decoder or NULL on error.
+ */
+void *
+perf_pt_init_cached_block_decoder(void *buf, uint64_t len, const struct perf_pt_cpu *cpu,
+                                  struct perf_pt_cache *cache, int *decoder_status,
+                                  struct perf_pt_cerror *err) {
+    // The image only refers to the cached sections, so this is cheap.
+    struct pt_image *image = alloc_image(err);
+    if (image == NULL) {
+        return NULL;
+    }
+    for (size_t i = 0; i < cache->nisids; i++) {
+        int rv = pt_image_add_cached(image, cache->iscache, cache->isids[i], NULL);
+        if (rv < 0) {
+            perf_pt_set_err(err, perf_pt_cerror_ipt, -rv);
+            pt_image_free(image);
+            return NULL;
+        }
+    }
+
+    struct pt_block_decoder *decoder =
+        init_block_decoder(buf, len, cpu, NULL, image, decoder_status, err);
+    if (decoder == NULL) {
+        pt_image_free(image);
+    }
+    return decoder;
+}
+
+/*
+ * Free a decoder cache.
+ */
+void
+perf_pt_free_cache(struct perf_pt_cache *cache) {
+    if (cache != NULL) {
+        pt_iscache_free(cache->iscache);
+        free(cache->isids);
+        free(cache);
+    }
+}
+
+/*
+ * Write the version of libipt into the buffer `buf` of size `len` as a
+ * NUL-terminated string of the form "major.minor.patch-build[ext]", truncating
+ * it if it doesn't fit.
+ */
+void
+perf_pt_libipt_version(char *buf, size_t len) {
+    struct pt_version v = pt_library_version();
+    snprintf(buf, len, "%" PRIu8 ".%" PRIu8 ".%" PRIu16 "-%" PRIu32 "%s",
+             v.major, v.minor, v.patch, v.build, v.ext ? v.ext : "");
+}
+
+/*
+ * If the libipt error code `err` means that a decoder on this thread couldn't
+ * find code in its image, store the address of the code in `*ip` and return
+ * true. Otherwise return false.
+ *
+ * This should be called once per error, since the address is forgotten here.
+ */
+bool
+perf_pt_take_nomap_ip(int err, uint64_t *ip) {
+    if ((err != pte_nomap) || !nomap_valid) {
+        return false;
+    }
+    *ip = nomap_ip;
+    nomap_valid = false;
+    return true;
+}
+
+/*
+ * Store the kind of the current CPU in `*cpu`. If it can't be determined, then
+ * the vendor is set to `perf_pt_cpu_unknown`.
+ */
+void
+perf_pt_detect_cpu(struct perf_pt_cpu *cpu) {
+    struct pt_cpu current;
+    memset(cpu, 0, sizeof(*cpu)); // The vendor defaults to unknown.
+    if (pt_cpu_read(&current) != pte_ok || current.vendor != pcv_intel) {
+        return;
     }
+    cpu->vendor = perf_pt_cpu_intel;
+    cpu->family = current.family;
+    cpu->model = current.model;
+    cpu->stepping = current.stepping;
 }
@@ -1,23 +1,74 @@
-use super::PerfPTConfig;
+mod perf_data;
+
+use super::{pt_supported, AuxWatermark, BackendKind, PerfPTConfig, TimingMode, TraceSink};
+use crate::cost;
 use crate::errors::HWTracerError;
-use crate::{Block, ThreadTracer, Trace, Tracer, TracerState};
-use libc::{c_char, c_int, c_void, free, geteuid, malloc, size_t};
+use crate::process::TASKS_PATH;
+use crate::stream::BlockSource;
+use crate::{
+    serialize, BackendInfo, Block, BlockStream, ClassifiedBlock, EdgeKind, IpCompression, PacketIp,
+    PtCpu, PtCpuVendor, PtPacket, PtxedInvocation, RawThreadTracer, RawTrace, ReturnKind,
+    ThreadTracer, TimedBlock, Trace, TraceStats, TraceTiming, Tracer, TracerState,
+};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use libc::{
+    c_char, c_int, c_void, close, free, geteuid, malloc, mmap, munmap, pid_t, size_t, EBADF,
+    EINVAL, ESRCH, MAP_FAILED, MAP_SHARED, PROT_READ,
+};
+use phdrs::{PF_X, PT_LOAD};
+use std::cell::Cell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::env;
 use std::error::Error;
-use std::ffi::{self, CStr, CString};
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fmt::{self, Display, Formatter};
-use std::fs::File;
-use std::io::{self, Read};
-use std::iter::Iterator;
-use std::num::ParseIntError;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::iter::{self, Iterator};
+use std::num::NonZeroU64;
 #[cfg(debug_assertions)]
 use std::ops::Drop;
-use std::os::unix::io::AsRawFd;
+use std::ops::Range;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::ptr;
+use std::slice;
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 
 // The sysfs path used to set perf permissions.
 const PERF_PERMS_PATH: &str = "/proc/sys/kernel/perf_event_paranoid";
 
+// The CPUID leaf which describes Intel PT capabilities.
+const CPUID_PT_LEAF: u32 = 0x14;
+
+// The MTC period encoding used when emitting MTC packets. Must stay in sync with the C code.
+const PERF_PT_MTC_PERIOD: u32 = 3;
+
+// The name which the dynamic linker gives to the VDSO.
+const VDSO_FILENAME: &str = "linux-vdso.so.1";
+
+// The names of the files written by `Trace::write_ptxed_inputs()`.
+const PTXED_TRACE_FILENAME: &str = "trace.pt";
+const PTXED_VDSO_FILENAME: &str = "vdso";
+
+// A Packet Stream Boundary (PSB) packet. Decoding can start at any PSB.
+const PSB: [u8; 16] = [
+    0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82,
+];
+
+// The flags stored in a serialized trace. See `Trace::serialize`.
+const FLAG_OVERFLOWED: u8 = 1;
+const FLAG_AUTO_STOPPED: u8 = 1 << 1;
+const FLAG_CPU: u8 = 1 << 2;
+const FLAG_DUTY_CYCLED: u8 = 1 << 3;
+const FLAG_RESYNC_ON_ERROR: u8 = 1 << 4;
+
 /// An error indicated by a C-level libipt error code.
 #[derive(Debug)]
 struct LibIPTError(c_int);
@@ -48,6 +99,147 @@ enum PerfPTCErrorKind {
     Unknown,
     Errno,
     IPT,
+    NoHWSupport,
+    Permissions,
+    NoPerfEvents,
+}
+
+// The kind of control flow transfer ending a block. Must stay in sync with the C code.
+#[repr(C)]
+#[allow(dead_code)] // Only C constructs these.
+#[derive(Clone, Copy)]
+enum PerfPTEdgeKind {
+    Call,
+    Return,
+    Jump,
+    CondJump,
+    FarCall,
+    FarReturn,
+    FarJump,
+    Unknown,
+}
+
+impl From<PerfPTEdgeKind> for EdgeKind {
+    fn from(kind: PerfPTEdgeKind) -> Self {
+        match kind {
+            PerfPTEdgeKind::Call => EdgeKind::Call,
+            PerfPTEdgeKind::Return => EdgeKind::Return,
+            PerfPTEdgeKind::Jump => EdgeKind::Jump,
+            PerfPTEdgeKind::CondJump => EdgeKind::ConditionalJump,
+            PerfPTEdgeKind::FarCall => EdgeKind::FarCall,
+            PerfPTEdgeKind::FarReturn => EdgeKind::FarReturn,
+            PerfPTEdgeKind::FarJump => EdgeKind::FarJump,
+            PerfPTEdgeKind::Unknown => EdgeKind::Unknown,
+        }
+    }
+}
+
+// How the IP of a TIP packet is compressed. Must stay in sync with the C code.
+#[repr(C)]
+#[allow(dead_code)] // Only C constructs these.
+#[derive(Clone, Copy)]
+enum PerfPTIpCompression {
+    Suppressed,
+    Update16,
+    Update32,
+    SignExtend48,
+    Update48,
+    Full,
+}
+
+impl From<PerfPTIpCompression> for Option<IpCompression> {
+    fn from(ipc: PerfPTIpCompression) -> Self {
+        match ipc {
+            // A suppressed IP gives no block's start address.
+            PerfPTIpCompression::Suppressed => None,
+            PerfPTIpCompression::Update16 => Some(IpCompression::Update16),
+            PerfPTIpCompression::Update32 => Some(IpCompression::Update32),
+            PerfPTIpCompression::SignExtend48 => Some(IpCompression::SignExtend48),
+            PerfPTIpCompression::Update48 => Some(IpCompression::Update48),
+            PerfPTIpCompression::Full => Some(IpCompression::Full),
+        }
+    }
+}
+
+// The kinds of packet returned by `perf_pt_next_packet()`. Must stay in sync with the C code.
+#[repr(C)]
+#[allow(dead_code)] // Only C constructs most of these.
+#[derive(Clone, Copy)]
+enum PerfPTPacketKind {
+    Eos, // Not a packet: the end of the packet stream.
+    Psb,
+    PsbEnd,
+    Pad,
+    Ovf,
+    Tnt,
+    Tip,
+    TipPge,
+    TipPgd,
+    Fup,
+    ModeExec,
+    ModeTsx,
+    Tsc,
+    Cyc,
+    Mtc,
+    Cbr,
+    Ptw,
+    Other,
+}
+
+// A packet, as returned by `perf_pt_next_packet()`. Must stay in sync with the C code.
+#[repr(C)]
+struct PerfPTPacket {
+    kind: PerfPTPacketKind,
+    ipc: PerfPTIpCompression, // The compression of an IP packet.
+    // The IP, TNT bits, or value of a TSC, CYC, MTC, CBR or PTW packet. For a MODE packet, bit 0
+    // is CSL or InTX, and bit 1 CSD or TXAbort.
+    payload: u64,
+    // The number of bits of a TNT packet. For a PTW packet, 1 if a FUP packet follows with the IP
+    // of the PTWRITE.
+    count: u8,
+}
+
+impl PerfPTPacket {
+    // Converts the packet to a `PtPacket`, or `None` at the end of the packet stream.
+    fn to_packet(&self) -> Option<PtPacket> {
+        let ip = Option::<IpCompression>::from(self.ipc).map(|ipc| PacketIp {
+            ipc,
+            ip: self.payload,
+        });
+        let pkt = match self.kind {
+            PerfPTPacketKind::Eos => return None,
+            PerfPTPacketKind::Psb => PtPacket::Psb,
+            PerfPTPacketKind::PsbEnd => PtPacket::PsbEnd,
+            PerfPTPacketKind::Pad => PtPacket::Pad,
+            PerfPTPacketKind::Ovf => PtPacket::Ovf,
+            PerfPTPacketKind::Tnt => PtPacket::Tnt {
+                count: self.count,
+                bits: self.payload,
+            },
+            PerfPTPacketKind::Tip => PtPacket::Tip(ip),
+            PerfPTPacketKind::TipPge => PtPacket::TipPge(ip),
+            PerfPTPacketKind::TipPgd => PtPacket::TipPgd(ip),
+            PerfPTPacketKind::Fup => PtPacket::Fup(ip),
+            PerfPTPacketKind::ModeExec => PtPacket::ModeExec {
+                csl: self.payload & 1 != 0,
+                csd: self.payload & 2 != 0,
+            },
+            PerfPTPacketKind::ModeTsx => PtPacket::ModeTsx {
+                intx: self.payload & 1 != 0,
+                abort: self.payload & 2 != 0,
+            },
+            PerfPTPacketKind::Tsc => PtPacket::Tsc(self.payload),
+            PerfPTPacketKind::Cyc => PtPacket::Cyc(self.payload),
+            PerfPTPacketKind::Mtc => PtPacket::Mtc(self.payload as u8),
+            PerfPTPacketKind::Cbr => PtPacket::Cbr(self.payload as u8),
+            PerfPTPacketKind::Ptw => PtPacket::Ptw {
+                payload: self.payload,
+                ip: self.count != 0,
+            },
+            PerfPTPacketKind::Other => PtPacket::Other,
+        };
+        Some(pkt)
+    }
 }
 
 /// Represents an error occurring in the C code in this backend.
@@ -75,18 +267,111 @@ impl From<PerfPTCError> for HWTracerError {
         match err.typ {
             PerfPTCErrorKind::Unused => HWTracerError::Unknown,
             PerfPTCErrorKind::Unknown => HWTracerError::Unknown,
-            PerfPTCErrorKind::Errno => HWTracerError::Errno(err.code),
+            PerfPTCErrorKind::Errno => HWTracerError::CFailed(err.code),
             PerfPTCErrorKind::IPT => {
-                // Overflow is a special case with its own error type.
-                match unsafe { perf_pt_is_overflow_err(err.code) } {
-                    true => HWTracerError::HWBufferOverflow,
-                    false => HWTracerError::Custom(Box::new(LibIPTError(err.code))),
+                // Overflow and missing code are special cases with their own error types.
+                let mut vaddr = 0;
+                if unsafe { perf_pt_is_overflow_err(err.code) } {
+                    HWTracerError::HWBufferOverflow
+                } else if unsafe { perf_pt_take_nomap_ip(err.code, &mut vaddr) } {
+                    HWTracerError::MissingMapping { vaddr }
+                } else {
+                    HWTracerError::Custom(Box::new(LibIPTError(err.code)))
                 }
             }
+            PerfPTCErrorKind::NoHWSupport => HWTracerError::Unsupported {
+                kind: BackendKind::PerfPT,
+                reason: format!(
+                    "Intel PT not supported by the kernel: {}",
+                    HWTracerError::CFailed(err.code)
+                ),
+            },
+            PerfPTCErrorKind::Permissions => HWTracerError::Permission {
+                errno: err.code,
+                hint: format!(
+                    "{}. Tracing threads other than the caller may also require CAP_SYS_PTRACE",
+                    perms_hint()
+                ),
+            },
+            PerfPTCErrorKind::NoPerfEvents => HWTracerError::Unsupported {
+                kind: BackendKind::PerfPT,
+                reason: format!(
+                    "kernel built without perf_events: {}",
+                    HWTracerError::CFailed(err.code)
+                ),
+            },
+        }
+    }
+}
+
+// The parts of `PerfPTConfig` needed by the C code. Must stay in sync with the C code.
+#[repr(C)]
+struct PerfPTCConfig {
+    data_bufsize: size_t,
+    aux_bufsize: size_t,
+    initial_trace_bufsize: size_t,
+    tsc: bool,
+    exclude_kernel: bool,
+    exclude_user: bool,
+    max_bytes: u64,    // 0 means no limit.
+    psb_period: c_int, // -1 means the CPU's default.
+    snapshot: bool,
+    enable_on_exec: bool, // Set by `start_tracing_on_exec()`, not by `PerfPTConfig`.
+    aux_watermark: f64,   // 0 means no callback.
+    aux_watermark_cb: Option<unsafe extern "C" fn(*const c_void)>,
+    aux_watermark_data: *const c_void, // An `AuxWatermark`, kept alive by the `PerfPTConfig`.
+    cgroup_cpu: c_int, // -1 means not tracing a cgroup. Set by `cgroup_cpu_tracer()`.
+    addr_filter: *const c_char, // See `addr_filter()`. Null means no filter.
+    ptwrite: bool,
+    mtc: bool,
+    cyc: bool,
+    noretcomp: bool,
+    duty_cycle_on_us: u64, // 0 means no duty cycling.
+    duty_cycle_off_us: u64,
+}
+
+impl From<&PerfPTConfig> for PerfPTCConfig {
+    fn from(config: &PerfPTConfig) -> Self {
+        Self {
+            data_bufsize: config.data_bufsize,
+            aux_bufsize: config.aux_bufsize,
+            initial_trace_bufsize: config.initial_trace_bufsize,
+            tsc: config.effective_timing() >= TimingMode::Tsc,
+            exclude_kernel: config.exclude_kernel,
+            exclude_user: config.exclude_user,
+            max_bytes: config.max_bytes.unwrap_or(0),
+            psb_period: config.psb_period.map_or(-1, c_int::from),
+            snapshot: config.snapshot,
+            enable_on_exec: false,
+            aux_watermark: config.aux_watermark.as_ref().map_or(0.0, |w| w.frac),
+            aux_watermark_cb: config
+                .aux_watermark
+                .as_ref()
+                .map(|_| call_aux_watermark as unsafe extern "C" fn(*const c_void)),
+            aux_watermark_data: config.aux_watermark.as_ref().map_or(ptr::null(), |w| {
+                &**w as *const AuxWatermark as *const c_void
+            }),
+            cgroup_cpu: -1,
+            addr_filter: ptr::null(),
+            ptwrite: config.ptwrite,
+            mtc: config.effective_timing() >= TimingMode::Mtc,
+            cyc: config.effective_timing() >= TimingMode::Cyc,
+            noretcomp: !config.ret_compression,
+            duty_cycle_on_us: config.duty_cycle_us.map_or(0, |(on, _)| on),
+            duty_cycle_off_us: config.duty_cycle_us.map_or(0, |(_, off)| off),
         }
     }
 }
 
+// Called by C, on the collection thread, when the AUX buffer reaches the watermark.
+unsafe extern "C" fn call_aux_watermark(watermark: *const c_void) {
+    let watermark = &*(watermark as *const AuxWatermark);
+    // Unwinding into C is undefined behaviour.
+    if panic::catch_unwind(AssertUnwindSafe(|| (watermark.callback)())).is_err() {
+        process::abort();
+    }
+}
+
 // FFI prototypes.
 //
 // XXX Rust bug. link_args always reported unused.
@@ -98,18 +383,34 @@ impl From<PerfPTCError> for HWTracerError {
 #[link_args = "-lipt"]
 extern "C" {
     // collect.c
-    fn perf_pt_init_tracer(conf: *const PerfPTConfig, err: *mut PerfPTCError) -> *mut c_void;
+    fn perf_pt_init_tracer(
+        conf: *const PerfPTCConfig,
+        target_tid: pid_t,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
     fn perf_pt_start_tracer(
         tr_ctx: *mut c_void,
         trace: *mut PerfPTTrace,
         err: *mut PerfPTCError,
     ) -> bool;
+    fn perf_pt_take_trace(
+        tr_ctx: *mut c_void,
+        out: *mut PerfPTTrace,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_snapshot(
+        tr_ctx: *mut c_void,
+        out: *mut PerfPTTrace,
+        resume: bool,
+        err: *mut PerfPTCError,
+    ) -> bool;
     fn perf_pt_stop_tracer(tr_ctx: *mut c_void, err: *mut PerfPTCError) -> bool;
     fn perf_pt_free_tracer(tr_ctx: *mut c_void, err: *mut PerfPTCError) -> bool;
     // decode.c
     fn perf_pt_init_block_decoder(
         buf: *const c_void,
         len: u64,
+        cpu: *const PerfPTCpu,
         vdso_fd: c_int,
         vdso_filename: *const c_char,
         decoder_status: *mut c_int,
@@ -120,75 +421,527 @@ extern "C" {
         decoder_status: *mut c_int,
         addr: *mut u64,
         len: *mut u64,
+        ninsn: *mut u64,
+        edge: *mut PerfPTEdgeKind,
+        overflow: *mut bool,
+        gap: *mut bool,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_init_file_block_decoder(
+        buf: *const c_void,
+        len: u64,
+        cpu: *const PerfPTCpu,
+        errata: *const Errata,
+        files: *const PerfPTImageFile,
+        nfiles: size_t,
+        decoder_status: *mut c_int,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
+    fn perf_pt_block_time(decoder: *mut c_void, tsc: *mut u64) -> bool;
+    fn perf_pt_has_timing(
+        buf: *const c_void,
+        len: u64,
+        has_timing: *mut bool,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_resync_block_decoder(
+        decoder: *mut c_void,
+        decoder_status: *mut c_int,
+        skipped: *mut u64,
+    ) -> bool;
+    fn perf_pt_block_offset(decoder: *mut c_void, offset: *mut u64) -> bool;
+    fn perf_pt_tip_packets(
+        buf: *const c_void,
+        len: u64,
+        tips: *mut c_void,
         err: *mut PerfPTCError,
     ) -> bool;
     fn perf_pt_free_block_decoder(decoder: *mut c_void);
+    fn perf_pt_init_packet_decoder(
+        buf: *const c_void,
+        len: u64,
+        eos: *mut bool,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
+    fn perf_pt_next_packet(
+        decoder: *mut c_void,
+        packet: *mut PerfPTPacket,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_free_packet_decoder(decoder: *mut c_void);
+    fn perf_pt_libipt_version(buf: *mut c_char, len: size_t);
+    fn perf_pt_detect_cpu(cpu: *mut PerfPTCpu);
+    fn perf_pt_take_nomap_ip(err: c_int, ip: *mut u64) -> bool;
+    fn dump_vdso(fd: c_int, vaddr: u64, len: size_t, err: *mut PerfPTCError) -> bool;
+    fn perf_pt_alloc_cache(err: *mut PerfPTCError) -> *mut c_void;
+    fn perf_pt_cache_add_self(
+        cache: *mut c_void,
+        vdso_fd: c_int,
+        vdso_filename: *const c_char,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_cache_add_elf(
+        cache: *mut c_void,
+        filename: *const c_char,
+        base: u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_cache_add_core(
+        cache: *mut c_void,
+        filename: *const c_char,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_cache_add_file(
+        cache: *mut c_void,
+        filename: *const c_char,
+        size: u64,
+        vaddr: u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_init_cached_block_decoder(
+        buf: *const c_void,
+        len: u64,
+        cpu: *const PerfPTCpu,
+        cache: *mut c_void,
+        decoder_status: *mut c_int,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
+    fn perf_pt_free_cache(cache: *mut c_void);
+    fn perf_pt_init_insn_decoder(
+        buf: *const c_void,
+        len: u64,
+        cpu: *const PerfPTCpu,
+        vdso_fd: c_int,
+        vdso_filename: *const c_char,
+        decoder_status: *mut c_int,
+        err: *mut PerfPTCError,
+    ) -> *mut c_void;
+    fn perf_pt_next_insn(
+        decoder: *mut c_void,
+        decoder_status: *mut c_int,
+        addr: *mut u64,
+        err: *mut PerfPTCError,
+    ) -> bool;
+    fn perf_pt_free_insn_decoder(decoder: *mut c_void);
     // util.c
     fn perf_pt_is_overflow_err(err: c_int) -> bool;
     // libipt
     fn pt_errstr(error_code: c_int) -> *const c_char;
 }
 
+// Explains how to obtain permission to trace, including the current perf paranoia level if it can
+// be determined.
+fn perms_hint() -> String {
+    let hint = format!("you must be root or {} must contain -1", PERF_PERMS_PATH);
+    match fs::read_to_string(PERF_PERMS_PATH) {
+        Ok(level) => format!("{} (it currently contains {})", hint, level.trim()),
+        Err(_) => hint,
+    }
+}
+
 // Iterate over the blocks of a PerfPTTrace.
 struct PerfPTBlockIterator<'t> {
     decoder: *mut c_void,  // C-level libipt block decoder.
     decoder_status: c_int, // Stores the current libipt-level status of the above decoder.
     #[allow(dead_code)] // Rust doesn't know that this exists only to keep the file long enough.
     vdso_tempfile: Option<NamedTempFile>, // VDSO code stored temporarily.
-    trace: &'t PerfPTTrace, // The trace we are iterating.
+    trace: &'t dyn Trace,  // The trace we are iterating.
     errored: bool,         // Set to true when an error occurs, thus invalidating the iterator.
+    edge: PerfPTEdgeKind,  // How the most recently decoded block ended.
+    // If set, decoding resumes at the next PSB packet after an error (see
+    // `PerfPTConfig::resync_on_error`).
+    resync_on_error: bool,
+    instrs: u64,   // The number of instructions in the blocks decoded so far.
+    blocks: usize, // The number of blocks decoded so far.
+    // Set if the last block was followed by a gap in tracing, which is still to be reported.
+    gap_pending: bool,
+    // The value of `blocks` when control flow was last lost (e.g. to an overflow). A gap in
+    // tracing is reported only if blocks have been decoded since then.
+    last_break: usize,
 }
 
-impl From<io::Error> for HWTracerError {
-    fn from(err: io::Error) -> Self {
-        HWTracerError::Custom(Box::new(err))
+// The signature shared by the C functions which initialise a libipt decoder.
+type InitDecoderFn = unsafe extern "C" fn(
+    *const c_void,
+    u64,
+    *const PerfPTCpu,
+    c_int,
+    *const c_char,
+    *mut c_int,
+    *mut PerfPTCError,
+) -> *mut c_void;
+
+// Returns the version of libipt in use, e.g. "2.0.1-0".
+fn libipt_version() -> String {
+    let mut buf = [0 as c_char; 64];
+    unsafe {
+        perf_pt_libipt_version(buf.as_mut_ptr(), buf.len());
+        CStr::from_ptr(buf.as_ptr())
     }
+    .to_string_lossy()
+    .into_owned()
 }
 
-impl From<ffi::NulError> for HWTracerError {
-    fn from(err: ffi::NulError) -> Self {
-        HWTracerError::Custom(Box::new(err))
+// Returns a bitmap of the PSB period encodings supported by the CPU, in which bit `n` is set if
+// encoding `n` is supported. This is empty if the PSB period can't be configured at all.
+fn supported_psb_periods() -> u16 {
+    if !pt_supported() || unsafe { __cpuid(0) }.eax < CPUID_PT_LEAF {
+        return 0;
+    }
+    let res = unsafe { __cpuid_count(CPUID_PT_LEAF, 0) };
+    // The PSB period is configurable only if bit 1 of EBX is set, in which case sub-leaf 1
+    // reports the supported encodings.
+    if res.ebx & (1 << 1) == 0 || res.eax < 1 {
+        return 0;
     }
+    (unsafe { __cpuid_count(CPUID_PT_LEAF, 1) }.ebx >> 16) as u16
 }
 
-impl From<ParseIntError> for HWTracerError {
-    fn from(err: ParseIntError) -> Self {
-        HWTracerError::Custom(Box::new(err))
+// Returns the number of address ranges by which the CPU can filter tracing, or 0 if it can't
+// filter by address at all.
+fn supported_addr_ranges() -> u32 {
+    if !pt_supported() || unsafe { __cpuid(0) }.eax < CPUID_PT_LEAF {
+        return 0;
     }
+    let res = unsafe { __cpuid_count(CPUID_PT_LEAF, 0) };
+    // IP filtering is supported only if bit 2 of EBX is set, in which case bits 0-2 of EAX of
+    // sub-leaf 1 report the number of ranges.
+    if res.ebx & (1 << 2) == 0 || res.eax < 1 {
+        return 0;
+    }
+    unsafe { __cpuid_count(CPUID_PT_LEAF, 1) }.eax & 0x7
 }
 
-impl<'t> PerfPTBlockIterator<'t> {
-    // Initialise the block decoder.
-    fn init_decoder(&mut self) -> Result<(), HWTracerError> {
-        // Make a temp file for the C code to write the VDSO code into.
-        //
-        // We have to do this because libipt lazily reads the code from the files you load into the
-        // image. We store it into `self` to ensure the file lives as long as the iterator.
-        let vdso_tempfile = NamedTempFile::new()?;
-        // File name of a NamedTempFile should always be valid UTF-8, unwrap() below can't fail.
-        let vdso_filename = CString::new(vdso_tempfile.path().to_str().unwrap())?;
-        let mut cerr = PerfPTCError::new();
-        let decoder = unsafe {
-            perf_pt_init_block_decoder(
-                self.trace.buf.0 as *const c_void,
-                self.trace.len,
-                vdso_tempfile.as_raw_fd(),
-                vdso_filename.as_ptr(),
-                &mut self.decoder_status,
-                &mut cerr,
+// Returns `true` if the CPU can trace PTWRITE instructions.
+fn ptwrite_supported() -> bool {
+    if !pt_supported() || unsafe { __cpuid(0) }.eax < CPUID_PT_LEAF {
+        return false;
+    }
+    // PTWRITE is supported if bit 4 of EBX is set.
+    unsafe { __cpuid_count(CPUID_PT_LEAF, 0) }.ebx & (1 << 4) != 0
+}
+
+// Returns `true` if the CPU can emit MTC packets with the period encoding which the C code asks for
+// (see `PERF_PT_MTC_PERIOD`).
+fn mtc_supported() -> bool {
+    if !pt_supported() || unsafe { __cpuid(0) }.eax < CPUID_PT_LEAF {
+        return false;
+    }
+    let res = unsafe { __cpuid_count(CPUID_PT_LEAF, 0) };
+    // MTC is supported if bit 3 of EBX is set, in which case bits 16-31 of EAX of sub-leaf 1 are
+    // a bitmap of the supported period encodings.
+    if res.ebx & (1 << 3) == 0 || res.eax < 1 {
+        return false;
+    }
+    unsafe { __cpuid_count(CPUID_PT_LEAF, 1) }.eax & (1 << (16 + PERF_PT_MTC_PERIOD)) != 0
+}
+
+// Returns `true` if the CPU can emit CYC packets.
+fn cyc_supported() -> bool {
+    if !pt_supported() || unsafe { __cpuid(0) }.eax < CPUID_PT_LEAF {
+        return false;
+    }
+    // Cycle-accurate mode is supported if bit 1 of EBX is set.
+    unsafe { __cpuid_count(CPUID_PT_LEAF, 0) }.ebx & (1 << 1) != 0
+}
+
+// Returns the perf address filter (see `PERF_EVENT_IOC_SET_FILTER`) which restricts tracing to
+// `ranges` of the current process, or `None` if there are no ranges. perf identifies user-space
+// code by file and file offset, so each range is looked up in the objects currently loaded.
+fn addr_filter(ranges: &[Range<u64>]) -> Result<Option<CString>, HWTracerError> {
+    if ranges.is_empty() {
+        return Ok(None);
+    }
+    let exe = env::current_exe()?;
+    let mut filters = Vec::new();
+    for r in ranges {
+        if r.start >= r.end {
+            return Err(HWTracerError::BadConfig(format!(
+                "the address filter range 0x{:x}..0x{:x} is empty",
+                r.start, r.end
+            )));
+        }
+        let mut found = None;
+        for obj in phdrs::objects() {
+            let obj_name = obj.name().to_bytes();
+            if obj_name == VDSO_FILENAME.as_bytes() {
+                continue; // The VDSO has no file.
+            }
+            for hdr in obj.iter_phdrs() {
+                if hdr.type_() != PT_LOAD || hdr.flags() & PF_X.0 == 0 {
+                    continue;
+                }
+                let vaddr = obj.addr() + hdr.vaddr();
+                if r.start >= vaddr && r.end <= vaddr + hdr.filesz() {
+                    let path = match obj_name.is_empty() {
+                        true => exe.clone(), // The main executable.
+                        false => PathBuf::from(OsStr::from_bytes(obj_name)),
+                    };
+                    found = Some((path, hdr.offset() + (r.start - vaddr)));
+                }
+            }
+        }
+        let (path, offset) = found.ok_or_else(|| {
+            HWTracerError::BadConfig(format!(
+                "the address filter range 0x{:x}..0x{:x} isn't within the code of a file",
+                r.start, r.end
+            ))
+        })?;
+        // Filters are separated by spaces or commas, so a path mustn't contain either.
+        let path = path.into_os_string().into_vec();
+        if path.iter().any(|c| *c == b' ' || *c == b',') {
+            return Err(HWTracerError::BadConfig(format!(
+                "the address filter range 0x{:x}..0x{:x} is in a file whose path has a space \
+                 or comma",
+                r.start, r.end
+            )));
+        }
+        let mut filter = format!("filter 0x{:x}/0x{:x}@", offset, r.end - r.start).into_bytes();
+        filter.extend(path);
+        filters.push(filter);
+    }
+    Ok(Some(CString::new(filters.join(&b','))?))
+}
+
+// Returns the `--raw` arguments with which `ptxed` loads the executable code of the current
+// process. The VDSO has no file of its own, so its code is dumped into a file in `dir`.
+fn self_ptxed_raw_args(dir: &Path) -> Result<Vec<OsString>, HWTracerError> {
+    let mut args = Vec::new();
+    let exe = env::current_exe()?;
+    let vdso_path = dir.join(PTXED_VDSO_FILENAME);
+    let mut vdso_file: Option<File> = None;
+    let mut vdso_len = 0;
+    for obj in phdrs::objects() {
+        let obj_name = obj.name().to_bytes();
+        for hdr in obj.iter_phdrs() {
+            if hdr.type_() != PT_LOAD || hdr.flags() & PF_X.0 == 0 {
+                continue; // Only look at loadable and executable segments.
+            }
+            let vaddr = obj.addr() + hdr.vaddr();
+            let (path, start, end) = if obj_name == VDSO_FILENAME.as_bytes() {
+                // Append the segment's code to the VDSO file.
+                if vdso_file.is_none() {
+                    vdso_file = Some(File::create(&vdso_path)?);
+                }
+                let fd = vdso_file.as_ref().unwrap().as_raw_fd();
+                let mut cerr = PerfPTCError::new();
+                if !unsafe { dump_vdso(fd, vaddr, hdr.memsz() as size_t, &mut cerr) } {
+                    return Err(cerr.into());
+                }
+                let start = vdso_len;
+                vdso_len += hdr.memsz();
+                (vdso_path.as_path(), start, vdso_len)
+            } else if obj_name.is_empty() {
+                // The main executable.
+                (exe.as_path(), hdr.offset(), hdr.offset() + hdr.filesz())
+            } else {
+                let path = Path::new(OsStr::from_bytes(obj_name));
+                (path, hdr.offset(), hdr.offset() + hdr.filesz())
+            };
+            let mut arg = path.as_os_str().to_owned();
+            arg.push(format!(":0x{:x}-0x{:x}:0x{:x}", start, end, vaddr));
+            args.push(OsString::from("--raw"));
+            args.push(arg);
+        }
+    }
+    Ok(args)
+}
+
+// Returns whether the x86_64 instruction with the lower case mnemonic `instr` ends a block.
+#[cfg(any(feature = "ptxed_verify", all(perf_pt_test, test)))]
+fn instr_terminates_block(instr: &str) -> bool {
+    assert!(instr.find(|c: char| !c.is_lowercase()).is_none());
+    match instr {
+        // JMP or Jcc are the only instructions beginning with 'j'.
+        m if m.starts_with('j') => true,
+        "call" | "ret" | "loop" | "loope" | "loopne" | "syscall" | "sysenter" | "sysexit"
+        | "sysret" | "xabort" => true,
+        _ => false,
+    }
+}
+
+// Decodes `trace` with `ptxed`, returning the start address of each block, in order.
+#[cfg(any(feature = "ptxed_verify", all(perf_pt_test, test)))]
+fn ptxed_block_starts(trace: &dyn Trace) -> Result<Vec<u64>, HWTracerError> {
+    let dir = tempfile::tempdir()?;
+    let mut inv = trace.write_ptxed_inputs(dir.path())?;
+    let block_args = vec![
+        "--block-decoder",
+        "--block:end-on-call",
+        "--block:end-on-jump",
+        "--block:show-blocks",
+    ];
+    inv.args
+        .splice(0..0, block_args.into_iter().map(OsString::from));
+
+    let out = inv.command().output()?;
+    let outstr = String::from_utf8_lossy(&out.stdout);
+    if !out.status.success() {
+        return Err(HWTracerError::Custom(
+            format!(
+                "ptxed failed:\nInvocation----------\n{:?}\n \
+                 Stdout\n------\n{}Stderr\n------\n{}",
+                inv.args,
+                outstr,
+                String::from_utf8_lossy(&out.stderr)
             )
-        };
-        if decoder.is_null() {
-            return Err(cerr.into());
+            .into(),
+        ));
+    }
+
+    let mut block_start = false;
+    let mut block_vaddrs = Vec::new();
+    let mut last_instr: Option<&str> = None;
+    for line in outstr.lines() {
+        let line = line.trim();
+        if line.contains("error") {
+            return Err(HWTracerError::Custom(
+                format!("error line in ptxed output:\n{}", line).into(),
+            ));
+        } else if line.starts_with('[') {
+            // It's a special line, e.g. [enabled], [disabled], [block]...
+            let after_branch = match last_instr {
+                Some(instr) => instr_terminates_block(instr),
+                None => true,
+            };
+            if line == "[block]" && after_branch {
+                // The next insruction we see will be the start of a block.
+                block_start = true;
+            }
+        } else {
+            // It's a regular instruction line.
+            let mut fields = line.split_whitespace();
+            let vaddr_s = fields.next().unwrap_or("");
+            if block_start {
+                // This instruction is the start of a block.
+                let vaddr = u64::from_str_radix(vaddr_s, 16)?;
+                block_vaddrs.push(vaddr);
+                block_start = false;
+            }
+            last_instr = fields.next();
+        }
+    }
+    Ok(block_vaddrs)
+}
+
+// Returns the raw Intel PT packets of `trace` for decoding.
+fn trace_data(trace: &dyn Trace) -> Result<&[u8], HWTracerError> {
+    trace.raw_data().ok_or_else(|| {
+        HWTracerError::UnsupportedOperation("the trace contains no raw data to decode".into())
+    })
+}
+
+// Initialise a C-level libipt decoder for `trace` using `init_fn`.
+//
+// Returns the decoder and the temporary file holding the VDSO code, which must outlive the decoder.
+fn init_decoder(
+    init_fn: InitDecoderFn,
+    trace: &dyn Trace,
+    decoder_status: &mut c_int,
+) -> Result<(*mut c_void, NamedTempFile), HWTracerError> {
+    let data = trace_data(trace)?;
+    // Make a temp file for the C code to write the VDSO code into.
+    //
+    // We have to do this because libipt lazily reads the code from the files you load into the
+    // image. The caller stores it to ensure the file lives as long as the decoder.
+    let vdso_tempfile = NamedTempFile::new()?;
+    // File name of a NamedTempFile should always be valid UTF-8, unwrap() below can't fail.
+    let vdso_filename = CString::new(vdso_tempfile.path().to_str().unwrap())?;
+    let cpu = trace.pt_cpu().map(PerfPTCpu::from);
+    let mut cerr = PerfPTCError::new();
+    let decoder = unsafe {
+        init_fn(
+            data.as_ptr() as *const c_void,
+            data.len() as u64,
+            cpu_ptr(&cpu),
+            vdso_tempfile.as_raw_fd(),
+            vdso_filename.as_ptr(),
+            decoder_status,
+            &mut cerr,
+        )
+    };
+    if decoder.is_null() {
+        return Err(cerr.into());
+    }
+
+    vdso_tempfile.as_file().sync_all()?;
+    Ok((decoder, vdso_tempfile))
+}
+
+impl<'t> PerfPTBlockIterator<'t> {
+    // Makes an iterator over the blocks of `trace`. The decoder is initialised lazily.
+    fn new(trace: &'t dyn Trace) -> Self {
+        Self {
+            decoder: ptr::null_mut(),
+            decoder_status: 0,
+            vdso_tempfile: None,
+            trace,
+            errored: false,
+            edge: PerfPTEdgeKind::Unknown,
+            resync_on_error: false,
+            instrs: 0,
+            blocks: 0,
+            gap_pending: false,
+            last_break: 0,
         }
+    }
 
-        vdso_tempfile.as_file().sync_all()?;
+    // Initialise the block decoder.
+    fn init_decoder(&mut self) -> Result<(), HWTracerError> {
+        let (decoder, vdso_tempfile) = init_decoder(
+            perf_pt_init_block_decoder,
+            self.trace,
+            &mut self.decoder_status,
+        )?;
         self.decoder = decoder;
         self.vdso_tempfile = Some(vdso_tempfile);
         Ok(())
     }
 }
 
+impl<'t> PerfPTBlockIterator<'t> {
+    // After an error, skip to the next point in the trace from which decoding can continue,
+    // making the iterator usable again.
+    //
+    // Returns the number of bytes of trace data skipped, or `None` if decoding can't continue.
+    fn resync(&mut self) -> Option<u64> {
+        let mut skipped = 0;
+        if self.decoder.is_null()
+            || !unsafe {
+                perf_pt_resync_block_decoder(self.decoder, &mut self.decoder_status, &mut skipped)
+            }
+        {
+            return None;
+        }
+        self.errored = false;
+        self.gap_pending = false;
+        self.last_break = self.blocks;
+        Some(skipped)
+    }
+
+    // Returns the error reporting a gap in tracing after the blocks decoded so far, or `None` if
+    // control flow was already lost there (e.g. at the start of the trace).
+    fn gap(&mut self) -> Option<HWTracerError> {
+        if self.blocks == self.last_break {
+            return None;
+        }
+        self.last_break = self.blocks;
+        Some(HWTracerError::TracingDisabled {
+            at_block: self.blocks,
+        })
+    }
+
+    // Returns the offset in the packet stream before which the decoder has consumed all packets,
+    // or 0 if it isn't synchronised yet.
+    fn offset(&self) -> u64 {
+        let mut offset = 0;
+        if self.decoder.is_null() || !unsafe { perf_pt_block_offset(self.decoder, &mut offset) } {
+            return 0;
+        }
+        offset
+    }
+}
+
 impl<'t> Drop for PerfPTBlockIterator<'t> {
     fn drop(&mut self) {
         unsafe { perf_pt_free_block_decoder(self.decoder) };
@@ -204,6 +957,13 @@ impl<'t> Iterator for PerfPTBlockIterator<'t> {
             return None;
         }
 
+        if self.gap_pending {
+            self.gap_pending = false;
+            if let Some(e) = self.gap() {
+                return Some(Err(e));
+            }
+        }
+
         // Lazily initialise the block decoder.
         if self.decoder.is_null() {
             if let Err(e) = self.init_decoder() {
@@ -212,551 +972,4072 @@ impl<'t> Iterator for PerfPTBlockIterator<'t> {
             }
         }
 
-        let mut first_instr = 0;
-        let mut last_instr = 0;
-        let mut cerr = PerfPTCError::new();
-        let rv = unsafe {
-            perf_pt_next_block(
-                self.decoder,
-                &mut self.decoder_status,
-                &mut first_instr,
-                &mut last_instr,
-                &mut cerr,
-            )
-        };
-        if !rv {
-            self.errored = true; // This iterator is unusable now.
-            return Some(Err(HWTracerError::from(cerr)));
+        loop {
+            let mut first_instr = 0;
+            let mut last_instr = 0;
+            let mut ninsn = 0;
+            let mut overflow = false;
+            let mut gap = false;
+            let mut cerr = PerfPTCError::new();
+            let rv = unsafe {
+                perf_pt_next_block(
+                    self.decoder,
+                    &mut self.decoder_status,
+                    &mut first_instr,
+                    &mut last_instr,
+                    &mut ninsn,
+                    &mut self.edge,
+                    &mut overflow,
+                    &mut gap,
+                    &mut cerr,
+                )
+            };
+            if !rv {
+                if self.resync_on_error {
+                    if let Some(skipped_bytes) = self.resync() {
+                        return Some(Err(HWTracerError::DecodeGap { skipped_bytes }));
+                    }
+                }
+                self.errored = true; // This iterator is unusable now.
+                return Some(Err(HWTracerError::from(cerr)));
+            }
+            if overflow {
+                // Decoding carries on where the hardware resumed tracing.
+                self.last_break = self.blocks;
+                return Some(Err(HWTracerError::Overflow {
+                    at_block: self.blocks,
+                }));
+            }
+            if gap && first_instr == 0 {
+                // Decoding carries on where tracing was re-enabled.
+                match self.gap() {
+                    Some(e) => return Some(Err(e)),
+                    None => continue,
+                }
+            }
+            if first_instr == 0 {
+                // End of packet stream. If the trace overflowed, then this isn't where execution
+                // really stopped, so the consumer gets an error instead.
+                if self.trace.overflowed() {
+                    self.errored = true;
+                    return Some(Err(HWTracerError::HWBufferOverflow));
+                }
+                return None;
+            }
+            self.instrs += ninsn;
+            self.blocks += 1;
+            // A gap after the block is reported by the next call.
+            self.gap_pending = gap;
+            return Some(Ok(Block::new(first_instr, last_instr)));
         }
-        if first_instr == 0 {
-            None // End of packet stream.
-        } else {
-            Some(Ok(Block::new(first_instr, last_instr)))
+    }
+}
+
+// Iterate over the blocks of a PerfPTTrace, measuring how long decoding takes. Once decoding has
+// finished, the time is recorded in the trace, unless an earlier iterator recorded one already.
+struct PerfPTTimingIterator<'t> {
+    blocks: PerfPTBlockIterator<'t>,
+    trace: &'t PerfPTTrace,
+    // The time spent in `next()` so far.
+    elapsed: Duration,
+    // Set if decoding stopped at an error, so that the time spent isn't that of a full decode.
+    failed: bool,
+}
+
+impl<'t> Iterator for PerfPTTimingIterator<'t> {
+    type Item = Result<Block, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let res = self.blocks.next();
+        self.elapsed += start.elapsed();
+        // Decoding carries on after an overflow or a gap. An overflowed trace ends with an
+        // overflow error once all of it has been decoded, but any other error stops decoding
+        // part-way through.
+        let finished = match res {
+            Some(Ok(_))
+            | Some(Err(HWTracerError::Overflow { .. }))
+            | Some(Err(HWTracerError::TracingDisabled { .. }))
+            | Some(Err(HWTracerError::DecodeGap { .. })) => false,
+            Some(Err(HWTracerError::HWBufferOverflow)) | None => !self.failed,
+            Some(Err(_)) => {
+                self.failed = true;
+                false
+            }
+        };
+        if finished && self.trace.decoding.get().is_none() {
+            self.trace.decoding.set(duration_nanos(self.elapsed));
+            cost::record_decode(self.trace.len as usize, self.elapsed);
         }
+        res
     }
 }
 
-/// A wrapper around a manually malloc/free'd buffer for holding an Intel PT trace. We've split
-/// this out from PerfPTTrace so that we can mark just this raw pointer as `unsafe Send`.
-#[repr(C)]
-#[derive(Debug)]
-struct PerfPTTraceBuf(*mut u8);
+// Wraps the block iterator of a PerfPTTrace, keeping the blocks as they are decoded, and caching
+// them in the trace if the iterator is consumed in full. See `PerfPTConfig::cache_blocks`.
+struct PerfPTCachingIterator<'t> {
+    blocks: Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 't>,
+    trace: &'t PerfPTTrace,
+    // The blocks decoded so far, or `None` if an error means that they can't be cached.
+    pending: Option<Vec<Block>>,
+    // Whether an overflow error has been yielded. This must be the last item to cache the blocks.
+    overflow_seen: bool,
+}
 
-/// We need to be able to transfer `PerfPTTraceBuf`s between threads to allow background
-/// compilation. However, `PerfPTTraceBuf` wraps a raw pointer, which is not `Send`, so nor is
-/// `PerfPTTraceBuf`. As long as we take great care to never: a) give out copies of the pointer to
-/// the wider world, or b) dereference the pointer when we shouldn't, then we can manually (and
-/// unsafely) mark the struct as being Send.
-unsafe impl Send for PerfPTTrace {}
+impl<'t> Iterator for PerfPTCachingIterator<'t> {
+    type Item = Result<Block, HWTracerError>;
 
-/// An Intel PT trace, obtained via Linux perf.
-#[repr(C)]
-#[derive(Debug)]
-pub struct PerfPTTrace {
-    // The trace buffer.
-    buf: PerfPTTraceBuf,
-    // The length of the trace (in bytes).
-    len: u64,
-    // `buf`'s allocation size (in bytes), <= `len`.
-    capacity: u64,
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.blocks.next();
+        match res {
+            Some(Ok(b)) if !self.overflow_seen => {
+                if let Some(ref mut pending) = self.pending {
+                    pending.push(b);
+                }
+            }
+            Some(Err(HWTracerError::HWBufferOverflow)) if !self.overflow_seen => {
+                self.overflow_seen = true
+            }
+            Some(_) => self.pending = None,
+            None => {
+                // A cached trace replays an overflow error only if the trace overflowed.
+                if self.overflow_seen == self.trace.overflowed {
+                    if let Some(pending) = self.pending.take() {
+                        self.trace.cached_blocks.fill(pending);
+                    }
+                }
+            }
+        }
+        res
+    }
 }
 
-impl PerfPTTrace {
-    /// Makes a new trace, initially allocating the specified number of bytes for the PT trace
-    /// packet buffer.
-    ///
-    /// The allocation is automatically freed by Rust when the struct falls out of scope.
-    fn new(capacity: size_t) -> Result<Self, HWTracerError> {
-        let buf = unsafe { malloc(capacity) as *mut u8 };
-        if buf.is_null() {
-            return Err(HWTracerError::Unknown);
+// Replays the cached blocks of a PerfPTTrace. See `PerfPTConfig::cache_blocks`.
+struct PerfPTCachedBlockIterator<'t> {
+    blocks: slice::Iter<'t, Block>,
+    // Whether an overflow error is still to be yielded after the blocks.
+    overflowed: bool,
+}
+
+impl<'t> Iterator for PerfPTCachedBlockIterator<'t> {
+    type Item = Result<Block, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.blocks.next() {
+            Some(b) => Some(Ok(*b)),
+            // Like the decoder, end an overflowed trace with an overflow error.
+            None if self.overflowed => {
+                self.overflowed = false;
+                Some(Err(HWTracerError::HWBufferOverflow))
+            }
+            None => None,
         }
-        Ok(Self {
-            buf: PerfPTTraceBuf(buf),
-            len: 0,
-            capacity: capacity as u64,
-        })
+    }
+
+    // The number of items is known exactly, so that collecting them needn't reallocate.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.blocks.len() + self.overflowed as usize;
+        (len, Some(len))
     }
 }
 
-impl Trace for PerfPTTrace {
-    /// Write the raw trace packets into the specified file.
-    #[cfg(test)]
-    fn to_file(&self, file: &mut File) {
-        use std::io::prelude::*;
-        use std::slice;
+// Iterate over the blocks of a PerfPTTrace, with the time at which each was executed.
+struct PerfPTTimedBlockIterator<'t> {
+    blocks: PerfPTBlockIterator<'t>,
+}
 
-        let slice = unsafe { slice::from_raw_parts(self.buf.0 as *const u8, self.len as usize) };
-        file.write_all(slice).unwrap();
-    }
+impl<'t> Iterator for PerfPTTimedBlockIterator<'t> {
+    type Item = Result<TimedBlock, HWTracerError>;
 
-    fn iter_blocks<'t: 'i, 'i>(
-        &'t self,
-    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
-        let itr = PerfPTBlockIterator {
-            decoder: ptr::null_mut(),
-            decoder_status: 0,
-            vdso_tempfile: None,
-            trace: self,
-            errored: false,
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = match self.blocks.next()? {
+            Ok(b) => b,
+            Err(e) => return Some(Err(e)),
         };
-        Box::new(itr)
+        // The decoder must be initialised now that it has produced a block.
+        let mut tsc = 0;
+        let tsc = match unsafe { perf_pt_block_time(self.blocks.decoder, &mut tsc) } {
+            true => Some(tsc),
+            false => None,
+        };
+        Some(Ok(TimedBlock::new(block, tsc)))
     }
+}
 
-    #[cfg(test)]
-    fn capacity(&self) -> usize {
-        self.capacity as usize
-    }
+// Iterate over the blocks of a PerfPTTrace, with the kind of control flow transfer ending each.
+//
+// libipt's block decoder doesn't say which packets gave a block's start address, so the TIP and
+// TIP.PGE packets of the trace are found separately, and matched against the part of the packet
+// stream consumed while decoding each block. The decoder only consumes a TIP packet when it needs
+// the target of a branch, but it may consume other packets (e.g. those of asynchronous events)
+// along the way. So a TIP packet consumed while decoding a block is only taken to give the target
+// of its last instruction if its IP is the start address of the next block, which is therefore
+// decoded ahead of time. Likewise, a TIP.PGE packet must give the start address of the block it
+// is taken to have started.
+struct PerfPTClassifiedBlockIterator<'t> {
+    blocks: PerfPTBlockIterator<'t>,
+    // The TIP and TIP.PGE packets which the decoder hasn't yet consumed. Found when the first
+    // block is decoded.
+    tips: Option<VecDeque<PerfPTTip>>,
+    // The next block, once it has been decoded ahead of the current one. `Some(None)` means that
+    // there are no more blocks.
+    lookahead: Option<Option<PerfPTDecodedBlock>>,
+    // The IP compression of the start address of the next block, if it came from a TIP packet.
+    next_ipc: Option<IpCompression>,
+    // Set if the TIP packets couldn't be found, which ends the iterator.
+    failed: bool,
 }
 
-impl Drop for PerfPTTrace {
-    fn drop(&mut self) {
-        if !self.buf.0.is_null() {
-            unsafe { free(self.buf.0 as *mut c_void) };
-        }
-    }
+// A TIP or TIP.PGE packet whose IP isn't suppressed. See `perf_pt_tip_packets()`.
+struct PerfPTTip {
+    offset: u64,
+    // Whether it is a TIP.PGE packet.
+    enable: bool,
+    ipc: PerfPTIpCompression,
+    // The full IP given by the packet.
+    ip: u64,
 }
 
-#[derive(Debug)]
-pub struct PerfPTTracer {
-    config: PerfPTConfig,
+// A block decoded by a `PerfPTClassifiedBlockIterator`, along with the kind of control flow
+// transfer ending it, and the offsets `start..end` of the packets consumed while decoding it.
+struct PerfPTDecodedBlock {
+    block: Result<Block, HWTracerError>,
+    edge_kind: EdgeKind,
+    start: u64,
+    end: u64,
 }
 
-impl PerfPTTracer {
-    pub(super) fn new(config: PerfPTConfig) -> Result<Self, HWTracerError>
-    where
-        Self: Sized,
-    {
-        // Check for inavlid configuration.
-        fn power_of_2(v: size_t) -> bool {
-            (v & (v - 1)) == 0
-        }
-        if !power_of_2(config.data_bufsize) {
-            return Err(HWTracerError::BadConfig(String::from(
-                "data_bufsize must be a positive power of 2",
-            )));
+impl<'t> PerfPTClassifiedBlockIterator<'t> {
+    fn new(blocks: PerfPTBlockIterator<'t>) -> Self {
+        Self {
+            blocks,
+            tips: None,
+            lookahead: None,
+            next_ipc: None,
+            failed: false,
         }
-        if !power_of_2(config.aux_bufsize) {
-            return Err(HWTracerError::BadConfig(String::from(
-                "aux_bufsize must be a positive power of 2",
-            )));
+    }
+
+    // Finds the TIP and TIP.PGE packets of the trace.
+    fn find_tips(&self) -> Result<VecDeque<PerfPTTip>, HWTracerError> {
+        let data = trace_data(self.blocks.trace)?;
+        let mut tips = VecDeque::new();
+        let mut cerr = PerfPTCError::new();
+        if !unsafe {
+            perf_pt_tip_packets(
+                data.as_ptr() as *const c_void,
+                data.len() as u64,
+                &mut tips as *mut _ as *mut c_void,
+                &mut cerr,
+            )
+        } {
+            return Err(cerr.into());
         }
+        Ok(tips)
+    }
 
-        Self::check_perf_perms()?;
-        Ok(Self { config })
+    // Decodes the next block.
+    fn decode_block(&mut self) -> Option<PerfPTDecodedBlock> {
+        let start = self.blocks.offset();
+        let block = self.blocks.next()?;
+        Some(PerfPTDecodedBlock {
+            block,
+            edge_kind: self.blocks.edge.into(),
+            start,
+            end: self.blocks.offset(),
+        })
     }
+}
 
-    fn check_perf_perms() -> Result<(), HWTracerError> {
-        if unsafe { geteuid() } == 0 {
-            // Root can always trace.
-            return Ok(());
+// Called by C to store a TIP or TIP.PGE packet.
+#[no_mangle]
+extern "C" fn perf_pt_push_tip(
+    tips: &mut VecDeque<PerfPTTip>,
+    offset: u64,
+    enable: bool,
+    ipc: PerfPTIpCompression,
+    ip: u64,
+) {
+    tips.push_back(PerfPTTip {
+        offset,
+        enable,
+        ipc,
+        ip,
+    });
+}
+
+impl<'t> Iterator for PerfPTClassifiedBlockIterator<'t> {
+    type Item = Result<ClassifiedBlock, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.tips.is_none() {
+            match self.find_tips() {
+                Ok(tips) => self.tips = Some(tips),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
         }
 
-        let mut f = File::open(&PERF_PERMS_PATH)?;
-        let mut buf = String::new();
-        f.read_to_string(&mut buf)?;
-        let perm = buf.trim().parse::<i8>()?;
-        if perm != -1 {
-            let msg = format!(
-                "Tracing not permitted: you must be root or {} must contain -1",
-                PERF_PERMS_PATH
-            );
-            return Err(HWTracerError::Permissions(msg));
+        let decoded = match self.lookahead.take() {
+            Some(decoded) => decoded,
+            None => self.decode_block(),
+        }?;
+        let block = match decoded.block {
+            Ok(b) => b,
+            Err(e) => {
+                // Whatever follows an error wasn't reached from the last block.
+                self.next_ipc = None;
+                return Some(Err(e));
+            }
+        };
+        let next = self.decode_block();
+        // If the next block is unknown (e.g. at the end of the trace), a TIP packet consumed while
+        // decoding this block can't be checked.
+        let next_start = match next {
+            Some(PerfPTDecodedBlock { block: Ok(b), .. }) => Some(b.first_instr()),
+            _ => None,
+        };
+        self.lookahead = Some(next);
+
+        let mut ipc = self.next_ipc.take();
+        let mut transfer = None;
+        let mut next_enable = None;
+        let tips = self.tips.as_mut().unwrap();
+        while let Some(tip) = tips.front() {
+            if tip.offset >= decoded.end {
+                break;
+            }
+            let tip = tips.pop_front().unwrap();
+            // TIP packets before `start` were skipped by the decoder (e.g. when resynchronising).
+            if tip.offset < decoded.start {
+                continue;
+            }
+            if tip.enable {
+                if ipc.is_none() && tip.ip == block.first_instr() {
+                    ipc = tip.ipc.into();
+                } else if next_enable.is_none() && Some(tip.ip) == next_start {
+                    next_enable = Some(tip.ipc);
+                }
+            } else if transfer.is_none()
+                // The target of a conditional jump is never given by a TIP packet.
+                && decoded.edge_kind != EdgeKind::ConditionalJump
+                && next_start.map_or(true, |s| s == tip.ip)
+            {
+                transfer = Some(tip.ipc);
+            }
         }
+        self.next_ipc = transfer.or(next_enable).and_then(Option::from);
 
-        Ok(())
+        let mut block = ClassifiedBlock::new(block, decoded.edge_kind);
+        if let Some(ipc) = ipc {
+            block = block.with_ip_compression(ipc);
+        }
+        // Without a TIP packet, a return's target came from the decoder's call stack.
+        if decoded.edge_kind == EdgeKind::Return {
+            block = block.with_return_kind(match transfer {
+                Some(_) => ReturnKind::Observed,
+                None => ReturnKind::Compressed,
+            });
+        }
+        Some(Ok(block))
     }
 }
 
-impl Tracer for PerfPTTracer {
-    fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
-        Box::new(PerfPTThreadTracer::new(self.config.clone()))
-    }
+// Iterate over the instruction addresses of a PerfPTTrace.
+struct PerfPTInstrIterator<'t> {
+    decoder: *mut c_void,  // C-level libipt instruction decoder.
+    decoder_status: c_int, // Stores the current libipt-level status of the above decoder.
+    #[allow(dead_code)] // Rust doesn't know that this exists only to keep the file long enough.
+    vdso_tempfile: Option<NamedTempFile>, // VDSO code stored temporarily.
+    trace: &'t dyn Trace,  // The trace we are iterating.
+    errored: bool,         // Set to true when an error occurs, thus invalidating the iterator.
 }
 
-/// A tracer that uses the Linux Perf interface to Intel Processor Trace.
-pub struct PerfPTThreadTracer {
-    // The configuration for this tracer.
-    config: PerfPTConfig,
-    // Opaque C pointer representing the tracer context.
-    tracer_ctx: *mut c_void,
-    // The state of the tracer.
-    state: TracerState,
-    // The trace currently being collected, or `None`.
-    trace: Option<Box<PerfPTTrace>>,
+impl<'t> Drop for PerfPTInstrIterator<'t> {
+    fn drop(&mut self) {
+        unsafe { perf_pt_free_insn_decoder(self.decoder) };
+    }
 }
 
-impl PerfPTThreadTracer {
-    fn new(config: PerfPTConfig) -> Self {
-        Self {
-            config,
-            tracer_ctx: ptr::null_mut(),
-            state: TracerState::Stopped,
-            trace: None,
+impl<'t> Iterator for PerfPTInstrIterator<'t> {
+    type Item = Result<u64, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // There was an error in a previous iteration.
+        if self.errored {
+            return None;
+        }
+
+        // Lazily initialise the instruction decoder.
+        if self.decoder.is_null() {
+            match init_decoder(
+                perf_pt_init_insn_decoder,
+                self.trace,
+                &mut self.decoder_status,
+            ) {
+                Ok((decoder, vdso_tempfile)) => {
+                    self.decoder = decoder;
+                    self.vdso_tempfile = Some(vdso_tempfile);
+                }
+                Err(e) => {
+                    self.errored = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let mut addr = 0;
+        let mut cerr = PerfPTCError::new();
+        let rv = unsafe {
+            perf_pt_next_insn(self.decoder, &mut self.decoder_status, &mut addr, &mut cerr)
+        };
+        if !rv {
+            self.errored = true; // This iterator is unusable now.
+            return Some(Err(HWTracerError::from(cerr)));
+        }
+        if addr == 0 {
+            // End of packet stream. As for blocks, an overflowed trace ends in an error.
+            if self.trace.overflowed() {
+                self.errored = true;
+                return Some(Err(HWTracerError::HWBufferOverflow));
+            }
+            None
+        } else {
+            Some(Ok(addr))
         }
     }
 }
 
-impl Default for PerfPTThreadTracer {
-    fn default() -> Self {
-        PerfPTThreadTracer::new(PerfPTConfig::default())
-    }
+// Iterate over the packets of a PerfPTTrace.
+struct PerfPTPacketIterator<'t> {
+    decoder: *mut c_void, // C-level libipt packet decoder.
+    trace: &'t dyn Trace, // The trace we are iterating.
+    finished: bool,       // Set to true at the end of the packet stream, or when an error occurs.
 }
 
-impl ThreadTracer for PerfPTThreadTracer {
-    fn start_tracing(&mut self) -> Result<(), HWTracerError> {
-        if self.state == TracerState::Started {
-            return Err(TracerState::Started.as_error());
-        }
+impl<'t> Drop for PerfPTPacketIterator<'t> {
+    fn drop(&mut self) {
+        unsafe { perf_pt_free_packet_decoder(self.decoder) };
+    }
+}
 
-        // At the time of writing, we have to use a fresh Perf file descriptor to ensure traces
-        // start with a `PSB+` packet sequence. This is required for correct instruction-level and
-        // block-level decoding. Therefore we have to re-initialise for each new tracing session.
+impl<'t> PerfPTPacketIterator<'t> {
+    // Initialises the packet decoder, returning `false` if there are no packets to decode.
+    fn init_decoder(&mut self) -> Result<bool, HWTracerError> {
+        let data = trace_data(self.trace)?;
+        let mut eos = false;
         let mut cerr = PerfPTCError::new();
-        self.tracer_ctx =
-            unsafe { perf_pt_init_tracer(&self.config as *const PerfPTConfig, &mut cerr) };
-        if self.tracer_ctx.is_null() {
+        self.decoder = unsafe {
+            perf_pt_init_packet_decoder(
+                data.as_ptr() as *const c_void,
+                data.len() as u64,
+                &mut eos,
+                &mut cerr,
+            )
+        };
+        if self.decoder.is_null() {
             return Err(cerr.into());
         }
+        Ok(!eos)
+    }
 
-        // It is essential we box the trace now to stop it from moving. If it were to move, then
-        // the reference which we pass to C here would become invalid. The interface to
-        // `stop_tracing` needs to return a Box<Tracer> anyway, so it's no big deal.
-        //
-        // Note that the C code will mutate the trace's members directly.
-        let mut trace = Box::new(PerfPTTrace::new(self.config.initial_trace_bufsize)?);
+    // Returns the next packet, or `None` at the end of the packet stream.
+    fn next_packet(&mut self) -> Result<Option<PtPacket>, HWTracerError> {
+        if self.decoder.is_null() && !self.init_decoder()? {
+            return Ok(None);
+        }
+        let mut packet = PerfPTPacket {
+            kind: PerfPTPacketKind::Eos,
+            ipc: PerfPTIpCompression::Suppressed,
+            payload: 0,
+            count: 0,
+        };
         let mut cerr = PerfPTCError::new();
-        if !unsafe { perf_pt_start_tracer(self.tracer_ctx, &mut *trace, &mut cerr) } {
+        if !unsafe { perf_pt_next_packet(self.decoder, &mut packet, &mut cerr) } {
             return Err(cerr.into());
         }
-        self.state = TracerState::Started;
-        self.trace = Some(trace);
-        Ok(())
+        Ok(packet.to_packet())
     }
+}
 
-    fn stop_tracing(&mut self) -> Result<Box<dyn Trace>, HWTracerError> {
-        if self.state == TracerState::Stopped {
-            return Err(TracerState::Stopped.as_error());
+impl<'t> Iterator for PerfPTPacketIterator<'t> {
+    type Item = Result<PtPacket, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
         }
-        let mut cerr = PerfPTCError::new();
-        let rc = unsafe { perf_pt_stop_tracer(self.tracer_ctx, &mut cerr) };
-        self.state = TracerState::Stopped;
-        if !rc {
-            return Err(cerr.into());
+        match self.next_packet() {
+            Ok(Some(pkt)) => Some(Ok(pkt)),
+            Ok(None) => {
+                self.finished = true;
+                // As for blocks, an overflowed trace ends in an error.
+                if self.trace.overflowed() {
+                    return Some(Err(HWTracerError::HWBufferOverflow));
+                }
+                None
+            }
+            Err(e) => {
+                self.finished = true; // This iterator is unusable now.
+                Some(Err(e))
+            }
         }
+    }
+}
 
-        let mut cerr = PerfPTCError::new();
-        if !unsafe { perf_pt_free_tracer(self.tracer_ctx, &mut cerr) } {
-            return Err(cerr.into());
-        }
-        self.tracer_ctx = ptr::null_mut();
+// The vendor of a CPU. Must stay in sync with the C code.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+enum PerfPTCpuVendor {
+    Unknown,
+    Intel,
+}
 
-        let ret = self.trace.take().unwrap();
-        self.trace = None;
-        Ok(ret as Box<dyn Trace>)
+// The kind of CPU which collected a trace. Must stay in sync with the C code.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct PerfPTCpu {
+    vendor: PerfPTCpuVendor,
+    family: u16,
+    model: u8,
+    stepping: u8,
+}
+
+impl From<PerfPTCpu> for PtCpu {
+    fn from(cpu: PerfPTCpu) -> Self {
+        Self {
+            vendor: match cpu.vendor {
+                PerfPTCpuVendor::Unknown => PtCpuVendor::Unknown,
+                PerfPTCpuVendor::Intel => PtCpuVendor::Intel,
+            },
+            family: cpu.family,
+            model: cpu.model,
+            stepping: cpu.stepping,
+        }
     }
 }
 
-// Called by C to store a ptxed argument into a Rust Vec.
-#[cfg(test)]
-#[no_mangle]
-pub unsafe extern "C" fn push_ptxed_arg(args: &mut Vec<String>, new_arg: *const c_char) {
-    let new_arg = CStr::from_ptr(new_arg)
-        .to_owned()
-        .to_str()
-        .unwrap()
-        .to_owned();
-    args.push(new_arg);
+impl From<PtCpu> for PerfPTCpu {
+    fn from(cpu: PtCpu) -> Self {
+        Self {
+            vendor: match cpu.vendor {
+                PtCpuVendor::Unknown => PerfPTCpuVendor::Unknown,
+                PtCpuVendor::Intel => PerfPTCpuVendor::Intel,
+            },
+            family: cpu.family,
+            model: cpu.model,
+            stepping: cpu.stepping,
+        }
+    }
 }
 
-#[cfg(all(perf_pt_test, test))]
-mod tests {
-    use super::PerfPTCError;
-    use super::{
-        c_int, ptr, size_t, AsRawFd, HWTracerError, NamedTempFile, PerfPTBlockIterator,
-        PerfPTConfig, PerfPTThreadTracer, PerfPTTrace, ThreadTracer, Trace,
+/// Returns the kind of the current CPU, as recorded in the traces it collects (see
+/// `Trace::pt_cpu()`). If it can't be determined, then the vendor is `PtCpuVendor::Unknown`.
+pub fn detect_pt_cpu() -> PtCpu {
+    let mut cpu = PerfPTCpu {
+        vendor: PerfPTCpuVendor::Unknown,
+        family: 0,
+        model: 0,
+        stepping: 0,
     };
-    use crate::backends::{BackendConfig, TracerBuilder};
-    use crate::{test_helpers, Block};
-    use phdrs::{PF_X, PT_LOAD};
-    use std::convert::TryFrom;
-    use std::env;
-    use std::process::Command;
+    unsafe { perf_pt_detect_cpu(&mut cpu) };
+    cpu.into()
+}
+
+// Returns a pointer to `cpu` for passing to C, or NULL (meaning the current CPU) if it's `None`.
+fn cpu_ptr(cpu: &Option<PerfPTCpu>) -> *const PerfPTCpu {
+    cpu.as_ref().map_or(ptr::null(), |c| c as *const PerfPTCpu)
+}
+
+/// CPU errata for the decoder to work around. See `DecoderOptions::errata`.
+///
+/// Each field is named after the erratum it concerns, e.g. `bdm70` is erratum BDM70 of Intel's
+/// Broadwell processors. See libipt's `struct pt_errata` for their descriptions.
+// Must stay in sync with the C code.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Errata {
+    pub bdm70: bool,
+    pub bdm64: bool,
+    pub skd007: bool,
+    pub skd022: bool,
+    pub skd010: bool,
+    pub skl014: bool,
+    pub apl12: bool,
+    pub apl11: bool,
+}
+
+/// Options overriding how a `TraceDecoder` decodes traces, for traces whose CPU isn't (correctly)
+/// recorded, e.g. those collected on another machine and converted from another format.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DecoderOptions {
+    /// Decode traces for this CPU, rather than that which collected them (see
+    /// `Trace::pt_cpu()`).
+    pub cpu: Option<PtCpu>,
+    /// Work around exactly these errata, rather than those which libipt chooses for the CPU.
+    pub errata: Option<Errata>,
+}
+
+// Describes a file to load into a libipt image.
+// Must stay in sync with the C code.
+#[repr(C)]
+struct PerfPTImageFile {
+    filename: *const c_char,
+    vaddr: u64,
+    size: u64,
+    offset: u64,
+}
+
+// A file added to a `TraceDecoder`.
+#[derive(Debug)]
+struct ImageFile {
+    filename: CString,
+    vaddr: u64,
+    size: u64,
+    offset: u64,
+}
+
+/// Decodes Intel PT traces using code loaded from explicitly specified files, rather than the code
+/// of the current process.
+///
+/// This allows traces of other processes, or traces read back with `deserialize_trace()`, to be
+/// decoded. The traces must have been collected by the PerfPT backend. They are decoded for the
+/// CPU which collected them (see `Trace::pt_cpu()`), which needn't be of the same kind as that
+/// doing the decoding, unless overridden with `set_options()`.
+///
+/// ```no_run
+/// use hwtracer::backends::perf_pt::TraceDecoder;
+/// use std::path::Path;
+///
+/// let mut decoder = TraceDecoder::new();
+/// // Code from offset 0x1000 of `/usr/bin/prog`, 0x2000 bytes of which were loaded at 0x401000.
+/// decoder.add_image(Path::new("/usr/bin/prog"), 0x401000, 0x2000, 0x1000).unwrap();
+/// # let trace: Box<dyn hwtracer::Trace> = unimplemented!();
+/// for block in decoder.decode(&*trace) {
+///     println!("{:?}", block.unwrap());
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct TraceDecoder {
+    images: Vec<ImageFile>,
+    options: DecoderOptions,
+}
+
+impl TraceDecoder {
+    /// Create a decoder with no code loaded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `size` bytes of code, found at `file_offset` in the file `path`, which was loaded at the
+    /// virtual address `vaddr` in the traced process.
+    ///
+    /// The file is read lazily during decoding, so it must remain in place until decoding is
+    /// complete.
+    pub fn add_image(
+        &mut self,
+        path: &Path,
+        vaddr: u64,
+        size: u64,
+        file_offset: u64,
+    ) -> Result<(), HWTracerError> {
+        self.images.push(ImageFile {
+            filename: CString::new(path.as_os_str().as_bytes())?,
+            vaddr,
+            size,
+            offset: file_offset,
+        });
+        Ok(())
+    }
+
+    /// Override how traces are decoded. By default, each trace is decoded for the CPU which
+    /// collected it, working around that CPU's errata.
+    pub fn set_options(&mut self, options: DecoderOptions) {
+        self.options = options;
+    }
+
+    /// Iterate over the blocks of `trace`, recovering control flow from the images added to the
+    /// decoder.
+    pub fn decode<'t>(
+        &self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 't> {
+        let mut itr = PerfPTBlockIterator::new(trace);
+        match self.init_decoder(trace, &mut itr.decoder_status) {
+            Ok(decoder) => {
+                itr.decoder = decoder;
+                Box::new(itr)
+            }
+            Err(e) => Box::new(iter::once(Err(e))),
+        }
+    }
+
+    // Initialise a C-level libipt block decoder for `trace` using the images of this decoder.
+    fn init_decoder(
+        &self,
+        trace: &dyn Trace,
+        decoder_status: &mut c_int,
+    ) -> Result<*mut c_void, HWTracerError> {
+        let data = trace_data(trace)?;
+        // libipt copies the filenames, so these need only live until the decoder is initialised.
+        let files = self
+            .images
+            .iter()
+            .map(|img| PerfPTImageFile {
+                filename: img.filename.as_ptr(),
+                vaddr: img.vaddr,
+                size: img.size,
+                offset: img.offset,
+            })
+            .collect::<Vec<_>>();
+        let cpu = self
+            .options
+            .cpu
+            .or_else(|| trace.pt_cpu())
+            .map(PerfPTCpu::from);
+        let errata = self
+            .options
+            .errata
+            .as_ref()
+            .map_or(ptr::null(), |e| e as *const Errata);
+        let mut cerr = PerfPTCError::new();
+        let decoder = unsafe {
+            perf_pt_init_file_block_decoder(
+                data.as_ptr() as *const c_void,
+                data.len() as u64,
+                cpu_ptr(&cpu),
+                errata,
+                files.as_ptr(),
+                files.len(),
+                decoder_status,
+                &mut cerr,
+            )
+        };
+        if decoder.is_null() {
+            return Err(cerr.into());
+        }
+        Ok(decoder)
+    }
+}
+
+/// A cache of the code needed to decode Intel PT traces, for decoding many traces of the same code.
+///
+/// Decoding with `iter_blocks()` reloads the code of the current process for every trace, whereas
+/// a `DecoderCache` loads it once, up-front.
+///
+/// ```no_run
+/// use hwtracer::backends::perf_pt::DecoderCache;
+///
+/// let cache = DecoderCache::from_self_image().unwrap();
+/// # let traces: Vec<Box<dyn hwtracer::Trace>> = Vec::new();
+/// for trace in &traces {
+///     for block in cache.decode(&**trace) {
+///         println!("{:?}", block.unwrap());
+///     }
+/// }
+/// ```
+pub struct DecoderCache {
+    // Opaque C pointer to the cache.
+    cache: *mut c_void,
+    #[allow(dead_code)] // Rust doesn't know that this exists only to keep the file long enough.
+    vdso_tempfile: Option<NamedTempFile>, // VDSO code stored temporarily.
+    #[allow(dead_code)] // As for `vdso_tempfile`.
+    memory_tempfiles: Vec<NamedTempFile>, // Code added by `add_memory()`, stored temporarily.
+}
+
+impl DecoderCache {
+    // Makes an empty cache.
+    fn new() -> Result<Self, HWTracerError> {
+        let mut cerr = PerfPTCError::new();
+        let cache = unsafe { perf_pt_alloc_cache(&mut cerr) };
+        if cache.is_null() {
+            return Err(cerr.into());
+        }
+        Ok(Self {
+            cache,
+            vdso_tempfile: None,
+            memory_tempfiles: Vec::new(),
+        })
+    }
+
+    /// Make a cache of the code of the current process.
+    ///
+    /// The code of the executable and of every shared object loaded into the process is cached,
+    /// at the addresses it was loaded at. Code loaded into the process after the cache is made
+    /// (e.g. by `dlopen(3)`) isn't cached until `refresh_self_maps()` is called.
+    pub fn from_self_image() -> Result<Self, HWTracerError> {
+        let mut dc = Self::new()?;
+        dc.refresh_self_maps()?;
+        Ok(dc)
+    }
+
+    /// Add the code loaded into the current process since the cache was made (e.g. by
+    /// `dlopen(3)`) to the cache.
+    ///
+    /// Code which is already cached isn't loaded again. Code which has since been unloaded stays
+    /// in the cache, but code loaded at the same addresses since takes precedence over it.
+    pub fn refresh_self_maps(&mut self) -> Result<(), HWTracerError> {
+        // As for `iter_blocks()`, libipt needs the VDSO code in a file, which is read lazily. The
+        // VDSO never changes, so it only needs to be dumped once.
+        let vdso_tempfile = match self.vdso_tempfile {
+            Some(_) => None,
+            None => Some(NamedTempFile::new()?),
+        };
+        let (vdso_fd, vdso_filename) = match vdso_tempfile {
+            // File name of a NamedTempFile should always be valid UTF-8, unwrap() can't fail.
+            Some(ref f) => (f.as_raw_fd(), CString::new(f.path().to_str().unwrap())?),
+            None => (-1, CString::default()),
+        };
+        let mut cerr = PerfPTCError::new();
+        if !unsafe {
+            perf_pt_cache_add_self(self.cache, vdso_fd, vdso_filename.as_ptr(), &mut cerr)
+        } {
+            return Err(cerr.into());
+        }
+        if vdso_tempfile.is_some() {
+            self.vdso_tempfile = vdso_tempfile;
+        }
+        Ok(())
+    }
+
+    /// Make a cache of the executable code of the ELF file `path`, which was loaded `base` bytes
+    /// from its link-time addresses. `base` is 0 for executables which are not position
+    /// independent.
+    ///
+    /// The file is read lazily during decoding, so it must remain in place until decoding is
+    /// complete.
+    pub fn from_elf(path: &Path, base: u64) -> Result<Self, HWTracerError> {
+        let dc = Self::new()?;
+        let filename = CString::new(path.as_os_str().as_bytes())?;
+        let mut cerr = PerfPTCError::new();
+        if !unsafe { perf_pt_cache_add_elf(dc.cache, filename.as_ptr(), base, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(dc)
+    }
+
+    /// Make a cache of the executable code held in the core dump `path`, at the addresses it was
+    /// dumped from. This allows decoding traces of code which exists only in the dump (e.g. code
+    /// generated at runtime).
+    ///
+    /// Only code whose contents are in the dump is cached. By default, Linux doesn't dump the
+    /// contents of file-backed mappings, such as the code of the executable and its shared
+    /// objects (see `coredump_filter` in `core(5)`).
+    ///
+    /// The file is read lazily during decoding, so it must remain in place until decoding is
+    /// complete.
+    pub fn from_core_dump(path: &Path) -> Result<Self, HWTracerError> {
+        let dc = Self::new()?;
+        let filename = CString::new(path.as_os_str().as_bytes())?;
+        let mut cerr = PerfPTCError::new();
+        if !unsafe { perf_pt_cache_add_core(dc.cache, filename.as_ptr(), &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(dc)
+    }
+
+    /// Add a copy of `code`, which was executed at the virtual address `vaddr`, to the cache. This
+    /// allows decoding traces of code which isn't in any file, e.g. code generated by a JIT
+    /// compiler.
+    ///
+    /// The code is copied when it is added, so code which is later modified or regenerated at the
+    /// same addresses must be added again. As for `refresh_self_maps()`, code added later takes
+    /// precedence over code already cached at the same addresses.
+    pub fn add_memory(&mut self, vaddr: u64, code: &[u8]) -> Result<(), HWTracerError> {
+        // libipt only reads code from files, so the code is stored in a temporary file, which is
+        // read lazily and must live as long as the cache.
+        let mut file = NamedTempFile::new()?;
+        file.write_all(code)?;
+        let filename = CString::new(file.path().as_os_str().as_bytes())?;
+        let mut cerr = PerfPTCError::new();
+        if !unsafe {
+            perf_pt_cache_add_file(
+                self.cache,
+                filename.as_ptr(),
+                code.len() as u64,
+                vaddr,
+                &mut cerr,
+            )
+        } {
+            return Err(cerr.into());
+        }
+        self.memory_tempfiles.push(file);
+        Ok(())
+    }
+
+    /// Iterate over the blocks of `trace`, recovering control flow from the cached code.
+    pub fn decode<'t>(
+        &'t self,
+        trace: &'t dyn Trace,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 't> {
+        let mut blocks = PerfPTBlockIterator::new(trace);
+        let data = match trace_data(trace) {
+            Ok(d) => d,
+            Err(e) => return Box::new(iter::once(Err(e))),
+        };
+        let cpu = trace.pt_cpu().map(PerfPTCpu::from);
+        let mut cerr = PerfPTCError::new();
+        blocks.decoder = unsafe {
+            perf_pt_init_cached_block_decoder(
+                data.as_ptr() as *const c_void,
+                data.len() as u64,
+                cpu_ptr(&cpu),
+                self.cache,
+                &mut blocks.decoder_status,
+                &mut cerr,
+            )
+        };
+        if blocks.decoder.is_null() {
+            return Box::new(iter::once(Err(cerr.into())));
+        }
+        // The decoder refers to the cache, so the iterator must not outlive it.
+        Box::new(blocks)
+    }
+}
+
+impl Drop for DecoderCache {
+    fn drop(&mut self) {
+        unsafe { perf_pt_free_cache(self.cache) };
+    }
+}
+
+/// A wrapper around a manually malloc/free'd buffer for holding an Intel PT trace. We've split
+/// this out from PerfPTTrace so that we can mark just this raw pointer as `unsafe Send`.
+#[repr(C)]
+#[derive(Debug)]
+struct PerfPTTraceBuf(*mut u8);
+
+/// We need to be able to transfer `PerfPTTraceBuf`s between threads to allow background
+/// compilation. However, `PerfPTTraceBuf` wraps a raw pointer, which is not `Send`, so nor is
+/// `PerfPTTraceBuf`. As long as we take great care to never: a) give out copies of the pointer to
+/// the wider world, or b) dereference the pointer when we shouldn't, then we can manually (and
+/// unsafely) mark the struct as being Send.
+unsafe impl Send for PerfPTTrace {}
+
+/// The blocks of a `PerfPTTrace`, once they have been decoded in full. See
+/// `PerfPTConfig::cache_blocks`.
+///
+/// To keep `PerfPTTrace` FFI-safe, this holds an opaque pointer to a boxed `Vec<Block>`, or null
+/// if there are no blocks yet. Once set, the pointer doesn't change until the cache is dropped, so
+/// references to the blocks remain valid for as long as the cache.
+#[repr(transparent)]
+struct PerfPTBlockCache(Cell<*mut c_void>);
+
+impl PerfPTBlockCache {
+    fn new() -> Self {
+        Self(Cell::new(ptr::null_mut()))
+    }
+
+    // Returns the cached blocks, or `None` if there are none.
+    fn get(&self) -> Option<&[Block]> {
+        let blocks = self.0.get() as *const Vec<Block>;
+        match blocks.is_null() {
+            true => None,
+            false => Some(unsafe { &*blocks }.as_slice()),
+        }
+    }
+
+    // Caches `blocks`, unless blocks have already been cached.
+    fn fill(&self, blocks: Vec<Block>) {
+        if self.0.get().is_null() {
+            self.0.set(Box::into_raw(Box::new(blocks)) as *mut c_void);
+        }
+    }
+}
+
+impl Drop for PerfPTBlockCache {
+    fn drop(&mut self) {
+        let blocks = self.0.get() as *mut Vec<Block>;
+        if !blocks.is_null() {
+            drop(unsafe { Box::from_raw(blocks) });
+        }
+    }
+}
+
+impl fmt::Debug for PerfPTBlockCache {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.get() {
+            Some(blocks) => write!(f, "PerfPTBlockCache({} blocks)", blocks.len()),
+            None => write!(f, "PerfPTBlockCache(empty)"),
+        }
+    }
+}
+
+/// The time at which the kernel reported that the trace data up to `offset` had been written.
+// Must stay in sync with the C code.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct PerfPTAuxTime {
+    offset: u64,
+    time: u64,
+}
+
+/// The `PerfPTAuxTime`s of a trace, in an array allocated by the C code and freed by Rust.
+// Must stay in sync with the C code.
+#[repr(C)]
+#[derive(Debug)]
+struct PerfPTAuxTimes {
+    p: *mut PerfPTAuxTime,
+    len: size_t,
+    capacity: size_t,
+}
+
+impl PerfPTAuxTimes {
+    fn new() -> Self {
+        Self {
+            p: ptr::null_mut(),
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[PerfPTAuxTime] {
+        if self.p.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.p, self.len) }
+        }
+    }
+}
+
+impl Drop for PerfPTAuxTimes {
+    fn drop(&mut self) {
+        unsafe { free(self.p as *mut c_void) };
+    }
+}
+
+/// An Intel PT trace, obtained via Linux perf.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerfPTTrace {
+    // The trace buffer.
+    buf: PerfPTTraceBuf,
+    // The length of the trace (in bytes).
+    len: u64,
+    // `buf`'s allocation size (in bytes), <= `len`.
+    capacity: u64,
+    // Set by the C code if the AUX buffer overflowed during collection.
+    overflowed: bool,
+    // If not -1, the file which the trace is collected into. Once collection has finished, `buf`
+    // is a read-only mapping of the file (see `map_file()`).
+    fd: c_int,
+    // Set by the C code if collection stopped because the trace reached `PerfPTConfig::max_bytes`.
+    auto_stopped: bool,
+    // The position in the AUX data stream up to which data has been copied into the trace.
+    aux_pos: u64,
+    // The times at which the trace data was written. See `Trace::iter_aux_timestamps()`.
+    aux_times: PerfPTAuxTimes,
+    // The size of the AUX buffer (in bytes), or 0 if unknown.
+    aux_size: u64,
+    // The most data (in bytes) waiting at once in the AUX buffer. See `Trace::bytes_used()`.
+    aux_peak: u64,
+    // The remaining fields are only used by Rust, so they aren't part of the C struct. Since a
+    // pointer to the struct is passed to C, durations are stored as FFI-safe nanosecond counts
+    // (see `duration_nanos()`).
+    //
+    // How long the tracer was running for, if known.
+    collection: Option<NonZeroU64>,
+    // How long the blocks took to decode the first time they were iterated over, if known.
+    decoding: Cell<Option<NonZeroU64>>,
+    // Whether the trace was collected with `PerfPTConfig::resync_on_error` set.
+    resync_on_error: bool,
+    // Whether the trace was collected with `PerfPTConfig::cache_blocks` set.
+    cache_blocks: bool,
+    // Whether the trace was collected with `PerfPTConfig::duty_cycle_us` set.
+    duty_cycled: bool,
+    // The blocks of the trace, once they have been decoded in full, if `cache_blocks` is set.
+    cached_blocks: PerfPTBlockCache,
+    // The kind of CPU which collected the trace.
+    cpu: PerfPTCpu,
+    // The OS-level ID of the traced thread, or 0 if unknown. See `Trace::write_perf_data()`.
+    tid: pid_t,
+    // The `perf_event_attr.config` with which the trace was collected, as far as timing packets
+    // and return compression are concerned. See `perf_data::attr_config()`.
+    perf_config: u64,
+}
+
+// Converts `d` to a nanosecond count for storing in a `PerfPTTrace`. Durations shorter than
+// the clock's resolution are rounded up to 1ns, since `None` means "unknown".
+fn duration_nanos(d: Duration) -> Option<NonZeroU64> {
+    NonZeroU64::new(cmp::max(
+        cmp::min(d.as_nanos(), u128::from(u64::MAX)) as u64,
+        1,
+    ))
+}
+
+impl PerfPTTrace {
+    /// Makes a new trace, initially allocating the specified number of bytes for the PT trace
+    /// packet buffer.
+    ///
+    /// The allocation is automatically freed by Rust when the struct falls out of scope.
+    fn new(capacity: size_t) -> Result<Self, HWTracerError> {
+        let buf = unsafe { malloc(capacity) as *mut u8 };
+        if buf.is_null() {
+            return Err(HWTracerError::Unknown);
+        }
+        Ok(Self {
+            buf: PerfPTTraceBuf(buf),
+            len: 0,
+            capacity: capacity as u64,
+            overflowed: false,
+            fd: -1,
+            auto_stopped: false,
+            aux_pos: 0,
+            aux_times: PerfPTAuxTimes::new(),
+            aux_size: 0,
+            aux_peak: 0,
+            collection: None,
+            decoding: Cell::new(None),
+            resync_on_error: false,
+            cache_blocks: false,
+            duty_cycled: false,
+            cached_blocks: PerfPTBlockCache::new(),
+            cpu: detect_pt_cpu().into(),
+            tid: 0,
+            perf_config: 0,
+        })
+    }
+
+    /// Makes a new trace which is collected into a new file at `path`, replacing any existing one.
+    ///
+    /// Once collection has finished, `map_file()` must be called before the trace is decoded.
+    fn with_file(path: &Path) -> Result<Self, HWTracerError> {
+        // An existing file may be mapped by an earlier trace, which would fault if the file were
+        // truncated under it. So the file is unlinked instead, leaving the earlier trace its
+        // contents. Another tracer may create the file in between, so retry until ours wins.
+        let file = loop {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                res => break res?,
+            }
+        };
+        Ok(Self {
+            buf: PerfPTTraceBuf(ptr::null_mut()),
+            len: 0,
+            capacity: 0,
+            overflowed: false,
+            fd: file.into_raw_fd(),
+            auto_stopped: false,
+            aux_pos: 0,
+            aux_times: PerfPTAuxTimes::new(),
+            aux_size: 0,
+            aux_peak: 0,
+            collection: None,
+            decoding: Cell::new(None),
+            resync_on_error: false,
+            cache_blocks: false,
+            duty_cycled: false,
+            cached_blocks: PerfPTBlockCache::new(),
+            cpu: detect_pt_cpu().into(),
+            tid: 0,
+            perf_config: 0,
+        })
+    }
+
+    // Makes an iterator over the blocks of the trace, resuming after errors if the trace was
+    // collected with `PerfPTConfig::resync_on_error` set.
+    fn blocks(&self) -> PerfPTBlockIterator<'_> {
+        let mut blocks = PerfPTBlockIterator::new(self);
+        blocks.resync_on_error = self.resync_on_error;
+        blocks
+    }
+
+    // Makes a new trace for collecting into `sink`.
+    fn for_sink(sink: &TraceSink, capacity: size_t) -> Result<Self, HWTracerError> {
+        match sink {
+            TraceSink::Memory => Self::new(capacity),
+            TraceSink::File(path) => Self::with_file(path),
+        }
+    }
+
+    // The size of the mapping of a file-backed trace. Empty files are mapped too, so that `buf`
+    // is never NULL: mmap(2) doesn't allow zero-length mappings.
+    fn map_len(&self) -> size_t {
+        cmp::max(self.len as size_t, 1)
+    }
+
+    // Maps the file which a trace was collected into, so that it can be decoded. The kernel
+    // reads in pages of the file as they are decoded, so the whole trace needn't fit in memory.
+    fn map_file(&mut self) -> Result<(), HWTracerError> {
+        debug_assert!(self.fd != -1 && self.buf.0.is_null());
+        let buf = unsafe {
+            mmap(
+                ptr::null_mut(),
+                self.map_len(),
+                PROT_READ,
+                MAP_SHARED,
+                self.fd,
+                0,
+            )
+        };
+        if buf == MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.buf = PerfPTTraceBuf(buf as *mut u8);
+        self.capacity = self.len;
+        Ok(())
+    }
+
+    // Makes a new trace holding a copy of the raw Intel PT packets `data`.
+    fn from_bytes(data: &[u8], overflowed: bool) -> Result<Self, HWTracerError> {
+        // malloc(3) may return NULL for a zero-sized allocation, so allocate at least one byte.
+        let mut trace = Self::new(cmp::max(data.len(), 1))?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), trace.buf.0, data.len()) };
+        trace.len = data.len() as u64;
+        trace.overflowed = overflowed;
+        Ok(trace)
+    }
+
+    // Returns the raw Intel PT packet data of the trace.
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.buf.0 as *const u8, self.len as usize) }
+    }
+
+    /// Read the backend-specific part of a serialized trace. See `Trace::serialize`.
+    pub(crate) fn deserialize(r: &mut dyn Read) -> Result<Self, HWTracerError> {
+        let flags = serialize::read_u8(r)?;
+        let len = serialize::read_u64(r)?;
+        // The length comes from the input, so the data is read incrementally rather than
+        // allocating however much a corrupt trace claims to hold.
+        let mut data = Vec::new();
+        r.take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut trace = Self::from_bytes(&data, flags & FLAG_OVERFLOWED != 0)?;
+        trace.auto_stopped = flags & FLAG_AUTO_STOPPED != 0;
+        trace.duty_cycled = flags & FLAG_DUTY_CYCLED != 0;
+        trace.resync_on_error = flags & FLAG_RESYNC_ON_ERROR != 0;
+        // Traces serialized before the CPU was recorded are assumed to be from the current CPU.
+        if flags & FLAG_CPU != 0 {
+            let mut cpu = [0; 5];
+            r.read_exact(&mut cpu)?;
+            trace.cpu = PerfPTCpu {
+                vendor: match cpu[0] {
+                    1 => PerfPTCpuVendor::Intel,
+                    _ => PerfPTCpuVendor::Unknown,
+                },
+                family: u16::from_le_bytes([cpu[1], cpu[2]]),
+                model: cpu[3],
+                stepping: cpu[4],
+            };
+        }
+        Ok(trace)
+    }
+}
+
+impl RawTrace for PerfPTTrace {
+    /// Returns the raw Intel PT packets, as would be fed to e.g. `ptxed`.
+    fn raw_data(&self) -> Option<&[u8]> {
+        Some(self.bytes())
+    }
+
+    /// Decoding starts at the first PSB packet. Each tracing session uses a fresh perf event, for
+    /// which the hardware emits a PSB packet as soon as it's enabled, so no control flow is
+    /// missing from the start of a trace returned by `stop_tracing()`: its first block is the
+    /// first executed in user space after tracing started. In snapshot mode (see
+    /// `PerfPTConfig::snapshot`), the start of the trace is lost once the AUX buffer wraps, and
+    /// the blocks before the first remaining PSB packet are missing.
+    fn sync_offset(&self) -> Option<usize> {
+        self.bytes().windows(PSB.len()).position(|w| w == PSB)
+    }
+
+    /// The CPU is that doing the collection, or for a deserialized trace, that recorded by
+    /// `serialize()`. Traces serialized before the CPU was recorded are assumed to be from the
+    /// current CPU.
+    fn pt_cpu(&self) -> Option<PtCpu> {
+        Some(self.cpu.into())
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// An overflowed trace filled the AUX buffer, so uses all of it.
+    fn bytes_used(&self) -> Option<usize> {
+        self.hw_buffer_size().map(|size| match self.overflowed {
+            true => size,
+            false => self.aux_peak as usize,
+        })
+    }
+
+    /// The size is that of the AUX buffer (see `PerfPTConfig::aux_bufsize`).
+    fn hw_buffer_size(&self) -> Option<usize> {
+        match self.aux_size {
+            0 => None,
+            size => Some(size as usize),
+        }
+    }
+
+    fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    fn auto_stopped(&self) -> bool {
+        self.auto_stopped
+    }
+
+    fn duty_cycled(&self) -> bool {
+        self.duty_cycled
+    }
+}
+
+impl Trace for PerfPTTrace {
+    /// Write the raw trace packets into the specified file.
+    #[cfg(test)]
+    fn to_file(&self, file: &mut File) {
+        file.write_all(self.bytes()).unwrap();
+    }
+
+    /// After the common header, a serialized perf_pt trace consists of:
+    ///
+    ///  - 1 byte: flags. Bit 0 is set if the trace overflowed, bit 1 if it was auto-stopped, bit 2
+    ///    if the CPU which collected the trace follows the packet data, bit 3 if the trace was
+    ///    duty cycled, and bit 4 if decoding resumes after errors.
+    ///  - 8 bytes: the length of the raw Intel PT packet data.
+    ///  - The raw Intel PT packet data itself.
+    ///  - 5 bytes, if bit 2 of the flags is set: the CPU which collected the trace, as its vendor
+    ///    (0 for unknown, 1 for Intel), 2 bytes of family, and 1 byte each of model and stepping.
+    fn serialize(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        serialize::write_header(w, BackendKind::PerfPT)?;
+        let mut flags = 0;
+        if self.overflowed {
+            flags |= FLAG_OVERFLOWED;
+        }
+        if self.auto_stopped {
+            flags |= FLAG_AUTO_STOPPED;
+        }
+        if self.duty_cycled {
+            flags |= FLAG_DUTY_CYCLED;
+        }
+        if self.resync_on_error {
+            flags |= FLAG_RESYNC_ON_ERROR;
+        }
+        flags |= FLAG_CPU;
+        w.write_all(&[flags])?;
+        w.write_all(&self.len.to_le_bytes())?;
+        w.write_all(self.bytes())?;
+        let vendor = match self.cpu.vendor {
+            PerfPTCpuVendor::Unknown => 0,
+            PerfPTCpuVendor::Intel => 1,
+        };
+        let family = self.cpu.family.to_le_bytes();
+        w.write_all(&[
+            vendor,
+            family[0],
+            family[1],
+            self.cpu.model,
+            self.cpu.stepping,
+        ])?;
+        Ok(())
+    }
+
+    /// The times are those of the kernel's `PERF_RECORD_AUX` records, which are made whenever
+    /// trace data is written to the AUX buffer. There are none in snapshot mode.
+    fn iter_aux_timestamps<'t: 'i, 'i>(&'t self) -> Box<dyn Iterator<Item = (usize, u64)> + 'i> {
+        Box::new(
+            self.aux_times
+                .as_slice()
+                .iter()
+                .map(|t| (t.offset as usize, t.time)),
+        )
+    }
+
+    /// The trace is assumed to be of the current process: `ptxed` is pointed at the executable
+    /// code of the objects currently loaded, so the files must be used before any of them are
+    /// unloaded or changed on disk.
+    fn write_ptxed_inputs(&self, dir: &Path) -> Result<PtxedInvocation, HWTracerError> {
+        let trace_path = dir.join(PTXED_TRACE_FILENAME);
+        fs::write(&trace_path, self.bytes())?;
+        // `ptxed` is told which CPU collected the trace, so that it decodes it as hwtracer does.
+        let cpu = match self.cpu.vendor {
+            PerfPTCpuVendor::Intel => format!(
+                "{}/{}/{}",
+                self.cpu.family, self.cpu.model, self.cpu.stepping
+            ),
+            PerfPTCpuVendor::Unknown => "auto".to_owned(),
+        };
+        let mut args = vec![
+            OsString::from("--cpu"),
+            OsString::from(cpu),
+            OsString::from("--pt"),
+            trace_path.into_os_string(),
+        ];
+        args.extend(self_ptxed_raw_args(dir)?);
+        Ok(PtxedInvocation {
+            ptxed: PathBuf::from(env!("PTXED")),
+            args,
+        })
+    }
+
+    /// As for `write_ptxed_inputs()`, the trace is assumed to be of the current process, and the
+    /// file must be used before any of its objects are unloaded. Deserialized traces are assumed
+    /// to be of the main thread, and to have no timing packets.
+    fn write_perf_data(&self, path: &Path) -> Result<(), HWTracerError> {
+        let mut file = io::BufWriter::new(File::create(path)?);
+        perf_data::write_perf_data(&mut file, self.bytes(), self.tid, self.perf_config)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// As for `write_ptxed_inputs()`, the trace is assumed to be of the current process.
+    #[cfg(feature = "ptxed_verify")]
+    fn verify_against_ptxed(&self) -> Result<Option<usize>, HWTracerError> {
+        let expected = ptxed_block_starts(self)?;
+        let mut got = self.iter_blocks();
+        for (i, vaddr) in expected.iter().enumerate() {
+            match got.next().transpose()? {
+                Some(b) if b.first_instr() == *vaddr => (),
+                _ => return Ok(Some(i)),
+            }
+        }
+        match got.next().transpose()? {
+            Some(_) => Ok(Some(expected.len())),
+            None => Ok(None),
+        }
+    }
+
+    fn iter_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        if let Some(blocks) = self.cached_blocks.get() {
+            return Box::new(PerfPTCachedBlockIterator {
+                blocks: blocks.iter(),
+                overflowed: self.overflowed,
+            });
+        }
+        let itr = self.blocks();
+        let itr: Box<dyn Iterator<Item = Result<Block, HWTracerError>>> =
+            if self.decoding.get().is_some() {
+                Box::new(itr)
+            } else {
+                Box::new(PerfPTTimingIterator {
+                    blocks: itr,
+                    trace: self,
+                    elapsed: Duration::default(),
+                    failed: false,
+                })
+            };
+        if !self.cache_blocks {
+            return itr;
+        }
+        Box::new(PerfPTCachingIterator {
+            blocks: itr,
+            trace: self,
+            pending: Some(Vec::new()),
+            overflow_seen: false,
+        })
+    }
+
+    /// The blocks of a decoded trace are walked in reverse where they are, rather than copied.
+    fn iter_blocks_rev<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        if let Some(blocks) = self.cached_blocks.get() {
+            // Like `iter_blocks()` in reverse, an overflowed trace starts with an overflow error.
+            let overflow = match self.overflowed {
+                true => Some(Err(HWTracerError::HWBufferOverflow)),
+                false => None,
+            };
+            return Box::new(
+                overflow
+                    .into_iter()
+                    .chain(blocks.iter().rev().map(|b| Ok(*b))),
+            );
+        }
+        Box::new(self.iter_blocks().collect::<Vec<_>>().into_iter().rev())
+    }
+
+    /// Only traces collected with `PerfPTConfig::cache_blocks` set are ever decoded, once their
+    /// block iterator has been consumed in full.
+    fn is_decoded(&self) -> bool {
+        self.cached_blocks.get().is_some()
+    }
+
+    fn timing(&self) -> TraceTiming {
+        TraceTiming {
+            collection: self.collection.map(|ns| Duration::from_nanos(ns.get())),
+            decoding: self.decoding.get().map(|ns| Duration::from_nanos(ns.get())),
+        }
+    }
+
+    /// Blocks are timed only if the trace was collected with `PerfPTConfig::tsc` set.
+    fn iter_timed_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<TimedBlock, HWTracerError>> + 'i> {
+        let blocks = self.blocks();
+        Box::new(PerfPTTimedBlockIterator { blocks })
+    }
+
+    fn iter_classified_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<ClassifiedBlock, HWTracerError>> + 'i> {
+        let blocks = self.blocks();
+        Box::new(PerfPTClassifiedBlockIterator::new(blocks))
+    }
+
+    /// Decoding is resumed at the next PSB packet after each error, so every error in the trace
+    /// is counted.
+    fn stats(&self) -> TraceStats {
+        let mut stats = TraceStats {
+            bytes: self.len as usize,
+            ..Default::default()
+        };
+        let mut blocks = PerfPTBlockIterator::new(self);
+        loop {
+            match blocks.next() {
+                Some(Ok(_)) => {
+                    stats.decoded_blocks += 1;
+                    continue;
+                }
+                Some(Err(HWTracerError::HWBufferOverflow)) => stats.overflow_events += 1,
+                Some(Err(HWTracerError::Overflow { .. })) => {
+                    // Decoding carries on after an overflow without resyncing.
+                    stats.overflow_events += 1;
+                    continue;
+                }
+                // No trace data is lost where tracing was disabled.
+                Some(Err(HWTracerError::TracingDisabled { .. })) => continue,
+                Some(Err(_)) => stats.decode_errors += 1,
+                None => break,
+            }
+            // An overflow at the end of the trace leaves nothing to resync to.
+            if blocks.resync().is_none() {
+                break;
+            }
+        }
+        stats.retired_instrs = blocks.instrs;
+        stats
+    }
+
+    /// Decoding directly into `out` avoids the dynamic dispatch of `iter_blocks()`.
+    fn decode_into(&self, out: &mut Vec<Block>) -> Result<(), HWTracerError> {
+        if let Some(blocks) = self.cached_blocks.get() {
+            out.extend_from_slice(blocks);
+            return match self.overflowed {
+                true => Err(HWTracerError::HWBufferOverflow),
+                false => Ok(()),
+            };
+        }
+        for block in self.blocks() {
+            out.push(block?);
+        }
+        Ok(())
+    }
+
+    /// Blocks are counted as they are decoded, so counting never caches them (see
+    /// `PerfPTConfig::cache_blocks`). The blocks of a decoded trace are counted without decoding.
+    fn block_count(&self) -> Result<usize, HWTracerError> {
+        if let Some(blocks) = self.cached_blocks.get() {
+            return match self.overflowed {
+                true => Err(HWTracerError::HWBufferOverflow),
+                false => Ok(blocks.len()),
+            };
+        }
+        let mut count = 0;
+        for block in self.blocks() {
+            block?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn iter_instrs<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<u64, HWTracerError>> + 'i> {
+        let itr = PerfPTInstrIterator {
+            decoder: ptr::null_mut(),
+            decoder_status: 0,
+            vdso_tempfile: None,
+            trace: self,
+            errored: false,
+        };
+        Box::new(itr)
+    }
+
+    fn iter_packets<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<PtPacket, HWTracerError>> + 'i> {
+        Box::new(PerfPTPacketIterator {
+            decoder: ptr::null_mut(),
+            trace: self,
+            finished: false,
+        })
+    }
+
+    /// Timing information is recorded as TSC and CYC packets. If the packets can't be examined
+    /// (e.g. because libipt doesn't recognise the CPU), then `false` is returned.
+    fn has_timing(&self) -> bool {
+        let mut has_timing = false;
+        let mut cerr = PerfPTCError::new();
+        let data = self.bytes();
+        let ok = unsafe {
+            perf_pt_has_timing(
+                data.as_ptr() as *const c_void,
+                data.len() as u64,
+                &mut has_timing,
+                &mut cerr,
+            )
+        };
+        ok && has_timing
+    }
+}
+
+impl Drop for PerfPTTrace {
+    fn drop(&mut self) {
+        if self.fd != -1 {
+            if !self.buf.0.is_null() {
+                unsafe { munmap(self.buf.0 as *mut c_void, self.map_len()) };
+            }
+            unsafe { close(self.fd) };
+        } else if !self.buf.0.is_null() {
+            unsafe { free(self.buf.0 as *mut c_void) };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PerfPTTracer {
+    config: PerfPTConfig,
+}
+
+impl PerfPTTracer {
+    pub(super) fn new(config: PerfPTConfig) -> Result<Self, HWTracerError>
+    where
+        Self: Sized,
+    {
+        // Check for inavlid configuration.
+        fn power_of_2(v: size_t) -> bool {
+            v != 0 && (v & (v - 1)) == 0
+        }
+        if !power_of_2(config.data_bufsize) {
+            return Err(HWTracerError::BadConfig(String::from(
+                "data_bufsize must be a positive power of 2",
+            )));
+        }
+        if !power_of_2(config.aux_bufsize) {
+            return Err(HWTracerError::BadConfig(String::from(
+                "aux_bufsize must be a positive power of 2",
+            )));
+        }
+        if config.exclude_kernel && config.exclude_user {
+            return Err(HWTracerError::BadConfig(String::from(
+                "exclude_kernel and exclude_user can't both be set",
+            )));
+        }
+        if config.max_bytes == Some(0) {
+            return Err(HWTracerError::BadConfig(String::from(
+                "max_bytes must be positive",
+            )));
+        }
+        if config.snapshot && config.sink != TraceSink::Memory {
+            return Err(HWTracerError::BadConfig(String::from(
+                "snapshot mode can't be used with a file sink",
+            )));
+        }
+        if config.snapshot && config.max_bytes.is_some() {
+            return Err(HWTracerError::BadConfig(String::from(
+                "snapshot mode can't be used with max_bytes",
+            )));
+        }
+        if let Some(ref watermark) = config.aux_watermark {
+            if !(watermark.frac > 0.0 && watermark.frac <= 1.0) {
+                return Err(HWTracerError::BadConfig(String::from(
+                    "the AUX watermark must be in the range (0, 1]",
+                )));
+            }
+            if config.snapshot {
+                return Err(HWTracerError::BadConfig(String::from(
+                    "snapshot mode can't be used with an AUX watermark",
+                )));
+            }
+        }
+        if !config.filter_ranges.is_empty() {
+            let supported = supported_addr_ranges();
+            if config.filter_ranges.len() > supported as usize {
+                return Err(HWTracerError::BadConfig(format!(
+                    "{} address filter ranges were requested, but this CPU supports {}",
+                    config.filter_ranges.len(),
+                    supported
+                )));
+            }
+            addr_filter(&config.filter_ranges)?;
+        }
+        if config.ptwrite && !ptwrite_supported() {
+            return Err(HWTracerError::BadConfig(String::from(
+                "ptwrite is not supported by this CPU",
+            )));
+        }
+        let timing = config.effective_timing();
+        if timing >= TimingMode::Mtc && !mtc_supported() {
+            return Err(HWTracerError::BadConfig(String::from(
+                "MTC timing is not supported by this CPU",
+            )));
+        }
+        if timing >= TimingMode::Cyc && !cyc_supported() {
+            return Err(HWTracerError::BadConfig(String::from(
+                "CYC timing is not supported by this CPU",
+            )));
+        }
+        if let Some((on_us, off_us)) = config.duty_cycle_us {
+            if on_us == 0 || off_us == 0 {
+                return Err(HWTracerError::BadConfig(String::from(
+                    "the duty cycle periods must be positive",
+                )));
+            }
+        }
+        if let Some(psb_period) = config.psb_period {
+            if psb_period >= 16 || supported_psb_periods() & (1 << psb_period) == 0 {
+                return Err(HWTracerError::BadConfig(format!(
+                    "psb_period {} is not supported by this CPU",
+                    psb_period
+                )));
+            }
+        }
+
+        Self::check_perf_perms()?;
+        Ok(Self { config })
+    }
+
+    fn check_perf_perms() -> Result<(), HWTracerError> {
+        if unsafe { geteuid() } == 0 {
+            // Root can always trace.
+            return Ok(());
+        }
+
+        let mut f = File::open(&PERF_PERMS_PATH)?;
+        let mut buf = String::new();
+        f.read_to_string(&mut buf)?;
+        let perm = buf.trim().parse::<i8>()?;
+        if perm != -1 {
+            // Report the error with which the kernel would refuse to open the perf event.
+            return Err(HWTracerError::Permission {
+                errno: libc::EACCES,
+                hint: perms_hint(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Tracer for PerfPTTracer {
+    fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
+        Box::new(PerfPTThreadTracer::new(self.config.clone()))
+    }
+
+    fn name(&self) -> &'static str {
+        "PerfPT"
+    }
+
+    fn backend_info(&self) -> BackendInfo {
+        BackendInfo {
+            name: self.name(),
+            decoder_version: Some(libipt_version()),
+        }
+    }
+
+    fn thread_tracer_for(&self, tid: pid_t) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        // Only threads of the current process can be traced, since decoding requires their code.
+        if tid <= 0 || !Path::new(&format!("{}/{}", TASKS_PATH, tid)).exists() {
+            return Err(HWTracerError::CFailed(ESRCH));
+        }
+        let mut tracer = PerfPTThreadTracer::new(self.config.clone());
+        tracer.tid = tid;
+        Ok(Box::new(tracer))
+    }
+
+    fn thread_tracer_on_exec(&self, pid: pid_t) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        // The kernel checks that the process exists when the tracer is started.
+        if pid <= 0 {
+            return Err(HWTracerError::CFailed(ESRCH));
+        }
+        if !self.config.filter_ranges.is_empty() {
+            return Err(HWTracerError::UnsupportedOperation(
+                "address filters can't be used when tracing other programs".into(),
+            ));
+        }
+        if self.config.duty_cycle_us.is_some() {
+            return Err(HWTracerError::UnsupportedOperation(
+                "duty cycling can't be used when tracing starts on exec".into(),
+            ));
+        }
+        let mut tracer = PerfPTThreadTracer::new(self.config.clone());
+        tracer.tid = pid;
+        tracer.on_exec = true;
+        Ok(Box::new(tracer))
+    }
+
+    fn cgroup_cpu_tracer(
+        &self,
+        cgroup_fd: RawFd,
+        cpu: usize,
+    ) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        // The kernel checks that the file descriptor is a cgroup when the tracer is started.
+        if cgroup_fd < 0 {
+            return Err(HWTracerError::CFailed(EBADF));
+        }
+        if !self.config.filter_ranges.is_empty() {
+            return Err(HWTracerError::UnsupportedOperation(
+                "address filters can't be used when tracing other programs".into(),
+            ));
+        }
+        let mut tracer = PerfPTThreadTracer::new(self.config.clone());
+        tracer.tid = cgroup_fd;
+        tracer.cgroup_cpu = Some(c_int::try_from(cpu).map_err(|_| HWTracerError::CFailed(EINVAL))?);
+        Ok(Box::new(tracer))
+    }
+}
+
+/// A tracer that uses the Linux Perf interface to Intel Processor Trace.
+pub struct PerfPTThreadTracer {
+    // The configuration for this tracer.
+    config: PerfPTConfig,
+    // Opaque C pointer representing the tracer context.
+    tracer_ctx: *mut c_void,
+    // The state of the tracer.
+    state: TracerState,
+    // The trace currently being collected, or `None`.
+    trace: Option<Box<PerfPTTrace>>,
+    // The OS-level ID of the thread to trace, or 0 to trace the thread which starts the tracer.
+    tid: pid_t,
+    // When the tracer was last started.
+    start_time: Option<Instant>,
+    // If set, the hardware is enabled by the kernel when the traced thread next calls execve(2),
+    // rather than when the tracer is started.
+    on_exec: bool,
+    // If set, `tid` is instead a cgroup file descriptor, whose tasks are traced on this CPU.
+    cgroup_cpu: Option<c_int>,
+}
+
+impl PerfPTThreadTracer {
+    fn new(config: PerfPTConfig) -> Self {
+        Self {
+            config,
+            tracer_ctx: ptr::null_mut(),
+            state: TracerState::Stopped,
+            trace: None,
+            tid: 0,
+            start_time: None,
+            on_exec: false,
+            cgroup_cpu: None,
+        }
+    }
+
+    // Record in `trace` how it is collected.
+    fn configure_trace(&self, trace: &mut PerfPTTrace) {
+        trace.resync_on_error = self.config.resync_on_error;
+        trace.cache_blocks = self.config.cache_blocks;
+        trace.duty_cycled = self.config.duty_cycle_us.is_some();
+        trace.tid = match (self.cgroup_cpu, self.tid) {
+            // The tasks of a cgroup aren't known.
+            (Some(_), _) => 0,
+            (None, 0) => (unsafe { libc::syscall(libc::SYS_gettid) }) as pid_t,
+            (None, tid) => tid,
+        };
+        trace.perf_config = perf_data::attr_config(
+            self.config.effective_timing(),
+            PERF_PT_MTC_PERIOD,
+            self.config.ret_compression,
+        );
+    }
+}
+
+impl Default for PerfPTThreadTracer {
+    fn default() -> Self {
+        PerfPTThreadTracer::new(PerfPTConfig::default())
+    }
+}
+
+impl RawThreadTracer for PerfPTThreadTracer {
+    type Trace = Box<dyn Trace>;
+    type Error = HWTracerError;
+
+    fn start_tracing(&mut self) -> Result<(), HWTracerError> {
+        if self.state == TracerState::Started {
+            return Err(TracerState::Started.as_error());
+        }
+
+        // At the time of writing, we have to use a fresh Perf file descriptor to ensure traces
+        // start with a `PSB+` packet sequence. This is required for correct instruction-level and
+        // block-level decoding. Therefore we have to re-initialise for each new tracing session.
+        let mut cerr = PerfPTCError::new();
+        let mut cconfig = PerfPTCConfig::from(&self.config);
+        cconfig.enable_on_exec = self.on_exec;
+        cconfig.cgroup_cpu = self.cgroup_cpu.unwrap_or(-1);
+        // The objects containing the ranges may have moved since the tracer was made.
+        let filter = addr_filter(&self.config.filter_ranges)?;
+        cconfig.addr_filter = filter.as_ref().map_or(ptr::null(), |f| f.as_ptr());
+        self.tracer_ctx =
+            unsafe { perf_pt_init_tracer(&cconfig as *const PerfPTCConfig, self.tid, &mut cerr) };
+        if self.tracer_ctx.is_null() {
+            return Err(cerr.into());
+        }
+
+        // It is essential we box the trace now to stop it from moving. If it were to move, then
+        // the reference which we pass to C here would become invalid. The interface to
+        // `stop_tracing` needs to return a Box<Tracer> anyway, so it's no big deal.
+        //
+        // Note that the C code will mutate the trace's members directly.
+        let mut trace = Box::new(PerfPTTrace::for_sink(
+            &self.config.sink,
+            self.config.initial_trace_bufsize,
+        )?);
+        self.configure_trace(&mut trace);
+        let mut cerr = PerfPTCError::new();
+        if !unsafe { perf_pt_start_tracer(self.tracer_ctx, &mut *trace, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        self.state = TracerState::Started;
+        self.trace = Some(trace);
+        self.start_time = Some(Instant::now());
+        Ok(())
+    }
+
+    fn stop_tracing(&mut self) -> Result<Box<dyn Trace>, HWTracerError> {
+        if self.state == TracerState::Stopped {
+            return Err(TracerState::Stopped.as_error());
+        }
+        let mut cerr = PerfPTCError::new();
+        let mut rc = unsafe { perf_pt_stop_tracer(self.tracer_ctx, &mut cerr) };
+        if rc && self.config.snapshot {
+            // The trace is whatever the AUX buffer holds now that tracing has stopped.
+            let trace = &mut **self.trace.as_mut().unwrap();
+            rc = unsafe { perf_pt_snapshot(self.tracer_ctx, trace, false, &mut cerr) };
+        }
+        let collection = self
+            .start_time
+            .take()
+            .and_then(|t| duration_nanos(t.elapsed()));
+        self.state = TracerState::Stopped;
+
+        // Free the tracer context even if stopping failed, so as not to leak perf resources.
+        let mut free_cerr = PerfPTCError::new();
+        let free_rc = unsafe { perf_pt_free_tracer(self.tracer_ctx, &mut free_cerr) };
+        self.tracer_ctx = ptr::null_mut();
+        let mut ret = self.trace.take().unwrap();
+        if !rc {
+            return Err(cerr.into());
+        }
+        if !free_rc {
+            return Err(free_cerr.into());
+        }
+        if ret.fd != -1 {
+            ret.map_file()?;
+        }
+        ret.collection = collection;
+
+        Ok(ret as Box<dyn Trace>)
+    }
+
+    fn is_tracing(&self) -> bool {
+        self.state == TracerState::Started
+    }
+}
+
+impl ThreadTracer for PerfPTThreadTracer {
+    /// If the calling thread is traced, then the code consuming the stream generates yet more
+    /// trace data, so iterating until `None` may take a while.
+    fn start_streaming(&mut self) -> Result<BlockStream<'_>, HWTracerError> {
+        if self.config.sink != TraceSink::Memory {
+            return Err(HWTracerError::UnsupportedOperation(
+                "streaming is not supported with a file sink".into(),
+            ));
+        }
+        if self.config.snapshot {
+            return Err(HWTracerError::UnsupportedOperation(
+                "streaming is not supported in snapshot mode".into(),
+            ));
+        }
+        // Load the code before tracing starts, so that we don't trace ourselves doing it.
+        let cache = DecoderCache::from_self_image()?;
+        let spare = PerfPTTrace::new(self.config.initial_trace_bufsize)?;
+        self.start_tracing()?;
+        Ok(BlockStream::new(Box::new(PerfPTBlockSource {
+            tracer: self,
+            cache,
+            spare,
+            pending: Vec::new(),
+            overflowed: false,
+            blocks: VecDeque::new(),
+            decoded: 0,
+            finished: false,
+        })))
+    }
+
+    /// Requires `PerfPTConfig::snapshot` to be set. Tracing is briefly paused whilst the AUX
+    /// buffer is copied.
+    fn snapshot(&mut self) -> Result<Box<dyn Trace>, HWTracerError> {
+        if !self.config.snapshot {
+            return Err(HWTracerError::UnsupportedOperation(
+                "snapshots require PerfPTConfig::snapshot to be set".into(),
+            ));
+        }
+        if self.state == TracerState::Stopped {
+            return Err(TracerState::Stopped.as_error());
+        }
+        let mut trace = PerfPTTrace::new(self.config.initial_trace_bufsize)?;
+        self.configure_trace(&mut trace);
+        let mut cerr = PerfPTCError::new();
+        if !unsafe { perf_pt_snapshot(self.tracer_ctx, &mut trace, true, &mut cerr) } {
+            return Err(cerr.into());
+        }
+        Ok(Box::new(trace))
+    }
+}
+
+// The source of a PerfPT `BlockStream`.
+//
+// Trace data is taken from the tracer as it is collected and decoded in segments, each starting
+// with a PSB packet. Since each segment is decoded independently, a block spanning a segment
+// boundary is reported as starting part-way through.
+struct PerfPTBlockSource<'a> {
+    // The tracer, which is running until `stop_tracing()` is called.
+    tracer: &'a mut PerfPTThreadTracer,
+    // The code of the current process, for decoding.
+    cache: DecoderCache,
+    // Swapped with the trace being collected to take its data (so this is usually empty).
+    spare: PerfPTTrace,
+    // Trace data not yet decoded. This never contains a complete segment.
+    pending: Vec<u8>,
+    // Set if the trace overflowed, after which no more trace data is collected.
+    overflowed: bool,
+    // Blocks decoded, but not yet yielded.
+    blocks: VecDeque<Result<Block, HWTracerError>>,
+    // The number of blocks decoded so far, from all segments.
+    decoded: usize,
+    // Set when there will be no more blocks.
+    finished: bool,
+}
+
+impl<'a> PerfPTBlockSource<'a> {
+    // Take the trace data collected so far and decode the complete segments within it.
+    //
+    // Returns `false` if there was nothing new to decode.
+    fn refill(&mut self) -> Result<bool, HWTracerError> {
+        if self.tracer.state == TracerState::Started && !self.overflowed {
+            let mut cerr = PerfPTCError::new();
+            if !unsafe { perf_pt_take_trace(self.tracer.tracer_ctx, &mut self.spare, &mut cerr) } {
+                return Err(cerr.into());
+            }
+            self.pending.extend_from_slice(self.spare.bytes());
+            self.spare.len = 0;
+            self.overflowed = self.spare.overflowed;
+        }
+
+        // Once tracing has stopped (or can't usefully continue), the last segment is complete.
+        let end = if self.tracer.state == TracerState::Stopped
+            || self.overflowed
+            || self.spare.auto_stopped
+        {
+            self.finished = true;
+            self.pending.len()
+        } else {
+            // Find the start of the last segment, which may not be complete yet.
+            match self
+                .pending
+                .windows(PSB.len())
+                .rposition(|w| w == PSB)
+                .filter(|&pos| pos > 0)
+            {
+                Some(pos) => pos,
+                None => return Ok(false),
+            }
+        };
+
+        if end == 0 {
+            // There's nothing left to decode.
+            if self.overflowed {
+                self.blocks.push_back(Err(HWTracerError::HWBufferOverflow));
+            }
+            return Ok(true);
+        }
+        let segment = PerfPTTrace::from_bytes(&self.pending[..end], self.overflowed)?;
+        self.pending.drain(..end);
+        for res in self.cache.decode(&segment) {
+            self.blocks.push_back(match res {
+                Ok(b) => {
+                    self.decoded += 1;
+                    Ok(b)
+                }
+                // The blocks of earlier segments count too.
+                Err(HWTracerError::Overflow { .. }) => Err(HWTracerError::Overflow {
+                    at_block: self.decoded,
+                }),
+                Err(HWTracerError::TracingDisabled { .. }) => Err(HWTracerError::TracingDisabled {
+                    at_block: self.decoded,
+                }),
+                Err(e) => Err(e),
+            });
+        }
+        Ok(true)
+    }
+}
+
+impl<'a> Iterator for PerfPTBlockSource<'a> {
+    type Item = Result<Block, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(res) = self.blocks.pop_front() {
+                if res.is_err()
+                    && !matches!(
+                        res,
+                        Err(HWTracerError::Overflow { .. })
+                            | Err(HWTracerError::TracingDisabled { .. })
+                    )
+                {
+                    // Any error other than an overflow or a gap in tracing ends the stream.
+                    self.blocks.clear();
+                    self.finished = true;
+                }
+                return Some(res);
+            }
+            if self.finished {
+                return None;
+            }
+            match self.refill() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> BlockSource for PerfPTBlockSource<'a> {
+    fn stop_tracing(&mut self) -> Result<(), HWTracerError> {
+        // The trace holds whatever was collected since data was last taken.
+        let trace = self.tracer.stop_tracing()?;
+        if !self.overflowed {
+            self.pending.extend_from_slice(trace_data(&*trace)?);
+            self.overflowed = trace.overflowed();
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for PerfPTBlockSource<'a> {
+    fn drop(&mut self) {
+        if self.tracer.state == TracerState::Started {
+            let _ = self.tracer.stop_tracing();
+        }
+    }
+}
+
+impl Drop for PerfPTThreadTracer {
+    fn drop(&mut self) {
+        // If the tracer is still running (e.g. the traced code panicked), then stop it so that
+        // the hardware is disabled and the perf file descriptor isn't leaked, discarding the trace.
+        if self.state == TracerState::Started {
+            let _ = self.stop_tracing();
+        }
+    }
+}
+
+// Called by C to store a ptxed argument into a Rust Vec.
+#[cfg(test)]
+#[no_mangle]
+pub unsafe extern "C" fn push_ptxed_arg(args: &mut Vec<String>, new_arg: *const c_char) {
+    let new_arg = CStr::from_ptr(new_arg)
+        .to_owned()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    args.push(new_arg);
+}
+
+#[cfg(all(perf_pt_test, test))]
+mod tests {
+    use super::{
+        addr_filter, cyc_supported, detect_pt_cpu, mtc_supported, ptwrite_supported,
+        ptxed_block_starts, supported_addr_ranges, PerfPTCError, PerfPTCErrorKind, PERF_PERMS_PATH,
+        PSB, PTXED_VDSO_FILENAME, VDSO_FILENAME,
+    };
+    use super::{
+        dump_vdso, pid_t, size_t, supported_psb_periods, AsRawFd, DecoderCache, DecoderOptions,
+        Errata, HWTracerError, NamedTempFile, PerfPTBlockIterator, PerfPTConfig,
+        PerfPTThreadTracer, PerfPTTrace, RawThreadTracer, RawTrace, ThreadTracer, TimingMode,
+        Trace, TraceDecoder,
+    };
+    use crate::backends::{BackendConfig, BackendKind, TraceSink, TracerBuilder};
+    use crate::{
+        deserialize_trace, test_helpers, Block, EdgeKind, IpCompression, ProcessTracer, PtCpu,
+        PtCpuVendor, PtPacket, ReturnKind, TracerState,
+    };
+    use phdrs::{PF_X, PT_LOAD};
+    use std::cmp;
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::mem;
+    use std::path::Path;
+    use std::process::Command;
+    use std::ptr;
+    use std::slice;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    // Given a trace, use ptxed to get a vector of block start vaddrs.
+    fn get_expected_blocks(trace: &Box<dyn Trace>) -> Vec<Block> {
+        ptxed_block_starts(&**trace)
+            .unwrap()
+            .into_iter()
+            .map(|vaddr| Block::new(vaddr, 0))
+            .collect()
+    }
+
+    // Trace a closure and then decode it and check the block iterator agrees with ptxed.
+    fn trace_and_check_blocks<T, F>(mut tracer: T, f: F)
+    where
+        T: ThreadTracer,
+        F: FnOnce() -> u64,
+    {
+        let trace = test_helpers::trace_closure(&mut tracer, f);
+        let expects = get_expected_blocks(&trace);
+        test_helpers::test_expected_blocks(trace, expects.iter());
+    }
+
+    #[test]
+    fn test_basic_usage() {
+        test_helpers::test_basic_usage(PerfPTThreadTracer::default());
+    }
+
+    #[test]
+    fn test_repeated_tracing() {
+        test_helpers::test_repeated_tracing(PerfPTThreadTracer::default());
+    }
+
+    // Each tracer has its own perf event and AUX buffer, so a trace holds only the blocks of its
+    // own thread: were any blocks of the other threads to find their way into a trace of the
+    // short loop, it would be as long as those of the long loop.
+    #[test]
+    fn test_concurrent_tracing() {
+        let counts = test_helpers::test_concurrent_tracing(PerfPTThreadTracer::default);
+        let count = |iters| {
+            counts
+                .iter()
+                .filter(move |(i, _)| *i == iters)
+                .map(|(_, c)| *c)
+        };
+        assert!(count(100).min().unwrap() > count(10).max().unwrap() * 5);
+    }
+
+    // Check that a thread which migrates between CPUs whilst it is traced still gets a complete
+    // trace, in execution order.
+    #[test]
+    fn test_cpu_migration() {
+        let mut orig_cpus = unsafe { mem::zeroed::<libc::cpu_set_t>() };
+        let set_size = mem::size_of::<libc::cpu_set_t>();
+        assert_eq!(
+            unsafe { libc::sched_getaffinity(0, set_size, &mut orig_cpus) },
+            0
+        );
+        let cpus = (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &orig_cpus) })
+            .collect::<Vec<_>>();
+
+        trace_and_check_blocks(PerfPTThreadTracer::default(), || {
+            let mut res = 0;
+            for &cpu in cpus.iter().cycle().take(cmp::max(cpus.len(), 2) * 2) {
+                let mut set = unsafe { mem::zeroed::<libc::cpu_set_t>() };
+                unsafe {
+                    libc::CPU_ZERO(&mut set);
+                    libc::CPU_SET(cpu, &mut set);
+                    assert_eq!(libc::sched_setaffinity(0, set_size, &set), 0);
+                }
+                res += test_helpers::work_loop(100);
+            }
+            res
+        });
+        assert_eq!(
+            unsafe { libc::sched_setaffinity(0, set_size, &orig_cpus) },
+            0
+        );
+    }
+
+    #[test]
+    fn test_already_started() {
+        test_helpers::test_already_started(PerfPTThreadTracer::default());
+    }
+
+    #[test]
+    fn test_not_started() {
+        test_helpers::test_not_started(PerfPTThreadTracer::default());
+    }
+
+    // Test writing a trace to file.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_to_file() {
+        use std::fs::File;
+        use std::io::prelude::*;
+        use std::slice;
+        use Trace;
+
+        // Allocate and fill a buffer to make a "trace" from.
+        let capacity = 1024;
+        let mut trace = PerfPTTrace::new(capacity).unwrap();
+        trace.len = capacity as u64;
+        let sl = unsafe { slice::from_raw_parts_mut(trace.buf.0 as *mut u8, capacity) };
+        for (i, byte) in sl.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // Make the trace and write it to a file.
+        let mut tmpf = NamedTempFile::new().unwrap();
+        trace.to_file(&mut tmpf.as_file_mut());
+        tmpf.as_file().sync_all().unwrap();
+
+        // Check the resulting file makes sense.
+        let file = File::open(tmpf.path().to_str().unwrap()).unwrap();
+        let mut total_bytes = 0;
+        for (i, byte) in file.bytes().enumerate() {
+            assert_eq!(i as u8, byte.unwrap());
+            total_bytes += 1;
+        }
+        assert_eq!(total_bytes, capacity);
+    }
+
+    // Check that the raw trace data is exposed.
+    #[test]
+    fn test_raw_data() {
+        let mut trace = PerfPTTrace::new(16).unwrap();
+        let sl = unsafe { slice::from_raw_parts_mut(trace.buf.0, 16) };
+        for (i, byte) in sl.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        trace.len = 8;
+        assert_eq!(trace.raw_data(), Some(&[0, 1, 2, 3, 4, 5, 6, 7][..]));
+    }
+
+    // Check that a deserialized trace decodes to the same blocks as the original.
+    #[test]
+    fn test_serialize_round_trip() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert_eq!(trace2.raw_data(), trace.raw_data());
+
+        let blocks1 = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        let blocks2 = trace2.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(blocks1, blocks2);
+    }
+
+    // Check that a trace records the CPU which collected it, and that it survives serialization.
+    #[test]
+    fn test_pt_cpu() {
+        let cpu = detect_pt_cpu();
+        assert_eq!(cpu.vendor, PtCpuVendor::Intel);
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert_eq!(trace.pt_cpu(), Some(cpu));
+
+        let mut trace = PerfPTTrace::new(0).unwrap();
+        let other = PtCpu {
+            vendor: PtCpuVendor::Intel,
+            family: 6,
+            model: 0x5c,
+            stepping: 9,
+        };
+        trace.cpu = other.into();
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert_eq!(trace2.pt_cpu(), Some(other));
+    }
+
+    // Check that a serialized trace claiming more data than it holds is rejected.
+    #[test]
+    fn test_deserialize_truncated() {
+        let trace = PerfPTTrace::from_bytes(&PSB, false).unwrap();
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        // Claim a huge length (just after the 11-byte header and the flags).
+        buf[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        match deserialize_trace(&mut buf.as_slice()) {
+            Err(HWTracerError::Custom(e)) => assert_eq!(
+                e.downcast_ref::<io::Error>().unwrap().kind(),
+                io::ErrorKind::UnexpectedEof
+            ),
+            _ => panic!(),
+        }
+    }
+
+    // Check that a trace serialized without its CPU is assumed to be from the current CPU.
+    #[test]
+    fn test_deserialize_without_cpu() {
+        let trace = PerfPTTrace::new(0).unwrap();
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        // Clear the CPU flag (just after the 11-byte common header) and drop the CPU.
+        buf[11] &= !super::FLAG_CPU;
+        buf.truncate(buf.len() - 5);
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert_eq!(trace2.pt_cpu(), Some(detect_pt_cpu()));
+    }
+
+    // Check that the AUX buffer usage is recorded during collection, but not serialized.
+    #[test]
+    fn test_bytes_used() {
+        let config = PerfPTConfig::default();
+        let aux_bytes = config.aux_bufsize * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert_eq!(trace.hw_buffer_size(), Some(aux_bytes));
+        let used = trace.bytes_used().unwrap();
+        assert!(used > 0 && used <= aux_bytes);
+
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert_eq!(trace2.bytes_used(), None);
+        assert_eq!(trace2.hw_buffer_size(), None);
+    }
+
+    // Check that an empty trace can be deserialized.
+    #[test]
+    fn test_deserialize_empty() {
+        let trace = PerfPTTrace::new(0).unwrap();
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        assert!(deserialize_trace(&mut buf.as_slice()).is_ok());
+    }
+
+    // Check that our block decoder agrees with the reference implementation in ptxed.
+    #[test]
+    fn test_block_iterator1() {
+        let tracer = PerfPTThreadTracer::default();
+        trace_and_check_blocks(tracer, || test_helpers::work_loop(10));
+    }
+
+    // Check that our block decoder agrees ptxed on a (likely) empty trace;
+    #[test]
+    fn test_block_iterator2() {
+        let tracer = PerfPTThreadTracer::default();
+        trace_and_check_blocks(tracer, || test_helpers::work_loop(0));
+    }
+
+    // Check that our block decoder deals with traces involving the VDSO correctly.
+    #[test]
+    fn test_block_iterator3() {
+        use libc::{clock_gettime, timespec, CLOCK_MONOTONIC};
+
+        let tracer = PerfPTThreadTracer::default();
+        trace_and_check_blocks(tracer, || {
+            let mut res = 0;
+            let mut tv = timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            for _ in 1..100 {
+                // clock_gettime(2) is in the Linux VDSO.
+                let rv = unsafe { clock_gettime(CLOCK_MONOTONIC, &mut tv) };
+                assert_eq!(rv, 0);
+                res += tv.tv_sec;
+            }
+            res as u64
+        });
+    }
+
+    // Check that the AUX timestamps lie within the trace, and within the time it was collected.
+    #[test]
+    fn test_aux_timestamps() {
+        use libc::{clock_gettime, timespec, CLOCK_MONOTONIC};
+
+        fn now() -> u64 {
+            let mut tv = timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            assert_eq!(unsafe { clock_gettime(CLOCK_MONOTONIC, &mut tv) }, 0);
+            tv.tv_sec as u64 * 1_000_000_000 + tv.tv_nsec as u64
+        }
+
+        let mut tracer = PerfPTThreadTracer::default();
+        let before = now();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(5000));
+        let after = now();
+        let len = trace.raw_data().unwrap().len();
+        let times = trace.iter_aux_timestamps().collect::<Vec<_>>();
+        for w in times.windows(2) {
+            assert!(w[0].0 <= w[1].0 && w[0].1 <= w[1].1);
+        }
+        for &(offset, time) in &times {
+            assert!(offset <= len);
+            assert!(before <= time && time <= after);
+        }
+        assert_eq!(trace.offset_at_time(u64::MAX), times.last().map(|t| t.0));
+        assert_eq!(trace.offset_at_time(before), None);
+    }
+
+    // Check that a shorter trace yields fewer blocks.
+    #[test]
+    fn test_block_iterator4() {
+        let tracer1 = PerfPTThreadTracer::default();
+        let tracer2 = PerfPTThreadTracer::default();
+        test_helpers::test_ten_times_as_many_blocks(tracer1, tracer2);
+    }
+
+    // Check that our block decoder agrees ptxed on long trace.
+    // XXX We use an even higher iteration count once our decoder uses a libipt image cache.
+    #[ignore] // Decoding long traces is slow.
+    #[test]
+    fn test_block_iterator5() {
+        let tracer = PerfPTThreadTracer::default();
+        trace_and_check_blocks(tracer, || test_helpers::work_loop(3000));
+    }
+
+    // Check that every block start address appears, in order, in the instruction stream.
+    #[test]
+    fn test_instr_iterator() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+
+        let mut instrs = trace.iter_instrs().map(|i| i.unwrap());
+        let mut num_blocks = 0;
+        for blk in trace.iter_blocks() {
+            let first = blk.unwrap().first_instr();
+            assert!(instrs.any(|i| i == first));
+            num_blocks += 1;
+        }
+        assert!(trace.iter_instrs().count() >= num_blocks);
+    }
+
+    // Check that a panic in traced code doesn't leave the tracer running.
+    #[test]
+    fn test_trace_closure_panics() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            tracer.trace(|| -> u64 { panic!("in traced code") })
+        }));
+        assert!(res.is_err());
+
+        // If the perf resources had leaked, the tracing below would fail.
+        let (trace, res) = tracer.trace(|| test_helpers::work_loop(10)).unwrap();
+        assert!(res > 0);
+        assert!(trace.iter_blocks().count() > 0);
+    }
+
+    // Check that the trace of a closure which panics holds the code executed before the panic.
+    #[test]
+    fn test_trace_catch_unwind() {
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        let (trace, res) = tracer
+            .trace_catch_unwind(|| -> u64 {
+                test_helpers::work_loop(10);
+                panic!("in traced code")
+            })
+            .unwrap();
+        assert!(res.is_err());
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!blocks.is_empty());
+    }
+
+    // Check that a long trace causes the trace buffer to reallocate.
+    #[test]
+    fn test_relloc_trace_buf1() {
+        let start_bufsize = 512;
+        let mut config = PerfPTConfig::default();
+        config.initial_trace_bufsize = start_bufsize;
+        let mut tracer = PerfPTThreadTracer::new(config);
+
+        tracer.start_tracing().unwrap();
+        let res = test_helpers::work_loop(10000);
+        let trace = tracer.stop_tracing().unwrap();
+
+        println!("res: {}", res); // Stop over-optimisation.
+        assert!(trace.capacity() > start_bufsize);
+    }
+
+    // Check that one thread can trace another.
+    #[test]
+    fn test_thread_tracer_for() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{mpsc, Arc};
+        use std::thread;
+        use std::time::Duration;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = Arc::clone(&stop);
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as pid_t)
+                .unwrap();
+            while !stop2.load(Ordering::Relaxed) {
+                test_helpers::work_loop(10);
+            }
+        });
+
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        let mut thr_tracer = tracer.thread_tracer_for(rx.recv().unwrap()).unwrap();
+        thr_tracer.start_tracing().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let trace = thr_tracer.stop_tracing().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        worker.join().unwrap();
+
+        let mut itr = trace.iter_blocks();
+        match itr.next() {
+            Some(Ok(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_thread_tracer_for_bad_tid() {
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        match tracer.thread_tracer_for(-1) {
+            Err(HWTracerError::CFailed(libc::ESRCH)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_cgroup_cpu_tracer_bad_fd() {
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        match tracer.cgroup_cpu_tracer(-1, 0) {
+            Err(HWTracerError::CFailed(libc::EBADF)) => (),
+            _ => panic!(),
+        }
+        // A file descriptor which isn't a cgroup is only rejected by the kernel.
+        let file = File::open(env::current_exe().unwrap()).unwrap();
+        let mut cpu_tracer = tracer.cgroup_cpu_tracer(file.as_raw_fd(), 0).unwrap();
+        assert!(cpu_tracer.start_tracing().is_err());
+    }
+
+    // Check that blocks are timed when timing packets are requested.
+    #[test]
+    fn test_timed_blocks() {
+        let mut config = PerfPTConfig::default();
+        config.tsc = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        let timed = trace
+            .iter_timed_blocks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(timed.len(), blocks.len());
+
+        let mut last_tsc = 0;
+        for (tb, b) in timed.iter().zip(blocks.iter()) {
+            assert_eq!(tb.block(), b);
+            // Time never goes backwards.
+            if let Some(tsc) = tb.tsc() {
+                assert!(tsc >= last_tsc);
+                last_tsc = tsc;
+            }
+        }
+        assert!(timed.last().unwrap().tsc().is_some());
+    }
+
+    // Check that blocks are cached only when requested, and only once fully decoded.
+    #[test]
+    fn test_cache_blocks() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        trace.iter_blocks().for_each(drop);
+        assert!(!trace.is_decoded());
+
+        let mut config = PerfPTConfig::default();
+        config.cache_blocks = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert!(!trace.is_decoded());
+        trace.iter_blocks().take(1).for_each(drop);
+        assert!(!trace.is_decoded());
+        let n = trace.block_count().unwrap();
+        assert!(!trace.is_decoded());
+        let decoded = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(trace.is_decoded());
+        assert_eq!(decoded.len(), n);
+        assert_eq!(trace.block_count().unwrap(), n);
+        assert_eq!(trace.iter_blocks().size_hint(), (n, Some(n)));
+        let cached = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(cached, decoded);
+        let mut out = Vec::new();
+        trace.decode_into(&mut out).unwrap();
+        assert_eq!(out, decoded);
+    }
+
+    // Check that reverse iteration yields the blocks of forward iteration, backwards.
+    #[test]
+    fn test_iter_blocks_rev() {
+        // Decoded traces are reversed from their cached blocks.
+        for &cache_blocks in &[false, true] {
+            let mut config = PerfPTConfig::default();
+            config.cache_blocks = cache_blocks;
+            let mut tracer = PerfPTThreadTracer::new(config);
+            let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+            let mut blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(trace.is_decoded(), cache_blocks);
+            blocks.reverse();
+            let rev = trace
+                .iter_blocks_rev()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(rev, blocks);
+        }
+    }
+
+    // Check that a limited iterator yields a prefix of the blocks, and doesn't record the time to
+    // decode the whole trace.
+    #[test]
+    fn test_iter_blocks_limited() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let limited = trace
+            .iter_blocks_limited(2)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(trace.timing().decoding.is_none());
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(limited, blocks[..cmp::min(2, blocks.len())]);
+    }
+
+    // Check that timing packets are found only when they were requested.
+    #[test]
+    fn test_has_timing() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert!(!trace.has_timing());
+
+        let mut config = PerfPTConfig::default();
+        config.tsc = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert!(trace.has_timing());
+    }
+
+    // Check that the packet stream starts with a PSB, and holds the packets needed to decode the
+    // work loop's control flow.
+    #[test]
+    fn test_iter_packets() {
+        let mut config = PerfPTConfig::default();
+        config.tsc = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let packets = trace.iter_packets().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(packets.first(), Some(&PtPacket::Psb));
+        assert!(packets.contains(&PtPacket::PsbEnd));
+        assert!(packets.iter().any(|p| match p {
+            PtPacket::Tnt { count, .. } => *count > 0,
+            _ => false,
+        }));
+        assert!(packets.iter().any(|p| matches!(p, PtPacket::Tip(Some(_)))));
+        assert!(packets.iter().any(|p| matches!(p, PtPacket::Tsc(_))));
+        assert!(packets.iter().any(|p| match p {
+            PtPacket::ModeExec { csl, .. } => *csl,
+            _ => false,
+        }));
+    }
+
+    // Check that decoding starts at the PSB packet emitted when tracing starts, preceded only by
+    // PAD packets, and that a trace without a PSB packet has nowhere to start.
+    #[test]
+    fn test_sync_offset() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let off = trace.sync_offset().unwrap();
+        let data = trace.raw_data().unwrap();
+        assert!(data[..off].iter().all(|&b| b == 0));
+        assert!(data[off..].starts_with(&PSB));
+
+        let trace = PerfPTTrace::from_bytes(&[0; 16], false).unwrap();
+        assert_eq!(trace.sync_offset(), None);
+    }
+
+    // Check that a trace without a PSB packet has no packets, and that a packet iterator returns
+    // none after an error.
+    #[test]
+    fn test_iter_packets_no_psb() {
+        let mut trace = PerfPTTrace::new(16).unwrap();
+        unsafe { ptr::write_bytes(trace.buf.0, 0, 16) }; // PAD packets.
+        trace.len = 16;
+        assert_eq!(trace.iter_packets().count(), 0);
+
+        // A zero-sized trace will lead to an error.
+        let trace = PerfPTTrace::new(0).unwrap();
+        let mut itr = trace.iter_packets();
+        assert!(matches!(itr.next(), Some(Err(_))));
+        assert!(itr.next().is_none());
+    }
+
+    // Check that classified blocks match the plain blocks, and that the work loop's calls and
+    // branches are recognised.
+    #[test]
+    fn test_classified_blocks() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        let classified = trace
+            .iter_classified_blocks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(classified.len(), blocks.len());
+        for (cb, b) in classified.iter().zip(blocks.iter()) {
+            assert_eq!(cb.block(), b);
+        }
+        assert!(classified
+            .iter()
+            .any(|cb| cb.edge_kind() == EdgeKind::ConditionalJump));
+        assert!(classified
+            .iter()
+            .any(|cb| cb.edge_kind() == EdgeKind::Return));
+    }
+
+    // The full IPs and IP compressions of the unsuppressed TIP and TIP.PGE packets of a trace, in
+    // order, along with whether each is a TIP.PGE packet.
+    fn tip_targets(trace: &dyn Trace) -> Vec<(u64, IpCompression, bool)> {
+        let mut targets = Vec::new();
+        let mut last_ip = 0;
+        for p in trace.iter_packets() {
+            let (ip, enable) = match p.unwrap() {
+                PtPacket::Psb => {
+                    last_ip = 0;
+                    continue;
+                }
+                PtPacket::Tip(Some(ip)) => (ip, false),
+                PtPacket::TipPge(Some(ip)) => (ip, true),
+                PtPacket::TipPgd(Some(ip)) | PtPacket::Fup(Some(ip)) => {
+                    last_ip = ip.full_ip(last_ip);
+                    continue;
+                }
+                _ => continue,
+            };
+            last_ip = ip.full_ip(last_ip);
+            targets.push((last_ip, ip.ipc, enable));
+        }
+        targets
+    }
+
+    // Check that blocks reached through TIP packets report how their start addresses were
+    // compressed, and that each such block is attributed a distinct packet giving its start
+    // address. `work_loop()` makes indirect calls into the VDSO via the PLT.
+    #[test]
+    fn test_classified_blocks_ip_compression() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let classified = trace
+            .iter_classified_blocks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(classified.iter().any(|cb| cb.ip_compression().is_some()));
+        for w in classified.windows(2) {
+            // The target of a conditional jump is never given by a TIP packet.
+            if w[1].ip_compression().is_some() {
+                assert_ne!(w[0].edge_kind(), EdgeKind::ConditionalJump);
+            }
+        }
 
-    extern "C" {
-        fn dump_vdso(fd: c_int, vaddr: u64, len: size_t, err: &PerfPTCError) -> bool;
+        // The blocks with IP compressions must match the packets in order.
+        let mut targets = tip_targets(&*trace).into_iter();
+        for cb in classified.iter().filter(|cb| cb.ip_compression().is_some()) {
+            let target = (cb.block().first_instr(), cb.ip_compression().unwrap());
+            assert!(targets.any(|(ip, ipc, _)| (ip, ipc) == target));
+        }
     }
 
-    const VDSO_FILENAME: &str = "linux-vdso.so.1";
+    // Check that returns are reported as compressed only when return compression is enabled.
+    // `work_loop()` calls and returns from library code within the trace.
+    #[test]
+    fn test_classified_blocks_return_kind() {
+        for &ret_compression in &[true, false] {
+            let mut config = PerfPTConfig::default();
+            config.ret_compression = ret_compression;
+            let mut tracer = PerfPTThreadTracer::new(config);
+            let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+            let classified = trace
+                .iter_classified_blocks()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let returns = classified
+                .iter()
+                .filter_map(|cb| cb.return_kind())
+                .collect::<Vec<_>>();
+            assert!(!returns.is_empty());
+            assert_eq!(returns.contains(&ReturnKind::Compressed), ret_compression);
+            for cb in &classified {
+                assert_eq!(
+                    cb.return_kind().is_some(),
+                    cb.edge_kind() == EdgeKind::Return
+                );
+            }
+        }
+    }
 
-    // Gets the ptxed arguments required to decode a trace for the current process.
-    //
-    // Returns a vector of arguments and a handle to a temproary file containing the VDSO code. The
-    // caller must make sure that this file lives long enough for ptxed to run (temp files are
-    // removed when they fall out of scope).
-    fn self_ptxed_args(trace_filename: &str) -> (Vec<String>, NamedTempFile) {
-        let ptxed_args = vec![
-            "--cpu",
-            "auto",
-            "--block-decoder",
-            "--block:end-on-call",
-            "--block:end-on-jump",
-            "--block:show-blocks",
-            "--pt",
-            trace_filename,
-        ];
-        let mut ptxed_args: Vec<String> = ptxed_args.into_iter().map(|e| String::from(e)).collect();
+    // Check that, without return compression, every return is observed, and that the target of
+    // each is given by the next TIP packet in the trace. The blocks must also agree with ptxed.
+    #[test]
+    fn test_classified_blocks_returns_observed() {
+        let mut config = PerfPTConfig::default();
+        config.ret_compression = false;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let classified = trace
+            .iter_classified_blocks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let starts = classified
+            .iter()
+            .map(|cb| cb.block().first_instr())
+            .collect::<Vec<_>>();
+        assert_eq!(starts, ptxed_block_starts(&*trace).unwrap());
 
-        // Make a temp file to dump the VDSO code into. This is necessary because ptxed cannot read
-        // code from a virtual address: it can only load from file.
-        let vdso_tempfile = NamedTempFile::new().unwrap();
+        let mut targets = tip_targets(&*trace)
+            .into_iter()
+            .filter(|&(_, _, enable)| !enable);
+        let mut nreturns = 0;
+        for w in classified.windows(2) {
+            if w[0].edge_kind() == EdgeKind::Return {
+                assert_eq!(w[0].return_kind(), Some(ReturnKind::Observed));
+                let start = w[1].block().first_instr();
+                let (_, ipc, _) = targets.find(|&(ip, _, _)| ip == start).unwrap();
+                assert_eq!(w[1].ip_compression(), Some(ipc));
+                nreturns += 1;
+            }
+        }
+        assert!(nreturns > 0);
+    }
+
+    // Check that the JSON export includes block times when they are known.
+    #[test]
+    fn test_to_json_tsc() {
+        let mut config = PerfPTConfig::default();
+        config.tsc = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let mut buf = Vec::new();
+        trace.to_json(&mut buf).unwrap();
+        let json = String::from_utf8(buf).unwrap();
+        assert!(json.starts_with("[{\"index\":0,"));
+        assert!(json.ends_with("}]"));
+        assert!(json.contains("\"tsc\":"));
+    }
+
+    // Check that blocks can be restricted to address ranges.
+    #[test]
+    fn test_iter_blocks_in() {
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let all = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!all.is_empty());
+
+        assert_eq!(trace.block_count().unwrap(), all.len());
+        assert_eq!(trace.first_block().unwrap(), all.first().cloned());
+        assert_eq!(trace.last_block().unwrap(), all.last().cloned());
+        assert_eq!(trace.iter_blocks_in(&[]).count(), 0);
+        // Two ranges which together cover the whole address space.
+        let first = all[0].first_instr();
+        let everything = [0..first, first..u64::MAX];
+        assert_eq!(trace.iter_blocks_in(&everything).count(), all.len());
+
+        // Restricting to the first block's address yields only blocks starting there.
+        let just_first = first..first + 1;
+        for b in trace.iter_blocks_in(slice::from_ref(&just_first)) {
+            assert_eq!(b.unwrap().first_instr(), first);
+        }
+    }
+
+    #[test]
+    fn test_decode_into() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let expect = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        let mut got = Vec::new();
+        trace.decode_into(&mut got).unwrap();
+        assert_eq!(got, expect);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let stats = trace.stats();
+        assert_eq!(stats.decoded_blocks, trace.block_count().unwrap() as u64);
+        // Every block has at least one instruction.
+        assert!(stats.retired_instrs >= stats.decoded_blocks);
+        assert_eq!(stats.decode_errors, 0);
+        assert_eq!(stats.overflow_events, 0);
+        assert_eq!(stats.bytes, trace.raw_data().unwrap().len());
+    }
+
+    // Check that an overflowed trace is reported as such by the block iterator.
+    #[test]
+    fn test_overflow_stops_block_iter() {
+        // Tiny buffers make an overflow likely (but not certain) on a long trace.
+        let mut config = PerfPTConfig::default();
+        config.data_bufsize = 1;
+        config.aux_bufsize = 1;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100000));
+
+        let last = trace.iter_blocks().last();
+        if trace.overflowed() {
+            match last {
+                Some(Err(HWTracerError::HWBufferOverflow)) => (),
+                _ => panic!(),
+            }
+            match trace.block_count() {
+                Err(HWTracerError::HWBufferOverflow) => (),
+                _ => panic!(),
+            }
+            assert!(trace.stats().overflow_events >= 1);
+        } else {
+            assert!(last.map_or(true, |b| b.is_ok()));
+        }
+    }
 
+    // Make a `TraceDecoder` loaded with the code of the current process.
+    //
+    // As for `self_ptxed_args()`, the returned temporary file holds the VDSO code and must outlive
+    // the decoder.
+    fn self_trace_decoder() -> (TraceDecoder, NamedTempFile) {
+        let mut decoder = TraceDecoder::new();
+        let vdso_tempfile = NamedTempFile::new().unwrap();
         let exe = env::current_exe().unwrap();
         for obj in phdrs::objects() {
             let obj_name = obj.name().to_str().unwrap();
-            let mut filename = if cfg!(target_os = "linux") && obj_name == "" {
-                exe.to_str().unwrap()
-            } else {
-                obj_name
-            };
-
             for hdr in obj.iter_phdrs() {
                 if hdr.type_() != PT_LOAD || hdr.flags() & PF_X.0 == 0 {
                     continue; // Only look at loadable and executable segments.
                 }
-
                 let vaddr = obj.addr() + hdr.vaddr();
-                let offset;
-
-                if filename == VDSO_FILENAME {
-                    let cerr = PerfPTCError::new();
-                    if !unsafe {
-                        dump_vdso(
-                            vdso_tempfile.as_raw_fd(),
-                            vaddr,
-                            size_t::try_from(hdr.memsz()).unwrap(),
-                            &cerr,
-                        )
-                    } {
+                if obj_name == VDSO_FILENAME {
+                    let mut cerr = PerfPTCError::new();
+                    let len = size_t::try_from(hdr.memsz()).unwrap();
+                    if !unsafe { dump_vdso(vdso_tempfile.as_raw_fd(), vaddr, len, &mut cerr) } {
                         panic!("failed to dump vdso");
                     }
-                    filename = vdso_tempfile.path().to_str().unwrap();
-                    offset = 0;
+                    decoder
+                        .add_image(vdso_tempfile.path(), vaddr, hdr.memsz(), 0)
+                        .unwrap();
                 } else {
-                    offset = hdr.offset();
+                    let path = if obj_name == "" {
+                        exe.as_path()
+                    } else {
+                        Path::new(obj_name)
+                    };
+                    decoder
+                        .add_image(path, vaddr, hdr.filesz(), hdr.offset())
+                        .unwrap();
                 }
+            }
+        }
+        (decoder, vdso_tempfile)
+    }
 
-                let raw_arg = format!(
-                    "{}:0x{:x}-0x{:x}:0x{:x}",
-                    filename,
-                    offset,
-                    hdr.offset() + hdr.filesz(),
-                    vaddr
-                );
-                ptxed_args.push("--raw".to_owned());
-                ptxed_args.push(raw_arg);
+    // Check that decoding with explicitly loaded code gives the same blocks as decoding with the
+    // code of the current process.
+    #[test]
+    fn test_trace_decoder() {
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let expect = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let (decoder, _vdso_tempfile) = self_trace_decoder();
+        let got = decoder
+            .decode(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, expect);
+    }
+
+    // Check that a trace decodes the same when its CPU is given explicitly, and that the errata
+    // workarounds can be overridden.
+    #[test]
+    fn test_trace_decoder_options() {
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let expect = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let (mut decoder, _vdso_tempfile) = self_trace_decoder();
+        decoder.set_options(DecoderOptions {
+            cpu: Some(detect_pt_cpu()),
+            errata: None,
+        });
+        let got = decoder
+            .decode(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, expect);
+
+        // A simple loop doesn't trigger any errata, so working around none changes nothing.
+        decoder.set_options(DecoderOptions {
+            cpu: None,
+            errata: Some(Errata::default()),
+        });
+        let got = decoder
+            .decode(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, expect);
+    }
+
+    // Check that decoding with a cache gives the same blocks as decoding without one, and that the
+    // cache can be reused.
+    #[test]
+    fn test_decoder_cache() {
+        let cache = DecoderCache::from_self_image().unwrap();
+        for _ in 0..2 {
+            let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+            let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+            let expect = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+            let got = cache
+                .decode(&*trace)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(got, expect);
+        }
+    }
+
+    // Check that refreshing a cache loads the code of the current process, but doesn't duplicate
+    // code which is already cached.
+    #[test]
+    fn test_decoder_cache_refresh() {
+        let mut cache = DecoderCache::new().unwrap();
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let expect = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        // An empty cache can't decode anything.
+        assert!(cache
+            .decode(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .is_err());
+        for _ in 0..2 {
+            cache.refresh_self_maps().unwrap();
+            let got = cache
+                .decode(&*trace)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(got, expect);
+        }
+    }
+
+    // Check that code which isn't in any file (here, code "generated" at runtime) can be decoded
+    // once it has been added to a cache.
+    #[test]
+    fn test_decoder_cache_add_memory() {
+        // mov eax, 10; loop: dec eax; jnz loop; ret
+        let code = [0xb8, 0x0a, 0x00, 0x00, 0x00, 0xff, 0xc8, 0x75, 0xfc, 0xc3];
+        let page = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len()) };
+        let jitted = unsafe { mem::transmute::<*mut libc::c_void, extern "C" fn() -> u64>(page) };
+        let vaddr = page as u64;
+
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || jitted());
+        let mut cache = DecoderCache::from_self_image().unwrap();
+        match cache.decode(&*trace).find(|b| b.is_err()) {
+            Some(Err(HWTracerError::MissingMapping { vaddr: v })) => assert_eq!(v, vaddr),
+            _ => panic!(),
+        }
+
+        cache.add_memory(vaddr, &code).unwrap();
+        unsafe { libc::munmap(page, 4096) };
+        let blocks = cache
+            .decode(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(blocks.contains(&Block::new(vaddr, vaddr + 7)));
+        assert_eq!(
+            blocks
+                .iter()
+                .filter(|b| b.first_instr() == vaddr + 5)
+                .count(),
+            9
+        );
+        assert!(blocks.contains(&Block::new(vaddr + 9, vaddr + 9)));
+    }
+
+    // Check that blocks can be consumed both whilst tracing and after tracing stops.
+    #[test]
+    fn test_streaming() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let mut stream = tracer.start_streaming().unwrap();
+        let mut blocks = Vec::new();
+        for _ in 0..10 {
+            test_helpers::work_loop(1000);
+            for block in stream.by_ref() {
+                blocks.push(block.unwrap());
+            }
+        }
+        stream.stop_tracing().unwrap();
+        for block in stream {
+            blocks.push(block.unwrap());
+        }
+        assert!(!blocks.is_empty());
+        // The tracer can be used again once the stream is gone.
+        test_helpers::test_basic_usage(tracer);
+    }
+
+    // A reset tracer's trace must still be decodable.
+    #[test]
+    fn test_reset() {
+        let mut tracer = PerfPTThreadTracer::default();
+        tracer.start_tracing().unwrap();
+        test_helpers::work_loop(10);
+        tracer.reset().unwrap();
+        test_helpers::work_loop(10);
+        let trace = tracer.stop_tracing().unwrap();
+        assert!(trace.iter_blocks().all(|b| b.is_ok()));
+    }
+
+    #[test]
+    fn test_backend_info() {
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        assert_eq!(tracer.name(), "PerfPT");
+        let info = tracer.backend_info();
+        assert_eq!(info.name, "PerfPT");
+        let version = info.decoder_version.unwrap();
+        assert!(version.chars().next().unwrap().is_ascii_digit());
+    }
+
+    #[test]
+    fn test_timing() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert!(trace.timing().collection.is_some());
+        assert!(trace.timing().decoding.is_none());
+
+        // Decoding is only timed once it has finished.
+        let mut blocks = trace.iter_blocks();
+        blocks.next().unwrap().unwrap();
+        assert!(trace.timing().decoding.is_none());
+        for b in blocks {
+            b.unwrap();
+        }
+        let decoding = trace.timing().decoding.unwrap();
+
+        // Only the first decoding is timed.
+        assert!(trace.block_count().unwrap() > 0);
+        assert_eq!(trace.timing().decoding.unwrap(), decoding);
+    }
+
+    // Check that the decode cost of a trace is estimated before it is decoded, and is the time
+    // taken by its first decode afterwards.
+    #[test]
+    fn test_estimated_decode_cost() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100));
+        assert!(trace.estimated_decode_cost() > Duration::from_secs(0));
+        assert!(trace.block_count().unwrap() > 0);
+        assert_eq!(
+            trace.estimated_decode_cost(),
+            trace.timing().decoding.unwrap()
+        );
+        assert_eq!(
+            trace.into_shared().unwrap().estimated_decode_cost(),
+            Duration::from_secs(0)
+        );
+    }
+
+    // Check that collection stops once the trace reaches its size limit, leaving a trace which
+    // decodes without error.
+    #[test]
+    fn test_max_bytes() {
+        let mut config = PerfPTConfig::default();
+        config.max_bytes = Some(4096);
+        // Intel PT is x86-only, where pages are 4KiB.
+        let aux_bytes = config.aux_bufsize as u64 * 4096;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100000));
+        assert!(trace.auto_stopped());
+        assert!(!trace.overflowed());
+        let len = trace.raw_data().unwrap().len() as u64;
+        assert!(len >= 4096 && len < 4096 + aux_bytes);
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!blocks.is_empty());
+
+        // The flag survives serialisation.
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        assert!(deserialize_trace(&mut buf.as_slice())
+            .unwrap()
+            .auto_stopped());
+    }
+
+    // Check that a duty-cycled trace decodes with gaps between its segments, that no edge joins
+    // the segments, and that the trace is flagged as such.
+    #[test]
+    fn test_duty_cycle() {
+        let mut config = PerfPTConfig::default();
+        config.duty_cycle(100, 400);
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100000));
+        assert!(trace.duty_cycled());
+        assert!(!trace.overflowed());
+        let mut nblocks = 0;
+        let mut segments = 0;
+        let mut gaps = 0;
+        let mut in_segment = false;
+        for res in trace.iter_blocks() {
+            match res {
+                Ok(_) => {
+                    if !in_segment {
+                        segments += 1;
+                        in_segment = true;
+                    }
+                    nblocks += 1;
+                }
+                Err(HWTracerError::TracingDisabled { at_block }) => {
+                    assert!(in_segment);
+                    assert_eq!(at_block, nblocks);
+                    in_segment = false;
+                    gaps += 1;
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(gaps > 0);
+        let edges = trace.iter_edges().filter(|r| r.is_ok()).count();
+        assert_eq!(edges, nblocks - segments);
+        trace.coverage_bitmap(1 << 16).unwrap();
+
+        // The flag survives serialisation.
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        assert!(deserialize_trace(&mut buf.as_slice())
+            .unwrap()
+            .duty_cycled());
+    }
+
+    #[test]
+    fn test_config_duty_cycle() {
+        for &(on_us, off_us) in &[(0, 100), (100, 0)] {
+            let mut bldr = TracerBuilder::new().perf_pt();
+            match bldr.config() {
+                BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.duty_cycle(on_us, off_us),
+                _ => panic!(),
+            }
+            match bldr.build() {
+                Err(HWTracerError::BadConfig(s)) => {
+                    assert_eq!(s, "the duty cycle periods must be positive")
+                }
+                _ => panic!(),
+            }
+        }
+
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.duty_cycle(100, 100),
+            _ => panic!(),
+        }
+        let tracer = bldr.build().unwrap();
+        match tracer.thread_tracer_on_exec(1) {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    // Check that a stream ends cleanly once the trace reaches its size limit, even though the
+    // tracer is still running.
+    #[test]
+    fn test_max_bytes_streaming() {
+        let mut config = PerfPTConfig::default();
+        config.max_bytes = Some(4096);
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let mut stream = tracer.start_streaming().unwrap();
+        let mut nblocks = 0;
+        for _ in 0..10 {
+            test_helpers::work_loop(10000);
+            for block in stream.by_ref() {
+                block.unwrap();
+                nblocks += 1;
+            }
+        }
+        assert!(nblocks > 0);
+        stream.stop_tracing().unwrap();
+        assert!(stream.next().is_none());
+    }
+
+    // Check that the AUX watermark callback is made as the AUX buffer fills.
+    #[test]
+    fn test_aux_watermark() {
+        let mut config = PerfPTConfig::default();
+        config.aux_bufsize = 2;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = Arc::clone(&calls);
+        config.on_aux_watermark(0.5, move || {
+            calls2.fetch_add(1, Ordering::Relaxed);
+        });
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(100000));
+        assert!(calls.load(Ordering::Relaxed) > 0);
+        assert!(trace.iter_blocks().next().is_some());
+    }
+
+    // Returns the address of `test_helpers::work_loop()`.
+    fn work_loop_addr() -> u64 {
+        test_helpers::work_loop as fn(u64) -> u64 as usize as u64
+    }
+
+    // Check that only the code in a filter range is traced.
+    #[test]
+    fn test_filter_range() {
+        if supported_addr_ranges() == 0 {
+            return;
+        }
+        let start = work_loop_addr();
+        let range = start..start + 16;
+        let mut config = PerfPTConfig::default();
+        config.filter_range(range.start, range.end);
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!blocks.is_empty());
+        assert!(blocks.iter().all(|b| range.contains(&b.first_instr())));
+    }
+
+    #[test]
+    fn test_config_too_many_filter_ranges() {
+        let nranges = supported_addr_ranges() as u64 + 1;
+        let start = work_loop_addr();
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => {
+                for i in 0..nranges {
+                    ppt_conf.filter_range(start + i, start + i + 1);
+                }
+            }
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => assert!(s.contains("address filter ranges")),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_addr_filter() {
+        assert!(addr_filter(&[]).unwrap().is_none());
+        let start = work_loop_addr();
+        let filter = addr_filter(slice::from_ref(&(start..start + 1)))
+            .unwrap()
+            .unwrap();
+        let filter = filter.to_str().unwrap();
+        assert!(filter.starts_with("filter 0x"));
+        assert!(filter.ends_with(env::current_exe().unwrap().to_str().unwrap()));
+        for r in &[start..start, 0x10..0x20] {
+            match addr_filter(slice::from_ref(r)) {
+                Err(HWTracerError::BadConfig(_)) => (),
+                _ => panic!(),
+            }
+        }
+    }
+
+    // Check that code which executes no PTWRITE instructions yields no PTWRITEs, and that the
+    // option is rejected on CPUs which can't trace them.
+    #[test]
+    fn test_ptwrite() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.ptwrite = true,
+            _ => panic!(),
+        }
+        if !ptwrite_supported() {
+            match bldr.build() {
+                Err(HWTracerError::BadConfig(s)) => assert!(s.contains("ptwrite")),
+                _ => panic!(),
+            }
+            return;
+        }
+        let mut tracer = bldr.build().unwrap().thread_tracer();
+        let trace = test_helpers::trace_closure(&mut *tracer, || test_helpers::work_loop(10));
+        assert_eq!(
+            trace
+                .iter_ptwrites()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![]
+        );
+        assert!(trace.iter_blocks().all(|b| b.is_ok()));
+    }
+
+    // Check that each timing mode yields timing information, or is rejected on CPUs which can't
+    // emit its packets.
+    #[test]
+    fn test_timing_modes() {
+        for &(mode, supported) in &[
+            (TimingMode::Tsc, true),
+            (TimingMode::Mtc, mtc_supported()),
+            (TimingMode::Cyc, mtc_supported() && cyc_supported()),
+        ] {
+            let mut config = PerfPTConfig::default();
+            config.timing_mode(mode);
+            let mut bldr = TracerBuilder::new().perf_pt();
+            *bldr.config() = BackendConfig::PerfPT(config);
+            if !supported {
+                match bldr.build() {
+                    Err(HWTracerError::BadConfig(s)) => assert!(s.contains("timing")),
+                    _ => panic!(),
+                }
+                continue;
+            }
+            let mut tracer = bldr.build().unwrap().thread_tracer();
+            let trace = test_helpers::trace_closure(&mut *tracer, || test_helpers::work_loop(10));
+            assert!(trace.has_timing());
+            assert!(trace.iter_timed_blocks().all(|b| b.is_ok()));
+        }
+    }
+
+    #[test]
+    fn test_config_bad_aux_watermark() {
+        for frac in &[0.0, -0.5, 1.5, f64::NAN] {
+            let mut bldr = TracerBuilder::new().perf_pt();
+            match bldr.config() {
+                BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.on_aux_watermark(*frac, || ()),
+                _ => panic!(),
+            }
+            match bldr.build() {
+                Err(HWTracerError::BadConfig(s)) => {
+                    assert_eq!(s, "the AUX watermark must be in the range (0, 1]")
+                }
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_snapshot_aux_watermark() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => {
+                ppt_conf.snapshot = true;
+                ppt_conf.on_aux_watermark(0.5, || ());
+            }
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => {
+                assert_eq!(s, "snapshot mode can't be used with an AUX watermark")
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_config_zero_max_bytes() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.max_bytes = Some(0),
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => assert_eq!(s, "max_bytes must be positive"),
+            _ => panic!(),
+        }
+    }
+
+    // Check that snapshots can be taken whilst tracing continues, and decode without error.
+    #[test]
+    fn test_snapshot() {
+        let mut config = PerfPTConfig::default();
+        config.snapshot = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        tracer.start_tracing().unwrap();
+        test_helpers::work_loop(100);
+        let snap = tracer.snapshot().unwrap();
+        test_helpers::work_loop(100);
+        let snap2 = tracer.snapshot().unwrap();
+        let trace = tracer.stop_tracing().unwrap();
+        for t in &[snap, snap2, trace] {
+            let blocks = t.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+            assert!(!blocks.is_empty());
+            assert!(!t.overflowed());
+        }
+    }
+
+    // Check that a snapshot holds only the most recent trace data once the AUX buffer wraps.
+    #[test]
+    fn test_snapshot_wrapped() {
+        let mut config = PerfPTConfig::default();
+        config.snapshot = true;
+        config.aux_bufsize = 8;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        tracer.start_tracing().unwrap();
+        test_helpers::work_loop(100000);
+        let snap = tracer.snapshot().unwrap();
+        tracer.stop_tracing().unwrap();
+        // Intel PT is x86-only, where pages are 4KiB.
+        assert_eq!(snap.raw_data().unwrap().len(), 8 * 4096);
+        let blocks = snap.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(!blocks.is_empty());
+        let off = snap.sync_offset().unwrap();
+        assert!(snap.raw_data().unwrap()[off..].starts_with(&PSB));
+    }
+
+    #[test]
+    fn test_snapshot_not_configured() {
+        let mut tracer = PerfPTThreadTracer::default();
+        tracer.start_tracing().unwrap();
+        match tracer.snapshot() {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+        tracer.stop_tracing().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_not_started() {
+        let mut config = PerfPTConfig::default();
+        config.snapshot = true;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        match tracer.snapshot() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_config_snapshot_file_sink() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => {
+                ppt_conf.snapshot = true;
+                ppt_conf.sink = TraceSink::File("/tmp/trace".into());
+            }
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => {
+                assert_eq!(s, "snapshot mode can't be used with a file sink")
             }
+            _ => panic!(),
         }
+    }
 
-        (ptxed_args, vdso_tempfile)
+    // Returns the offsets of the PSB packets in the raw trace data `data`.
+    fn psb_offsets(data: &[u8]) -> Vec<usize> {
+        data.windows(PSB.len())
+            .enumerate()
+            .filter(|(_, w)| *w == PSB)
+            .map(|(i, _)| i)
+            .collect()
     }
 
-    /*
-     * Determine if the given x86_64 assembler mnemonic should terminate a block.
-     *
-     * Mnemonic assumed to be lower case.
-     */
-    fn instr_terminates_block(instr: &str) -> bool {
-        assert!(instr.find(|c: char| !c.is_lowercase()).is_none());
-        match instr {
-            // JMP or Jcc are the only instructions beginning with 'j'.
-            m if m.starts_with("j") => true,
-            "call" | "ret" | "loop" | "loope" | "loopne" | "syscall" | "sysenter" | "sysexit"
-            | "sysret" | "xabort" => true,
-            _ => false,
+    // Check that decoding resumes at the next PSB packet after corrupt trace data, but only when
+    // asked to.
+    #[test]
+    fn test_resync_on_error() {
+        // Use the most frequent PSB packets which the CPU supports, to keep the trace small.
+        let mut config = PerfPTConfig::default();
+        let supported = supported_psb_periods();
+        if supported != 0 {
+            config.psb_period = Some(supported.trailing_zeros() as u8);
+        }
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10000));
+        let mut data = trace.raw_data().unwrap().to_vec();
+        let psbs = psb_offsets(&data);
+        assert!(psbs.len() >= 3);
+
+        // Replace the packets between the second and third PSBs with invalid opcodes.
+        for b in &mut data[psbs[1] + 16..psbs[2]] {
+            *b = 0x02;
         }
+        let mut trace = PerfPTTrace::from_bytes(&data, false).unwrap();
+        let got = trace.iter_blocks().collect::<Vec<_>>();
+        assert!(got.last().unwrap().is_err());
+        assert!(!got
+            .iter()
+            .any(|r| matches!(r, Err(HWTracerError::DecodeGap { .. }))));
+        // A decode which stopped part-way through isn't timed.
+        assert!(trace.timing().decoding.is_none());
+
+        trace.resync_on_error = true;
+        let got = trace.iter_blocks().collect::<Vec<_>>();
+        let gap = got
+            .iter()
+            .position(|r| match r {
+                Err(HWTracerError::DecodeGap { skipped_bytes }) => *skipped_bytes > 0,
+                _ => false,
+            })
+            .unwrap();
+        assert!(got[gap + 1..].iter().any(|r| r.is_ok()));
+
+        // The setting survives serialisation.
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert!(trace2
+            .iter_blocks()
+            .any(|r| matches!(r, Err(HWTracerError::DecodeGap { .. }))));
     }
 
-    // Given a trace, use ptxed to get a vector of block start vaddrs.
-    fn get_expected_blocks(trace: &Box<dyn Trace>) -> Vec<Block> {
-        // Write the trace out to a temp file so ptxed can decode it.
-        let mut tmpf = NamedTempFile::new().unwrap();
-        trace.to_file(&mut tmpf.as_file_mut());
-        let (args, _vdso_tempfile) = self_ptxed_args(tmpf.path().to_str().unwrap());
+    // Check that an OVF packet in the trace is reported where it occurs, and that decoding carries
+    // on after it.
+    #[test]
+    fn test_overflow_packet() {
+        let mut config = PerfPTConfig::default();
+        let supported = supported_psb_periods();
+        if supported != 0 {
+            config.psb_period = Some(supported.trailing_zeros() as u8);
+        }
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10000));
+        let mut data = trace.raw_data().unwrap().to_vec();
+        let psbs = psb_offsets(&data);
+        assert!(psbs.len() >= 2);
 
-        let out = Command::new(env!("PTXED")).args(&args).output().unwrap();
-        let outstr = String::from_utf8(out.stdout).unwrap();
-        if !out.status.success() {
-            let errstr = String::from_utf8(out.stderr).unwrap();
-            panic!(
-                "ptxed failed:\nInvocation----------\n{:?}\n \
-                   Stdout\n------\n{}Stderr\n------\n{}",
-                args, outstr, errstr
-            );
+        // Tracing resumes at the PSB+ following the OVF packet.
+        data.splice(psbs[1]..psbs[1], [0x02, 0xf3].iter().cloned());
+        let trace = PerfPTTrace::from_bytes(&data, false).unwrap();
+        let got = trace.iter_blocks().collect::<Vec<_>>();
+        let ovf = got.iter().position(|r| r.is_err()).unwrap();
+        match got[ovf] {
+            Err(HWTracerError::Overflow { at_block }) => assert_eq!(at_block, ovf),
+            _ => panic!(),
         }
+        assert!(got[ovf + 1..].iter().all(|r| r.is_ok()));
+        assert!(ovf + 1 < got.len());
+        let stats = trace.stats();
+        assert_eq!(stats.overflow_events, 1);
+        assert_eq!(stats.decode_errors, 0);
+        assert_eq!(stats.decoded_blocks as usize, got.len() - 1);
+    }
 
-        let mut block_start = false;
-        let mut block_vaddrs = Vec::new();
-        let mut last_instr: Option<&str> = None;
-        for line in outstr.lines() {
-            let line = line.trim();
-            if line.contains("error") {
-                panic!("error line in ptxed output:\n{}", line);
-            } else if line.starts_with("[") {
-                // It's a special line, e.g. [enabled], [disabled], [block]...
-                if line == "[block]"
-                    && (last_instr.is_none() || instr_terminates_block(last_instr.unwrap()))
-                {
-                    // The next insruction we see will be the start of a block.
-                    block_start = true;
-                }
-            } else {
-                // It's a regular instruction line.
-                if block_start {
-                    // This instruction is the start of a block.
-                    let vaddr_s = line.split_whitespace().next().unwrap();
-                    let vaddr = u64::from_str_radix(vaddr_s, 16).unwrap();
-                    block_vaddrs.push(Block::new(vaddr, 0));
-                    block_start = false;
-                }
-                last_instr = Some(line.split_whitespace().nth(1).unwrap());
+    #[test]
+    fn test_config_bad_psb_period() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.psb_period = Some(16),
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => {
+                assert_eq!(s, "psb_period 16 is not supported by this CPU")
             }
+            _ => panic!(),
         }
-
-        block_vaddrs
     }
 
-    // Trace a closure and then decode it and check the block iterator agrees with ptxed.
-    fn trace_and_check_blocks<T, F>(mut tracer: T, f: F)
-    where
-        T: ThreadTracer,
-        F: FnOnce() -> u64,
-    {
-        let trace = test_helpers::trace_closure(&mut tracer, f);
-        let expects = get_expected_blocks(&trace);
-        test_helpers::test_expected_blocks(trace, expects.iter());
+    // Check that tracing works with each PSB period that the CPU supports, and that unsupported
+    // ones are rejected.
+    #[test]
+    fn test_psb_period() {
+        let supported = supported_psb_periods();
+        for psb_period in 0..16 {
+            let mut bldr = TracerBuilder::new().perf_pt();
+            match bldr.config() {
+                BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.psb_period = Some(psb_period),
+                _ => panic!(),
+            }
+            match bldr.build() {
+                Ok(tracer) => {
+                    assert!(supported & (1 << psb_period) != 0);
+                    let mut tt = tracer.thread_tracer();
+                    let trace =
+                        test_helpers::trace_closure(&mut *tt, || test_helpers::work_loop(10));
+                    assert!(trace.iter_blocks().all(|b| b.is_ok()));
+                }
+                Err(HWTracerError::BadConfig(_)) => assert!(supported & (1 << psb_period) == 0),
+                _ => panic!(),
+            }
+        }
     }
 
+    // Check that the ptxed inputs hold the trace and load the code of the current process.
     #[test]
-    fn test_basic_usage() {
-        test_helpers::test_basic_usage(PerfPTThreadTracer::default());
+    fn test_write_ptxed_inputs() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let dir = tempfile::tempdir().unwrap();
+        let inv = trace.write_ptxed_inputs(dir.path()).unwrap();
+        assert!(inv.ptxed.exists());
+
+        let pt_idx = inv.args.iter().position(|a| a == "--pt").unwrap();
+        let data = std::fs::read(&inv.args[pt_idx + 1]).unwrap();
+        assert_eq!(data.as_slice(), trace.raw_data().unwrap());
+
+        // There's a `--raw` for at least the executable and the VDSO.
+        let exe = env::current_exe().unwrap();
+        let raws = inv
+            .args
+            .windows(2)
+            .filter(|w| w[0] == "--raw")
+            .map(|w| w[1].to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert!(raws.iter().any(|r| r.starts_with(exe.to_str().unwrap())));
+        assert!(dir.path().join(PTXED_VDSO_FILENAME).exists());
+
+        let out = inv.command().output().unwrap();
+        assert!(out.status.success());
     }
 
+    #[cfg(feature = "ptxed_verify")]
     #[test]
-    fn test_repeated_tracing() {
-        test_helpers::test_repeated_tracing(PerfPTThreadTracer::default());
+    fn test_verify_against_ptxed() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert_eq!(trace.verify_against_ptxed().unwrap(), None);
     }
 
+    // Check that a trace collected into a file decodes from the file.
     #[test]
-    fn test_already_started() {
-        test_helpers::test_already_started(PerfPTThreadTracer::default());
+    fn test_file_sink() {
+        let tmpf = NamedTempFile::new().unwrap();
+        let mut config = PerfPTConfig::default();
+        config.sink = TraceSink::File(tmpf.path().to_owned());
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert!(trace.block_count().unwrap() > 0);
+        assert_eq!(
+            trace.raw_data().unwrap(),
+            &*std::fs::read(tmpf.path()).unwrap()
+        );
+
+        // The file is replaced when tracing starts again.
+        let trace2 = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        assert_eq!(
+            trace2.raw_data().unwrap(),
+            &*std::fs::read(tmpf.path()).unwrap()
+        );
     }
 
+    // Check that an earlier file-backed trace still decodes once tracing has started again, and
+    // so replaced the file which it maps.
     #[test]
-    fn test_not_started() {
-        test_helpers::test_not_started(PerfPTThreadTracer::default());
+    fn test_file_sink_earlier_trace() {
+        let tmpf = NamedTempFile::new().unwrap();
+        let mut config = PerfPTConfig::default();
+        config.sink = TraceSink::File(tmpf.path().to_owned());
+        let mut tracer = PerfPTThreadTracer::new(config.clone());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+
+        // Another tracer with the same config shares the path.
+        let mut tracer2 = PerfPTThreadTracer::new(config);
+        tracer2.start_tracing().unwrap();
+        tracer.start_tracing().unwrap();
+        test_helpers::work_loop(10);
+        let got = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, blocks);
+        tracer.stop_tracing().unwrap();
+        let trace2 = tracer2.stop_tracing().unwrap();
+        assert!(trace2.block_count().unwrap() > 0);
+        assert_eq!(trace.iter_blocks().count(), blocks.len());
     }
 
-    // Test writing a trace to file.
-    #[cfg(debug_assertions)]
     #[test]
-    fn test_to_file() {
-        use std::fs::File;
-        use std::io::prelude::*;
-        use std::slice;
-        use Trace;
-
-        // Allocate and fill a buffer to make a "trace" from.
-        let capacity = 1024;
-        let mut trace = PerfPTTrace::new(capacity).unwrap();
-        trace.len = capacity as u64;
-        let sl = unsafe { slice::from_raw_parts_mut(trace.buf.0 as *mut u8, capacity) };
-        for (i, byte) in sl.iter_mut().enumerate() {
-            *byte = i as u8;
+    fn test_file_sink_streaming() {
+        let tmpf = NamedTempFile::new().unwrap();
+        let mut config = PerfPTConfig::default();
+        config.sink = TraceSink::File(tmpf.path().to_owned());
+        let mut tracer = PerfPTThreadTracer::new(config);
+        let res = tracer.start_streaming();
+        match res {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
         }
+    }
 
-        // Make the trace and write it to a file.
-        let mut tmpf = NamedTempFile::new().unwrap();
-        trace.to_file(&mut tmpf.as_file_mut());
-        tmpf.as_file().sync_all().unwrap();
+    // Check that a child process is traced from its exec, and not the parent's code.
+    #[test]
+    fn test_spawn_traced() {
+        let tracer = TracerBuilder::new().perf_pt().build().unwrap();
+        let (mut child, mut proc_tracer) =
+            ProcessTracer::spawn_traced(&*tracer, Command::new("true")).unwrap();
+        assert!(child.wait().unwrap().success());
+        let traces = proc_tracer.stop_tracing().unwrap();
+        let trace = &traces[&(child.id() as pid_t)];
+        assert!(!trace.raw_data().unwrap().is_empty());
 
-        // Check the resulting file makes sense.
-        let file = File::open(tmpf.path().to_str().unwrap()).unwrap();
-        let mut total_bytes = 0;
-        for (i, byte) in file.bytes().enumerate() {
-            assert_eq!(i as u8, byte.unwrap());
-            total_bytes += 1;
+        match tracer.thread_tracer_on_exec(0) {
+            Err(HWTracerError::CFailed(libc::ESRCH)) => (),
+            _ => panic!(),
         }
-        assert_eq!(total_bytes, capacity);
     }
 
-    // Check that our block decoder agrees with the reference implementation in ptxed.
     #[test]
-    fn test_block_iterator1() {
-        let tracer = PerfPTThreadTracer::default();
-        trace_and_check_blocks(tracer, || test_helpers::work_loop(10));
+    fn test_streaming_already_started() {
+        let mut tracer = PerfPTThreadTracer::default();
+        tracer.start_tracing().unwrap();
+        match tracer.start_streaming() {
+            Err(HWTracerError::TracerState(TracerState::Started)) => (),
+            _ => panic!(),
+        }
+        tracer.stop_tracing().unwrap();
     }
 
-    // Check that our block decoder agrees ptxed on a (likely) empty trace;
     #[test]
-    fn test_block_iterator2() {
-        let tracer = PerfPTThreadTracer::default();
-        trace_and_check_blocks(tracer, || test_helpers::work_loop(0));
+    fn test_decoder_cache_from_elf() {
+        let exe = env::current_exe().unwrap();
+        assert!(DecoderCache::from_elf(&exe, 0).is_ok());
+
+        // Something that isn't an ELF file.
+        let mut tmpf = NamedTempFile::new().unwrap();
+        tmpf.as_file_mut().write_all(b"not an ELF file").unwrap();
+        match DecoderCache::from_elf(tmpf.path(), 0) {
+            Err(HWTracerError::CFailed(libc::ENOEXEC)) => (),
+            _ => panic!(),
+        }
+
+        match DecoderCache::from_elf(Path::new("/does/not/exist"), 0) {
+            Err(HWTracerError::CFailed(libc::ENOENT)) => (),
+            _ => panic!(),
+        }
     }
 
-    // Check that our block decoder deals with traces involving the VDSO correctly.
-    #[test]
-    fn test_block_iterator3() {
-        use libc::{clock_gettime, timespec, CLOCK_MONOTONIC};
+    // Write a core dump of the executable code of the current process to `file`. There is also a
+    // segment whose contents weren't dumped, as the kernel does for file-backed mappings.
+    fn write_core_dump(file: &mut File) {
+        let mut segs = vec![(0, 0)];
+        for obj in phdrs::objects() {
+            for hdr in obj.iter_phdrs() {
+                if hdr.type_() != PT_LOAD || hdr.flags() & PF_X.0 == 0 {
+                    continue; // Only look at loadable and executable segments.
+                }
+                let len = if obj.name().to_bytes() == VDSO_FILENAME.as_bytes() {
+                    hdr.memsz()
+                } else {
+                    hdr.filesz()
+                };
+                segs.push((obj.addr() + hdr.vaddr(), len));
+            }
+        }
 
-        let tracer = PerfPTThreadTracer::default();
-        trace_and_check_blocks(tracer, || {
-            let mut res = 0;
-            let mut tv = timespec {
-                tv_sec: 0,
-                tv_nsec: 0,
-            };
-            for _ in 1..100 {
-                // clock_gettime(2) is in the Linux VDSO.
-                let rv = unsafe { clock_gettime(CLOCK_MONOTONIC, &mut tv) };
-                assert_eq!(rv, 0);
-                res += tv.tv_sec;
+        const EHDR_SIZE: u16 = 64;
+        const PHDR_SIZE: u16 = 56;
+        let mut hdrs = Vec::new();
+        hdrs.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        hdrs.resize(16, 0);
+        hdrs.extend_from_slice(&4u16.to_le_bytes()); // e_type: ET_CORE.
+        hdrs.extend_from_slice(&62u16.to_le_bytes()); // e_machine: EM_X86_64.
+        hdrs.extend_from_slice(&1u32.to_le_bytes()); // e_version.
+        hdrs.extend_from_slice(&0u64.to_le_bytes()); // e_entry.
+        hdrs.extend_from_slice(&u64::from(EHDR_SIZE).to_le_bytes()); // e_phoff.
+        hdrs.extend_from_slice(&0u64.to_le_bytes()); // e_shoff.
+        hdrs.extend_from_slice(&0u32.to_le_bytes()); // e_flags.
+        hdrs.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+        hdrs.extend_from_slice(&PHDR_SIZE.to_le_bytes());
+        hdrs.extend_from_slice(&u16::try_from(segs.len()).unwrap().to_le_bytes());
+        hdrs.extend_from_slice(&[0; 6]); // No section headers.
+        let mut offset = u64::from(EHDR_SIZE) + u64::from(PHDR_SIZE) * segs.len() as u64;
+        for &(vaddr, len) in &segs {
+            hdrs.extend_from_slice(&1u32.to_le_bytes()); // p_type: PT_LOAD.
+            hdrs.extend_from_slice(&5u32.to_le_bytes()); // p_flags: PF_R | PF_X.
+            hdrs.extend_from_slice(&offset.to_le_bytes());
+            hdrs.extend_from_slice(&vaddr.to_le_bytes());
+            hdrs.extend_from_slice(&0u64.to_le_bytes()); // p_paddr.
+            hdrs.extend_from_slice(&len.to_le_bytes()); // p_filesz.
+            hdrs.extend_from_slice(&len.to_le_bytes()); // p_memsz.
+            hdrs.extend_from_slice(&1u64.to_le_bytes()); // p_align.
+            offset += len;
+        }
+        file.write_all(&hdrs).unwrap();
+
+        for &(vaddr, len) in &segs {
+            let mut cerr = PerfPTCError::new();
+            let len = size_t::try_from(len).unwrap();
+            if !unsafe { dump_vdso(file.as_raw_fd(), vaddr, len, &mut cerr) } {
+                panic!("failed to dump code");
             }
-            res as u64
-        });
+        }
     }
 
-    // Check that a shorter trace yields fewer blocks.
+    // Check that decoding against a core dump gives the same blocks as decoding against the
+    // current process.
     #[test]
-    fn test_block_iterator4() {
-        let tracer1 = PerfPTThreadTracer::default();
-        let tracer2 = PerfPTThreadTracer::default();
-        test_helpers::test_ten_times_as_many_blocks(tracer1, tracer2);
+    fn test_decoder_cache_from_core_dump() {
+        let mut core = NamedTempFile::new().unwrap();
+        write_core_dump(core.as_file_mut());
+        let cache = DecoderCache::from_core_dump(core.path()).unwrap();
+
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let expect = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        let got = cache
+            .decode(&*trace)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, expect);
     }
 
-    // Check that our block decoder agrees ptxed on long trace.
-    // XXX We use an even higher iteration count once our decoder uses a libipt image cache.
-    #[ignore] // Decoding long traces is slow.
     #[test]
-    fn test_block_iterator5() {
-        let tracer = PerfPTThreadTracer::default();
-        trace_and_check_blocks(tracer, || test_helpers::work_loop(3000));
+    fn test_decoder_cache_from_core_dump_errors() {
+        // An ELF file which isn't a core dump.
+        let exe = env::current_exe().unwrap();
+        match DecoderCache::from_core_dump(&exe) {
+            Err(HWTracerError::CFailed(libc::ENOEXEC)) => (),
+            _ => panic!(),
+        }
+
+        match DecoderCache::from_core_dump(Path::new("/does/not/exist")) {
+            Err(HWTracerError::CFailed(libc::ENOENT)) => (),
+            _ => panic!(),
+        }
     }
 
-    // Check that a long trace causes the trace buffer to reallocate.
+    // Without any code to decode against, decoding fails at the first block.
     #[test]
-    fn test_relloc_trace_buf1() {
-        let start_bufsize = 512;
-        let mut config = PerfPTConfig::default();
-        config.initial_trace_bufsize = start_bufsize;
-        let mut tracer = PerfPTThreadTracer::new(config);
+    fn test_trace_decoder_no_images() {
+        let mut tracer = PerfPTThreadTracer::new(PerfPTConfig::default());
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let first = trace.iter_blocks().next().unwrap().unwrap();
+        let decoder = TraceDecoder::new();
+        let err = decoder.decode(&*trace).find(|b| b.is_err());
+        match err {
+            Some(Err(HWTracerError::MissingMapping { vaddr })) => {
+                assert_eq!(vaddr, first.first_instr())
+            }
+            _ => panic!(),
+        }
+    }
 
+    // Traces without raw data can't be decoded.
+    #[test]
+    fn test_trace_decoder_no_raw_data() {
+        let mut tracer = TracerBuilder::new()
+            .dummy()
+            .build()
+            .unwrap()
+            .thread_tracer();
         tracer.start_tracing().unwrap();
-        let res = test_helpers::work_loop(10000);
         let trace = tracer.stop_tracing().unwrap();
-
-        println!("res: {}", res); // Stop over-optimisation.
-        assert!(trace.capacity() > start_bufsize);
+        let mut itr = TraceDecoder::new().decode(&*trace);
+        match itr.next() {
+            Some(Err(HWTracerError::UnsupportedOperation(_))) => (),
+            _ => panic!(),
+        }
+        assert!(itr.next().is_none());
     }
 
     // Check that a block iterator returns none after an error.
@@ -764,13 +5045,7 @@ mod tests {
     fn test_error_stops_block_iter1() {
         // A zero-sized trace will lead to an error.
         let trace = PerfPTTrace::new(0).unwrap();
-        let mut itr = PerfPTBlockIterator {
-            decoder: ptr::null_mut(),
-            decoder_status: 0,
-            vdso_tempfile: None,
-            trace: &trace,
-            errored: false,
-        };
+        let mut itr = PerfPTBlockIterator::new(&trace);
 
         // First we expect a libipt error.
         match itr.next() {
@@ -814,4 +5089,111 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_config_exclude_everything() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.exclude_user = true,
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => {
+                assert_eq!(s, "exclude_kernel and exclude_user can't both be set");
+            }
+            _ => panic!(),
+        }
+    }
+
+    // By default, only user-space code (in the lower half of the address space) is traced.
+    #[test]
+    fn test_exclude_kernel() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        for b in trace.iter_blocks() {
+            assert!(b.unwrap().first_instr() < 0x8000_0000_0000_0000);
+        }
+    }
+
+    // Tracing the kernel requires privileges, the lack of which must be reported as such.
+    #[test]
+    fn test_trace_kernel() {
+        let mut config = PerfPTConfig::default();
+        config.exclude_kernel = false;
+        let mut tracer = PerfPTThreadTracer::new(config);
+        match tracer.start_tracing() {
+            Ok(()) => {
+                tracer.stop_tracing().unwrap();
+            }
+            Err(HWTracerError::Permission { .. }) => (),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // Check that C errors describing why tracing can't start become typed errors.
+    #[test]
+    fn test_cerror_kinds() {
+        let mut cerr = PerfPTCError::new();
+        cerr.typ = PerfPTCErrorKind::Permissions;
+        cerr.code = libc::EACCES;
+        match HWTracerError::from(cerr) {
+            HWTracerError::Permission { errno, hint } => {
+                assert_eq!(errno, libc::EACCES);
+                assert!(hint.contains(PERF_PERMS_PATH));
+            }
+            _ => panic!(),
+        }
+
+        let mut cerr = PerfPTCError::new();
+        cerr.typ = PerfPTCErrorKind::NoHWSupport;
+        cerr.code = libc::ENOENT;
+        match HWTracerError::from(cerr) {
+            HWTracerError::Unsupported {
+                kind: BackendKind::PerfPT,
+                reason,
+            } => assert!(reason.contains("not supported by the kernel")),
+            _ => panic!(),
+        }
+
+        let mut cerr = PerfPTCError::new();
+        cerr.typ = PerfPTCErrorKind::NoPerfEvents;
+        cerr.code = libc::ENOSYS;
+        match HWTracerError::from(cerr) {
+            HWTracerError::Unsupported {
+                kind: BackendKind::PerfPT,
+                reason,
+            } => assert!(reason.contains("without perf_events")),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_config_zero_data_bufsize() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.data_bufsize = 0,
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => {
+                assert_eq!(s, "data_bufsize must be a positive power of 2");
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_config_zero_aux_bufsize() {
+        let mut bldr = TracerBuilder::new().perf_pt();
+        match bldr.config() {
+            BackendConfig::PerfPT(ref mut ppt_conf) => ppt_conf.aux_bufsize = 0,
+            _ => panic!(),
+        }
+        match bldr.build() {
+            Err(HWTracerError::BadConfig(s)) => {
+                assert_eq!(s, "aux_bufsize must be a positive power of 2");
+            }
+            _ => panic!(),
+        }
+    }
 }
@@ -0,0 +1,480 @@
+//! Writing Intel PT traces as `perf.data` files, for analysis with Linux perf tools. See
+//! `Trace::write_perf_data()`.
+//!
+//! The file holds what `perf record -e intel_pt//u` would have recorded for a single thread in
+//! per-thread mode: the attributes of the event, a `PERF_RECORD_AUXTRACE_INFO` record describing
+//! the Intel PT configuration, `PERF_RECORD_COMM` and `PERF_RECORD_MMAP2` records describing the
+//! thread and the code it may have run, and the trace data in a `PERF_RECORD_AUXTRACE` record.
+//! All integers are in native byte order, as perf expects.
+
+use crate::backends::TimingMode;
+use crate::errors::HWTracerError;
+use crate::maps::{self, MapsEntry};
+use core::arch::x86_64::__cpuid;
+use libc::{getpid, pid_t};
+use std::fs;
+use std::io::{self, Write};
+
+// The file describing the perf event type of Intel PT.
+const PT_TYPE_PATH: &str = "/sys/bus/event_source/devices/intel_pt/type";
+// The file giving the CPU's maximum non-turbo frequency ratio, if the kernel knows it.
+const PT_MAX_NONTURBO_RATIO_PATH: &str =
+    "/sys/bus/event_source/devices/intel_pt/max_nonturbo_ratio";
+// The file giving the name of the current process.
+const COMM_PATH: &str = "/proc/self/comm";
+
+const MAGIC: &[u8; 8] = b"PERFILE2";
+// The sizes of `struct perf_file_header` and `struct perf_file_section`.
+const FILE_HEADER_SIZE: u64 = 104;
+const FILE_SECTION_SIZE: u64 = 16;
+// The size of the `struct perf_event_attr` we write (`PERF_ATTR_SIZE_VER5`).
+const ATTR_SIZE: u64 = 112;
+
+// Record types.
+const PERF_RECORD_COMM: u32 = 3;
+const PERF_RECORD_MMAP2: u32 = 10;
+const PERF_RECORD_FINISHED_ROUND: u32 = 68;
+const PERF_RECORD_AUXTRACE_INFO: u32 = 70;
+const PERF_RECORD_AUXTRACE: u32 = 71;
+const PERF_RECORD_MISC_USER: u16 = 2;
+// The `type` of a `PERF_RECORD_AUXTRACE_INFO` record describing Intel PT.
+const PERF_AUXTRACE_INTEL_PT: u32 = 1;
+// The sizes of the fixed parts of each kind of record, including the 8-byte header.
+const AUXTRACE_INFO_SIZE: usize = 16;
+const AUXTRACE_SIZE: usize = 48;
+
+// The sample fields of the event: each non-sample record ends with the pid, tid and time.
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+// The flags of `struct perf_event_attr` we set: exclude_hv, mmap, comm, sample_id_all, mmap2.
+const ATTR_FLAGS: u64 = 1 << 6 | 1 << 8 | 1 << 9 | 1 << 18 | 1 << 23;
+
+// The bits of `perf_event_attr.config` which configure Intel PT timing packets and return
+// compression. See
+// /sys/bus/event_source/devices/intel_pt/format/{cyc,mtc,tsc,noretcomp,mtc_period}.
+const CONFIG_CYC: u64 = 1 << 1;
+const CONFIG_MTC: u64 = 1 << 9;
+const CONFIG_TSC: u64 = 1 << 10;
+const CONFIG_NORETCOMP: u64 = 1 << 11;
+const CONFIG_MTC_PERIOD: u64 = 0xf << 14;
+const CONFIG_MTC_PERIOD_SHIFT: u64 = 14;
+
+// The number of `u64`s of Intel PT information in a `PERF_RECORD_AUXTRACE_INFO` record, up to and
+// including the (empty) address filter string.
+const INTEL_PT_PRIV_LEN: usize = 17;
+
+/// Returns the `perf_event_attr.config` with which Intel PT packets were collected in `timing`
+/// mode, with the MTC period encoding `mtc_period`, and with or without `ret_compression`.
+pub(super) fn attr_config(timing: TimingMode, mtc_period: u32, ret_compression: bool) -> u64 {
+    let mut config = 0;
+    if timing >= TimingMode::Tsc {
+        config |= CONFIG_TSC;
+    }
+    if timing >= TimingMode::Mtc {
+        config |= CONFIG_MTC | u64::from(mtc_period) << CONFIG_MTC_PERIOD_SHIFT;
+    }
+    if timing >= TimingMode::Cyc {
+        config |= CONFIG_CYC;
+    }
+    if !ret_compression {
+        config |= CONFIG_NORETCOMP;
+    }
+    config
+}
+
+/// Write a `perf.data` file to `w` holding the Intel PT packets `data`, which were collected
+/// from the thread `tid` of the current process with the `perf_event_attr.config` `config` (see
+/// `attr_config()`). If `tid` is 0, then the thread is assumed to be the main thread.
+pub(super) fn write_perf_data(
+    w: &mut dyn Write,
+    data: &[u8],
+    tid: pid_t,
+    config: u64,
+) -> Result<(), HWTracerError> {
+    let pt_type = fs::read_to_string(PT_TYPE_PATH)?.trim().parse::<u32>()?;
+    let pid = unsafe { getpid() };
+    let tid = if tid == 0 { pid } else { tid };
+
+    let mut records = Vec::new();
+    auxtrace_info_record(&mut records, pt_type);
+    // The thread may have exited since it was traced, in which case it is named after the process.
+    let comm = match fs::read_to_string(format!("/proc/self/task/{}/comm", tid)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::read_to_string(COMM_PATH)?,
+        res => res?,
+    };
+    comm_record(&mut records, pid, tid, comm.trim_end());
+    for entry in maps::self_map_entries()? {
+        mmap2_record(&mut records, pid, tid, &entry);
+    }
+    // The trace data is padded with PAD packets (zero bytes) to a multiple of 8 bytes.
+    let padding = (8 - data.len() % 8) % 8;
+    let padded_len = (data.len() + padding) as u64;
+    let data_size = records.len() as u64 + AUXTRACE_SIZE as u64 + padded_len + 8;
+
+    // The file header, and the attributes of the event, which has no IDs.
+    let attrs_offset = FILE_HEADER_SIZE;
+    let attrs_size = ATTR_SIZE + FILE_SECTION_SIZE;
+    let data_offset = attrs_offset + attrs_size;
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    for n in &[
+        FILE_HEADER_SIZE,
+        attrs_size,
+        attrs_offset,
+        attrs_size,
+        data_offset,
+        data_size,
+        0, // The unused event types section.
+        0,
+        0, // No optional features.
+        0,
+        0,
+        0,
+    ] {
+        header.extend_from_slice(&n.to_ne_bytes());
+    }
+    attr(&mut header, pt_type, config);
+    header.extend_from_slice(&[0; FILE_SECTION_SIZE as usize]);
+    w.write_all(&header)?;
+    w.write_all(&records)?;
+
+    // The trace data, and a marker telling perf that it can process the records so far.
+    let mut auxtrace = Vec::with_capacity(AUXTRACE_SIZE);
+    record_header(&mut auxtrace, PERF_RECORD_AUXTRACE, 0, AUXTRACE_SIZE);
+    auxtrace.extend_from_slice(&padded_len.to_ne_bytes());
+    auxtrace.extend_from_slice(&0u64.to_ne_bytes()); // The offset in the AUX data stream.
+    auxtrace.extend_from_slice(&0u64.to_ne_bytes()); // The reference, used only for snapshots.
+    auxtrace.extend_from_slice(&0u32.to_ne_bytes()); // The index of the AUX buffer.
+    auxtrace.extend_from_slice(&(tid as u32).to_ne_bytes());
+    auxtrace.extend_from_slice(&u32::MAX.to_ne_bytes()); // Any CPU.
+    auxtrace.extend_from_slice(&0u32.to_ne_bytes());
+    w.write_all(&auxtrace)?;
+    w.write_all(data)?;
+    w.write_all(&[0; 8][..padding])?;
+    let mut finished = Vec::new();
+    record_header(&mut finished, PERF_RECORD_FINISHED_ROUND, 0, 8);
+    w.write_all(&finished)?;
+    Ok(())
+}
+
+// Append a `struct perf_event_attr` for the Intel PT event type `pt_type` to `buf`.
+fn attr(buf: &mut Vec<u8>, pt_type: u32, config: u64) {
+    buf.extend_from_slice(&pt_type.to_ne_bytes());
+    buf.extend_from_slice(&(ATTR_SIZE as u32).to_ne_bytes());
+    for n in &[
+        config,
+        1, // sample_period
+        PERF_SAMPLE_TID | PERF_SAMPLE_TIME,
+        0, // read_format
+        ATTR_FLAGS,
+    ] {
+        buf.extend_from_slice(&n.to_ne_bytes());
+    }
+    // The remaining fields (wakeup_events onwards) are unused.
+    buf.resize(buf.len() + ATTR_SIZE as usize - 48, 0);
+}
+
+// Append the header of a record of type `typ`, whose total size is `size` bytes, to `buf`.
+fn record_header(buf: &mut Vec<u8>, typ: u32, misc: u16, size: usize) {
+    buf.extend_from_slice(&typ.to_ne_bytes());
+    buf.extend_from_slice(&misc.to_ne_bytes());
+    buf.extend_from_slice(&(size as u16).to_ne_bytes());
+}
+
+// Append `s` to `buf` as a NUL-terminated string, padded with NULs to a multiple of 8 bytes.
+fn padded_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(s);
+    buf.resize(buf.len() + 8 - s.len() % 8, 0);
+}
+
+// Append the sample ID which ends each non-sample record (see `PERF_SAMPLE_TID` and
+// `PERF_SAMPLE_TIME`) to `buf`. All of the records precede the trace, so their time is 0.
+fn sample_id(buf: &mut Vec<u8>, pid: pid_t, tid: pid_t) {
+    buf.extend_from_slice(&(pid as u32).to_ne_bytes());
+    buf.extend_from_slice(&(tid as u32).to_ne_bytes());
+    buf.extend_from_slice(&0u64.to_ne_bytes());
+}
+
+// Append a `PERF_RECORD_AUXTRACE_INFO` record describing the Intel PT configuration to `buf`. The
+// layout of the information is that of `enum intel_pt_info_pos` in perf's `intel-pt.h`.
+fn auxtrace_info_record(buf: &mut Vec<u8>, pt_type: u32) {
+    // The ratio of the TSC to the crystal clock, from which perf computes time from MTC packets.
+    let (ctc_d, ctc_n) = if unsafe { __cpuid(0) }.eax >= 0x15 {
+        let res = unsafe { __cpuid(0x15) };
+        (res.eax, res.ebx)
+    } else {
+        (0, 0)
+    };
+    let max_nonturbo_ratio = fs::read_to_string(PT_MAX_NONTURBO_RATIO_PATH)
+        .ok()
+        .and_then(|r| r.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    let info: [u64; INTEL_PT_PRIV_LEN] = [
+        u64::from(pt_type),
+        // Perf time is converted from the TSC with a multiplier of 1, so timestamps are TSC
+        // values.
+        0, // time_shift
+        1, // time_mult
+        0, // time_zero
+        0, // cap_user_time_zero
+        CONFIG_TSC,
+        CONFIG_NORETCOMP,
+        0, // have_sched_switch
+        0, // snapshot_mode
+        0, // per_cpu_mmaps
+        CONFIG_MTC,
+        CONFIG_MTC_PERIOD,
+        u64::from(ctc_n),
+        u64::from(ctc_d),
+        CONFIG_CYC,
+        max_nonturbo_ratio,
+        0, // The length of the address filter string.
+    ];
+    record_header(
+        buf,
+        PERF_RECORD_AUXTRACE_INFO,
+        0,
+        AUXTRACE_INFO_SIZE + INTEL_PT_PRIV_LEN * 8,
+    );
+    buf.extend_from_slice(&PERF_AUXTRACE_INTEL_PT.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    for n in &info {
+        buf.extend_from_slice(&n.to_ne_bytes());
+    }
+}
+
+// Append a `PERF_RECORD_COMM` record naming the thread `tid` of the process `pid` to `buf`.
+fn comm_record(buf: &mut Vec<u8>, pid: pid_t, tid: pid_t, comm: &str) {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(pid as u32).to_ne_bytes());
+    body.extend_from_slice(&(tid as u32).to_ne_bytes());
+    padded_str(&mut body, comm.as_bytes());
+    sample_id(&mut body, pid, tid);
+    record_header(buf, PERF_RECORD_COMM, PERF_RECORD_MISC_USER, body.len() + 8);
+    buf.extend_from_slice(&body);
+}
+
+// Append a `PERF_RECORD_MMAP2` record to `buf` for the mapping `entry` of the current process, if
+// it's executable and has a name.
+fn mmap2_record(buf: &mut Vec<u8>, pid: pid_t, tid: pid_t, entry: &MapsEntry) {
+    if !entry.perms.contains('x') || entry.path.is_empty() {
+        return;
+    }
+    let prot = entry
+        .perms
+        .chars()
+        .zip(&[libc::PROT_READ, libc::PROT_WRITE, libc::PROT_EXEC])
+        .filter(|(c, _)| *c != '-')
+        .fold(0, |prot, (_, p)| prot | p);
+    let flags = match entry.perms.ends_with('s') {
+        true => libc::MAP_SHARED,
+        false => libc::MAP_PRIVATE,
+    };
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(pid as u32).to_ne_bytes());
+    body.extend_from_slice(&(tid as u32).to_ne_bytes());
+    body.extend_from_slice(&entry.start.to_ne_bytes());
+    body.extend_from_slice(&(entry.end - entry.start).to_ne_bytes());
+    body.extend_from_slice(&entry.offset.to_ne_bytes());
+    body.extend_from_slice(&entry.dev.0.to_ne_bytes());
+    body.extend_from_slice(&entry.dev.1.to_ne_bytes());
+    body.extend_from_slice(&entry.inode.to_ne_bytes());
+    body.extend_from_slice(&0u64.to_ne_bytes()); // The inode generation.
+    body.extend_from_slice(&(prot as u32).to_ne_bytes());
+    body.extend_from_slice(&(flags as u32).to_ne_bytes());
+    padded_str(&mut body, entry.path.as_bytes());
+    sample_id(&mut body, pid, tid);
+    record_header(
+        buf,
+        PERF_RECORD_MMAP2,
+        PERF_RECORD_MISC_USER,
+        body.len() + 8,
+    );
+    buf.extend_from_slice(&body);
+}
+
+#[cfg(all(perf_pt_test, test))]
+mod tests {
+    use super::{
+        attr_config, mmap2_record, ATTR_SIZE, AUXTRACE_SIZE, CONFIG_CYC, CONFIG_MTC,
+        CONFIG_NORETCOMP, CONFIG_TSC, FILE_HEADER_SIZE, MAGIC, PERF_RECORD_AUXTRACE,
+        PERF_RECORD_FINISHED_ROUND, PERF_RECORD_MMAP2,
+    };
+    use crate::backends::{perf_pt::PerfPTThreadTracer, TimingMode};
+    use crate::maps::MapsEntry;
+    use crate::test_helpers;
+    use std::collections::HashSet;
+    use std::convert::TryInto;
+    use std::env;
+    use std::process::Command;
+    use std::thread;
+
+    fn read_u32(buf: &[u8], off: usize) -> u32 {
+        u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap())
+    }
+
+    fn read_u64(buf: &[u8], off: usize) -> u64 {
+        u64::from_ne_bytes(buf[off..off + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_attr_config() {
+        assert_eq!(attr_config(TimingMode::None, 3, true), 0);
+        assert_eq!(attr_config(TimingMode::Tsc, 3, true), CONFIG_TSC);
+        assert_eq!(
+            attr_config(TimingMode::Cyc, 3, true),
+            CONFIG_TSC | CONFIG_MTC | 3 << 14 | CONFIG_CYC
+        );
+        assert_eq!(attr_config(TimingMode::None, 3, false), CONFIG_NORETCOMP);
+    }
+
+    // Make a mapping with the parts of `/proc/self/maps` that matter here.
+    fn entry(perms: &str, inode: u64, path: &str) -> MapsEntry {
+        MapsEntry {
+            start: 0x7f0000001000,
+            end: 0x7f0000003000,
+            perms: perms.to_owned(),
+            offset: 0x2000,
+            dev: (0xfd, 1),
+            inode,
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_mmap2_record() {
+        let mut buf = Vec::new();
+        mmap2_record(&mut buf, 1, 2, &entry("r-xp", 1234, "/usr/lib/libc.so.6"));
+        assert_eq!(read_u32(&buf, 0), PERF_RECORD_MMAP2);
+        assert_eq!(usize::from(u16::from_ne_bytes([buf[6], buf[7]])), buf.len());
+        assert_eq!(read_u64(&buf, 16), 0x7f0000001000);
+        assert_eq!(read_u64(&buf, 24), 0x2000);
+        assert_eq!(read_u64(&buf, 32), 0x2000);
+        assert_eq!((read_u32(&buf, 40), read_u32(&buf, 44)), (0xfd, 1));
+        assert_eq!(read_u64(&buf, 48), 1234);
+        assert!(buf[72..].starts_with(b"/usr/lib/libc.so.6\0"));
+
+        // Mappings which aren't executable, or have no name, are skipped.
+        let mut buf = Vec::new();
+        mmap2_record(&mut buf, 1, 2, &entry("rw-p", 0, "[heap]"));
+        mmap2_record(&mut buf, 1, 2, &entry("r-xp", 0, ""));
+        assert!(buf.is_empty());
+    }
+
+    // Check that the trace data and the mapping of the executable are in the file.
+    #[test]
+    fn test_write_perf_data() {
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("perf.data");
+        trace.write_perf_data(&path).unwrap();
+        let buf = std::fs::read(&path).unwrap();
+
+        assert_eq!(&buf[..8], MAGIC);
+        assert_eq!(read_u64(&buf, 8), FILE_HEADER_SIZE);
+        assert_eq!(read_u64(&buf, 16), ATTR_SIZE + 16);
+        let data_off = read_u64(&buf, 40) as usize;
+        let data_size = read_u64(&buf, 48) as usize;
+        assert_eq!(data_off + data_size, buf.len());
+
+        let exe = env::current_exe().unwrap();
+        let mut found_exe = false;
+        let mut found_data = false;
+        let mut off = data_off;
+        while off < buf.len() {
+            let typ = read_u32(&buf, off);
+            let size = usize::from(u16::from_ne_bytes([buf[off + 6], buf[off + 7]]));
+            assert_eq!(size % 8, 0);
+            if typ == PERF_RECORD_MMAP2 {
+                found_exe |= buf[off + 72..].starts_with(exe.to_str().unwrap().as_bytes());
+                off += size;
+            } else if typ == PERF_RECORD_AUXTRACE {
+                let len = read_u64(&buf, off + 8) as usize;
+                let data = trace.raw_data().unwrap();
+                let got = &buf[off + AUXTRACE_SIZE..off + AUXTRACE_SIZE + len];
+                assert_eq!(&got[..data.len()], data);
+                assert!(got[data.len()..].iter().all(|&b| b == 0));
+                found_data = true;
+                off += size + len;
+            } else {
+                if typ == PERF_RECORD_FINISHED_ROUND {
+                    assert_eq!(off + size, buf.len());
+                }
+                off += size;
+            }
+        }
+        assert_eq!(off, buf.len());
+        assert!(found_exe);
+        assert!(found_data);
+    }
+
+    // Check that the thread is named after itself, rather than after the main thread.
+    #[test]
+    fn test_write_perf_data_comm() {
+        let name = "perf_data_comm";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("perf.data");
+        let thread_path = path.clone();
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let mut tracer = PerfPTThreadTracer::default();
+                let trace =
+                    test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+                trace.write_perf_data(&thread_path).unwrap();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        let buf = std::fs::read(&path).unwrap();
+        let mut comm = name.as_bytes().to_vec();
+        comm.push(0);
+        assert!(buf.windows(comm.len()).any(|w| w == &comm[..]));
+    }
+
+    // Check that perf decodes the file to the same blocks as hwtracer. Skipped if perf isn't
+    // installed.
+    #[test]
+    fn test_perf_script() {
+        match Command::new("perf").arg("--version").output() {
+            Ok(out) if out.status.success() => (),
+            _ => return,
+        }
+        let mut tracer = PerfPTThreadTracer::default();
+        let trace = test_helpers::trace_closure(&mut tracer, || test_helpers::work_loop(10));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("perf.data");
+        trace.write_perf_data(&path).unwrap();
+
+        // Have perf report the address of every instruction in the trace.
+        let out = Command::new("perf")
+            .arg("script")
+            .arg("-i")
+            .arg(&path)
+            .arg("--itrace=i1i")
+            .arg("-F")
+            .arg("ip")
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let ips = String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|l| u64::from_str_radix(l.trim(), 16).ok())
+            .collect::<HashSet<_>>();
+        let mut nblocks = 0;
+        for b in trace.iter_blocks() {
+            let b = b.unwrap();
+            assert!(ips.contains(&b.first_instr()));
+            assert!(ips.contains(&b.last_instr()));
+            nblocks += 1;
+        }
+        assert!(nblocks > 0);
+    }
+}
@@ -52,6 +52,10 @@ enum perf_pt_cerror_kind {
     perf_pt_cerror_unknown,
     perf_pt_cerror_errno,
     perf_pt_cerror_ipt,
+    perf_pt_cerror_no_hw_support, // `code` is the errno explaining why.
+    perf_pt_cerror_perms,         // `code` is the errno explaining why.
+    perf_pt_cerror_no_perf_events, // The kernel was built without perf
+                                   // events. `code` is the errno.
 };
 
 struct perf_pt_cerror {
@@ -59,6 +63,109 @@ struct perf_pt_cerror {
     int code;                      // The error code itself.
 };
 
+// The kind of control flow transfer ending a block. Must stay in sync with the
+// Rust code.
+enum perf_pt_edge_kind {
+    perf_pt_edge_call,
+    perf_pt_edge_return,
+    perf_pt_edge_jump,
+    perf_pt_edge_cond_jump,
+    perf_pt_edge_far_call,
+    perf_pt_edge_far_return,
+    perf_pt_edge_far_jump,
+    perf_pt_edge_unknown,
+};
+
+// How the IP of a TIP packet is compressed. Must stay in sync with the Rust
+// code.
+enum perf_pt_ip_compression {
+    perf_pt_ipc_suppressed,
+    perf_pt_ipc_update_16,
+    perf_pt_ipc_update_32,
+    perf_pt_ipc_sext_48,
+    perf_pt_ipc_update_48,
+    perf_pt_ipc_full,
+};
+
+// The kinds of packet returned by perf_pt_next_packet(). Must stay in sync
+// with the Rust code.
+enum perf_pt_packet_kind {
+    perf_pt_packet_eos,       // Not a packet: the end of the packet stream.
+    perf_pt_packet_psb,
+    perf_pt_packet_psbend,
+    perf_pt_packet_pad,
+    perf_pt_packet_ovf,
+    perf_pt_packet_tnt,
+    perf_pt_packet_tip,
+    perf_pt_packet_tip_pge,
+    perf_pt_packet_tip_pgd,
+    perf_pt_packet_fup,
+    perf_pt_packet_mode_exec,
+    perf_pt_packet_mode_tsx,
+    perf_pt_packet_tsc,
+    perf_pt_packet_cyc,
+    perf_pt_packet_mtc,
+    perf_pt_packet_cbr,
+    perf_pt_packet_ptw,
+    perf_pt_packet_other,
+};
+
+// A packet, as returned by perf_pt_next_packet(). Must stay in sync with the
+// Rust code.
+struct perf_pt_packet {
+    enum perf_pt_packet_kind kind;
+    enum perf_pt_ip_compression ipc; // The compression of an IP packet.
+    uint64_t payload;                // The IP, TNT bits, or value of a TSC, CYC,
+                                     // MTC, CBR or PTW packet. For a MODE
+                                     // packet, bit 0 is CSL or InTX, and bit 1
+                                     // CSD or TXAbort.
+    uint8_t count;                   // The number of bits of a TNT packet. For
+                                     // a PTW packet, 1 if a FUP packet follows
+                                     // with the IP of the PTWRITE.
+};
+
+// The vendor of a CPU. Must stay in sync with the Rust code.
+enum perf_pt_cpu_vendor {
+    perf_pt_cpu_unknown,
+    perf_pt_cpu_intel,
+};
+
+// The kind of CPU which collected a trace. Must stay in sync with the Rust
+// code.
+struct perf_pt_cpu {
+    enum perf_pt_cpu_vendor vendor;
+    uint16_t family;
+    uint8_t model;
+    uint8_t stepping;
+};
+
+// CPU errata for the decoder to work around, overriding those which libipt
+// would choose for the CPU. Each field is named after the erratum. Must stay in
+// sync with the Rust code.
+struct perf_pt_errata {
+    bool bdm70;
+    bool bdm64;
+    bool skd007;
+    bool skd022;
+    bool skd010;
+    bool skl014;
+    bool apl12;
+    bool apl11;
+};
+
+// A file to load into a libipt image. Must stay in sync with the Rust code.
+struct perf_pt_image_file {
+    char *filename;  // The file containing the code.
+    uint64_t vaddr;  // The virtual address at which the code is loaded.
+    uint64_t size;   // The size of the code (in bytes).
+    uint64_t offset; // The offset of the code within the file.
+};
+
+// The MTC period encoding used when emitting MTC packets: one packet every
+// 2^PERF_PT_MTC_PERIOD crystal clock ticks, as perf does by default. Must stay
+// in sync with the Rust code.
+#define PERF_PT_MTC_PERIOD 3
+
 bool dump_vdso(int, uint64_t, size_t, struct perf_pt_cerror *);
 void perf_pt_set_err(struct perf_pt_cerror *, int, int);
 
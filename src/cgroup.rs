@@ -0,0 +1,141 @@
+//! Tracing all of the tasks in a cgroup.
+
+use crate::{errors::HWTracerError, tracer_set::TracerSet, Trace, Tracer};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::io::RawFd;
+
+// The sysfs file listing the online CPUs.
+const ONLINE_CPUS_PATH: &str = "/sys/devices/system/cpu/online";
+
+/// Traces every task in a cgroup.
+///
+/// Obtained from [cgroup_tracer](trait.Tracer.html#method.cgroup_tracer). The kernel only traces
+/// a cgroup one CPU at a time, so there is a trace per CPU which was online when tracing started,
+/// recording whichever of the cgroup's tasks ran on that CPU. Tasks can't be told apart within a
+/// trace, and a task which migrates between CPUs appears in several traces. The tasks may belong
+/// to other programs, so the traces must be decoded against those programs' code (e.g. with
+/// `backends::perf_pt::TraceDecoder`), not that of the current process.
+///
+/// Dropping a `CgroupTracer` which is still tracing stops the tracer and discards the traces.
+pub struct CgroupTracer<'a> {
+    tracer: &'a dyn Tracer,
+    // A file descriptor for the cgroup's directory in the cgroup filesystem.
+    cgroup_fd: RawFd,
+    // The tracers of the CPUs being traced, keyed by CPU number.
+    cpu_tracers: TracerSet<usize>,
+}
+
+impl<'a> CgroupTracer<'a> {
+    pub(crate) fn new(tracer: &'a dyn Tracer, cgroup_fd: RawFd) -> Self {
+        Self {
+            tracer,
+            cgroup_fd,
+            cpu_tracers: TracerSet::new(),
+        }
+    }
+
+    /// Start tracing the cgroup on every online CPU.
+    pub fn start_tracing(&mut self) -> Result<(), HWTracerError> {
+        let (tracer, cgroup_fd) = (self.tracer, self.cgroup_fd);
+        self.cpu_tracers.start_tracing(
+            || parse_cpu_list(&fs::read_to_string(ONLINE_CPUS_PATH)?),
+            |&cpu| tracer.cgroup_cpu_tracer(cgroup_fd, cpu),
+            |_| false,
+        )
+    }
+
+    /// Returns `true` if the tracer has been started and not yet stopped.
+    pub fn is_tracing(&self) -> bool {
+        self.cpu_tracers.is_tracing()
+    }
+
+    /// Stop tracing, returning the trace of each CPU keyed by its CPU number.
+    ///
+    /// If any CPU's tracer fails to stop, then the first error is returned and all of the traces
+    /// are discarded.
+    pub fn stop_tracing(&mut self) -> Result<HashMap<usize, Box<dyn Trace>>, HWTracerError> {
+        self.cpu_tracers.stop_tracing()
+    }
+}
+
+// Parses a list of CPUs in the kernel's format (e.g. "0-3,5"), returning the CPU numbers.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>, HWTracerError> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut ends = range.splitn(2, '-');
+        let first = ends.next().unwrap().parse::<usize>()?;
+        let last = match ends.next() {
+            Some(last) => last.parse::<usize>()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cpu_list;
+    use crate::backends::TracerBuilder;
+    use crate::{HWTracerError, ThreadTracer, Tracer, TracerState};
+
+    // A tracer which can't trace cgroups.
+    struct NoCgroupTracer;
+
+    impl Tracer for NoCgroupTracer {
+        fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0\n").unwrap(), vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-3,5,7-8\n").unwrap(),
+            vec![0, 1, 2, 3, 5, 7, 8]
+        );
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+
+    // Check that there's a trace for every online CPU.
+    #[test]
+    fn test_cgroup_tracer() {
+        let ncpus = parse_cpu_list(&std::fs::read_to_string(super::ONLINE_CPUS_PATH).unwrap())
+            .unwrap()
+            .len();
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let mut cg_tracer = tracer.cgroup_tracer(0);
+        cg_tracer.start_tracing().unwrap();
+        assert!(cg_tracer.is_tracing());
+        let traces = cg_tracer.stop_tracing().unwrap();
+        assert_eq!(traces.len(), ncpus);
+        assert!(!cg_tracer.is_tracing());
+    }
+
+    #[test]
+    fn test_cgroup_tracer_states() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let mut cg_tracer = tracer.cgroup_tracer(0);
+        match cg_tracer.stop_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+        cg_tracer.start_tracing().unwrap();
+        match cg_tracer.start_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Started)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_cgroup_tracer_unsupported() {
+        let tracer: &dyn Tracer = &NoCgroupTracer;
+        match tracer.cgroup_tracer(0).start_tracing() {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+}
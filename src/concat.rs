@@ -0,0 +1,216 @@
+//! Joining traces collected one after another into a single trace.
+
+use crate::{
+    errors::HWTracerError, Block, ClassifiedBlock, PtPacket, RawTrace, TimedBlock, Trace,
+    TraceStats, TraceTiming,
+};
+#[cfg(test)]
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+
+/// Join traces collected one after another (e.g. by starting and stopping a tracer around
+/// regions of interest) into a single trace, whose blocks are those of each trace in turn.
+///
+/// Each trace is decoded independently, so no control flow is inferred across the boundary
+/// between two traces: e.g. `iter_blocks_rle()` never merges the last block of one trace with the
+/// first block of the next, and `iter_edges()` yields no edge between them. Errors (including
+/// overflows) are yielded where they occur, and blocks of subsequent traces follow them. The
+/// `at_block` of a `HWTracerError::Overflow` counts only the blocks of its own trace.
+///
+/// The resulting trace can't be serialized, since its traces may come from different backends:
+/// serialize each trace instead.
+pub fn concat_traces(traces: Vec<Box<dyn Trace>>) -> Box<dyn Trace> {
+    Box::new(ConcatTrace { traces })
+}
+
+/// A trace made of other traces. See `concat_traces()`.
+#[derive(Debug)]
+struct ConcatTrace {
+    traces: Vec<Box<dyn Trace>>,
+}
+
+impl RawTrace for ConcatTrace {
+    fn capacity(&self) -> usize {
+        self.traces.iter().map(|t| t.capacity()).sum()
+    }
+
+    fn overflowed(&self) -> bool {
+        self.traces.iter().any(|t| t.overflowed())
+    }
+
+    fn auto_stopped(&self) -> bool {
+        self.traces.iter().any(|t| t.auto_stopped())
+    }
+
+    fn duty_cycled(&self) -> bool {
+        self.traces.iter().any(|t| t.duty_cycled())
+    }
+}
+
+impl Trace for ConcatTrace {
+    #[cfg(test)]
+    fn to_file(&self, file: &mut File) {
+        for t in &self.traces {
+            t.to_file(file);
+        }
+    }
+
+    fn serialize(&self, _w: &mut dyn Write) -> Result<(), HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "concatenated traces can't be serialized".into(),
+        ))
+    }
+
+    fn iter_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_blocks()))
+    }
+
+    fn iter_blocks_rev<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().rev().flat_map(|t| t.iter_blocks_rev()))
+    }
+
+    fn iter_timed_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<TimedBlock, HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_timed_blocks()))
+    }
+
+    fn iter_classified_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<ClassifiedBlock, HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_classified_blocks()))
+    }
+
+    fn iter_blocks_rle<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<(Block, u64), HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_blocks_rle()))
+    }
+
+    fn iter_edges<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<(Block, Block), HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_edges()))
+    }
+
+    fn iter_instrs<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<u64, HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_instrs()))
+    }
+
+    fn iter_packets<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<PtPacket, HWTracerError>> + 'i> {
+        Box::new(self.traces.iter().flat_map(|t| t.iter_packets()))
+    }
+
+    fn has_timing(&self) -> bool {
+        self.traces.iter().any(|t| t.has_timing())
+    }
+
+    fn is_decoded(&self) -> bool {
+        self.traces.iter().all(|t| t.is_decoded())
+    }
+
+    fn stats(&self) -> TraceStats {
+        let mut stats = TraceStats::default();
+        for t in &self.traces {
+            let s = t.stats();
+            stats.decoded_blocks += s.decoded_blocks;
+            stats.retired_instrs += s.retired_instrs;
+            stats.decode_errors += s.decode_errors;
+            stats.overflow_events += s.overflow_events;
+            stats.bytes += s.bytes;
+        }
+        stats
+    }
+
+    /// Each timing is the sum of those of the traces, or `None` if any of them is unknown.
+    fn timing(&self) -> TraceTiming {
+        TraceTiming {
+            collection: self.traces.iter().map(|t| t.timing().collection).sum(),
+            decoding: self.traces.iter().map(|t| t.timing().decoding).sum(),
+        }
+    }
+
+    fn estimated_decode_cost(&self) -> Duration {
+        self.traces.iter().map(|t| t.estimated_decode_cost()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::concat_traces;
+    use crate::test_helpers::MockTrace;
+    use crate::{Block, HWTracerError, Trace};
+
+    // Make a trace consisting of `blocks`.
+    fn mock_trace(blocks: Vec<Block>) -> Box<dyn Trace> {
+        Box::new(MockTrace::with_blocks(blocks))
+    }
+
+    #[test]
+    fn test_concat_blocks() {
+        let b1 = Block::new(0x1000, 0x1010);
+        let b2 = Block::new(0x2000, 0x2008);
+        let b3 = Block::new(0x3000, 0x3000);
+        let trace = concat_traces(vec![
+            mock_trace(vec![b1, b2]),
+            mock_trace(vec![]),
+            mock_trace(vec![b3]),
+        ]);
+        let got = trace.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, vec![b1, b2, b3]);
+        let got = trace
+            .iter_blocks_rev()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![b3, b2, b1]);
+        assert_eq!(trace.stats().decoded_blocks, 3);
+        assert!(!trace.overflowed());
+    }
+
+    #[test]
+    fn test_concat_empty() {
+        let trace = concat_traces(vec![]);
+        assert_eq!(trace.iter_blocks().count(), 0);
+        assert_eq!(trace.capacity(), 0);
+    }
+
+    // Check that identical blocks either side of a boundary aren't merged.
+    #[test]
+    fn test_concat_rle_boundary() {
+        let blk = Block::new(0x1000, 0x1010);
+        let trace = concat_traces(vec![mock_trace(vec![blk, blk]), mock_trace(vec![blk])]);
+        let got = trace
+            .iter_blocks_rle()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![(blk, 2), (blk, 1)]);
+    }
+
+    // Check that no edge is made between the last block of one trace and the first of the next.
+    #[test]
+    fn test_concat_edges_boundary() {
+        let b1 = Block::new(0x1000, 0x1010);
+        let b2 = Block::new(0x2000, 0x2008);
+        let trace = concat_traces(vec![mock_trace(vec![b1, b2]), mock_trace(vec![b1, b2])]);
+        let got = trace.iter_edges().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, vec![(b1, b2), (b1, b2)]);
+    }
+
+    #[test]
+    fn test_concat_serialize() {
+        let trace = concat_traces(vec![mock_trace(vec![])]);
+        match trace.serialize(&mut Vec::new()) {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+}
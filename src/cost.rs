@@ -0,0 +1,71 @@
+//! Estimating how long traces take to decode.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// A rough decoding rate (in bytes per second), used until a decode has been measured.
+const DEFAULT_BYTES_PER_SEC: u64 = 32 * 1024 * 1024;
+
+// The raw data decoded so far by this process, and how long it took (in nanoseconds). See
+// `record_decode()`.
+static DECODED_BYTES: AtomicU64 = AtomicU64::new(0);
+static DECODING_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Record that decoding `bytes` bytes of raw trace data took `elapsed`, calibrating later
+/// estimates. Backends should call this once per trace, when it is first decoded in full.
+#[allow(dead_code)] // Only used by the PerfPT backend, if compiled in.
+pub(crate) fn record_decode(bytes: usize, elapsed: Duration) {
+    let nanos = elapsed.as_nanos();
+    if bytes == 0 || nanos == 0 || nanos > u128::from(u64::MAX) {
+        return;
+    }
+    DECODED_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+    DECODING_NANOS.fetch_add(nanos as u64, Ordering::Relaxed);
+}
+
+/// Estimate how long decoding `bytes` bytes of raw trace data takes, at the average rate of the
+/// decodes recorded so far (or a default rate, if there are none).
+pub(crate) fn estimate(bytes: usize) -> Duration {
+    // The two counters are read separately, so they may be off by one decode. That's fine for
+    // an estimate.
+    estimate_at(
+        bytes,
+        DECODED_BYTES.load(Ordering::Relaxed),
+        DECODING_NANOS.load(Ordering::Relaxed),
+    )
+}
+
+// Estimate how long decoding `bytes` bytes takes, given that `decoded_bytes` bytes took
+// `decoding_nanos` nanoseconds.
+fn estimate_at(bytes: usize, decoded_bytes: u64, decoding_nanos: u64) -> Duration {
+    let (decoded_bytes, decoding_nanos) = match (decoded_bytes, decoding_nanos) {
+        (0, _) | (_, 0) => (DEFAULT_BYTES_PER_SEC, 1_000_000_000),
+        counts => counts,
+    };
+    let nanos = bytes as u128 * u128::from(decoding_nanos) / u128::from(decoded_bytes);
+    Duration::from_nanos(nanos.min(u128::from(u64::MAX)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_at, DEFAULT_BYTES_PER_SEC};
+    use std::time::Duration;
+
+    #[test]
+    fn test_estimate_at() {
+        assert_eq!(
+            estimate_at(DEFAULT_BYTES_PER_SEC as usize, 0, 0),
+            Duration::from_secs(1)
+        );
+        assert_eq!(estimate_at(0, 1000, 1000), Duration::from_secs(0));
+        // 1000 bytes took 2ms, so 1MB takes 2s.
+        assert_eq!(
+            estimate_at(1_000_000, 1000, 2_000_000),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            estimate_at(usize::MAX, 1, u64::MAX),
+            Duration::from_nanos(u64::MAX)
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Fully decoded traces, which can be shared between threads.
+
+use crate::{errors::HWTracerError, Block, RawTrace, Trace, TraceStats, TraceTiming};
+#[cfg(test)]
+use std::fs::File;
+use std::io::Write;
+use std::iter::{self, Iterator};
+use std::mem;
+
+/// The blocks of a fully decoded trace. See `Trace::into_shared()`.
+///
+/// Unlike a `Trace`, which is only `Send`, a `DecodedTrace` is also `Sync`, so once it has been
+/// put in an `Arc` it can be analysed by several threads at once without decoding it again. A
+/// `DecodedTrace` is itself a `Trace`, whose iterators iterate over the decoded blocks.
+#[derive(Debug)]
+pub struct DecodedTrace {
+    blocks: Vec<Block>,
+    // The size of the raw data of the trace which was decoded.
+    bytes: usize,
+    timing: TraceTiming,
+    auto_stopped: bool,
+    duty_cycled: bool,
+}
+
+impl DecodedTrace {
+    // Decode all of `trace`, returning the first error encountered.
+    pub(crate) fn decode<T: Trace + ?Sized>(trace: &T) -> Result<Self, HWTracerError> {
+        let mut blocks = Vec::new();
+        trace.decode_into(&mut blocks)?;
+        Ok(Self {
+            blocks,
+            bytes: trace.raw_data().map_or(0, |d| d.len()),
+            timing: trace.timing(),
+            auto_stopped: trace.auto_stopped(),
+            duty_cycled: trace.duty_cycled(),
+        })
+    }
+
+    /// Returns the blocks of the trace.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+}
+
+impl RawTrace for DecodedTrace {
+    fn capacity(&self) -> usize {
+        self.blocks.capacity() * mem::size_of::<Block>()
+    }
+
+    /// Always `false`, since an overflowed trace can't be decoded into a `DecodedTrace`.
+    fn overflowed(&self) -> bool {
+        false
+    }
+
+    fn auto_stopped(&self) -> bool {
+        self.auto_stopped
+    }
+
+    fn duty_cycled(&self) -> bool {
+        self.duty_cycled
+    }
+}
+
+impl Trace for DecodedTrace {
+    #[cfg(test)]
+    fn to_file(&self, _: &mut File) {}
+
+    fn serialize(&self, _w: &mut dyn Write) -> Result<(), HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "decoded traces can't be serialized".into(),
+        ))
+    }
+
+    fn iter_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.blocks.iter().copied().map(Ok))
+    }
+
+    fn iter_blocks_rev<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.blocks.iter().rev().copied().map(Ok))
+    }
+
+    fn block_count(&self) -> Result<usize, HWTracerError> {
+        Ok(self.blocks.len())
+    }
+
+    fn decode_into(&self, out: &mut Vec<Block>) -> Result<(), HWTracerError> {
+        out.extend_from_slice(&self.blocks);
+        Ok(())
+    }
+
+    fn stats(&self) -> TraceStats {
+        TraceStats {
+            decoded_blocks: self.blocks.len() as u64,
+            bytes: self.bytes,
+            ..Default::default()
+        }
+    }
+
+    fn is_decoded(&self) -> bool {
+        true
+    }
+
+    /// The timings of the trace which was decoded.
+    fn timing(&self) -> TraceTiming {
+        self.timing
+    }
+
+    /// The raw data isn't kept, so the instructions of the blocks aren't known.
+    fn iter_instrs<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<u64, HWTracerError>> + 'i> {
+        Box::new(iter::once(Err(HWTracerError::UnsupportedOperation(
+            "decoded traces have no instructions".into(),
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecodedTrace;
+    use crate::test_helpers::MockTrace;
+    use crate::{Block, HWTracerError, Trace};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_into_shared() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DecodedTrace>();
+
+        let blocks = vec![Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2008)];
+        let shared = Box::new(MockTrace::with_blocks(blocks.clone()))
+            .into_shared()
+            .unwrap();
+        let handles = (0..2)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || shared.blocks().to_vec())
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), blocks);
+        }
+        assert!(shared.is_decoded());
+        assert_eq!(shared.block_count().unwrap(), 2);
+        assert_eq!(shared.stats().decoded_blocks, 2);
+        assert_eq!(shared.iter_blocks().size_hint(), (2, Some(2)));
+        let got = shared.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, blocks);
+        let got = shared
+            .iter_blocks_rev()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![blocks[1], blocks[0]]);
+    }
+
+    #[test]
+    fn test_into_shared_overflow() {
+        let trace: Box<dyn Trace> = Box::new(MockTrace::default().overflowed());
+        match trace.into_shared() {
+            Err(HWTracerError::HWBufferOverflow) => (),
+            _ => panic!(),
+        }
+    }
+}
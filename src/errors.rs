@@ -1,41 +1,88 @@
 use crate::{backends::BackendKind, TracerState};
 use libc::{c_int, strerror};
 use std::error::Error;
-use std::ffi::CStr;
+use std::ffi::{self, CStr};
 use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::num::ParseIntError;
 
 #[derive(Debug)]
 pub enum HWTracerError {
     HWBufferOverflow, // The trace buffer being used by the hardware overflowed.
     // This is considered a non-fatal error since retrying the tracing
     // may succeed.
-    NoHWSupport(String), // The hardware doesn't support a required feature. Not fatal for the
-    // same reason as `Permissions`. This may be non-fatal depending
+    HardwareSupport(String), // The hardware doesn't support a required feature. Not fatal for the
+    // same reason as `Permission`. This may be non-fatal depending
     // upon whether the consumer could (e.g.) try a different backend.
-    BackendUnavailable(BackendKind), // This backend was not compiled in to hwtracer.
-    Permissions(String),             // Tracing is not permitted using this backend.
-    Errno(c_int),                    // Something went wrong in C code.
-    TracerState(TracerState),        // The tracer is in the wrong state to do the requested task.
-    BadConfig(String),               // The tracer configuration was invalid.
-    Custom(Box<dyn Error>), // All other errors can be nested here, however, don't rely on this
-    // for performance since the `Box` incurs a runtime cost.
+    UnknownBackend(String),       // No backend has this name.
+    CFailed(c_int),               // A C function or system call failed with this errno.
+    TracerState(TracerState),     // The tracer is in the wrong state to do the requested task.
+    BadConfig(String),            // The tracer configuration was invalid.
+    UnsupportedOperation(String), // The backend doesn't support the requested operation.
+    Unsupported { kind: BackendKind, reason: String }, // This backend can't be used on this
+    // system, e.g. because it was not compiled in to hwtracer, or the hardware or kernel doesn't
+    // support it. `reason` says which.
+    Permission { errno: c_int, hint: String }, // Tracing is not permitted using this backend.
+    // `errno` is the error reported by the OS, and `hint` says how to obtain permission.
+    DecodeGap { skipped_bytes: u64 }, // Decoding skipped this many bytes of corrupt trace data,
+    // and resumed after them.
+    MissingMapping { vaddr: u64 }, // The trace ran code at this address, but the decoder wasn't
+    // given the code there (e.g. because it was unloaded before decoding).
+    Overflow { at_block: usize }, // The hardware's internal buffers overflowed after this many
+    // blocks, so some control flow was lost. Decoding resumes where tracing did.
+    TracingDisabled { at_block: usize }, // Tracing was disabled after this many blocks and
+    // re-enabled elsewhere (e.g. when duty cycling), so the blocks either side aren't connected
+    // by control flow. Decoding resumes where tracing did.
+    Custom(Box<dyn Error + Send + Sync>), // All other errors can be nested here, however, don't
+    // rely on this for performance since the `Box` incurs a runtime cost.
     Unknown, // An unknown error. Used sparingly in C code which doesn't set errno.
 }
 
+impl HWTracerError {
+    // The error for a backend which wasn't compiled in to hwtracer.
+    pub(crate) fn not_compiled_in(kind: BackendKind) -> Self {
+        HWTracerError::Unsupported {
+            kind,
+            reason: "not compiled in to hwtracer".into(),
+        }
+    }
+}
+
 impl Display for HWTracerError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
             HWTracerError::HWBufferOverflow => write!(f, "Hardware trace buffer overflow"),
-            HWTracerError::BackendUnavailable(ref s) => write!(f, "Backend unavailble: {:?}", s),
-            HWTracerError::NoHWSupport(ref s) => write!(f, "{}", s),
-            HWTracerError::Permissions(ref s) => write!(f, "{}", s),
-            HWTracerError::Errno(n) => {
+            HWTracerError::Unsupported { kind, ref reason } => {
+                write!(f, "Backend unavailble: {:?} ({})", kind, reason)
+            }
+            HWTracerError::UnknownBackend(ref s) => write!(f, "Unknown backend: {:?}", s),
+            HWTracerError::HardwareSupport(ref s) => write!(f, "{}", s),
+            HWTracerError::Permission { errno, ref hint } => write!(
+                f,
+                "Tracing not permitted ({}): {}",
+                HWTracerError::CFailed(errno),
+                hint
+            ),
+            HWTracerError::CFailed(n) => {
                 // Ask libc for a string representation of the error code.
                 let err_str = unsafe { CStr::from_ptr(strerror(n)) };
                 write!(f, "{}", err_str.to_str().unwrap())
             }
             HWTracerError::TracerState(ref s) => write!(f, "Tracer in wrong state: {}", s),
             HWTracerError::BadConfig(ref s) => write!(f, "{}", s),
+            HWTracerError::UnsupportedOperation(ref s) => write!(f, "{}", s),
+            HWTracerError::DecodeGap { skipped_bytes } => {
+                write!(f, "Decoding skipped {} bytes of trace data", skipped_bytes)
+            }
+            HWTracerError::MissingMapping { vaddr } => {
+                write!(f, "No code is available to decode at 0x{:x}", vaddr)
+            }
+            HWTracerError::Overflow { at_block } => {
+                write!(f, "Trace data was lost after block {}", at_block)
+            }
+            HWTracerError::TracingDisabled { at_block } => {
+                write!(f, "Tracing was disabled after block {}", at_block)
+            }
             HWTracerError::Custom(ref bx) => write!(f, "{}", bx),
             HWTracerError::Unknown => write!(f, "Unknown error"),
         }
@@ -50,14 +97,38 @@ impl Error for HWTracerError {
     fn cause(&self) -> Option<&dyn Error> {
         match *self {
             HWTracerError::HWBufferOverflow => None,
-            HWTracerError::BackendUnavailable(_) => None,
-            HWTracerError::NoHWSupport(_) => None,
-            HWTracerError::Permissions(_) => None,
+            HWTracerError::Unsupported { .. } => None,
+            HWTracerError::UnknownBackend(_) => None,
+            HWTracerError::HardwareSupport(_) => None,
+            HWTracerError::Permission { .. } => None,
             HWTracerError::TracerState(_) => None,
             HWTracerError::BadConfig(_) => None,
-            HWTracerError::Errno(_) => None,
+            HWTracerError::UnsupportedOperation(_) => None,
+            HWTracerError::DecodeGap { .. } => None,
+            HWTracerError::MissingMapping { .. } => None,
+            HWTracerError::Overflow { .. } => None,
+            HWTracerError::TracingDisabled { .. } => None,
+            HWTracerError::CFailed(_) => None,
             HWTracerError::Custom(ref bx) => Some(bx.as_ref()),
             HWTracerError::Unknown => None,
         }
     }
 }
+
+impl From<io::Error> for HWTracerError {
+    fn from(err: io::Error) -> Self {
+        HWTracerError::Custom(Box::new(err))
+    }
+}
+
+impl From<ffi::NulError> for HWTracerError {
+    fn from(err: ffi::NulError) -> Self {
+        HWTracerError::Custom(Box::new(err))
+    }
+}
+
+impl From<ParseIntError> for HWTracerError {
+    fn from(err: ParseIntError) -> Self {
+        HWTracerError::Custom(Box::new(err))
+    }
+}
@@ -4,79 +4,974 @@
 #![feature(link_args)]
 
 pub mod backends;
+mod cgroup;
+mod concat;
+mod cost;
+mod decoded;
 pub mod errors;
+mod maps;
+mod process;
+pub mod serialize;
+mod stream;
+mod symbolize;
+mod tracer_set;
 
+pub use cgroup::CgroupTracer;
+pub use concat::concat_traces;
+pub use decoded::DecodedTrace;
 pub use errors::HWTracerError;
+pub use hwtracer_core::{
+    Block, ClassifiedBlock, EdgeKind, IpCompression, Mapping, Maps, PacketIp, PtCpu, PtCpuVendor,
+    PtPacket, RawThreadTracer, RawTrace, ReturnKind, TimedBlock, TraceStats, TraceTiming,
+};
+use libc::pid_t;
+pub use maps::{process_maps, self_maps};
+pub use process::ProcessTracer;
+#[cfg(feature = "compression")]
+pub use serialize::deserialize_compressed;
+pub use serialize::deserialize_trace;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fmt::Debug;
 use std::fmt::{self, Display, Formatter};
 #[cfg(test)]
 use std::fs::File;
-use std::iter::Iterator;
+use std::io::Write;
+use std::iter::{self, Iterator, Peekable};
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::panic::{self, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+pub use stream::BlockStream;
+#[cfg(feature = "addr2line")]
+pub use symbolize::SelfSymbolizer;
+pub use symbolize::{SourceBlock, SourceMapper, SymbolInfo, SymbolizedBlock, Symbolizer};
 
-/// Information about a basic block.
-#[derive(Debug, Eq, PartialEq)]
-pub struct Block {
-    /// Virtual address of the first instruction in this block.
-    first_instr: u64,
-    /// Virtual address of the last instruction in this block.
-    last_instr: u64,
+/// Information about a tracing backend. See `Tracer::backend_info()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BackendInfo {
+    /// The name of the backend, as returned by `Tracer::name()`.
+    pub name: &'static str,
+    /// The version of the library used to decode traces (e.g. libipt for the PerfPT backend), or
+    /// `None` if the backend doesn't use one.
+    pub decoder_version: Option<String>,
 }
 
-impl Block {
-    /// Creates a new basic block from a start address and a length in bytes.
-    pub fn new(first_instr: u64, last_instr: u64) -> Self {
-        Self {
-            first_instr,
-            last_instr,
-        }
-    }
-
-    /// Returns the virtual address of the first instruction in this block.
-    pub fn first_instr(&self) -> u64 {
-        self.first_instr
-    }
+/// A command line for decoding a trace with Intel's reference decoder, `ptxed`. See
+/// `Trace::write_ptxed_inputs()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PtxedInvocation {
+    /// The path to the `ptxed` binary.
+    pub ptxed: PathBuf,
+    /// The arguments to pass to `ptxed`. Options selecting the decoder (e.g. `--block-decoder`)
+    /// must come before these.
+    pub args: Vec<OsString>,
+}
 
-    /// Returns the virtual address of the last instruction in this block.
-    pub fn last_instr(&self) -> u64 {
-        self.last_instr
+impl PtxedInvocation {
+    /// Returns a `Command` which runs `ptxed` with the arguments.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.ptxed);
+        cmd.args(&self.args);
+        cmd
     }
 }
 
 /// Represents a generic trace.
 ///
 /// Each backend has its own concrete implementation.
-pub trait Trace: Debug + Send {
+///
+/// Collecting and decoding a trace are separate steps: stopping a tracer only gathers the raw
+/// trace data, and the trace is decoded (which may be slow) only as its iterators are consumed.
+/// Since traces are `Send`, decoding can be moved off the thread which collected the trace, e.g.
+/// onto the blocking thread pool of an async runtime, so as not to stall it.
+///
+/// The methods which don't need `std`, e.g. `capacity()`, are those of `RawTrace`.
+pub trait Trace: RawTrace + Debug + Send {
     /// Dump the trace to the specified filename.
     ///
     /// The exact format varies per-backend.
     #[cfg(test)]
     fn to_file(&self, file: &mut File);
 
+    /// Write the trace to `w` in hwtracer's stable on-disk format.
+    ///
+    /// The trace can be read back with [deserialize_trace](fn.deserialize_trace.html). See the
+    /// [serialize](serialize/index.html) module for details of the format.
+    fn serialize(&self, w: &mut dyn Write) -> Result<(), HWTracerError>;
+
+    /// As `serialize()`, but compress the trace with zstd at compression `level` (1-21, or 0 for
+    /// zstd's default). Raw trace data typically compresses well, so this is worthwhile for traces
+    /// which are kept. Only available with the `compression` feature.
+    ///
+    /// The trace can be read back with
+    /// [deserialize_compressed](fn.deserialize_compressed.html).
+    #[cfg(feature = "compression")]
+    fn serialize_compressed(&self, w: &mut dyn Write, level: i32) -> Result<(), HWTracerError> {
+        let mut enc = zstd::stream::write::Encoder::new(w, level)?;
+        self.serialize(&mut enc)?;
+        enc.finish()?;
+        Ok(())
+    }
+
+    /// Decode the trace, writing its blocks to `w` as a JSON array.
+    ///
+    /// Each block is an object of the form `{"index": 0, "first": "0x1000", "last": "0x1010"}`,
+    /// with an additional `"tsc"` member holding an integer if the block's time is known (see
+    /// `iter_timed_blocks()`). Blocks are written as they are decoded, so `w` should be buffered.
+    /// If an error occurs while decoding, then it is returned and the output is left incomplete.
+    fn to_json(&self, w: &mut dyn Write) -> Result<(), HWTracerError> {
+        w.write_all(b"[")?;
+        for (i, res) in self.iter_timed_blocks().enumerate() {
+            let tb = res?;
+            if i > 0 {
+                w.write_all(b",")?;
+            }
+            write!(
+                w,
+                "{{\"index\":{},\"first\":\"0x{:x}\",\"last\":\"0x{:x}\"",
+                i,
+                tb.block().first_instr(),
+                tb.block().last_instr()
+            )?;
+            if let Some(tsc) = tb.tsc() {
+                write!(w, ",\"tsc\":{}", tsc)?;
+            }
+            w.write_all(b"}")?;
+        }
+        w.write_all(b"]")?;
+        Ok(())
+    }
+
     /// Iterate over the blocks of the trace.
+    ///
+    /// Decoding usually stops at an error. The exception is `HWTracerError::Overflow`, which
+    /// marks where the hardware lost trace data, and is followed by the blocks executed once
+    /// tracing resumed.
     fn iter_blocks<'t: 'i, 'i>(
         &'t self,
     ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i>;
 
-    /// Get the capacity of the trace in bytes.
-    #[cfg(test)]
-    fn capacity(&self) -> usize;
+    /// Iterate over the blocks of the trace in reverse order, last block first.
+    ///
+    /// Decoding only works forwards, so the whole trace is decoded before the first block is
+    /// yielded, and its blocks are held in memory until the iterator is dropped (unless the trace
+    /// already holds them: see `is_decoded()`). The items are exactly those of `iter_blocks()` in
+    /// reverse, so an overflowed trace starts with a `HWTracerError::HWBufferOverflow` error.
+    fn iter_blocks_rev<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.iter_blocks().collect::<Vec<_>>().into_iter().rev())
+    }
+
+    /// Iterate over at most the first `n` blocks of the trace, e.g. to preview a huge trace.
+    ///
+    /// Blocks are decoded lazily as the iterator is advanced, so decoding stops once `n` blocks
+    /// (or errors) have been yielded: the rest of the trace is never decoded.
+    fn iter_blocks_limited<'t: 'i, 'i>(
+        &'t self,
+        n: usize,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.iter_blocks().take(n))
+    }
+
+    /// Iterate over the blocks of the trace from the first one whose first instruction is at
+    /// `start_addr`, e.g. to skip the part of a trace before some marker.
+    ///
+    /// Unlike a filter, this is a one-shot seek: once the block has been found, all subsequent
+    /// blocks are yielded, whatever their addresses. Errors are yielded even before it has been
+    /// found, so that a trace which fails to decode isn't mistaken for one without the block. If
+    /// no block starts at `start_addr`, only errors (if any) are yielded.
+    fn iter_blocks_from<'t: 'i, 'i>(
+        &'t self,
+        start_addr: u64,
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        let mut found = false;
+        Box::new(self.iter_blocks().filter(move |res| match res {
+            Ok(blk) if !found => {
+                found = blk.first_instr() == start_addr;
+                found
+            }
+            _ => true,
+        }))
+    }
+
+    /// Iterate over the blocks of the trace with one item of lookahead: `peek()` returns a
+    /// reference to the block (or error) which the next call of `next()` will return, without
+    /// consuming it.
+    fn blocks_peekable<'t: 'i, 'i>(&'t self) -> PeekableBlocks<'i> {
+        Blocks(self.iter_blocks()).peekable()
+    }
+
+    /// Iterate over the blocks of the trace, each with the time at which it was executed.
+    ///
+    /// Timing information is only available if the backend supports it and was configured to
+    /// collect it (e.g. `PerfPTConfig::timing`). Otherwise every block's time is `None`. Use
+    /// `has_timing()` to check whether any timing information was recorded.
+    fn iter_timed_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<TimedBlock, HWTracerError>> + 'i> {
+        Box::new(
+            self.iter_blocks()
+                .map(|res| res.map(|b| TimedBlock::new(b, None))),
+        )
+    }
+
+    /// Iterate over the blocks of the trace, each with the kind of control flow transfer made by
+    /// its last instruction.
+    ///
+    /// This is useful for reconstructing call graphs without disassembling the traced code.
+    /// Backends which can't classify blocks report every block's kind as `EdgeKind::Unknown`,
+    /// and no block's `ip_compression()` or `return_kind()`.
+    fn iter_classified_blocks<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<ClassifiedBlock, HWTracerError>> + 'i> {
+        Box::new(
+            self.iter_blocks()
+                .map(|res| res.map(|b| ClassifiedBlock::new(b, EdgeKind::Unknown))),
+        )
+    }
+
+    /// Iterate over the blocks of the trace whose first instruction lies within any of `ranges`.
+    ///
+    /// This is useful for ignoring code you don't care about (e.g. libc). Errors are always
+    /// yielded, regardless of `ranges`.
+    fn iter_blocks_in<'t: 'i, 'i>(
+        &'t self,
+        ranges: &'i [Range<u64>],
+    ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+        Box::new(self.iter_blocks().filter(move |res| match res {
+            Ok(b) => ranges.iter().any(|r| r.contains(&b.first_instr())),
+            Err(_) => true,
+        }))
+    }
+
+    /// Iterate over the blocks of the trace, collapsing runs of identical consecutive blocks.
+    ///
+    /// Each item is a block paired with the number of times it was executed consecutively. This
+    /// is computed as the trace is decoded, so the trace is never held in memory in its entirety.
+    fn iter_blocks_rle<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<(Block, u64), HWTracerError>> + 'i> {
+        Box::new(BlockRLEIterator {
+            blocks: self.iter_blocks(),
+            pending: None,
+        })
+    }
+
+    /// Iterate over the control flow edges of the trace: each pair of consecutively executed
+    /// blocks `(from, to)`.
+    ///
+    /// This is computed as the trace is decoded, so the trace is never held in memory in its
+    /// entirety. An error means that control flow between the blocks either side of it is
+    /// unknown, so no edge is yielded across an error.
+    fn iter_edges<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<(Block, Block), HWTracerError>> + 'i> {
+        Box::new(BlockEdgeIterator {
+            blocks: self.iter_blocks(),
+            prev: None,
+        })
+    }
+
+    /// Iterate over the blocks of the trace, each annotated with the symbol containing its first
+    /// instruction, as resolved by `sym`.
+    ///
+    /// `sym` is called once per block, so expensive symbolizers may wish to cache their results.
+    fn iter_symbolized_blocks<'t: 'i, 'i>(
+        &'t self,
+        sym: &'i dyn Symbolizer,
+    ) -> Box<dyn Iterator<Item = Result<SymbolizedBlock, HWTracerError>> + 'i> {
+        Box::new(self.iter_blocks().map(move |res| {
+            res.map(|b| {
+                let info = sym
+                    .symbolize(b.first_instr())
+                    .map(|(name, offset)| SymbolInfo::new(name, offset));
+                (b, info)
+            })
+        }))
+    }
+
+    /// Iterate over the blocks of the trace, each annotated with the source file and line of its
+    /// first instruction, as mapped by `mapper`.
+    ///
+    /// `mapper` is called once per block, so expensive mappers may wish to cache their results.
+    fn iter_source_blocks<'t: 'i, 'i>(
+        &'t self,
+        mapper: &'i dyn SourceMapper,
+    ) -> Box<dyn Iterator<Item = Result<SourceBlock, HWTracerError>> + 'i> {
+        Box::new(
+            self.iter_blocks()
+                .map(move |res| res.map(|b| (b, mapper.lookup(b.first_instr())))),
+        )
+    }
+
+    /// Returns the number of blocks in the trace.
+    ///
+    /// By default this iterates over `iter_blocks()`, so it costs as much as decoding the whole
+    /// trace. The first error encountered while decoding is returned.
+    fn block_count(&self) -> Result<usize, HWTracerError> {
+        let mut count = 0;
+        for res in self.iter_blocks() {
+            res?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns the first block of the trace, or `None` if the trace is empty.
+    ///
+    /// Decoding stops as soon as the first block is found.
+    fn first_block(&self) -> Result<Option<Block>, HWTracerError> {
+        self.iter_blocks().next().transpose()
+    }
+
+    /// Returns `true` if a block of the trace contains `addr` (see `Block::contains()`), e.g. to
+    /// check that a function was executed.
+    ///
+    /// Decoding stops as soon as such a block is found. The first error encountered before then is
+    /// returned.
+    fn contains_address(&self, addr: u64) -> Result<bool, HWTracerError> {
+        for res in self.iter_blocks() {
+            if res?.contains(addr) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the last block of the trace, or `None` if the trace is empty.
+    ///
+    /// This decodes the whole trace, but keeps only the most recent block. The first error
+    /// encountered while decoding is returned.
+    fn last_block(&self) -> Result<Option<Block>, HWTracerError> {
+        let mut last = None;
+        for res in self.iter_blocks() {
+            last = Some(res?);
+        }
+        Ok(last)
+    }
+
+    /// Returns the number of times each block of the trace was executed, keyed by the address of
+    /// the block's first instruction.
+    ///
+    /// This decodes the trace, but keeps only the counts. Blocks which start at the same address
+    /// are counted together, even if they end at different addresses (e.g. because of an
+    /// interrupt). The first error encountered while decoding is returned.
+    fn block_histogram(&self) -> Result<HashMap<u64, u64>, HWTracerError> {
+        let mut counts = HashMap::new();
+        for res in self.iter_blocks() {
+            *counts.entry(res?.first_instr()).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Returns an AFL-style edge coverage bitmap of `size` entries, which must be a power of two
+    /// (otherwise `HWTracerError::BadConfig` is returned).
+    ///
+    /// Each edge of `iter_edges()` is hashed into the map as AFL's QEMU mode does, from the
+    /// addresses of the first instructions of the edge's blocks, and its entry is incremented
+    /// (wrapping on overflow). The result can be merged into an AFL shared memory map of the same
+    /// size. No edge is counted across a gap in tracing (`HWTracerError::TracingDisabled`), but
+    /// the first other error encountered while decoding is returned.
+    fn coverage_bitmap(&self, size: usize) -> Result<Vec<u8>, HWTracerError> {
+        if !size.is_power_of_two() {
+            return Err(HWTracerError::BadConfig(format!(
+                "coverage bitmap size {} is not a power of two",
+                size
+            )));
+        }
+        let mask = size as u64 - 1;
+        let loc = |b: Block| ((b.first_instr() >> 4) ^ (b.first_instr() << 8)) & mask;
+        let mut map = vec![0u8; size];
+        for res in self.iter_edges() {
+            let (from, to) = match res {
+                Ok(edge) => edge,
+                Err(HWTracerError::TracingDisabled { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            let i = (loc(to) ^ (loc(from) >> 1)) as usize;
+            map[i] = map[i].wrapping_add(1);
+        }
+        Ok(map)
+    }
+
+    /// Decodes the trace, appending its blocks to `out`.
+    ///
+    /// This is cheaper than collecting `iter_blocks()` when decoding many traces, since `out` can
+    /// be reused. If an error occurs, then it is returned and `out` holds the blocks decoded
+    /// before the error.
+    fn decode_into(&self, out: &mut Vec<Block>) -> Result<(), HWTracerError> {
+        for block in self.iter_blocks() {
+            out.push(block?);
+        }
+        Ok(())
+    }
+
+    /// Decodes the trace into `out` as `(first_instr, last_instr)` pairs, returning the number of
+    /// blocks written. This suits callers (e.g. across a C ABI) which provide their own buffer.
+    ///
+    /// Decoding stops once `out` is full, so a return value of `out.len()` may mean that the trace
+    /// has more blocks: use `block_count()` to size `out` if they are all needed. If an error
+    /// occurs, then it is returned and `out` holds the blocks decoded before the error.
+    fn decode_to_slice(&self, out: &mut [(u64, u64)]) -> Result<usize, HWTracerError> {
+        let mut count = 0;
+        for (slot, block) in out.iter_mut().zip(self.iter_blocks()) {
+            let block = block?;
+            *slot = (block.first_instr(), block.last_instr());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Decodes the trace, gathering statistics about how much of it could be decoded.
+    ///
+    /// Backends which can't recover from errors part-way through decoding report at most one
+    /// error or overflow.
+    fn stats(&self) -> TraceStats {
+        let mut stats = TraceStats {
+            bytes: self.raw_data().map_or(0, |d| d.len()),
+            ..Default::default()
+        };
+        for res in self.iter_blocks() {
+            match res {
+                Ok(_) => stats.decoded_blocks += 1,
+                Err(HWTracerError::HWBufferOverflow) | Err(HWTracerError::Overflow { .. }) => {
+                    stats.overflow_events += 1
+                }
+                // No trace data is lost where tracing was disabled.
+                Err(HWTracerError::TracingDisabled { .. }) => (),
+                Err(_) => stats.decode_errors += 1,
+            }
+        }
+        stats
+    }
+
+    /// Returns `true` if the trace contains timing information, from which
+    /// `iter_timed_blocks()` can report when blocks were executed.
+    ///
+    /// Even when a backend is configured to collect timing information, the hardware may not
+    /// record any, so this inspects the trace itself. This is cheaper than decoding the trace.
+    fn has_timing(&self) -> bool {
+        false
+    }
+
+    /// Returns how long it took to collect and decode the trace.
+    ///
+    /// Timings are measured with a monotonic clock (at nanosecond resolution, where the platform
+    /// allows), so they can be used to measure the overhead of tracing. Backends which don't
+    /// record timings, and traces which were deserialized, report `None` for every timing.
+    fn timing(&self) -> TraceTiming {
+        TraceTiming::default()
+    }
+
+    /// Returns a cheap estimate of how long iterating over the blocks of the trace will take,
+    /// e.g. for deciding whether to decode the trace now or defer it. Nothing is decoded.
+    ///
+    /// A trace whose blocks are held in memory (see `is_decoded()`) costs nothing, and the cost of
+    /// decoding a trace again is the time its first decode took (see `timing()`). Otherwise the
+    /// cost is estimated from the size of the raw data, at the average rate of the decodes
+    /// measured so far by the process (or a default rate, before any have been measured). The
+    /// block density of a trace isn't known until it is decoded, so traces of tight loops may
+    /// be underestimated. Traces without raw data are estimated to cost nothing.
+    fn estimated_decode_cost(&self) -> Duration {
+        if self.is_decoded() {
+            return Duration::from_secs(0);
+        }
+        if let Some(decoding) = self.timing().decoding {
+            return decoding;
+        }
+        cost::estimate(self.raw_data().map_or(0, |d| d.len()))
+    }
+
+    /// Iterate over the addresses of the individual instructions executed in the trace.
+    ///
+    /// Backends which can't decode individual instructions yield a single
+    /// `HWTracerError::UnsupportedOperation` error.
+    fn iter_instrs<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<u64, HWTracerError>> + 'i> {
+        Box::new(iter::once(Err(HWTracerError::UnsupportedOperation(
+            "instruction iteration is not supported by this backend".into(),
+        ))))
+    }
+
+    /// Iterate over the raw Intel PT packets of the trace, without reconstructing any control
+    /// flow. This is useful for debugging trace collection.
+    ///
+    /// As for blocks, an overflowed trace ends with a `HWTracerError::HWBufferOverflow` error.
+    /// Backends which don't collect Intel PT traces yield a single `HWTracerError::UnsupportedOperation`
+    /// error.
+    fn iter_packets<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<PtPacket, HWTracerError>> + 'i> {
+        Box::new(iter::once(Err(HWTracerError::UnsupportedOperation(
+            "packet iteration is not supported by this backend".into(),
+        ))))
+    }
+
+    /// Iterate over the PTWRITE instructions executed in the trace, yielding the address of each
+    /// instruction and the value it wrote. This allows values computed by the traced code to be
+    /// correlated with its control flow.
+    ///
+    /// PTWRITEs are only recorded if the backend was asked for them (e.g. with
+    /// `PerfPTConfig::ptwrite`). They are found from `iter_packets()`, whose errors are passed
+    /// through, so backends which don't collect Intel PT traces yield a single
+    /// `HWTracerError::UnsupportedOperation` error. PTWRITEs whose address wasn't recorded are skipped.
+    fn iter_ptwrites<'t: 'i, 'i>(
+        &'t self,
+    ) -> Box<dyn Iterator<Item = Result<(u64, u64), HWTracerError>> + 'i> {
+        Box::new(PtWriteIterator {
+            packets: self.iter_packets(),
+            last_ip: 0,
+            pending: None,
+        })
+    }
+
+    /// Decode the whole trace into a `DecodedTrace`, which (unlike a `Trace`) is `Sync`, so that
+    /// it can be analysed by several threads at once without decoding it again.
+    ///
+    /// The first error encountered while decoding is returned, so an overflowed trace can't be
+    /// shared: use `decode_into()` to keep the blocks decoded before an error.
+    fn into_shared(self: Box<Self>) -> Result<Arc<DecodedTrace>, HWTracerError> {
+        DecodedTrace::decode(&*self).map(Arc::new)
+    }
+
+    /// Returns `true` if the blocks of the trace are held in memory, so that iterating over them
+    /// doesn't decode the trace (again).
+    ///
+    /// Backends which decode the trace afresh for every iterator always return `false`. See also
+    /// `PerfPTConfig::cache_blocks`.
+    fn is_decoded(&self) -> bool {
+        false
+    }
+
+    /// Iterate over the times at which the raw trace data was written by the hardware, as
+    /// `(offset, time)` pairs, each meaning that the first `offset` bytes of `raw_data()` had
+    /// been written by `time`. Times are in nanoseconds of the `CLOCK_MONOTONIC` clock (as
+    /// returned by `clock_gettime(2)`), so trace data can be correlated with events logged by
+    /// other code. The pairs are in order of increasing offset and time.
+    ///
+    /// Backends which don't record these times, and traces which were deserialized, yield
+    /// nothing. See also `offset_at_time()`.
+    fn iter_aux_timestamps<'t: 'i, 'i>(&'t self) -> Box<dyn Iterator<Item = (usize, u64)> + 'i> {
+        Box::new(iter::empty())
+    }
+
+    /// Returns the number of bytes of `raw_data()` known to have been written by the
+    /// `CLOCK_MONOTONIC` time `ts` (in nanoseconds), or `None` if no time at or before `ts` was
+    /// recorded. See `iter_aux_timestamps()`.
+    fn offset_at_time(&self, ts: u64) -> Option<usize> {
+        self.iter_aux_timestamps()
+            .take_while(|&(_, time)| time <= ts)
+            .last()
+            .map(|(offset, _)| offset)
+    }
+
+    /// Write the files needed to decode the trace with `ptxed` into the directory `dir`, returning
+    /// the command line which does so. This is useful for checking hwtracer's decoding against
+    /// the reference decoder.
+    ///
+    /// Backends which don't collect Intel PT traces return `HWTracerError::UnsupportedOperation`.
+    fn write_ptxed_inputs(&self, _dir: &Path) -> Result<PtxedInvocation, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "ptxed can't decode traces of this backend".into(),
+        ))
+    }
+
+    /// Write the trace to the file `path` in the `perf.data` format of Linux perf, so that it
+    /// can be analysed with tools such as `perf script` and `perf report`. Along with the trace
+    /// data, the file describes the traced thread and the executable code it may have run.
+    ///
+    /// Backends which don't collect Intel PT traces return `HWTracerError::UnsupportedOperation`.
+    fn write_perf_data(&self, _path: &Path) -> Result<(), HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "perf.data files can't be written for traces of this backend".into(),
+        ))
+    }
+
+    /// Decode the trace both with hwtracer and with `ptxed` (see `write_ptxed_inputs()`), and
+    /// compare the start addresses of the blocks found by each. Returns the index of the first
+    /// block at which they differ, or `None` if they agree. This is useful for catching decoder
+    /// regressions. Only available with the `ptxed_verify` feature.
+    ///
+    /// Errors reported by either decoder are returned. Backends which don't collect Intel PT
+    /// traces return `HWTracerError::UnsupportedOperation`.
+    #[cfg(feature = "ptxed_verify")]
+    fn verify_against_ptxed(&self) -> Result<Option<usize>, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "ptxed can't decode traces of this backend".into(),
+        ))
+    }
+}
+
+/// An iterator over the blocks of a trace, as returned by `Trace::iter_blocks()`.
+///
+/// This allows a `for` loop to iterate over a trace reference directly:
+///
+/// ```
+/// use hwtracer::backends::TracerBuilder;
+///
+/// let mut tracer = TracerBuilder::new().dummy().build().unwrap().thread_tracer();
+/// tracer.start_tracing().unwrap();
+/// let trace = tracer.stop_tracing().unwrap();
+/// for block in &trace {
+///     println!("{}", block.unwrap());
+/// }
+/// ```
+pub struct Blocks<'a>(Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'a>);
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Result<Block, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl<'a> IntoIterator for &'a dyn Trace {
+    type Item = Result<Block, HWTracerError>;
+    type IntoIter = Blocks<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Blocks(self.iter_blocks())
+    }
+}
+
+impl<'a> IntoIterator for &'a Box<dyn Trace> {
+    type Item = Result<Block, HWTracerError>;
+    type IntoIter = Blocks<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Blocks(self.iter_blocks())
+    }
+}
+
+/// An iterator over the blocks of a trace with one item of lookahead, as returned by
+/// `Trace::blocks_peekable()`.
+pub type PeekableBlocks<'a> = Peekable<Blocks<'a>>;
+
+/// The point at which two traces differ: the index of the first differing block, and the block at
+/// that index in each trace (or `None` if the trace has no block at that index). See
+/// `first_divergence()`.
+pub type Divergence = (usize, Option<Block>, Option<Block>);
+
+/// Finds the first block at which two traces differ.
+///
+/// Both traces are decoded in lockstep and, if they differ, the index of the first differing
+/// block is returned along with the block from each trace. If one trace is a prefix of the other,
+/// then the shorter trace's block is `None`. Returns `Ok(None)` if the traces are identical. The
+/// first error encountered while decoding either trace is returned.
+pub fn first_divergence(a: &dyn Trace, b: &dyn Trace) -> Result<Option<Divergence>, HWTracerError> {
+    let mut a_blocks = a.iter_blocks();
+    let mut b_blocks = b.iter_blocks();
+    let mut idx = 0;
+    loop {
+        let a_blk = a_blocks.next().transpose()?;
+        let b_blk = b_blocks.next().transpose()?;
+        match (a_blk, b_blk) {
+            (None, None) => return Ok(None),
+            (Some(x), Some(y)) if x == y => idx += 1,
+            (x, y) => return Ok(Some((idx, x, y))),
+        }
+    }
+}
+
+/// Run-length encodes a stream of blocks. See `Trace::iter_blocks_rle()`.
+struct BlockRLEIterator<'i> {
+    blocks: Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i>,
+    // An item read from `blocks` that ended the previous run, but which is yet to be yielded.
+    pending: Option<Result<Block, HWTracerError>>,
+}
+
+impl Iterator for BlockRLEIterator<'_> {
+    type Item = Result<(Block, u64), HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = match self.pending.take().or_else(|| self.blocks.next())? {
+            Ok(b) => b,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut count = 1;
+        loop {
+            match self.blocks.next() {
+                Some(Ok(ref b)) if *b == block => count += 1,
+                other => {
+                    self.pending = other;
+                    break;
+                }
+            }
+        }
+        Some(Ok((block, count)))
+    }
+}
+
+/// Pairs up consecutive blocks of a stream of blocks. See `Trace::iter_edges()`.
+struct BlockEdgeIterator<'i> {
+    blocks: Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i>,
+    // The most recent block, if it was not followed by an error.
+    prev: Option<Block>,
+}
+
+impl Iterator for BlockEdgeIterator<'_> {
+    type Item = Result<(Block, Block), HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.blocks.next()? {
+                Ok(b) => {
+                    if let Some(prev) = self.prev.replace(b) {
+                        return Some(Ok((prev, b)));
+                    }
+                }
+                Err(e) => {
+                    self.prev = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Pairs the PTW packets of a packet stream with the IPs of the FUP packets which follow them. See
+/// `Trace::iter_ptwrites()`.
+struct PtWriteIterator<'i> {
+    packets: Box<dyn Iterator<Item = Result<PtPacket, HWTracerError>> + 'i>,
+    // The full IP of the last packet which carried one, against which compressed IPs are
+    // decompressed.
+    last_ip: u64,
+    // The payload of a PTW packet awaiting the FUP packet giving its IP.
+    pending: Option<u64>,
+}
+
+impl Iterator for PtWriteIterator<'_> {
+    type Item = Result<(u64, u64), HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ip = match self.packets.next()? {
+                Ok(PtPacket::Ptw { payload, ip }) => {
+                    self.pending = if ip { Some(payload) } else { None };
+                    continue;
+                }
+                Ok(PtPacket::Psb) | Ok(PtPacket::Ovf) => {
+                    // The last IP isn't carried across a PSB or an overflow.
+                    self.last_ip = 0;
+                    self.pending = None;
+                    continue;
+                }
+                Ok(PtPacket::Fup(ip)) => ip,
+                Ok(PtPacket::Tip(ip)) | Ok(PtPacket::TipPge(ip)) | Ok(PtPacket::TipPgd(ip)) => {
+                    self.pending = None;
+                    ip
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    self.pending = None;
+                    return Some(Err(e));
+                }
+            };
+            let payload = self.pending.take();
+            if let Some(ip) = ip {
+                self.last_ip = ip.full_ip(self.last_ip);
+                if let Some(payload) = payload {
+                    return Some(Ok((self.last_ip, payload)));
+                }
+            }
+        }
+    }
 }
 
 /// The interface offered by all tracer types.
 pub trait Tracer: Send + Sync {
     /// Return a `ThreadTracer` for tracing the current thread.
     fn thread_tracer(&self) -> Box<dyn ThreadTracer>;
+
+    /// Returns the name of the backend, e.g. `"PerfPT"`.
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Returns information about the backend, for recording which backend (and decoder) produced
+    /// a trace.
+    fn backend_info(&self) -> BackendInfo {
+        BackendInfo {
+            name: self.name(),
+            decoder_version: None,
+        }
+    }
+
+    /// Return a `ThreadTracer` for tracing the thread of the current process whose OS-level
+    /// thread ID is `tid`. The tracer can be started and stopped from any thread.
+    ///
+    /// An error is returned if there is no such thread, or if the backend can only trace the
+    /// calling thread.
+    fn thread_tracer_for(&self, _tid: pid_t) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "tracing other threads is not supported by this backend".into(),
+        ))
+    }
+
+    /// Return a `ThreadTracer` for tracing the main thread of the process `pid` (which needn't be
+    /// the current process) from the next time it calls `execve(2)`. Once the tracer is started,
+    /// nothing is recorded until the exec, and then the new program is traced from its first
+    /// instruction. See [ProcessTracer::spawn_traced](struct.ProcessTracer.html#method.spawn_traced).
+    ///
+    /// The trace is of another program, so it must be decoded against that program's code (e.g.
+    /// with `backends::perf_pt::TraceDecoder`), not that of the current process.
+    fn thread_tracer_on_exec(&self, _pid: pid_t) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "tracing from exec is not supported by this backend".into(),
+        ))
+    }
+
+    /// Return a `ThreadTracer` for tracing the tasks of the cgroup `cgroup_fd` (a file descriptor
+    /// for the cgroup's directory in the cgroup filesystem) while they run on the CPU `cpu`. See
+    /// [cgroup_tracer](trait.Tracer.html#method.cgroup_tracer), which traces every CPU.
+    fn cgroup_cpu_tracer(
+        &self,
+        _cgroup_fd: RawFd,
+        _cpu: usize,
+    ) -> Result<Box<dyn ThreadTracer>, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "tracing cgroups is not supported by this backend".into(),
+        ))
+    }
+}
+
+impl dyn Tracer {
+    /// Trace the closure `f` on the current thread, returning the trace and the closure's result.
+    ///
+    /// If `f` panics, then tracing is stopped and the panic propagates to the caller.
+    pub fn trace<F, R>(&self, f: F) -> Result<(Box<dyn Trace>, R), HWTracerError>
+    where
+        F: FnOnce() -> R,
+    {
+        // If `f` panics, dropping the guard stops the tracer.
+        let mut thr_tracer = self.thread_tracer();
+        let guard = thr_tracer.start_guarded()?;
+        let res = f();
+        let trace = guard.finish()?;
+        Ok((trace, res))
+    }
+
+    /// Trace the closure `f` on the current thread, like `trace()`, but catching any panic in
+    /// `f`. The trace is returned whether or not `f` panicked, alongside either the closure's
+    /// result or the panic's payload, so that the code leading up to a panic can be examined.
+    ///
+    /// As for `panic::catch_unwind()`, `f` must be `UnwindSafe` (wrap it in
+    /// `panic::AssertUnwindSafe` if need be), and panics which abort aren't caught.
+    pub fn trace_catch_unwind<F, R>(
+        &self,
+        f: F,
+    ) -> Result<(Box<dyn Trace>, thread::Result<R>), HWTracerError>
+    where
+        F: FnOnce() -> R + UnwindSafe,
+    {
+        let mut thr_tracer = self.thread_tracer();
+        let guard = thr_tracer.start_guarded()?;
+        let res = panic::catch_unwind(f);
+        let trace = guard.finish()?;
+        Ok((trace, res))
+    }
+
+    /// Return a `ProcessTracer` for tracing all of the threads of the current process.
+    pub fn process_tracer(&self) -> ProcessTracer<'_> {
+        ProcessTracer::new(self)
+    }
+
+    /// Return a `CgroupTracer` for tracing all of the tasks in the cgroup `cgroup_fd`, a file
+    /// descriptor for the cgroup's directory in the cgroup filesystem (e.g. a directory under
+    /// `/sys/fs/cgroup`). The file descriptor must stay open while the tracer is in use.
+    pub fn cgroup_tracer(&self, cgroup_fd: RawFd) -> CgroupTracer<'_> {
+        CgroupTracer::new(self, cgroup_fd)
+    }
 }
 
-pub trait ThreadTracer {
-    /// Start recording a trace.
+/// Traces a single thread.
+///
+/// Dropping a `ThreadTracer` which is still tracing stops the tracer and discards the trace. The
+/// tracer is started and stopped by the methods of `RawThreadTracer`.
+pub trait ThreadTracer: RawThreadTracer<Trace = Box<dyn Trace>, Error = HWTracerError> {
+    /// Discard the trace collected so far, without stopping the tracer.
+    ///
+    /// This is a hook for backends which can do this more cheaply than stopping and restarting
+    /// the tracer, which is what the default implementation does.
     ///
-    /// Tracing continues until [stop_tracing](trait.ThreadTracer.html#method.stop_tracing) is called.
-    fn start_tracing(&mut self) -> Result<(), HWTracerError>;
-    /// Turns off the tracer.
+    /// [start_tracing](trait.RawThreadTracer.html#method.start_tracing) must have been called
+    /// prior.
+    fn reset(&mut self) -> Result<(), HWTracerError> {
+        self.stop_tracing()?;
+        self.start_tracing()
+    }
+
+    /// Start recording a trace, returning a stream which decodes blocks whilst tracing is still
+    /// running.
+    ///
+    /// Tracing continues until [stop_tracing](struct.BlockStream.html#method.stop_tracing) is
+    /// called on the stream, or the stream is dropped. If the traced thread is the thread
+    /// consuming the stream, then the stream's own decoding work is also traced.
+    fn start_streaming(&mut self) -> Result<BlockStream<'_>, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "streaming is not supported by this backend".into(),
+        ))
+    }
+
+    /// Copy the trace data held by the tracer into a new trace, without stopping the tracer.
     ///
-    /// [start_tracing](trait.ThreadTracer.html#method.start_tracing) must have been called prior.
-    fn stop_tracing(&mut self) -> Result<Box<dyn Trace>, HWTracerError>;
+    /// This is for tracers which keep only the most recent trace data, overwriting the oldest
+    /// (e.g. the PerfPT backend with `PerfPTConfig::snapshot` set). Such a tracer acts as a flight
+    /// recorder: a snapshot taken when something goes wrong shows what led up to it. Backends
+    /// and configurations which don't keep trace data this way return
+    /// `HWTracerError::UnsupportedOperation`.
+    ///
+    /// [start_tracing](trait.RawThreadTracer.html#method.start_tracing) must have been called
+    /// prior.
+    fn snapshot(&mut self) -> Result<Box<dyn Trace>, HWTracerError> {
+        Err(HWTracerError::UnsupportedOperation(
+            "snapshots are not supported by this backend".into(),
+        ))
+    }
+}
+
+impl dyn ThreadTracer {
+    /// Start recording a trace, returning a guard which stops the tracer when dropped.
+    ///
+    /// Call [finish](struct.TracingGuard.html#method.finish) on the guard to retrieve the trace.
+    pub fn start_guarded(&mut self) -> Result<TracingGuard<'_>, HWTracerError> {
+        self.start_tracing()?;
+        Ok(TracingGuard { tracer: Some(self) })
+    }
+}
+
+/// Stops a running `ThreadTracer` when dropped.
+///
+/// Obtained from [start_guarded](trait.ThreadTracer.html#method.start_guarded). If the guard is
+/// dropped without calling [finish](#method.finish), the tracer is stopped and the trace
+/// discarded.
+pub struct TracingGuard<'a> {
+    // The tracer, or `None` if it has already been stopped.
+    tracer: Option<&'a mut dyn ThreadTracer>,
+}
+
+impl<'a> TracingGuard<'a> {
+    /// Stop the tracer and return the trace.
+    pub fn finish(mut self) -> Result<Box<dyn Trace>, HWTracerError> {
+        self.tracer.take().unwrap().stop_tracing()
+    }
+}
+
+impl<'a> Drop for TracingGuard<'a> {
+    fn drop(&mut self) {
+        if let Some(tracer) = self.tracer.take() {
+            // There's nobody to report an error to, and the trace is unwanted anyway.
+            let _ = tracer.stop_tracing();
+        }
+    }
 }
 
 // Keeps track of the internal state of a tracer.
@@ -102,15 +997,576 @@ impl Display for TracerState {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{
+        first_divergence, Block, BlockEdgeIterator, ClassifiedBlock, EdgeKind, HWTracerError,
+        IpCompression, PacketIp, PtPacket, RawTrace, SymbolInfo, TimedBlock, Trace, TraceStats,
+        TraceTiming, Tracer, TracerState,
+    };
+    use crate::backends::{dummy::DummyTracer, TracerBuilder};
+    use crate::test_helpers::MockTrace;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_iter_blocks_rle() {
+        let (b1, b2) = (Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2010));
+        let trace = MockTrace::with_blocks(vec![b1, b1, b1, b2, b1, b2, b2]);
+        let got = trace
+            .iter_blocks_rle()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![(b1, 3), (b2, 1), (b1, 1), (b2, 2)]);
+        assert_eq!(
+            MockTrace::with_blocks(Vec::new()).iter_blocks_rle().count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_iter_edges() {
+        let (b1, b2, b3) = (
+            Block::new(0x1000, 0x1010),
+            Block::new(0x2000, 0x2010),
+            Block::new(0x3000, 0x3010),
+        );
+        let trace = MockTrace::with_blocks(vec![b1, b2, b1, b3]);
+        let got = trace.iter_edges().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, vec![(b1, b2), (b2, b1), (b1, b3)]);
+        assert_eq!(MockTrace::with_blocks(vec![b1]).iter_edges().count(), 0);
+        assert_eq!(MockTrace::with_blocks(Vec::new()).iter_edges().count(), 0);
+    }
+
+    #[test]
+    fn test_iter_blocks_rev() {
+        let (b1, b2, b3) = (
+            Block::new(0x1000, 0x1010),
+            Block::new(0x2000, 0x2010),
+            Block::new(0x3000, 0x3010),
+        );
+        let trace = MockTrace::with_blocks(vec![b1, b2, b3, b1]);
+        let got = trace
+            .iter_blocks_rev()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![b1, b3, b2, b1]);
+        assert_eq!(
+            MockTrace::with_blocks(Vec::new()).iter_blocks_rev().count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_iter_blocks_limited() {
+        let got = MockTrace::default()
+            .endless()
+            .iter_blocks_limited(3)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            got,
+            vec![Block::new(0, 0), Block::new(1, 1), Block::new(2, 2)]
+        );
+        assert_eq!(
+            MockTrace::default()
+                .endless()
+                .iter_blocks_limited(0)
+                .count(),
+            0
+        );
+        let b1 = Block::new(0x1000, 0x1010);
+        assert_eq!(
+            MockTrace::with_blocks(vec![b1])
+                .iter_blocks_limited(5)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_iter_blocks_from() {
+        let b1 = Block::new(0x1000, 0x1010);
+        let b2 = Block::new(0x2000, 0x2008);
+        let b3 = Block::new(0x3000, 0x3000);
+        let trace = MockTrace::with_blocks(vec![b1, b2, b3, b1, b2]);
+        let got = trace
+            .iter_blocks_from(0x2000)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![b2, b3, b1, b2]);
+        // The address must be the start of a block.
+        assert_eq!(trace.iter_blocks_from(0x2004).count(), 0);
+        assert_eq!(trace.iter_blocks_from(0x1000).count(), 5);
+    }
+
+    #[test]
+    fn test_blocks_peekable() {
+        let b1 = Block::new(0x1000, 0x1010);
+        let b2 = Block::new(0x2000, 0x2008);
+        let trace = MockTrace::with_blocks(vec![b1, b2]);
+        let mut blocks = trace.blocks_peekable();
+        assert_eq!(blocks.peek().unwrap().as_ref().unwrap(), &b1);
+        assert_eq!(blocks.peek().unwrap().as_ref().unwrap(), &b1);
+        assert_eq!(blocks.next().unwrap().unwrap(), b1);
+        assert_eq!(blocks.next().unwrap().unwrap(), b2);
+        assert!(blocks.peek().is_none());
+        assert!(blocks.next().is_none());
+    }
+
+    // Check that no edge is made across an error.
+    #[test]
+    fn test_iter_edges_error() {
+        let (b1, b2, b3) = (
+            Block::new(0x1000, 0x1010),
+            Block::new(0x2000, 0x2010),
+            Block::new(0x3000, 0x3010),
+        );
+        let blocks = vec![Ok(b1), Err(HWTracerError::Unknown), Ok(b2), Ok(b3)];
+        let mut itr = BlockEdgeIterator {
+            blocks: Box::new(blocks.into_iter()),
+            prev: None,
+        };
+        match itr.next() {
+            Some(Err(HWTracerError::Unknown)) => (),
+            _ => panic!(),
+        }
+        assert_eq!(itr.next().unwrap().unwrap(), (b2, b3));
+        assert!(itr.next().is_none());
+    }
+
+    // Without timing support, blocks have no time.
+    #[test]
+    fn test_iter_timed_blocks_default() {
+        let blk = Block::new(0x1000, 0x1010);
+        let trace = MockTrace::with_blocks(vec![blk]);
+        let got = trace
+            .iter_timed_blocks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![TimedBlock::new(blk, None)]);
+    }
+
+    #[test]
+    fn test_has_timing_default() {
+        let trace = MockTrace::with_blocks(vec![Block::new(0x1000, 0x1010)]);
+        assert!(!trace.has_timing());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let trace =
+            MockTrace::with_blocks(vec![Block::new(0x1000, 0x1010), Block::new(0x20, 0x2f)]);
+        let mut buf = Vec::new();
+        trace.to_json(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            concat!(
+                r#"[{"index":0,"first":"0x1000","last":"0x1010"},"#,
+                r#"{"index":1,"first":"0x20","last":"0x2f"}]"#
+            )
+        );
+
+        let mut buf = Vec::new();
+        MockTrace::with_blocks(Vec::new())
+            .to_json(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"[]");
+    }
+
+    #[test]
+    fn test_iter_symbolized_blocks() {
+        let (b1, b2) = (Block::new(0x1004, 0x1010), Block::new(0x2000, 0x2010));
+        let trace = MockTrace::with_blocks(vec![b1, b2]);
+        let sym = |addr: u64| {
+            if (0x1000..0x2000).contains(&addr) {
+                Some((String::from("foo"), addr - 0x1000))
+            } else {
+                None
+            }
+        };
+        let got = trace
+            .iter_symbolized_blocks(&sym)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            got,
+            vec![
+                (b1, Some(SymbolInfo::new(String::from("foo"), 4))),
+                (b2, None)
+            ]
+        );
+        assert_eq!(got[0].1.as_ref().unwrap().name(), "foo");
+    }
+
+    #[test]
+    fn test_iter_source_blocks() {
+        let (b1, b2) = (Block::new(0x1004, 0x1010), Block::new(0x2000, 0x2010));
+        let trace = MockTrace::with_blocks(vec![b1, b2]);
+        let mapper = |addr: u64| {
+            if (0x1000..0x2000).contains(&addr) {
+                Some((PathBuf::from("foo.c"), 10 + (addr - 0x1000) as u32))
+            } else {
+                None
+            }
+        };
+        let got = trace
+            .iter_source_blocks(&mapper)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            got,
+            vec![(b1, Some((PathBuf::from("foo.c"), 14))), (b2, None)]
+        );
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let (b1, b2, b3) = (
+            Block::new(0x1000, 0x1010),
+            Block::new(0x2000, 0x2010),
+            Block::new(0x3000, 0x3010),
+        );
+        let t1 = MockTrace::with_blocks(vec![b1, b2, b3]);
+        let t2 = MockTrace::with_blocks(vec![b1, b3, b3]);
+        assert_eq!(
+            first_divergence(&t1, &t2).unwrap(),
+            Some((1, Some(b2), Some(b3)))
+        );
+        assert_eq!(first_divergence(&t1, &t1).unwrap(), None);
+
+        // One trace is a prefix of the other.
+        let prefix = MockTrace::with_blocks(vec![b1, b2]);
+        assert_eq!(
+            first_divergence(&t1, &prefix).unwrap(),
+            Some((2, Some(b3), None))
+        );
+        assert_eq!(
+            first_divergence(&MockTrace::with_blocks(Vec::new()), &t1).unwrap(),
+            Some((0, None, Some(b1)))
+        );
+    }
+
+    #[test]
+    fn test_first_last_block() {
+        let (b1, b2, b3) = (
+            Block::new(0x1000, 0x1010),
+            Block::new(0x2000, 0x2010),
+            Block::new(0x3000, 0x3010),
+        );
+        let trace = MockTrace::with_blocks(vec![b1, b2, b3]);
+        assert_eq!(trace.first_block().unwrap(), Some(b1));
+        assert_eq!(trace.last_block().unwrap(), Some(b3));
+
+        let trace = MockTrace::with_blocks(Vec::new());
+        assert_eq!(trace.first_block().unwrap(), None);
+        assert_eq!(trace.last_block().unwrap(), None);
+    }
+
+    #[test]
+    fn test_contains_address() {
+        let trace =
+            MockTrace::with_blocks(vec![Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2010)]);
+        assert!(trace.contains_address(0x1000).unwrap());
+        assert!(trace.contains_address(0x2008).unwrap());
+        assert!(trace.contains_address(0x2010).unwrap());
+        assert!(!trace.contains_address(0x1800).unwrap());
+        assert!(!MockTrace::with_blocks(Vec::new())
+            .contains_address(0)
+            .unwrap());
+        // Decoding stops at the first matching block.
+        assert!(MockTrace::default().endless().contains_address(5).unwrap());
+    }
+
+    #[test]
+    fn test_timing_default() {
+        let trace = MockTrace::with_blocks(vec![Block::new(0x1000, 0x1010)]);
+        assert_eq!(trace.block_count().unwrap(), 1);
+        assert_eq!(trace.timing(), TraceTiming::default());
+    }
+
+    #[test]
+    fn test_estimated_decode_cost() {
+        // The trace has no raw data from which to estimate the cost.
+        let trace = MockTrace::with_blocks(vec![Block::new(0x1000, 0x1010)]);
+        assert_eq!(trace.estimated_decode_cost(), Duration::from_secs(0));
+        let shared = Box::new(trace).into_shared().unwrap();
+        assert_eq!(shared.estimated_decode_cost(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let blks = vec![Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2000)];
+        let trace: Box<dyn Trace> = Box::new(MockTrace::with_blocks(blks.clone()));
+        let mut got = Vec::new();
+        for b in &trace {
+            got.push(b.unwrap());
+        }
+        assert_eq!(got, blks);
+
+        let trace_ref: &dyn Trace = &*trace;
+        assert_eq!(
+            trace_ref
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            blks
+        );
+    }
+
+    #[test]
+    fn test_write_ptxed_inputs_default() {
+        let trace = MockTrace::with_blocks(vec![]);
+        match trace.write_ptxed_inputs(&std::env::temp_dir()) {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_sync_offset_default() {
+        assert_eq!(MockTrace::with_blocks(vec![]).sync_offset(), None);
+    }
+
+    #[test]
+    fn test_write_perf_data_default() {
+        let trace = MockTrace::with_blocks(vec![]);
+        match trace.write_perf_data(&std::env::temp_dir().join("perf.data")) {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_iter_packets_default() {
+        let trace = MockTrace::with_blocks(vec![]);
+        let got = trace.iter_packets().collect::<Vec<_>>();
+        assert_eq!(got.len(), 1);
+        match got[0] {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[cfg(feature = "ptxed_verify")]
+    #[test]
+    fn test_verify_against_ptxed_default() {
+        let trace = MockTrace::with_blocks(vec![]);
+        match trace.verify_against_ptxed() {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_iter_classified_blocks_default() {
+        let blk = Block::new(0x1000, 0x1010);
+        let trace = MockTrace::with_blocks(vec![blk]);
+        let got = trace
+            .iter_classified_blocks()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, vec![ClassifiedBlock::new(blk, EdgeKind::Unknown)]);
+        assert_eq!(got[0].ip_compression(), None);
+    }
+
+    #[test]
+    fn test_decode_into_default() {
+        let (b1, b2) = (Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2010));
+        let mut out = vec![b1];
+        MockTrace::with_blocks(vec![b2, b1])
+            .decode_into(&mut out)
+            .unwrap();
+        assert_eq!(out, vec![b1, b2, b1]);
+    }
+
+    #[test]
+    fn test_decode_to_slice() {
+        let (b1, b2) = (Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2010));
+        let trace = MockTrace::with_blocks(vec![b1, b2]);
+        let mut out = [(0, 0); 3];
+        assert_eq!(trace.decode_to_slice(&mut out).unwrap(), 2);
+        assert_eq!(out, [(0x1000, 0x1010), (0x2000, 0x2010), (0, 0)]);
+
+        // A full buffer stops decoding.
+        let mut out = [(0, 0); 1];
+        assert_eq!(trace.decode_to_slice(&mut out).unwrap(), 1);
+        assert_eq!(out, [(0x1000, 0x1010)]);
+        assert_eq!(
+            MockTrace::default()
+                .endless()
+                .decode_to_slice(&mut [(0, 0); 4])
+                .unwrap(),
+            4
+        );
+        assert_eq!(trace.decode_to_slice(&mut []).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stats_default() {
+        let blk = Block::new(0x1000, 0x1010);
+        let trace = MockTrace::with_blocks(vec![blk, blk]);
+        let expect = TraceStats {
+            decoded_blocks: 2,
+            ..Default::default()
+        };
+        assert_eq!(trace.stats(), expect);
+    }
+
+    #[test]
+    fn test_block_histogram() {
+        let trace = MockTrace::with_blocks(vec![
+            Block::new(0x1000, 0x1010),
+            Block::new(0x2000, 0x2008),
+            Block::new(0x1000, 0x1010),
+            Block::new(0x1000, 0x1004),
+        ]);
+        let hist = trace.block_histogram().unwrap();
+        assert_eq!(hist.len(), 2);
+        assert_eq!(hist[&0x1000], 3);
+        assert_eq!(hist[&0x2000], 1);
+        assert!(MockTrace::with_blocks(vec![])
+            .block_histogram()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_coverage_bitmap() {
+        let (b1, b2) = (Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2008));
+        let trace = MockTrace::with_blocks(vec![b1, b2, b1, b2, b1]);
+        let map = trace.coverage_bitmap(1 << 16).unwrap();
+        assert_eq!(map.len(), 1 << 16);
+        // b1 and b2 hash to 0x100100 and 0x200200, masked to 0x100 and 0x200.
+        assert_eq!(map[0x200 ^ 0x80], 2);
+        assert_eq!(map[0x100 ^ 0x100], 2);
+        assert_eq!(map.iter().map(|&c| u64::from(c)).sum::<u64>(), 4);
+
+        // Counts wrap, as in AFL.
+        let trace = MockTrace::with_blocks(vec![b1; 257]);
+        assert_eq!(trace.coverage_bitmap(1 << 16).unwrap()[0x100 ^ 0x80], 0);
+        assert!(MockTrace::with_blocks(vec![])
+            .coverage_bitmap(8)
+            .unwrap()
+            .iter()
+            .all(|&c| c == 0));
+    }
+
+    #[test]
+    fn test_coverage_bitmap_bad_size() {
+        for &size in &[0, 100] {
+            match MockTrace::with_blocks(vec![]).coverage_bitmap(size) {
+                Err(HWTracerError::BadConfig(_)) => (),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter_ptwrites() {
+        let fup = |ipc, ip| Ok(PtPacket::Fup(Some(PacketIp { ipc, ip })));
+        let ptw = |payload, ip| Ok(PtPacket::Ptw { payload, ip });
+        let trace = MockTrace::default().packets(vec![
+            Ok(PtPacket::Psb),
+            fup(IpCompression::SignExtend48, 0x4000_1000),
+            Ok(PtPacket::PsbEnd),
+            ptw(1, true),
+            Ok(PtPacket::Tnt { count: 1, bits: 1 }),
+            fup(IpCompression::Update16, 0x2000),
+            // A PTW without an IP is skipped, as is a PTW whose FUP never arrives.
+            ptw(2, false),
+            ptw(3, true),
+            Ok(PtPacket::Tip(None)),
+            ptw(4, true),
+            fup(IpCompression::Update32, 0x5000_3000),
+            Err(HWTracerError::HWBufferOverflow),
+            ptw(5, true),
+            fup(IpCompression::Full, 0x6000),
+        ]);
+        let got = trace.iter_ptwrites().collect::<Vec<_>>();
+        assert_eq!(got.len(), 4);
+        assert_eq!(got[0].as_ref().unwrap(), &(0x4000_2000, 1));
+        assert_eq!(got[1].as_ref().unwrap(), &(0x5000_3000, 4));
+        match got[2] {
+            Err(HWTracerError::HWBufferOverflow) => (),
+            _ => panic!(),
+        }
+        assert_eq!(got[3].as_ref().unwrap(), &(0x6000, 5));
+    }
+
+    #[test]
+    fn test_offset_at_time() {
+        let trace =
+            MockTrace::default().aux_timestamps(vec![(0x100, 1000), (0x180, 2000), (0x400, 3000)]);
+        assert_eq!(trace.offset_at_time(999), None);
+        assert_eq!(trace.offset_at_time(1000), Some(0x100));
+        assert_eq!(trace.offset_at_time(2500), Some(0x180));
+        assert_eq!(trace.offset_at_time(u64::MAX), Some(0x400));
+        assert_eq!(MockTrace::with_blocks(vec![]).offset_at_time(1000), None);
+    }
+
+    #[test]
+    fn test_iter_ptwrites_unsupported() {
+        let trace = MockTrace::with_blocks(vec![]);
+        match trace.iter_ptwrites().collect::<Vec<_>>().as_slice() {
+            [Err(HWTracerError::UnsupportedOperation(_))] => (),
+            _ => panic!(),
+        }
+    }
+
+    // Check that a tracing guard hands back the trace and leaves the tracer stopped.
+    #[test]
+    fn test_guard_finish() {
+        let mut tracer = TracerBuilder::new().build().unwrap().thread_tracer();
+        let guard = tracer.start_guarded().unwrap();
+        assert!(guard.finish().is_ok());
+        match tracer.stop_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+    }
+
+    // Check that dropping a tracing guard stops the tracer.
+    #[test]
+    fn test_guard_drop() {
+        let mut tracer = TracerBuilder::new().build().unwrap().thread_tracer();
+        {
+            let _guard = tracer.start_guarded().unwrap();
+        }
+        tracer.start_tracing().unwrap();
+        tracer.stop_tracing().unwrap();
+    }
+
+    // Check that a trace can be decoded on a thread other than the one which collected it.
+    #[test]
+    fn test_decode_on_other_thread() {
+        let blks = vec![Block::new(0x1000, 0x1010), Block::new(0x2000, 0x2010)];
+        let mut tracer = DummyTracer::with_blocks(blks.clone()).thread_tracer();
+        tracer.start_tracing().unwrap();
+        let trace = tracer.stop_tracing().unwrap();
+        let got = thread::spawn(move || trace.iter_blocks().collect::<Result<Vec<_>, _>>())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(got, blks);
+    }
+}
+
 // Test helpers.
 //
 // Each struct implementing the [ThreadTracer](trait.ThreadTracer.html) trait should include tests
 // calling the following helpers.
 #[cfg(test)]
 mod test_helpers {
-    use super::{Block, HWTracerError, ThreadTracer, TracerState};
+    use super::{Block, HWTracerError, PtPacket, RawTrace, ThreadTracer, TracerState};
     use crate::Trace;
+    use std::fs::File;
+    use std::io::Write;
+    use std::iter;
     use std::slice::Iter;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
     use std::time::SystemTime;
 
     // A loop that does some work that we can use to build a trace.
@@ -136,12 +1592,116 @@ mod test_helpers {
         trace
     }
 
+    // A trace with predetermined contents, for testing the generic parts of `Trace` and the code
+    // which consumes traces. Start from `MockTrace::with_blocks()` and add whatever else the test
+    // needs with the other methods.
+    #[derive(Debug, Default)]
+    pub struct MockTrace {
+        blocks: Vec<Block>,
+        // If set, the trace has infinitely many blocks, `[0..0]`, `[1..1]` and so on, instead of
+        // `blocks`, for checking that iterators don't decode too much.
+        endless: bool,
+        overflowed: bool,
+        // The packets of the trace, or `None` if packet iteration isn't supported. Every error is
+        // reported as a `HWBufferOverflow`, since errors can't be cloned.
+        packets: Option<Vec<Result<PtPacket, HWTracerError>>>,
+        aux_timestamps: Vec<(usize, u64)>,
+    }
+
+    impl MockTrace {
+        // Make a trace consisting of `blocks`.
+        pub fn with_blocks(blocks: Vec<Block>) -> Self {
+            MockTrace {
+                blocks,
+                ..Default::default()
+            }
+        }
+
+        pub fn endless(mut self) -> Self {
+            self.endless = true;
+            self
+        }
+
+        // Mark the trace as having overflowed after its blocks.
+        pub fn overflowed(mut self) -> Self {
+            self.overflowed = true;
+            self
+        }
+
+        pub fn packets(mut self, packets: Vec<Result<PtPacket, HWTracerError>>) -> Self {
+            self.packets = Some(packets);
+            self
+        }
+
+        pub fn aux_timestamps(mut self, aux_timestamps: Vec<(usize, u64)>) -> Self {
+            self.aux_timestamps = aux_timestamps;
+            self
+        }
+    }
+
+    impl RawTrace for MockTrace {
+        fn capacity(&self) -> usize {
+            0
+        }
+
+        fn overflowed(&self) -> bool {
+            self.overflowed
+        }
+    }
+
+    impl Trace for MockTrace {
+        fn to_file(&self, _file: &mut File) {
+            unreachable!();
+        }
+
+        fn serialize(&self, _w: &mut dyn Write) -> Result<(), HWTracerError> {
+            unreachable!();
+        }
+
+        fn iter_blocks<'t: 'i, 'i>(
+            &'t self,
+        ) -> Box<dyn Iterator<Item = Result<Block, HWTracerError>> + 'i> {
+            if self.endless {
+                return Box::new((0..).map(|i| Ok(Block::new(i, i))));
+            }
+            let overflow = if self.overflowed {
+                Some(Err(HWTracerError::HWBufferOverflow))
+            } else {
+                None
+            };
+            Box::new(self.blocks.iter().cloned().map(Ok).chain(overflow))
+        }
+
+        fn iter_packets<'t: 'i, 'i>(
+            &'t self,
+        ) -> Box<dyn Iterator<Item = Result<PtPacket, HWTracerError>> + 'i> {
+            match self.packets {
+                Some(ref packets) => Box::new(packets.iter().map(|p| match p {
+                    Ok(p) => Ok(*p),
+                    Err(_) => Err(HWTracerError::HWBufferOverflow),
+                })),
+                None => Box::new(iter::once(Err(HWTracerError::UnsupportedOperation(
+                    "packet iteration is not supported by this backend".into(),
+                )))),
+            }
+        }
+
+        fn iter_aux_timestamps<'t: 'i, 'i>(
+            &'t self,
+        ) -> Box<dyn Iterator<Item = (usize, u64)> + 'i> {
+            Box::new(self.aux_timestamps.iter().copied())
+        }
+    }
+
     // Check that starting and stopping a tracer works.
     pub fn test_basic_usage<T>(mut tracer: T)
     where
         T: ThreadTracer,
     {
-        trace_closure(&mut tracer, || work_loop(500));
+        let trace = trace_closure(&mut tracer, || work_loop(500));
+        assert!(!trace.overflowed());
+        assert!(!trace.auto_stopped());
+        assert!(!trace.duty_cycled());
     }
 
     // Check that repeated usage of the same tracer works.
@@ -154,6 +1714,48 @@ mod test_helpers {
         }
     }
 
+    // The number of threads which `test_concurrent_tracing()` traces at once.
+    const CONCURRENT_THREADS: usize = 4;
+
+    // Check that tracers on several threads at once don't interfere with each other: each thread
+    // repeatedly traces itself with its own tracer (made by `new_tracer`), starting at the same
+    // time as the others, and its traces must decode without error. The threads only synchronise
+    // before anything can panic, so that a failing thread can't leave the others waiting.
+    // Even-numbered threads trace a loop of 10 iterations and odd-numbered threads one of 100.
+    // Returns the number of iterations and the number of blocks of each trace, for
+    // backend-specific checks.
+    pub fn test_concurrent_tracing<F, T>(new_tracer: F) -> Vec<(u64, usize)>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+        T: ThreadTracer,
+    {
+        let new_tracer = Arc::new(new_tracer);
+        let barrier = Arc::new(Barrier::new(CONCURRENT_THREADS));
+        let handles = (0..CONCURRENT_THREADS)
+            .map(|i| {
+                let new_tracer = Arc::clone(&new_tracer);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    let mut tracer = new_tracer();
+                    let iters = if i % 2 == 0 { 10 } else { 100 };
+                    (0..10)
+                        .map(|_| {
+                            let trace = trace_closure(&mut tracer, || work_loop(iters));
+                            assert!(!trace.overflowed());
+                            let blocks = trace.iter_blocks().collect::<Result<Vec<_>, _>>();
+                            (iters, blocks.unwrap().len())
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    }
+
     // Check that starting a tracer twice makes an appropriate error.
     pub fn test_already_started<T>(mut tracer: T)
     where
@@ -164,7 +1766,10 @@ mod test_helpers {
             Err(HWTracerError::TracerState(TracerState::Started)) => (),
             _ => panic!(),
         };
+        // The failed start leaves the tracer running.
+        assert!(tracer.is_tracing());
         tracer.stop_tracing().unwrap();
+        assert!(!tracer.is_tracing());
     }
 
     // Check that stopping an unstarted tracer makes an appropriate error.
@@ -172,10 +1777,12 @@ mod test_helpers {
     where
         T: ThreadTracer,
     {
+        assert!(!tracer.is_tracing());
         match tracer.stop_tracing() {
             Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
             _ => panic!(),
         };
+        assert!(!tracer.is_tracing());
     }
 
     // Helper to check an expected list of blocks matches what we actually got.
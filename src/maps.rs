@@ -0,0 +1,157 @@
+//! Reading the memory mappings of processes, for relating addresses to the files they came from.
+
+use crate::{errors::HWTracerError, Mapping, Maps};
+use libc::pid_t;
+use std::fs;
+use std::io;
+
+// The file describing the memory mappings of the current process.
+const SELF_MAPS_PATH: &str = "/proc/self/maps";
+
+/// Returns the named memory mappings of the current process. See `Block::module_offset()`.
+///
+/// The mappings are those at the time of the call: code loaded or unloaded afterwards (e.g. with
+/// `dlopen(3)`) isn't reflected in the result.
+pub fn self_maps() -> Result<Maps, HWTracerError> {
+    parse_maps(&fs::read_to_string(SELF_MAPS_PATH)?)
+}
+
+/// Returns the named memory mappings of the process `pid`. As for `self_maps()`, the mappings
+/// are those at the time of the call.
+pub fn process_maps(pid: pid_t) -> Result<Maps, HWTracerError> {
+    parse_maps(&fs::read_to_string(format!("/proc/{}/maps", pid))?)
+}
+
+/// Returns every memory mapping of the current process, anonymous or not, with all that
+/// `/proc/self/maps` says about each.
+#[allow(dead_code)] // Only used by the PerfPT backend, if compiled in.
+pub(crate) fn self_map_entries() -> Result<Vec<MapsEntry>, HWTracerError> {
+    parse_entries(&fs::read_to_string(SELF_MAPS_PATH)?)
+}
+
+/// A line of a `/proc/<pid>/maps` file.
+#[allow(dead_code)] // As for `self_map_entries()`.
+#[derive(Debug)]
+pub(crate) struct MapsEntry {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    /// The permissions of the mapping, e.g. `r-xp`.
+    pub(crate) perms: String,
+    pub(crate) offset: u64,
+    /// The major and minor numbers of the device holding the file.
+    pub(crate) dev: (u32, u32),
+    pub(crate) inode: u64,
+    /// The path of the file, or an empty string for an anonymous mapping.
+    pub(crate) path: String,
+}
+
+// Parse the contents of a `/proc/<pid>/maps` file.
+fn parse_entries(maps: &str) -> Result<Vec<MapsEntry>, HWTracerError> {
+    let mut entries = Vec::new();
+    for line in maps.lines() {
+        // The fields are separated by single spaces, but the path (if any) is padded.
+        let fields = line.splitn(6, ' ').collect::<Vec<_>>();
+        if fields.len() < 5 {
+            return Err(bad_line(line));
+        }
+        let mut range = fields[0].splitn(2, '-');
+        let start = u64::from_str_radix(range.next().unwrap(), 16)?;
+        let end = u64::from_str_radix(range.next().ok_or_else(|| bad_line(line))?, 16)?;
+        let mut dev = fields[3].splitn(2, ':');
+        let maj = u32::from_str_radix(dev.next().unwrap(), 16)?;
+        let min = u32::from_str_radix(dev.next().ok_or_else(|| bad_line(line))?, 16)?;
+        entries.push(MapsEntry {
+            start,
+            end,
+            perms: fields[1].to_owned(),
+            offset: u64::from_str_radix(fields[2], 16)?,
+            dev: (maj, min),
+            inode: fields[4].parse()?,
+            path: fields.get(5).map_or("", |p| p.trim_start()).to_owned(),
+        });
+    }
+    Ok(entries)
+}
+
+// Parse the contents of a `/proc/<pid>/maps` file. Anonymous mappings are skipped.
+fn parse_maps(maps: &str) -> Result<Maps, HWTracerError> {
+    let mappings = parse_entries(maps)?
+        .into_iter()
+        .filter(|e| !e.path.is_empty())
+        .map(|e| Mapping::new(e.start, e.end, e.offset, e.path))
+        .collect();
+    Ok(Maps::new(mappings))
+}
+
+fn bad_line(line: &str) -> HWTracerError {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("can't parse the maps line {:?}", line),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_entries, parse_maps, process_maps, self_maps};
+    use crate::{Block, HWTracerError};
+    use std::env;
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = parse_maps(
+            "55d000001000-55d000003000 r-xp 00001000 fd:01 1234    /usr/bin/prog\n\
+             55d000003000-55d000004000 rw-p 00000000 00:00 0 \n\
+             7ffd00000000-7ffd00002000 r-xp 00000000 00:00 0                          [vdso]\n",
+        )
+        .unwrap();
+        let paths = maps.mappings().iter().map(|m| m.path()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/usr/bin/prog", "[vdso]"]);
+        assert_eq!(
+            Block::new(0x55d000001010, 0x55d000001020).module_offset(&maps),
+            Some(("/usr/bin/prog".to_owned(), 0x1010))
+        );
+
+        match parse_maps("55d000001000 r-xp") {
+            Err(HWTracerError::Custom(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let entries = parse_entries(
+            "55d000001000-55d000003000 r-xp 00001000 fd:01 1234    /usr/bin/prog\n\
+             55d000003000-55d000004000 rw-p 00000000 00:00 0 \n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        let e = &entries[0];
+        assert_eq!(
+            (e.start, e.end, e.offset),
+            (0x55d000001000, 0x55d000003000, 0x1000)
+        );
+        assert_eq!(e.perms, "r-xp");
+        assert_eq!((e.dev, e.inode), ((0xfd, 1), 1234));
+        assert_eq!(e.path, "/usr/bin/prog");
+        assert_eq!(entries[1].path, "");
+
+        match parse_entries("55d000001000-55d000003000 r-xp 00001000 fd 1234") {
+            Err(HWTracerError::Custom(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    // Check that the code of this test is found in the test executable.
+    #[test]
+    fn test_self_maps() {
+        let addr = test_self_maps as fn() as usize as u64;
+        let exe = env::current_exe().unwrap();
+        let maps = self_maps().unwrap();
+        let (path, _) = Block::new(addr, addr).module_offset(&maps).unwrap();
+        assert_eq!(path, exe.to_str().unwrap());
+
+        let pid = unsafe { libc::getpid() };
+        assert_eq!(process_maps(pid).unwrap().find(addr), maps.find(addr));
+    }
+}
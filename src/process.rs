@@ -0,0 +1,256 @@
+//! Tracing all of the threads of the current process at once, or a child process from its start.
+
+use crate::{errors::HWTracerError, tracer_set::TracerSet, Trace, Tracer};
+use libc::{c_void, pid_t, ECANCELED, ESRCH, O_CLOEXEC};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::thread;
+
+// The procfs directory listing the threads of the current process.
+pub(crate) const TASKS_PATH: &str = "/proc/self/task";
+
+/// Traces every thread of the current process.
+///
+/// Obtained from [process_tracer](trait.Tracer.html#method.process_tracer). Only threads which
+/// exist when tracing starts are traced: threads spawned afterwards are not. Each thread gets its
+/// own (per-thread) hardware trace buffer, so the backend must support tracing threads other than
+/// the calling thread (see [thread_tracer_for](trait.Tracer.html#method.thread_tracer_for)).
+///
+/// A child process can instead be traced from its start with
+/// [spawn_traced](struct.ProcessTracer.html#method.spawn_traced).
+///
+/// Dropping a `ProcessTracer` which is still tracing stops the tracer and discards the traces.
+pub struct ProcessTracer<'a> {
+    tracer: &'a dyn Tracer,
+    // The tracers of the threads being traced, keyed by thread ID.
+    thread_tracers: TracerSet<pid_t>,
+}
+
+impl<'a> ProcessTracer<'a> {
+    pub(crate) fn new(tracer: &'a dyn Tracer) -> Self {
+        Self {
+            tracer,
+            thread_tracers: TracerSet::new(),
+        }
+    }
+
+    /// Spawn `cmd` as a child process, tracing its main thread from the first instruction of the
+    /// program it runs.
+    ///
+    /// The child waits until its tracer has been started (see
+    /// [thread_tracer_on_exec](trait.Tracer.html#method.thread_tracer_on_exec)) before calling
+    /// `execve(2)`, so none of the program's startup is missed. The returned tracer is already
+    /// tracing, and `stop_tracing()` returns the child's trace, keyed by its process ID. Threads
+    /// which the child spawns aren't traced. If the returned tracer is restarted, then it traces
+    /// the threads of the current process, as usual.
+    ///
+    /// If the tracer can't be started, then the child doesn't exec, and the tracer's error is
+    /// returned.
+    pub fn spawn_traced(
+        tracer: &'a dyn Tracer,
+        mut cmd: Command,
+    ) -> Result<(Child, Self), HWTracerError> {
+        // The child sends its process ID down one pipe, then waits for a byte down the other
+        // saying whether to go ahead with the exec. The exec closes the child's ends of the pipes.
+        let (mut pid_rx, pid_tx) = cloexec_pipe()?;
+        let (go_rx, go_tx) = cloexec_pipe()?;
+        let (pid_fd, go_fd) = (pid_tx.as_raw_fd(), go_rx.as_raw_fd());
+        unsafe {
+            cmd.pre_exec(move || {
+                // Only async-signal-safe functions may be called between fork(2) and execve(2).
+                let pid = libc::getpid();
+                let len = mem::size_of::<pid_t>();
+                if libc::write(pid_fd, &pid as *const pid_t as *const c_void, len) != len as isize {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut go = 0u8;
+                if libc::read(go_fd, &mut go as *mut u8 as *mut c_void, 1) != 1 || go == 0 {
+                    return Err(io::Error::from_raw_os_error(ECANCELED));
+                }
+                Ok(())
+            })
+        };
+
+        // `Command::spawn()` doesn't return until the child has exec'd, so the child is spawned by
+        // another thread, leaving this one to start the tracer.
+        let spawner = thread::spawn(move || {
+            let res = cmd.spawn();
+            // If the child couldn't be forked, then reading its process ID must not block forever.
+            drop(pid_tx);
+            drop(go_rx);
+            res
+        });
+
+        let mut pid_buf = [0; mem::size_of::<pid_t>()];
+        if let Err(e) = pid_rx.read_exact(&mut pid_buf) {
+            drop(go_tx);
+            spawner.join().unwrap()?;
+            return Err(e.into());
+        }
+        let pid = pid_t::from_ne_bytes(pid_buf);
+        let thr_tracer = tracer
+            .thread_tracer_on_exec(pid)
+            .and_then(|mut thr_tracer| {
+                thr_tracer.start_tracing()?;
+                Ok(thr_tracer)
+            });
+        // If the byte can't be sent, then the child sees the pipe close and doesn't exec.
+        let _ = (&go_tx).write_all(&[thr_tracer.is_ok() as u8]);
+        drop(go_tx);
+        let child = spawner.join().unwrap();
+        let thr_tracer = thr_tracer?;
+        let child = child?;
+
+        let proc_tracer = Self {
+            tracer,
+            thread_tracers: TracerSet::started(vec![(pid, thr_tracer)]),
+        };
+        Ok((child, proc_tracer))
+    }
+
+    /// Start tracing all of the threads of the current process.
+    pub fn start_tracing(&mut self) -> Result<(), HWTracerError> {
+        let tracer = self.tracer;
+        self.thread_tracers.start_tracing(
+            thread_ids,
+            |&tid| tracer.thread_tracer_for(tid),
+            // The thread exited before we could start tracing it.
+            |e| matches!(e, HWTracerError::CFailed(ESRCH)),
+        )
+    }
+
+    /// Returns `true` if the tracer has been started and not yet stopped.
+    pub fn is_tracing(&self) -> bool {
+        self.thread_tracers.is_tracing()
+    }
+
+    /// Stop tracing, returning the trace of each thread keyed by its thread ID.
+    ///
+    /// If any thread's tracer fails to stop, then the first error is returned and all of the
+    /// traces are discarded.
+    pub fn stop_tracing(&mut self) -> Result<HashMap<pid_t, Box<dyn Trace>>, HWTracerError> {
+        self.thread_tracers.stop_tracing()
+    }
+}
+
+// Makes a pipe whose file descriptors are closed on exec, returning its read and write ends.
+fn cloexec_pipe() -> Result<(File, File), HWTracerError> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// Returns the IDs of the threads of the current process.
+fn thread_ids() -> Result<Vec<pid_t>, HWTracerError> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(TASKS_PATH)? {
+        let name = entry?.file_name();
+        // procfs entries are always numeric thread IDs.
+        tids.push(name.to_string_lossy().parse::<pid_t>()?);
+    }
+    Ok(tids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProcessTracer;
+    use crate::backends::TracerBuilder;
+    use crate::{HWTracerError, ThreadTracer, Tracer, TracerState};
+    use libc::pid_t;
+    use std::env;
+    use std::process::{self, Command};
+    use std::sync::mpsc;
+    use std::thread;
+
+    // A tracer which can't trace from exec.
+    struct NoExecTracer;
+
+    impl Tracer for NoExecTracer {
+        fn thread_tracer(&self) -> Box<dyn ThreadTracer> {
+            unreachable!();
+        }
+    }
+
+    // Check that all of the process' threads are traced.
+    #[test]
+    fn test_process_tracer() {
+        let (tid_tx, tid_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let worker = thread::spawn(move || {
+            tid_tx
+                .send(unsafe { libc::syscall(libc::SYS_gettid) } as pid_t)
+                .unwrap();
+            stop_rx.recv().unwrap();
+        });
+        let worker_tid = tid_rx.recv().unwrap();
+        let my_tid = unsafe { libc::syscall(libc::SYS_gettid) } as pid_t;
+
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let mut proc_tracer = tracer.process_tracer();
+        proc_tracer.start_tracing().unwrap();
+        let traces = proc_tracer.stop_tracing().unwrap();
+        stop_tx.send(()).unwrap();
+        worker.join().unwrap();
+
+        assert!(traces.contains_key(&my_tid));
+        assert!(traces.contains_key(&worker_tid));
+    }
+
+    #[test]
+    fn test_process_tracer_states() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let mut proc_tracer = tracer.process_tracer();
+        match proc_tracer.stop_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+        assert!(!proc_tracer.is_tracing());
+        proc_tracer.start_tracing().unwrap();
+        match proc_tracer.start_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Started)) => (),
+            _ => panic!(),
+        }
+        assert!(proc_tracer.is_tracing());
+    }
+
+    #[test]
+    fn test_spawn_traced() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let (mut child, mut proc_tracer) =
+            ProcessTracer::spawn_traced(&*tracer, Command::new("true")).unwrap();
+        assert!(proc_tracer.is_tracing());
+        assert!(child.wait().unwrap().success());
+        let traces = proc_tracer.stop_tracing().unwrap();
+        assert_eq!(traces.len(), 1);
+        assert!(traces.contains_key(&(child.id() as pid_t)));
+    }
+
+    #[test]
+    fn test_spawn_traced_bad_command() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        match ProcessTracer::spawn_traced(&*tracer, Command::new("/does/not/exist")) {
+            Err(HWTracerError::Custom(_)) => (),
+            _ => panic!(),
+        };
+    }
+
+    // Check that the child doesn't run if its tracer can't be started.
+    #[test]
+    fn test_spawn_traced_unsupported() {
+        let path = env::temp_dir().join(format!("hwtracer-spawn-{}", process::id()));
+        let mut cmd = Command::new("touch");
+        cmd.arg(&path);
+        match ProcessTracer::spawn_traced(&NoExecTracer, cmd) {
+            Err(HWTracerError::UnsupportedOperation(_)) => (),
+            _ => panic!(),
+        }
+        assert!(!path.exists());
+    }
+}
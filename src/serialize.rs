@@ -0,0 +1,192 @@
+//! A stable on-disk format for traces.
+//!
+//! A serialized trace starts with a common header:
+//!
+//!  - 8 bytes: the magic string `HWTRACER`.
+//!  - 2 bytes: the format version, little-endian.
+//!  - 1 byte: the backend which collected the trace.
+//!
+//! The remainder of the file is backend-specific and is documented alongside each backend's
+//! implementation of [Trace::serialize](../trait.Trace.html#tymethod.serialize). All integers are
+//! stored little-endian.
+//!
+//! Serialized traces contain only what the backend collected, not the code that was traced.
+//! Re-decoding a trace requires the same code to be available to the decoder.
+//!
+//! With the `compression` feature, traces can also be stored compressed: a compressed trace is a
+//! zstd frame whose contents are the format described above.
+
+#[cfg(coresight)]
+use crate::backends::coresight::CoreSightTrace;
+#[cfg(lbr)]
+use crate::backends::lbr::LBRTrace;
+#[cfg(perf_pt)]
+use crate::backends::perf_pt::PerfPTTrace;
+use crate::backends::{dummy::DummyTrace, BackendKind};
+use crate::{errors::HWTracerError, Trace};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"HWTRACER";
+const FORMAT_VERSION: u16 = 1;
+
+// The backend identifiers recorded in the header.
+const BACKEND_DUMMY: u8 = 0;
+const BACKEND_PERF_PT: u8 = 1;
+const BACKEND_CORESIGHT: u8 = 2;
+const BACKEND_LBR: u8 = 3;
+
+/// Write the common header for a trace collected by the backend `kind`.
+pub(crate) fn write_header(w: &mut dyn Write, kind: BackendKind) -> Result<(), HWTracerError> {
+    let backend = match kind {
+        BackendKind::Dummy => BACKEND_DUMMY,
+        BackendKind::PerfPT => BACKEND_PERF_PT,
+        BackendKind::CoreSight => BACKEND_CORESIGHT,
+        BackendKind::LBR => BACKEND_LBR,
+    };
+    w.write_all(MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    w.write_all(&[backend])?;
+    Ok(())
+}
+
+pub(crate) fn read_u8(r: &mut dyn Read) -> Result<u8, HWTracerError> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+#[allow(dead_code)] // Not all backends are compiled in.
+pub(crate) fn read_u64(r: &mut dyn Read) -> Result<u64, HWTracerError> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn bad_format(msg: &str) -> HWTracerError {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Read back a trace written by [Trace::serialize](../trait.Trace.html#tymethod.serialize).
+///
+/// An error is returned if the data is malformed, or if the backend which collected the trace was
+/// not compiled in to hwtracer.
+pub fn deserialize_trace(r: &mut dyn Read) -> Result<Box<dyn Trace>, HWTracerError> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(bad_format("not a hwtracer trace"));
+    }
+
+    let mut version = [0; 2];
+    r.read_exact(&mut version)?;
+    if u16::from_le_bytes(version) != FORMAT_VERSION {
+        return Err(bad_format("unsupported trace format version"));
+    }
+
+    match read_u8(r)? {
+        BACKEND_DUMMY => Ok(Box::new(DummyTrace::deserialize(r)?)),
+        BACKEND_PERF_PT => {
+            #[cfg(perf_pt)]
+            return Ok(Box::new(PerfPTTrace::deserialize(r)?));
+            #[cfg(not(perf_pt))]
+            Err(HWTracerError::not_compiled_in(BackendKind::PerfPT))
+        }
+        BACKEND_CORESIGHT => {
+            #[cfg(coresight)]
+            return Ok(Box::new(CoreSightTrace::deserialize(r)?));
+            #[cfg(not(coresight))]
+            Err(HWTracerError::not_compiled_in(BackendKind::CoreSight))
+        }
+        BACKEND_LBR => {
+            #[cfg(lbr)]
+            return Ok(Box::new(LBRTrace::deserialize(r)?));
+            #[cfg(not(lbr))]
+            Err(HWTracerError::not_compiled_in(BackendKind::LBR))
+        }
+        _ => Err(bad_format("unknown backend")),
+    }
+}
+
+/// Read back a trace written by
+/// [Trace::serialize_compressed](../trait.Trace.html#method.serialize_compressed). Errors are as
+/// for `deserialize_trace()`, and data which isn't zstd-compressed is an error too.
+#[cfg(feature = "compression")]
+pub fn deserialize_compressed(r: &mut dyn Read) -> Result<Box<dyn Trace>, HWTracerError> {
+    deserialize_trace(&mut zstd::stream::read::Decoder::new(r)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::deserialize_trace;
+    use crate::backends::TracerBuilder;
+    use crate::HWTracerError;
+
+    // Check that a trace survives a round trip through the on-disk format.
+    #[test]
+    fn test_round_trip() {
+        let mut tracer = TracerBuilder::new()
+            .dummy()
+            .build()
+            .unwrap()
+            .thread_tracer();
+        tracer.start_tracing().unwrap();
+        let trace = tracer.stop_tracing().unwrap();
+
+        let mut buf = Vec::new();
+        trace.serialize(&mut buf).unwrap();
+        let trace2 = deserialize_trace(&mut buf.as_slice()).unwrap();
+        assert_eq!(trace2.iter_blocks().count(), 0);
+        assert!(!trace2.overflowed());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_round_trip_compressed() {
+        use super::deserialize_compressed;
+        use crate::backends::dummy::DummyTracer;
+        use crate::{Block, Tracer};
+
+        let blocks = vec![Block::new(0x1000, 0x1010); 1000];
+        let mut tracer = DummyTracer::with_blocks(blocks.clone()).thread_tracer();
+        tracer.start_tracing().unwrap();
+        let trace = tracer.stop_tracing().unwrap();
+
+        let mut plain = Vec::new();
+        trace.serialize(&mut plain).unwrap();
+        let mut buf = Vec::new();
+        trace.serialize_compressed(&mut buf, 3).unwrap();
+        assert!(buf.len() < plain.len() / 10);
+        let trace2 = deserialize_compressed(&mut buf.as_slice()).unwrap();
+        let got = trace2.iter_blocks().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(got, blocks);
+
+        // An uncompressed trace isn't accepted.
+        assert!(deserialize_compressed(&mut plain.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        match deserialize_trace(&mut &b"NOTATRACE\x01\x00\x00"[..]) {
+            Err(HWTracerError::Custom(e)) => assert_eq!(e.to_string(), "not a hwtracer trace"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_bad_version() {
+        match deserialize_trace(&mut &b"HWTRACER\xff\xff\x00"[..]) {
+            Err(HWTracerError::Custom(e)) => {
+                assert_eq!(e.to_string(), "unsupported trace format version")
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        match deserialize_trace(&mut &b"HWTR"[..]) {
+            Err(HWTracerError::Custom(_)) => (),
+            _ => panic!(),
+        }
+    }
+}
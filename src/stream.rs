@@ -0,0 +1,49 @@
+//! Decoding blocks whilst tracing is still running.
+
+use crate::{errors::HWTracerError, Block};
+
+/// The backend-specific part of a `BlockStream`.
+pub(crate) trait BlockSource: Iterator<Item = Result<Block, HWTracerError>> {
+    /// Stop collecting trace data. Blocks already collected remain available from the iterator.
+    fn stop_tracing(&mut self) -> Result<(), HWTracerError>;
+}
+
+/// Blocks decoded from a trace whilst it is still being collected.
+///
+/// Obtained from [start_streaming](trait.ThreadTracer.html#method.start_streaming). Iterating
+/// yields the blocks of the trace data collected so far and then `None`, but this is not the end
+/// of the stream: once the traced thread has executed more code, calling `next()` again yields
+/// more blocks. After [stop_tracing](#method.stop_tracing) is called, the stream ends once the
+/// remaining blocks have been yielded.
+///
+/// An overflow within the trace (`HWTracerError::Overflow`) and a gap in tracing
+/// (`HWTracerError::TracingDisabled`) are yielded as errors, but the stream carries on past them
+/// with the blocks that follow. Any other error ends the stream.
+///
+/// Trace data is freed once it has been decoded, so a long-running trace needs only as much
+/// memory as the trace data collected between calls to `next()`.
+///
+/// Dropping a `BlockStream` which is still tracing stops the tracer and discards the remaining
+/// blocks.
+pub struct BlockStream<'a> {
+    source: Box<dyn BlockSource + 'a>,
+}
+
+impl<'a> BlockStream<'a> {
+    pub(crate) fn new(source: Box<dyn BlockSource + 'a>) -> Self {
+        Self { source }
+    }
+
+    /// Stop tracing. The blocks not yet consumed can still be iterated over.
+    pub fn stop_tracing(&mut self) -> Result<(), HWTracerError> {
+        self.source.stop_tracing()
+    }
+}
+
+impl<'a> Iterator for BlockStream<'a> {
+    type Item = Result<Block, HWTracerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.source.next()
+    }
+}
@@ -0,0 +1,205 @@
+//! Annotating blocks with symbol and source information supplied by the user.
+
+use crate::Block;
+#[cfg(feature = "addr2line")]
+use crate::{errors::HWTracerError, self_maps, Maps};
+#[cfg(feature = "addr2line")]
+use addr2line::object::{self, Object, ObjectSegment, SymbolKind};
+use std::path::PathBuf;
+#[cfg(feature = "addr2line")]
+use std::{env, fs};
+
+/// A block paired with the symbol containing its first instruction, if known.
+pub type SymbolizedBlock = (Block, Option<SymbolInfo>);
+
+/// A block paired with the source file and line of its first instruction, if known.
+pub type SourceBlock = (Block, Option<(PathBuf, u32)>);
+
+/// Resolves addresses to symbols. See `Trace::iter_symbolized_blocks()`.
+///
+/// How addresses are resolved (e.g. using debug info or `dladdr(3)`) is up to the implementor.
+/// Closures of type `Fn(u64) -> Option<(String, u64)>` implement this trait, as does
+/// `SelfSymbolizer` (with the `addr2line` feature).
+pub trait Symbolizer {
+    /// Returns the name of the symbol containing `addr` and the offset of `addr` from the start
+    /// of that symbol, or `None` if `addr` can't be resolved.
+    fn symbolize(&self, addr: u64) -> Option<(String, u64)>;
+}
+
+impl<F> Symbolizer for F
+where
+    F: Fn(u64) -> Option<(String, u64)>,
+{
+    fn symbolize(&self, addr: u64) -> Option<(String, u64)> {
+        self(addr)
+    }
+}
+
+/// Maps addresses to source locations. See `Trace::iter_source_blocks()`.
+///
+/// The implementor decides how addresses are mapped (e.g. using the DWARF line tables of the
+/// traced code). Closures of type `Fn(u64) -> Option<(PathBuf, u32)>` implement this trait, as
+/// does `SelfSymbolizer` (with the `addr2line` feature).
+pub trait SourceMapper {
+    /// Returns the source file and line number of the instruction at `addr`, or `None` if `addr`
+    /// can't be mapped.
+    fn lookup(&self, addr: u64) -> Option<(PathBuf, u32)>;
+}
+
+impl<F> SourceMapper for F
+where
+    F: Fn(u64) -> Option<(PathBuf, u32)>,
+{
+    fn lookup(&self, addr: u64) -> Option<(PathBuf, u32)> {
+        self(addr)
+    }
+}
+
+/// The symbol containing the first instruction of a block.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SymbolInfo {
+    name: String,
+    offset: u64,
+}
+
+impl SymbolInfo {
+    /// Creates a new symbol annotation.
+    pub fn new(name: String, offset: u64) -> Self {
+        Self { name, offset }
+    }
+
+    /// Returns the name of the symbol.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the offset of the block's first instruction from the start of the symbol.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/// Resolves addresses in the executable of the current process, using its symbol table and
+/// DWARF line tables. Only available with the `addr2line` feature.
+///
+/// This is both a `Symbolizer` and a `SourceMapper`, so that traces of the current process can be
+/// annotated without further setup:
+///
+/// ```no_run
+/// # use hwtracer::{SelfSymbolizer, Trace};
+/// # let trace: Box<dyn Trace> = unimplemented!();
+/// let sym = SelfSymbolizer::new().unwrap();
+/// for (block, info) in trace.iter_symbolized_blocks(&sym).map(Result::unwrap) {
+///     println!("{} {}", block, info.as_ref().map_or("??", |i| i.name()));
+/// }
+/// ```
+///
+/// Symbol names are as they appear in the symbol table, i.e. mangled. Addresses outside the
+/// executable (e.g. in shared objects) aren't resolved.
+#[cfg(feature = "addr2line")]
+pub struct SelfSymbolizer {
+    // The mappings of the executable in the current process.
+    maps: Maps,
+    // The file offset, size and link-time address of each loadable segment of the executable.
+    segments: Vec<(u64, u64, u64)>,
+    // The link-time address, size and name of each function symbol, sorted by address.
+    symbols: Vec<(u64, u64, String)>,
+    ctx: addr2line::ObjectContext,
+}
+
+#[cfg(feature = "addr2line")]
+impl SelfSymbolizer {
+    /// Read the symbols and debug info of the executable of the current process.
+    pub fn new() -> Result<Self, HWTracerError> {
+        let exe = env::current_exe()?;
+        let data = fs::read(&exe)?;
+        let file = object::File::parse(&data).map_err(|e| HWTracerError::Custom(e.into()))?;
+        let ctx = addr2line::Context::new(&file).map_err(|e| HWTracerError::Custom(e.into()))?;
+
+        let exe = exe.to_string_lossy();
+        let maps = Maps::new(
+            self_maps()?
+                .mappings()
+                .iter()
+                .filter(|m| m.path() == exe)
+                .cloned()
+                .collect(),
+        );
+        let segments = file
+            .segments()
+            .map(|seg| {
+                let (offset, size) = seg.file_range();
+                (offset, size, seg.address())
+            })
+            .collect();
+        let mut symbols = file
+            .symbols()
+            .filter(|(_, sym)| sym.kind() == SymbolKind::Text)
+            .filter_map(|(_, sym)| Some((sym.address(), sym.size(), sym.name()?.to_owned())))
+            .collect::<Vec<_>>();
+        symbols.sort();
+        Ok(Self {
+            maps,
+            segments,
+            symbols,
+            ctx,
+        })
+    }
+
+    // Returns the link-time address of the code at `addr` in the current process, or `None` if
+    // `addr` isn't in the executable.
+    fn link_addr(&self, addr: u64) -> Option<u64> {
+        let file_offset = self.maps.find(addr)?.file_offset(addr);
+        self.segments
+            .iter()
+            .find(|&&(offset, size, _)| file_offset >= offset && file_offset - offset < size)
+            .map(|&(offset, _, vaddr)| vaddr + (file_offset - offset))
+    }
+}
+
+#[cfg(feature = "addr2line")]
+impl Symbolizer for SelfSymbolizer {
+    fn symbolize(&self, addr: u64) -> Option<(String, u64)> {
+        let addr = self.link_addr(addr)?;
+        // The last symbol starting at or before `addr`.
+        let i = match self.symbols.binary_search_by_key(&addr, |s| s.0) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (start, size, ref name) = self.symbols[i];
+        if addr - start < size.max(1) {
+            Some((name.clone(), addr - start))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "addr2line")]
+impl SourceMapper for SelfSymbolizer {
+    fn lookup(&self, addr: u64) -> Option<(PathBuf, u32)> {
+        let loc = self.ctx.find_location(self.link_addr(addr)?).ok()??;
+        Some((PathBuf::from(loc.file?), loc.line?))
+    }
+}
+
+#[cfg(all(feature = "addr2line", test))]
+mod tests {
+    use super::{SelfSymbolizer, SourceMapper, Symbolizer};
+
+    // Check that the code of this test is resolved to this test, and to this file.
+    #[test]
+    fn test_self_symbolizer() {
+        let sym = SelfSymbolizer::new().unwrap();
+        let addr = test_self_symbolizer as fn() as usize as u64;
+        let (name, offset) = sym.symbolize(addr).unwrap();
+        assert!(name.contains("test_self_symbolizer"));
+        assert_eq!(offset, 0);
+        let (path, _) = sym.lookup(addr).unwrap();
+        assert!(path.ends_with("symbolize.rs"));
+
+        assert_eq!(sym.symbolize(0), None);
+        assert_eq!(sym.lookup(0), None);
+    }
+}
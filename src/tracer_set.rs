@@ -0,0 +1,156 @@
+//! Thread tracers which are started and stopped together, e.g. those of a `ProcessTracer`.
+
+use crate::{errors::HWTracerError, ThreadTracer, Trace, TracerState};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// A set of thread tracers, each identified by a key (e.g. a thread ID or a CPU number). Dropping
+// the set stops any tracers which are still running and discards their traces.
+pub(crate) struct TracerSet<K> {
+    tracers: Vec<(K, Box<dyn ThreadTracer>)>,
+    state: TracerState,
+}
+
+impl<K: Eq + Hash> TracerSet<K> {
+    // Makes an empty set, which isn't tracing.
+    pub(crate) fn new() -> Self {
+        Self {
+            tracers: Vec::new(),
+            state: TracerState::Stopped,
+        }
+    }
+
+    // Makes a set of tracers which have already been started.
+    pub(crate) fn started(tracers: Vec<(K, Box<dyn ThreadTracer>)>) -> Self {
+        Self {
+            tracers,
+            state: TracerState::Started,
+        }
+    }
+
+    // Starts a tracer made by `make` for each of the keys returned by `keys`. A key is skipped if
+    // its tracer can't be made or started because of an error for which `skip` returns `true`.
+    // Any other error stops the tracers started so far and is returned.
+    pub(crate) fn start_tracing<I, M, S>(
+        &mut self,
+        keys: I,
+        mut make: M,
+        skip: S,
+    ) -> Result<(), HWTracerError>
+    where
+        I: FnOnce() -> Result<Vec<K>, HWTracerError>,
+        M: FnMut(&K) -> Result<Box<dyn ThreadTracer>, HWTracerError>,
+        S: Fn(&HWTracerError) -> bool,
+    {
+        if self.state == TracerState::Started {
+            return Err(TracerState::Started.as_error());
+        }
+
+        for key in keys()? {
+            let res = make(&key).and_then(|mut tracer| {
+                tracer.start_tracing()?;
+                Ok(tracer)
+            });
+            match res {
+                Ok(tracer) => self.tracers.push((key, tracer)),
+                Err(ref e) if skip(e) => (),
+                Err(e) => {
+                    // Dropping the tracers started so far stops them.
+                    self.tracers.clear();
+                    return Err(e);
+                }
+            }
+        }
+        self.state = TracerState::Started;
+        Ok(())
+    }
+
+    // Returns `true` if the set has been started and not yet stopped.
+    pub(crate) fn is_tracing(&self) -> bool {
+        self.state == TracerState::Started
+    }
+
+    // Stops every tracer, returning their traces by key. If any tracer fails to stop, then the
+    // first error is returned and all of the traces are discarded.
+    pub(crate) fn stop_tracing(&mut self) -> Result<HashMap<K, Box<dyn Trace>>, HWTracerError> {
+        if self.state == TracerState::Stopped {
+            return Err(TracerState::Stopped.as_error());
+        }
+        self.state = TracerState::Stopped;
+
+        let mut traces = HashMap::new();
+        let mut first_err = None;
+        for (key, mut tracer) in self.tracers.drain(..) {
+            match tracer.stop_tracing() {
+                Ok(trace) => {
+                    traces.insert(key, trace);
+                }
+                Err(e) => {
+                    if first_err.is_none() {
+                        first_err = Some(e);
+                    }
+                }
+            }
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(traces),
+        }
+    }
+}
+
+impl<K> Drop for TracerSet<K> {
+    fn drop(&mut self) {
+        for (_, mut tracer) in self.tracers.drain(..) {
+            // There's nobody to report an error to, and the trace is unwanted anyway.
+            let _ = tracer.stop_tracing();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TracerSet;
+    use crate::backends::TracerBuilder;
+    use crate::{HWTracerError, TracerState};
+
+    // Check that a key whose tracer fails is skipped only if requested, and that otherwise the
+    // error is returned and nothing is traced.
+    #[test]
+    fn test_start_skip() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let make = |k: &usize| match *k {
+            1 => Err(HWTracerError::Unknown),
+            _ => Ok(tracer.thread_tracer()),
+        };
+
+        let mut set = TracerSet::new();
+        set.start_tracing(|| Ok(vec![0, 1, 2]), make, |_| true)
+            .unwrap();
+        let traces = set.stop_tracing().unwrap();
+        assert_eq!(traces.len(), 2);
+        assert!(!traces.contains_key(&1));
+
+        let mut set = TracerSet::new();
+        match set.start_tracing(|| Ok(vec![0, 1, 2]), make, |_| false) {
+            Err(HWTracerError::Unknown) => (),
+            _ => panic!(),
+        }
+        assert!(!set.is_tracing());
+    }
+
+    // Check that stopping the set fails if any of its tracers fails to stop.
+    #[test]
+    fn test_stop_error() {
+        let tracer = TracerBuilder::new().dummy().build().unwrap();
+        let mut started = tracer.thread_tracer();
+        started.start_tracing().unwrap();
+        // A tracer which was never started fails to stop.
+        let mut set = TracerSet::started(vec![(0, tracer.thread_tracer()), (1, started)]);
+        match set.stop_tracing() {
+            Err(HWTracerError::TracerState(TracerState::Stopped)) => (),
+            _ => panic!(),
+        }
+        assert!(!set.is_tracing());
+    }
+}